@@ -0,0 +1,164 @@
+//! Access log (`access_log`): one JSON line when a session opens and one when it closes, written
+//! to a file or an inherited fd regardless of the tracing log level.
+//!
+//! Records share the session's `id`, so an auditor can pair them, and a session still open shows
+//! up as an `open` without its `close`.
+//!
+//! The same records are streamed to [`follow`]ers (`pfwd tail` on the admin socket) as they are
+//! written, whether or not a file is configured.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::fd::{FromRawFd, RawFd};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, bail};
+use nix::fcntl::{FcntlArg, fcntl};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tracing::warn;
+
+/// Records a follower may fall behind by before it misses some.
+const FOLLOW_BACKLOG: usize = 1024;
+
+static LOG: OnceLock<Mutex<File>> = OnceLock::new();
+
+static FOLLOWERS: LazyLock<broadcast::Sender<Arc<Followed>>> =
+    LazyLock::new(|| broadcast::channel(FOLLOW_BACKLOG).0);
+
+/// A record as written, with what followers filter on.
+pub struct Followed {
+    pub label: String,
+    pub peer: Option<String>,
+    /// The JSON record, without its newline.
+    pub line: String,
+}
+
+/// Receive every record written from now on.
+pub fn follow() -> broadcast::Receiver<Arc<Followed>> {
+    FOLLOWERS.subscribe()
+}
+
+/// Where records go: a file appended to, or a descriptor pfwd was started with.
+pub enum Destination {
+    Path(String),
+    Fd(RawFd),
+}
+
+impl Destination {
+    /// `fd:N` for an inherited descriptor, anything else is a path.
+    pub fn parse(value: &str) -> Result<Self> {
+        if let Some(fd) = value.strip_prefix("fd:") {
+            let fd = fd
+                .parse::<RawFd>()
+                .with_context(|| format!("invalid access_log fd {value}"))?;
+            if fd < 0 {
+                bail!("invalid access_log fd {value}");
+            }
+            return Ok(Self::Fd(fd));
+        }
+        if value.is_empty() {
+            bail!("`access_log` must not be empty");
+        }
+        Ok(Self::Path(value.to_string()))
+    }
+}
+
+/// Open the access log for the rest of the process; sessions before this are not logged.
+pub fn init(destination: &str) -> Result<()> {
+    let file = match Destination::parse(destination)? {
+        Destination::Path(path) => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("failed to open access log {path}"))?,
+        Destination::Fd(fd) => {
+            fcntl(fd, FcntlArg::F_GETFD)
+                .with_context(|| format!("access log fd {fd} is not open"))?;
+            // SAFETY: the descriptor is open, and nothing else in pfwd owns an inherited fd.
+            unsafe { File::from_raw_fd(fd) }
+        }
+    };
+    if LOG.set(Mutex::new(file)).is_err() {
+        bail!("access log already open");
+    }
+    Ok(())
+}
+
+/// A session was accepted.
+#[derive(Serialize)]
+pub struct Open<'a> {
+    pub id: u64,
+    pub label: &'a str,
+    pub peer: Option<&'a str>,
+    pub namespace: Option<&'a str>,
+}
+
+/// A session ended; `reason` is `eof`, `rejected`, or the error that ended it.
+#[derive(Serialize)]
+pub struct Close<'a> {
+    pub id: u64,
+    pub label: &'a str,
+    pub peer: Option<&'a str>,
+    pub namespace: Option<&'a str>,
+    pub target: &'a str,
+    pub duration_ms: u64,
+    pub bytes_client_to_target: u64,
+    pub bytes_target_to_client: u64,
+    pub reason: &'a str,
+}
+
+#[derive(Serialize)]
+struct Record<'a, T> {
+    time_unix_ms: u64,
+    event: &'a str,
+    #[serde(flatten)]
+    fields: T,
+}
+
+pub fn open(record: Open) {
+    write("open", record.label, record.peer, &record);
+}
+
+pub fn close(record: Close) {
+    write("close", record.label, record.peer, &record);
+}
+
+fn write<T: Serialize>(event: &str, label: &str, peer: Option<&str>, fields: T) {
+    let log = LOG.get();
+    if log.is_none() && FOLLOWERS.receiver_count() == 0 {
+        return;
+    }
+    let time_unix_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let mut line = match serde_json::to_vec(&Record {
+        time_unix_ms,
+        event,
+        fields,
+    }) {
+        Ok(line) => line,
+        Err(err) => {
+            warn!(error = %err, "failed to encode access log record");
+            return;
+        }
+    };
+    if FOLLOWERS.receiver_count() > 0 {
+        let _ = FOLLOWERS.send(Arc::new(Followed {
+            label: label.to_string(),
+            peer: peer.map(str::to_string),
+            line: String::from_utf8_lossy(&line).into_owned(),
+        }));
+    }
+    let Some(log) = log else {
+        return;
+    };
+    line.push(b'\n');
+    // One write per record, so lines stay whole when the fd is shared with other writers.
+    let mut file = log.lock().expect("access log poisoned");
+    if let Err(err) = file.write_all(&line) {
+        warn!(error = %err, "failed to write access log record");
+    }
+}
@@ -0,0 +1,80 @@
+//! Typed `host:port` parsing for `listen` and `target` addresses.
+//!
+//! Addresses are checked at config load so a missing port, an unbracketed IPv6 literal, or a port
+//! out of range fails at startup instead of at the first bind or connection.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use anyhow::{Context, Result, bail};
+
+/// `ip:port`, `[ipv6]:port`, or `hostname:port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostPort {
+    Ip(SocketAddr),
+    Name { host: String, port: u16 },
+}
+
+impl HostPort {
+    /// The socket address when the host is an IP literal, so no lookup is needed.
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            HostPort::Ip(addr) => Some(*addr),
+            HostPort::Name { .. } => None,
+        }
+    }
+}
+
+impl FromStr for HostPort {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(rest) = s.strip_prefix('[') {
+            let (host, tail) = rest
+                .split_once(']')
+                .with_context(|| format!("unterminated IPv6 bracket in {s}"))?;
+            let ip: IpAddr = host
+                .parse()
+                .with_context(|| format!("{host} in {s} is not an IPv6 address"))?;
+            let port = tail
+                .strip_prefix(':')
+                .with_context(|| format!("{s} is missing a port (expected [{host}]:<port>)"))?;
+            return Ok(HostPort::Ip(SocketAddr::new(ip, parse_port(port, s)?)));
+        }
+        let Some((host, port)) = s.rsplit_once(':') else {
+            bail!("{s} is missing a port (expected host:port)");
+        };
+        if host.contains(':') {
+            bail!("IPv6 address in {s} needs brackets, e.g. [::1]:22");
+        }
+        if host.is_empty() {
+            bail!("{s} is missing a host");
+        }
+        let port = parse_port(port, s)?;
+        Ok(match host.parse::<IpAddr>() {
+            Ok(ip) => HostPort::Ip(SocketAddr::new(ip, port)),
+            Err(_) => HostPort::Name {
+                host: host.to_string(),
+                port,
+            },
+        })
+    }
+}
+
+impl fmt::Display for HostPort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostPort::Ip(addr) => write!(f, "{addr}"),
+            HostPort::Name { host, port } => write!(f, "{host}:{port}"),
+        }
+    }
+}
+
+fn parse_port(port: &str, addr: &str) -> Result<u16> {
+    if port.is_empty() {
+        bail!("{addr} is missing a port");
+    }
+    port.parse()
+        .with_context(|| format!("invalid port `{port}` in {addr} (expected 0-65535)"))
+}
@@ -0,0 +1,479 @@
+//! The admin socket (`admin_socket`): a Unix stream socket on which `pfwd top` and other local
+//! tools ask the running daemon what it is doing, and tell it to reload, drain, or stop.
+//!
+//! It speaks newline-delimited JSON. Each request is one object naming its `command`, e.g.
+//! `{"command": "status"}`, and is answered by one line: the result, or `{"error": "..."}`. A
+//! connection may send any number of requests. The socket is created with mode 0600, so only the
+//! user pfwd runs as can connect; `admin_socket_mode` and `admin_socket_owner` can let a group in,
+//! but never other users.
+//!
+//! Commands:
+//! - `status`: the same snapshot as `status_file`.
+//! - `forwards`: each forward's state, as in `status`.
+//! - `sessions`: the open sessions, as in `status`.
+//! - `stats`: each leg's counters, as in `status`.
+//! - `reload`: re-read the configuration and apply what changed to the forwards; answers with the
+//!   labels of those `added`, `removed`, and `restarted`, and how many were `unchanged`.
+//! - `drain`: stop accepting on every forward and exit once the open sessions have ended, or after
+//!   `timeout_secs`; answers with the number of `sessions` still open. With `label` or `selector`,
+//!   only the forwards picked stop accepting, and are removed once their sessions have ended; after
+//!   `timeout_secs`, those left are killed. Answers with their `labels` too.
+//! - `shutdown`: exit now, as on Ctrl-C.
+//! - `log_level`: set the log filter to `filter`, given as for `--log-level`, e.g.
+//!   `info,pfwd::pipeline::tcp=debug`; answers with the `filter` in effect, which is all it does
+//!   without one.
+//! - `kill`: kill the open session with `session_id`, as logged, or numbered `id`, as listed by
+//!   `sessions`, closing both its sockets; answers with the number of `sessions_killed`.
+//! - `pause`: stop accepting on the forwards with `label`, leaving their listeners bound, so new
+//!   connections wait in the accept backlog; with `reject`, they are accepted and closed instead.
+//!   Answers with the `labels` paused.
+//! - `resume`: accept on the forwards with `label` again; answers with the `labels` resumed.
+//! - `add`: start the `forward` given in the `--forward` key=value grammar, with `[defaults]`
+//!   applied and the same checks as a configured forward. It must have a `label` no running forward
+//!   has, unless `replace` is set, which stops those first. Answers once its listeners are bound,
+//!   or with the error that kept them from binding.
+//! - `remove`: stop the forwards with `label`. Their open sessions carry on until they end, unless
+//!   `kill_sessions` is set; answers with their `labels`, the number of `forwards` stopped, and
+//!   `sessions_killed`.
+//! - `capture`: start a packet capture, as on `SIGUSR2`, of the forwards with `label`, or of every
+//!   forward without one; answers with the `captures` started, by `label`, `namespace`, and `path`,
+//!   and those `failed`, with their `error`. Needs a `capture_dir`.
+//! - `tail`: from then on, every [`access_log`](crate::access_log) record as its own line, for as
+//!   long as the connection stays open; `label` and `peer` keep only matching ones. A client that
+//!   falls behind gets `{"event": "lagged", "missed": N}` in place of what it missed.
+//!
+//! In place of `label`, `drain`, `pause`, `resume`, `remove`, and `capture` take a `selector`: `label=<name>`,
+//! or `label~=<pattern>` with `*` and `?` wildcards, e.g. `label~=qdhcp-*` to act on every forward
+//! of one tenant at once.
+//!
+//! `reload`, `drain`, `shutdown`, `add`, `remove`, and `capture` are not available under `pfwd exec`, whose
+//! forwards live as long as its command. Forwards added at runtime are gone after a `reload` unless
+//! the configuration has them too.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, bail};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use serde_with::{DisplayFromStr, PickFirst, serde_as};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader, Lines};
+use tokio::net::UnixStream;
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tracing::{debug, info};
+
+use crate::access_log::{self, Followed};
+use crate::config::{ForwardInline, Owner, UdsType};
+use crate::forward::{Control, ControlTx};
+use crate::pipeline::pause::{self, Pause};
+use crate::pipeline::{ShutdownRx, session};
+use crate::selector::Selector;
+use crate::uds::{self, UdsConnection};
+use crate::{log_level, metrics, status};
+
+/// Where the client subcommands look for the socket unless told otherwise.
+pub const DEFAULT_SOCKET: &str = "/run/pfwd/admin.sock";
+
+/// Mode the socket is created with unless `admin_socket_mode` says otherwise.
+const DEFAULT_MODE: u32 = 0o600;
+
+/// Where the admin socket is bound, and who may connect.
+pub struct Socket {
+    pub path: PathBuf,
+    pub owner: Option<Owner>,
+    pub mode: Option<u32>,
+}
+
+/// One admin command, as sent over the socket or the [`admin_http`](crate::admin_http) API.
+#[serde_as]
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    Forwards,
+    Sessions,
+    Stats,
+    Reload,
+    /// With a `label` or `selector`, only the forwards picked, rather than all of pfwd.
+    Drain {
+        label: Option<String>,
+        /// `label=<name>` or `label~=<pattern>`, e.g. `label~=qdhcp-*`.
+        selector: Option<String>,
+        /// A number, or a string of one from an HTTP query string.
+        #[serde(default)]
+        #[serde_as(as = "Option<PickFirst<(_, DisplayFromStr)>>")]
+        timeout_secs: Option<u64>,
+    },
+    Shutdown,
+    LogLevel {
+        filter: Option<String>,
+    },
+    Kill {
+        #[serde(default)]
+        #[serde_as(as = "Option<PickFirst<(_, DisplayFromStr)>>")]
+        id: Option<u64>,
+        session_id: Option<String>,
+    },
+    Pause {
+        label: Option<String>,
+        selector: Option<String>,
+        #[serde(default)]
+        #[serde_as(as = "PickFirst<(_, DisplayFromStr)>")]
+        reject: bool,
+    },
+    Resume {
+        label: Option<String>,
+        selector: Option<String>,
+    },
+    Add {
+        /// In the `--forward` key=value grammar.
+        forward: String,
+        #[serde(default)]
+        #[serde_as(as = "PickFirst<(_, DisplayFromStr)>")]
+        replace: bool,
+    },
+    Remove {
+        label: Option<String>,
+        selector: Option<String>,
+        #[serde(default)]
+        #[serde_as(as = "PickFirst<(_, DisplayFromStr)>")]
+        kill_sessions: bool,
+    },
+    /// With neither `label` nor `selector`, every forward.
+    Capture {
+        label: Option<String>,
+        selector: Option<String>,
+    },
+    Tail {
+        label: Option<String>,
+        peer: Option<String>,
+    },
+}
+
+/// Serve the admin socket until shutdown, removing it afterwards. Requests that change forwards or
+/// stop pfwd go to `control`.
+pub fn spawn(
+    socket: Socket,
+    control: Option<ControlTx>,
+    mut shutdown: ShutdownRx,
+) -> JoinHandle<Result<()>> {
+    tokio::spawn(async move {
+        let mode = socket.mode.unwrap_or(DEFAULT_MODE);
+        let listener = uds::bind_listener(
+            &socket.path,
+            UdsType::Stream,
+            None,
+            socket.owner,
+            Some(mode),
+        )
+        .context("failed to bind admin socket")?;
+        info!(path = %socket.path.display(), "admin socket listening");
+        loop {
+            tokio::select! {
+                res = shutdown.changed() => {
+                    if res.is_err() || *shutdown.borrow() {
+                        return Ok(());
+                    }
+                }
+                accept_res = listener.accept() => {
+                    let UdsConnection::Stream(stream) = accept_res? else {
+                        unreachable!("admin socket is a stream socket");
+                    };
+                    let control = control.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = serve(stream, control).await {
+                            debug!(error = %err, "admin connection failed");
+                        }
+                    });
+                }
+            }
+        }
+    })
+}
+
+/// Answer requests on one connection until the client closes it.
+async fn serve(stream: UnixStream, control: Option<ControlTx>) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let answer = match serde_json::from_str::<Request>(&line) {
+            Ok(Request::Tail { label, peer }) => return tail(lines, write, label, peer).await,
+            Ok(request) => handle(request, control.as_ref()).await,
+            Err(err) => Err(anyhow!("invalid request: {err}")),
+        };
+        let mut out = match answer {
+            Ok(out) => out,
+            Err(err) => serde_json::to_vec(&json!({ "error": format!("{err:#}") }))?,
+        };
+        out.push(b'\n');
+        write.write_all(&out).await?;
+    }
+    Ok(())
+}
+
+/// Carry out `request`, other than `tail`, and return its JSON answer.
+pub async fn handle(request: Request, control: Option<&ControlTx>) -> Result<Vec<u8>> {
+    let send = |command: &str, request: Control| {
+        control
+            .with_context(|| format!("`{command}` is not available under `pfwd exec`"))?
+            .send(request)
+            .map_err(|_| anyhow!("pfwd is shutting down"))
+    };
+    match request {
+        Request::Status => Ok(serde_json::to_vec(&status::snapshot())?),
+        Request::Forwards => Ok(serde_json::to_vec(&status::forwards())?),
+        Request::Sessions => Ok(serde_json::to_vec(&session::live())?),
+        Request::Stats => Ok(serde_json::to_vec(&metrics::samples())?),
+        Request::Reload => {
+            let (reply, reloaded) = oneshot::channel();
+            send("reload", Control::Reload(reply))?;
+            let reloaded = reloaded
+                .await
+                .map_err(|_| anyhow!("pfwd is shutting down"))??;
+            Ok(serde_json::to_vec(&reloaded)?)
+        }
+        Request::Drain {
+            label,
+            selector,
+            timeout_secs,
+        } => {
+            let timeout = timeout_secs.map(Duration::from_secs);
+            let Some(selector) = Selector::from_args(label, selector)? else {
+                send("drain", Control::Drain(timeout))?;
+                return Ok(serde_json::to_vec(
+                    &json!({ "sessions": session::open_count() }),
+                )?);
+            };
+            let (reply, drained) = oneshot::channel();
+            send(
+                "drain",
+                Control::DrainForward {
+                    selector,
+                    timeout,
+                    reply,
+                },
+            )?;
+            let drained = drained
+                .await
+                .map_err(|_| anyhow!("pfwd is shutting down"))??;
+            Ok(serde_json::to_vec(&drained)?)
+        }
+        Request::Shutdown => {
+            send("shutdown", Control::Shutdown)?;
+            Ok(b"{}".to_vec())
+        }
+        Request::LogLevel { filter } => {
+            if let Some(filter) = filter {
+                let from = log_level::current()?;
+                log_level::set(&filter)?;
+                info!(from, to = filter, "log filter changed");
+            }
+            Ok(serde_json::to_vec(
+                &json!({ "filter": log_level::current()? }),
+            )?)
+        }
+        Request::Kill { id, session_id } => {
+            let sessions_killed = match (id, session_id) {
+                (Some(id), None) => usize::from(session::kill_id(id)),
+                (None, Some(session_id)) => {
+                    session::kill_session_id(session::parse_id(&session_id)?)
+                }
+                _ => bail!("`kill` needs one of `id` and `session_id`"),
+            };
+            if sessions_killed == 0 {
+                bail!("no such open session");
+            }
+            Ok(serde_json::to_vec(
+                &json!({ "sessions_killed": sessions_killed }),
+            )?)
+        }
+        Request::Pause {
+            label,
+            selector,
+            reject,
+        } => {
+            let selector = Selector::from_args(label, selector)?
+                .context("`pause` needs a `label` or `selector`")?;
+            let forwards = status::forwards();
+            let labels = selector.select(forwards.iter().map(|forward| forward.label.as_str()));
+            if labels.is_empty() {
+                bail!("no forward matches {selector}");
+            }
+            let mode = if reject { Pause::Reject } else { Pause::Queue };
+            for label in &labels {
+                pause::pause(label, mode);
+                info!(label, reject, "forward paused");
+            }
+            Ok(serde_json::to_vec(&json!({ "labels": labels }))?)
+        }
+        Request::Resume { label, selector } => {
+            let selector = Selector::from_args(label, selector)?
+                .context("`resume` needs a `label` or `selector`")?;
+            let labels = selector.select(pause::labels().iter().map(String::as_str));
+            if labels.is_empty() {
+                bail!("no paused forward matches {selector}");
+            }
+            for label in &labels {
+                pause::resume(label);
+                info!(label, "forward resumed");
+            }
+            Ok(serde_json::to_vec(&json!({ "labels": labels }))?)
+        }
+        Request::Add { forward, replace } => {
+            let ForwardInline(spec) = forward.parse()?;
+            let (reply, added) = oneshot::channel();
+            send(
+                "add",
+                Control::Add {
+                    spec: Box::new(spec),
+                    replace,
+                    reply,
+                },
+            )?;
+            added
+                .await
+                .map_err(|_| anyhow!("pfwd is shutting down"))??;
+            Ok(b"{}".to_vec())
+        }
+        Request::Remove {
+            label,
+            selector,
+            kill_sessions,
+        } => {
+            let selector = Selector::from_args(label, selector)?
+                .context("`remove` needs a `label` or `selector`")?;
+            let (reply, removed) = oneshot::channel();
+            send(
+                "remove",
+                Control::Remove {
+                    selector,
+                    kill_sessions,
+                    reply,
+                },
+            )?;
+            let removed = removed
+                .await
+                .map_err(|_| anyhow!("pfwd is shutting down"))??;
+            Ok(serde_json::to_vec(&removed)?)
+        }
+        Request::Capture { label, selector } => {
+            let selector = Selector::from_args(label, selector)?;
+            let (reply, captured) = oneshot::channel();
+            send("capture", Control::Capture { selector, reply })?;
+            let captured = captured
+                .await
+                .map_err(|_| anyhow!("pfwd is shutting down"))??;
+            Ok(serde_json::to_vec(&captured)?)
+        }
+        Request::Tail { .. } => unreachable!("tail takes over the connection"),
+    }
+}
+
+/// Stream access log records matching `label` and `peer` until the client goes away.
+pub async fn tail<R, W>(
+    mut lines: Lines<R>,
+    mut write: W,
+    label: Option<String>,
+    peer: Option<String>,
+) -> Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut records = access_log::follow();
+    loop {
+        let line = tokio::select! {
+            // The stream ends when the client closes its end; anything it sends meanwhile is
+            // ignored.
+            line = lines.next_line() => match line {
+                Ok(Some(_)) => continue,
+                Ok(None) | Err(_) => return Ok(()),
+            },
+            record = records.recv() => match record {
+                Ok(record) if matches(&record, label.as_deref(), peer.as_deref()) => {
+                    record.line.clone()
+                }
+                Ok(_) => continue,
+                Err(RecvError::Lagged(missed)) => {
+                    json!({ "event": "lagged", "missed": missed }).to_string()
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+        };
+        write.write_all(format!("{line}\n").as_bytes()).await?;
+    }
+}
+
+/// Whether `record` is for the forward labelled `label` and from `peer`, either of which may be
+/// left open. `peer` is a whole address or just its IP.
+pub fn matches(record: &Followed, label: Option<&str>, peer: Option<&str>) -> bool {
+    if label.is_some_and(|label| label != record.label) {
+        return false;
+    }
+    let Some(peer) = peer else {
+        return true;
+    };
+    record.peer.as_deref().is_some_and(|addr| {
+        addr == peer
+            || addr
+                .parse::<SocketAddr>()
+                .is_ok_and(|addr| addr.ip().to_string() == peer)
+    })
+}
+
+/// A connection to the daemon's admin socket.
+pub struct Client {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    write: OwnedWriteHalf,
+}
+
+impl Client {
+    pub async fn connect(path: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(path).await.with_context(|| {
+            format!(
+                "failed to connect to admin socket {}; is pfwd running with `--admin-socket`?",
+                path.display()
+            )
+        })?;
+        let (read, write) = stream.into_split();
+        Ok(Self {
+            lines: BufReader::new(read).lines(),
+            write,
+        })
+    }
+
+    /// Send `request` and wait for its answer, failing with the error the daemon answered with.
+    pub async fn call(&mut self, request: &Value) -> Result<Value> {
+        self.send(request).await?;
+        self.receive().await
+    }
+
+    /// Send `request` without waiting for an answer, for commands that stream theirs.
+    pub async fn send(&mut self, request: &Value) -> Result<()> {
+        let mut line = serde_json::to_vec(request)?;
+        line.push(b'\n');
+        self.write.write_all(&line).await?;
+        Ok(())
+    }
+
+    /// Wait for the next line from the daemon, failing with the error it answered with.
+    pub async fn receive(&mut self) -> Result<Value> {
+        let answer = self
+            .lines
+            .next_line()
+            .await?
+            .context("pfwd closed the admin socket")?;
+        let answer: Value = serde_json::from_str(&answer).context("invalid answer from pfwd")?;
+        if let Some(error) = answer.get("error").and_then(Value::as_str) {
+            bail!("pfwd: {error}");
+        }
+        Ok(answer)
+    }
+}
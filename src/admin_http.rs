@@ -0,0 +1,195 @@
+//! `admin_listen`: the [`admin`](crate::admin) socket's commands over HTTP, for orchestration
+//! systems that would rather speak HTTP than newline-delimited JSON on a Unix socket.
+//!
+//! Every request needs `Authorization: Bearer <token>`, with the token read from
+//! `admin_token_file` at startup. Commands are paths under `/v1/`: `GET` for those that only read
+//! (`status`, `forwards`, `sessions`, `stats`, `tail`), `POST` for those that act (`reload`,
+//! `drain`, `shutdown`, `log_level`, `kill`, `pause`, `resume`, `add`, `remove`, `capture`). Their arguments come from the query string, or from a JSON object body,
+//! e.g. `POST /v1/drain` with `{"timeout_secs": 30}`. Answers are the socket's, with `200`, or
+//! `{"error": "..."}` with `400` for a request that makes no sense and `500` for a command that
+//! failed. `GET /v1/tail` streams its records as newline-delimited JSON until the client
+//! disconnects.
+
+use std::path::PathBuf;
+
+use anyhow::{Context, Result, anyhow, bail};
+use serde_json::{Map, Value, json};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tracing::{debug, info};
+
+use crate::admin::{self, Request};
+use crate::forward::ControlTx;
+use crate::http;
+use crate::pipeline::ShutdownRx;
+
+const JSON: &str = "application/json";
+
+/// Commands answered to `GET`.
+const READS: [&str; 5] = ["status", "forwards", "sessions", "stats", "tail"];
+/// Commands answered to `POST`.
+const ACTIONS: [&str; 10] = [
+    "reload",
+    "drain",
+    "shutdown",
+    "log_level",
+    "kill",
+    "pause",
+    "resume",
+    "add",
+    "remove",
+    "capture",
+];
+
+/// Serve the admin API on `listen` until shutdown, to clients presenting the token in
+/// `token_file`. Requests that change forwards or stop pfwd go to `control`.
+pub fn spawn(
+    listen: String,
+    token_file: PathBuf,
+    control: Option<ControlTx>,
+    mut shutdown: ShutdownRx,
+) -> JoinHandle<Result<()>> {
+    tokio::spawn(async move {
+        let token = std::fs::read_to_string(&token_file)
+            .with_context(|| format!("failed to read admin token {}", token_file.display()))?
+            .trim()
+            .to_string();
+        if token.is_empty() {
+            bail!("admin token file {} is empty", token_file.display());
+        }
+        let listener = TcpListener::bind(&listen)
+            .await
+            .with_context(|| format!("failed to bind admin listener {listen}"))?;
+        info!(%listen, "admin API listening");
+        loop {
+            tokio::select! {
+                res = shutdown.changed() => {
+                    if res.is_err() || *shutdown.borrow() {
+                        return Ok(());
+                    }
+                }
+                accept_res = listener.accept() => {
+                    let (stream, peer) = accept_res?;
+                    let token = token.clone();
+                    let control = control.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = respond(stream, &token, control).await {
+                            debug!(%peer, error = %err, "admin API request failed");
+                        }
+                    });
+                }
+            }
+        }
+    })
+}
+
+/// Answer one request and close the connection, or stream a `tail` until the client leaves.
+async fn respond(mut stream: TcpStream, token: &str, control: Option<ControlTx>) -> Result<()> {
+    let request = http::read_request(&mut stream).await?;
+    let authorized = request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|presented| same(presented.trim().as_bytes(), token.as_bytes()));
+    if !authorized {
+        let body = error("missing or wrong bearer token");
+        let headers = [("WWW-Authenticate", "Bearer")];
+        return http::write_response(&mut stream, "401 Unauthorized", JSON, &headers, &body).await;
+    }
+    let Some(command) = request.path.strip_prefix("/v1/") else {
+        return http::write_response(&mut stream, "404 Not Found", JSON, &[], &error("not found"))
+            .await;
+    };
+    let method = if READS.contains(&command) {
+        "GET"
+    } else if ACTIONS.contains(&command) {
+        "POST"
+    } else {
+        return http::write_response(&mut stream, "404 Not Found", JSON, &[], &error("not found"))
+            .await;
+    };
+    if request.method != method {
+        let body = error(&format!("`{command}` takes {method}"));
+        let headers = [("Allow", method)];
+        return http::write_response(&mut stream, "405 Method Not Allowed", JSON, &headers, &body)
+            .await;
+    }
+    let parsed = arguments(&request).and_then(|mut arguments| {
+        arguments.insert("command".to_string(), Value::String(command.to_string()));
+        serde_json::from_value::<Request>(Value::Object(arguments))
+            .map_err(|err| anyhow!("invalid request: {err}"))
+    });
+    let (status, body) = match parsed {
+        Ok(Request::Tail { label, peer }) => {
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nConnection: close\r\n\r\n",
+                )
+                .await?;
+            let (read, write) = stream.into_split();
+            return admin::tail(BufReader::new(read).lines(), write, label, peer).await;
+        }
+        Ok(request) => match admin::handle(request, control.as_ref()).await {
+            Ok(body) => ("200 OK", body),
+            Err(err) => ("500 Internal Server Error", error(&format!("{err:#}"))),
+        },
+        Err(err) => ("400 Bad Request", error(&format!("{err:#}"))),
+    };
+    http::write_response(&mut stream, status, JSON, &[], &body).await
+}
+
+/// The command's arguments: the JSON object in the body, if any, then the query string's
+/// parameters as strings.
+fn arguments(request: &http::Request) -> Result<Map<String, Value>> {
+    let mut arguments = if request.body.iter().all(u8::is_ascii_whitespace) {
+        Map::new()
+    } else {
+        serde_json::from_slice(&request.body).context("body is not a JSON object")?
+    };
+    for pair in request.query.as_deref().unwrap_or_default().split('&') {
+        if pair.is_empty() {
+            continue;
+        }
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        arguments.insert(decode(key)?, Value::String(decode(value)?));
+    }
+    Ok(arguments)
+}
+
+/// Undo the percent-encoding of a query string component.
+fn decode(text: &str) -> Result<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = rest
+                    .get(..2)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .with_context(|| format!("invalid percent-encoding in `{text}`"))?;
+                bytes.push(hex);
+                rest = &rest[2..];
+            }
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).with_context(|| format!("`{text}` is not UTF-8"))
+}
+
+/// Compare the presented token with the configured one in time independent of where they differ.
+/// The gRPC API checks its bearer token with this too.
+pub fn same(presented: &[u8], token: &[u8]) -> bool {
+    presented.len() == token.len()
+        && presented
+            .iter()
+            .zip(token)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn error(message: &str) -> Vec<u8> {
+    json!({ "error": message }).to_string().into_bytes()
+}
@@ -0,0 +1,134 @@
+//! Optional zstd compression on the UDS leg, enabled with the `zstd` cargo feature.
+//!
+//! Specs with `uds_compress = "zstd"` negotiate per session: the host proxy opens each UDS
+//! connection with a one-byte codec offer and the namespace endpoint answers with the codec it
+//! will use, so both sides agree before any payload flows. Each direction is then one zstd frame
+//! that is flushed whenever the sender goes idle, keeping interactive protocols responsive.
+
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+
+use anyhow::{Result, bail};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+#[cfg(feature = "zstd")]
+use tokio::io::{BufReader, Join, ReadHalf, WriteHalf};
+
+use crate::config::UdsCompress;
+
+const CODEC_NONE: u8 = 0;
+const CODEC_ZSTD: u8 = 1;
+
+/// Reject `uds_compress` at config load when the feature is compiled out.
+pub fn validate(compress: UdsCompress) -> Result<()> {
+    if compress == UdsCompress::Zstd && !cfg!(feature = "zstd") {
+        bail!("`uds_compress = \"zstd\"` needs rebuilding pfwd with `--features zstd`");
+    }
+    Ok(())
+}
+
+/// UDS stream after negotiation.
+pub enum MaybeCompressed<S> {
+    Plain(S),
+    #[cfg(feature = "zstd")]
+    Zstd(Box<ZstdStream<S>>),
+}
+
+#[cfg(feature = "zstd")]
+type ZstdStream<S> = Join<
+    async_compression::tokio::bufread::ZstdDecoder<BufReader<ReadHalf<S>>>,
+    async_compression::tokio::write::ZstdEncoder<WriteHalf<S>>,
+>;
+
+/// Host proxy side: offer `compress` and wrap the stream in whatever the endpoint accepts. Nothing
+/// is exchanged when compression is off, so such specs keep the plain byte stream.
+pub async fn offer<S>(mut stream: S, compress: UdsCompress) -> Result<MaybeCompressed<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let offered = match compress {
+        UdsCompress::None => return Ok(MaybeCompressed::Plain(stream)),
+        UdsCompress::Zstd => CODEC_ZSTD,
+    };
+    stream.write_all(&[offered]).await?;
+    let accepted = stream.read_u8().await?;
+    if accepted != CODEC_NONE && accepted != offered {
+        bail!("namespace endpoint answered with unknown codec {accepted}");
+    }
+    Ok(wrap(stream, accepted))
+}
+
+/// Namespace endpoint side: read the host proxy's offer and accept it when this build can.
+pub async fn accept<S>(mut stream: S) -> Result<MaybeCompressed<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let offered = stream.read_u8().await?;
+    let accepted = match offered {
+        CODEC_ZSTD if cfg!(feature = "zstd") => CODEC_ZSTD,
+        _ => CODEC_NONE,
+    };
+    stream.write_all(&[accepted]).await?;
+    Ok(wrap(stream, accepted))
+}
+
+fn wrap<S>(stream: S, codec: u8) -> MaybeCompressed<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match codec {
+        #[cfg(feature = "zstd")]
+        CODEC_ZSTD => {
+            use async_compression::tokio::{bufread::ZstdDecoder, write::ZstdEncoder};
+            let (read, write) = io::split(stream);
+            MaybeCompressed::Zstd(Box::new(io::join(
+                ZstdDecoder::new(BufReader::new(read)),
+                ZstdEncoder::new(write),
+            )))
+        }
+        _ => MaybeCompressed::Plain(stream),
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for MaybeCompressed<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeCompressed::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "zstd")]
+            MaybeCompressed::Zstd(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for MaybeCompressed<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeCompressed::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "zstd")]
+            MaybeCompressed::Zstd(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeCompressed::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "zstd")]
+            MaybeCompressed::Zstd(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeCompressed::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "zstd")]
+            MaybeCompressed::Zstd(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
@@ -1,19 +1,51 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::fs;
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::time::Duration;
 
 use anyhow::{Context, Result, bail};
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 use serde::Deserialize;
 use serde_with::{DisplayFromStr, serde_as};
 use users::{get_group_by_name, get_user_by_name};
 
+use crate::access_log;
+use crate::addr::HostPort;
+use crate::admin;
+use crate::compress;
+use crate::container::ContainerRef;
+use crate::filter;
+use crate::grpc;
+use crate::jail;
+use crate::lua;
+use crate::otel;
+use crate::pipeline::broadcast::BroadcastEndpoint;
+use crate::pipeline::multicast::MulticastListen;
+use crate::sctp;
+use crate::syslog::Priorities;
+use crate::target;
+use crate::uds;
+use crate::vsock::VsockAddr;
+
+/// Upper bound on `target_pool`, which holds that many idle sockets per forward.
+const MAX_TARGET_POOL: usize = 1024;
+/// Where `ip netns` keeps named namespaces, unless `netns_dir` says otherwise.
+pub const DEFAULT_NETNS_DIR: &str = "/var/run/netns";
+/// Replaced with the namespace name in the `uds` and `label` of a `glob:` forward's instances.
+pub const NAMESPACE_PLACEHOLDER: &str = "{namespace}";
+/// Replaced with the network or router UUID in the `uds` and `label` of a `neutron:` forward's
+/// instances.
+pub const UUID_PLACEHOLDER: &str = "{uuid}";
+
 #[derive(Debug, Parser)]
 #[command(author, version, about)]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Optional path to a TOML configuration file.
     #[arg(long)]
     pub config: Option<PathBuf>,
@@ -22,10 +54,94 @@ pub struct Cli {
     #[arg(long)]
     pub log_level: Option<String>,
 
+    /// Where logs go: stdout (stderr under `exec`), syslog to send them to the local syslog
+    /// daemon, or journald to keep their fields as journal fields.
+    #[arg(long, value_name = "OUTPUT")]
+    pub log_output: Option<LogOutput>,
+
+    /// Log line format: text (the default) or json, one object per line with the event's fields
+    /// at the top level.
+    #[arg(long, value_name = "FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// Append the log to this file instead of stdout, rotating it as `log_file_*` in the config
+    /// says.
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+
+    /// Syslog facility for `--log-output syslog` or `journald` (default daemon).
+    #[arg(long, value_name = "FACILITY")]
+    pub syslog_facility: Option<String>,
+
+    /// Serve Prometheus metrics for every forward at `http://<addr>/metrics`.
+    #[arg(long, value_name = "ADDR")]
+    pub metrics_listen: Option<String>,
+
+    /// Answer `/healthz` and `/readyz` probes over HTTP on this address.
+    #[arg(long, value_name = "ADDR")]
+    pub health_listen: Option<String>,
+
+    /// Export a trace per session and push forward metrics to this OTLP collector (e.g.
+    /// http://localhost:4318); requires the `otel` build feature.
+    #[arg(long, value_name = "URL")]
+    pub otlp_endpoint: Option<String>,
+
+    /// OTLP transport: http (protobuf over HTTP, the default) or grpc.
+    #[arg(long, value_name = "PROTOCOL")]
+    pub otlp_protocol: Option<OtlpProtocol>,
+
+    /// Extra OpenTelemetry resource attribute; repeatable.
+    #[arg(long = "otlp-resource", value_name = "key=value")]
+    pub otlp_resource: Vec<String>,
+
+    /// Push forward metrics to this statsd/DogStatsD agent over UDP (e.g. 127.0.0.1:8125).
+    #[arg(long = "statsd", value_name = "HOST:PORT")]
+    pub statsd_addr: Option<String>,
+
+    /// Append a JSON line per session open and close to this file, or to an inherited descriptor
+    /// given as `fd:N`, whatever the log level.
+    #[arg(long, value_name = "PATH|fd:N")]
+    pub access_log: Option<String>,
+
+    /// Export a pair of IPFIX flow records per session to this collector over UDP.
+    #[arg(long, value_name = "HOST:PORT")]
+    pub ipfix_collector: Option<String>,
+
+    /// Write a pcap of every forward's sessions into this directory on each SIGUSR2.
+    #[arg(long, value_name = "DIR")]
+    pub capture_dir: Option<PathBuf>,
+
+    /// Keep a JSON snapshot of listeners, counters, and last errors in this file, replaced
+    /// atomically every `status_interval_secs`.
+    #[arg(long, value_name = "PATH")]
+    pub status_file: Option<PathBuf>,
+
+    /// Answer `pfwd top` and other local tools, and take reload, drain, and shutdown requests, on
+    /// this Unix socket, created with mode 0600.
+    #[arg(long, value_name = "PATH")]
+    pub admin_socket: Option<PathBuf>,
+
+    /// Serve the admin socket's commands over HTTP on this address, to clients presenting the
+    /// bearer token in `admin_token_file`.
+    #[arg(long, value_name = "ADDR")]
+    pub admin_listen: Option<String>,
+
+    /// Serve the gRPC control API on this address, to clients presenting the bearer token in
+    /// `admin_token_file`; requires the `grpc` build feature.
+    #[arg(long, value_name = "ADDR")]
+    pub grpc_listen: Option<String>,
+
     /// Inline forward specifications. Each entry is a comma-separated key=value list.
     ///
-    /// Keys: listen, udp_listen, namespace, setns_path, uds, target, udp_target, mode, owner,
-    /// backlog, label, udp_idle_timeout.
+    /// Keys: listen, udp_listen, namespace, netns_dir, setns_path, setns_pid, setns_mount, setns_user, uds, target, udp_target, mode, owner,
+    /// backlog, label, udp_idle_timeout, namespace_wait_timeout, udp_session_key, mux, broadcast_listen, broadcast_target,
+    /// dns_listen, dns_target, dns_timeout_ms, dns_cache, mptcp, uds_listen, uds_target, reverse,
+    /// uds_mount_ns, target_exec, fd_passing, uds_type, uds_ssh, uds_tls, uds_http, ftp,
+    /// target_balance, resolve_in_namespace, target_resolve_ttl, ipv6_only, uds_compress,
+    /// uds_session_id, target_pool, mirror_target, record_dir, chaos_latency_ms, chaos_jitter_ms,
+    /// chaos_reset_probability, chaos_drop_probability, shape_rate, shape_burst, shape_delay_ms,
+    /// shape_delay_jitter_ms, shape_delay_distribution, filter_wasm, on_accept_lua,
+    /// on_connect_exec, on_up, on_down.
     ///
     /// Example (host proxy):
     /// --forward listen=0.0.0.0:2222,uds=/run/qdhcp/ssh.sock
@@ -33,15 +149,254 @@ pub struct Cli {
     /// Example (namespace endpoint):
     /// --forward namespace=qdhcp-1234,uds=/run/qdhcp/ssh.sock,target=192.168.31.201:22
     ///
+    /// Example (host-side unix listener bridging to a tcp service, no namespace):
+    /// --forward uds=/run/pfwd/db.sock,target=127.0.0.1:5432,mode=0660
+    ///
+    /// Example (host tcp service exposed as a socket inside a container's mount namespace):
+    /// --forward uds=/run/api.sock,uds_mount_ns=/proc/4242/ns/mnt,target=127.0.0.1:8775
+    ///
+    /// Example (reverse forward: namespace clients reach a host metadata API over the uds):
+    /// --forward reverse=true,namespace=qdhcp-1234,listen=169.254.169.254:80,uds=/run/pfwd/meta.sock,target=127.0.0.1:8775
+    ///
+    /// Example (host proxy receiving connected target sockets; the namespace endpoint must also set
+    /// fd_passing=true):
+    /// --forward listen=0.0.0.0:2222,uds=/run/qdhcp/ssh.sock,fd_passing=true
+    ///
+    /// Example (seqpacket endpoint for a management daemon that relies on record boundaries; the
+    /// host proxy must also set uds_type=seqpacket):
+    /// --forward namespace=qdhcp-1234,uds=/run/qdhcp/mgmt.sock,uds_type=seqpacket,target=192.168.31.201:830
+    ///
+    /// Example (zstd-compressed UDS leg; both sides need it and the `zstd` build feature):
+    /// --forward listen=0.0.0.0:8080,uds=/run/qdhcp/logs.sock,uds_compress=zstd
+    /// --forward namespace=qdhcp-1234,uds=/run/qdhcp/logs.sock,uds_compress=zstd,target=192.168.31.201:80
+    ///
+    /// Example (inetd-style handler spawned per connection inside a namespace):
+    /// --forward listen=0.0.0.0:7000,uds=/run/pfwd/echo.sock
+    /// --forward namespace=qdhcp-1234,uds=/run/pfwd/echo.sock,target_exec=/usr/local/bin/echo-handler
+    ///
+    /// Example (ftp server inside a namespace, data channels included):
+    /// --forward listen=0.0.0.0:21,namespace=qdhcp-1234,target=192.168.31.50:21,ftp=true
+    ///
+    /// Example (hostname target resolved with /etc/netns/qdhcp-1234/resolv.conf):
+    /// --forward namespace=qdhcp-1234,uds=/run/qdhcp/api.sock,target=api.internal:443,resolve_in_namespace=true
+    ///
     /// Example (direct tcp proxy):
     /// --forward listen=0.0.0.0:8443,target=10.0.0.23:443
     ///
+    /// Example (spread connections across every address behind a name):
+    /// --forward listen=0.0.0.0:8080,target=backends.internal:8080,target_balance=round_robin
+    ///
+    /// Example (shadow client traffic into a test namespace's copy of the service):
+    /// --forward namespace=qdhcp-1234,uds=/run/qdhcp/api.sock,target=192.168.31.201:80,mirror_target=192.168.31.202:80
+    ///
+    /// Example (record every session to /var/lib/pfwd/rec for `pfwd replay`):
+    /// --forward listen=0.0.0.0:8080,target=10.0.0.23:8080,record_dir=/var/lib/pfwd/rec
+    ///
+    /// Example (resilience testing: 200ms +/- 100ms latency and a reset in 1 of 1000 chunks):
+    /// --forward listen=0.0.0.0:8080,target=10.0.0.23:8080,chaos_latency_ms=200,chaos_jitter_ms=100,chaos_reset_probability=0.001
+    ///
+    /// Example (emulate a 3G link, 384 kbit/s with 150ms +/- 40ms normally distributed delay):
+    /// --forward namespace=qdhcp-1234,uds=/run/qdhcp/api.sock,target=192.168.31.201:80,shape_rate=48000,shape_burst=16000,shape_delay_ms=150,shape_delay_jitter_ms=40,shape_delay_distribution=normal
+    ///
+    /// Example (redact the byte stream with a WebAssembly filter; requires the `wasm` build feature):
+    /// --forward listen=0.0.0.0:8080,target=10.0.0.23:8080,filter_wasm=/etc/pfwd/redact.wasm
+    ///
+    /// Example (accept, reject, or re-target each client from a Lua policy; requires the `lua`
+    /// build feature):
+    /// --forward listen=0.0.0.0:8080,target=10.0.0.23:8080,on_accept_lua=/etc/pfwd/policy.lua
+    ///
+    /// Example (gate each client with an existing policy script; exit status 0 lets it through):
+    /// --forward listen=0.0.0.0:2222,uds=/run/qdhcp/ssh.sock,on_connect_exec=/usr/local/bin/allow-ssh
+    ///
+    /// Example (open the firewall while the listener is up; PFWD_LISTEN names the address):
+    /// --forward listen=0.0.0.0:8443,target=10.0.0.23:443,on_up=/usr/local/bin/fw-open,on_down=/usr/local/bin/fw-close
+    ///
+    /// Example (one metadata socket per qdhcp namespace, following namespaces as they come and go):
+    /// --forward namespace=glob:qdhcp-*,uds=/run/pfwd/{namespace}.sock,target=169.254.169.254:80
+    ///
+    /// Example (expose dnsmasq of every Neutron network, labelled with the network UUID):
+    /// --forward namespace=neutron:dhcp,uds=/run/pfwd/{uuid}-dns.sock,target=127.0.0.1:53
+    ///
+    /// Example (reach a service inside a running container's network namespace; `podman:`,
+    /// `lxd:`, and `lxc:` work the same way):
+    /// --forward namespace=docker:web,uds=/run/pfwd/web.sock,target=127.0.0.1:80
+    ///
+    /// Example (debug a pod-local service from the node; needs `crictl` on the node):
+    /// --forward listen=127.0.0.1:9090,namespace=k8s:prometheus-0,uds=/run/pfwd/prom.sock,target=127.0.0.1:9090
+    ///
+    /// Example (keep four target connections open ahead of demand):
+    /// --forward listen=0.0.0.0:8080,target=10.0.0.23:8080,target_pool=4
+    ///
+    /// Example (IPv6-only listener next to a separate IPv4 one):
+    /// --forward listen=[::]:8443,target=10.0.0.23:443,ipv6_only=true
+    ///
+    /// Example (tcp proxy through an ssh bastion; requires the `ssh` build feature):
+    /// --forward listen=0.0.0.0:5432,target=ssh://ops@bastion:22/10.0.0.5:5432
+    ///
+    /// Example (sctp proxy for Diameter; requires the `sctp` build feature):
+    /// --forward listen=sctp://0.0.0.0:3868,target=sctp://10.0.0.5:3868
+    ///
+    /// Example (expose a VM guest's ssh on the host over vsock):
+    /// --forward listen=0.0.0.0:2222,target=vsock:3:22
+    ///
+    /// Example (re-expose a root-owned control socket to an unprivileged group):
+    /// --forward uds_listen=/run/app/ctl-user.sock,uds_target=/run/app/ctl.sock,mode=0660,owner=0:1000
+    ///
     /// Example (udp proxy):
     /// --forward udp_listen=0.0.0.0:5353,udp_target=192.168.31.10:53,udp_idle_timeout=600
+    ///
+    /// Example (relay mDNS queries from a multicast group on eth0 to a unicast responder):
+    /// --forward udp_listen=224.0.0.251:5353@eth0,udp_target=192.168.31.10:5353
+    ///
+    /// Example (udp proxy that follows QUIC clients across NAT rebinding):
+    /// --forward udp_listen=0.0.0.0:443,udp_target=10.0.0.23:443,udp_session_key=quic
+    ///
+    /// Example (udp host proxy and namespace endpoint over a datagram UDS):
+    /// --forward udp_listen=0.0.0.0:5353,uds=/run/qdhcp/dns.sock
+    /// --forward namespace=qdhcp-1234,uds=/run/qdhcp/dns.sock,udp_target=192.168.31.10:53
+    ///
+    /// Example (udp into a namespace, both legs in this process):
+    /// --forward udp_listen=0.0.0.0:5353,namespace=qdhcp-1234,udp_target=192.168.31.10:53
+    ///
+    /// Example (re-broadcast DHCP discovery from eth0 into a namespace's bridge):
+    /// --forward broadcast_listen=0.0.0.0:67@eth0,broadcast_target=255.255.255.255:67@br0,namespace=qdhcp-1234
+    ///
+    /// Example (dns proxy on udp+tcp 53 for the dnsmasq inside a namespace, caching 4096 answers):
+    /// --forward dns_listen=0.0.0.0:53,dns_target=192.168.31.2:53,namespace=qdhcp-1234,dns_cache=4096
+    ///
+    /// Example (host proxy and namespace endpoint over an abstract socket, no filesystem entry):
+    /// --forward listen=0.0.0.0:2222,namespace=qdhcp-1234,uds=@pfwd-ssh,target=192.168.31.201:22
+    ///
+    /// Example (ssh and https on one port, routed to different endpoints by their first bytes):
+    /// --forward listen=0.0.0.0:443,uds_ssh=/run/qdhcp/ssh.sock,uds_tls=/run/qdhcp/https.sock
+    ///
+    /// Example (multiplexed host proxy; the namespace endpoint must also set mux=true):
+    /// --forward listen=0.0.0.0:2222,uds=/run/qdhcp/ssh.sock,mux=true
     #[arg(long = "forward", value_name = "key=value")]
     pub inline_forwards: Vec<ForwardInline>,
 }
 
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Re-send the client side of a session recorded with `record_dir` to a target.
+    Replay(ReplayArgs),
+    /// Run a command inside a namespace while the configured forwards are up, then tear them down.
+    Exec(ExecArgs),
+    /// List network namespaces in use, including anonymous ones no runtime registered by name.
+    Namespaces(NamespacesArgs),
+    /// Watch the running pfwd's forwards and open sessions, busiest first, over its admin socket.
+    Top(TopArgs),
+    /// Follow the running pfwd's session opens and closes over its admin socket.
+    Tail(TailArgs),
+    /// Show the running pfwd's forwards, their states and open sessions, and recent errors.
+    Status(StatusArgs),
+    /// Make the running pfwd re-read its configuration and apply what changed.
+    Reload(ReloadArgs),
+    /// Attach to a FreeBSD jail and open sockets there for the pfwd that started this helper.
+    #[command(hide = true)]
+    JailHelper(JailHelperArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct JailHelperArgs {
+    /// Jail name or id.
+    pub jail: String,
+}
+
+#[derive(Debug, Args)]
+pub struct StatusArgs {
+    /// Admin socket of the running pfwd (its `--admin-socket`).
+    #[arg(long, default_value = admin::DEFAULT_SOCKET)]
+    pub socket: PathBuf,
+
+    /// Print JSON instead of tables.
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct ReloadArgs {
+    /// Admin socket of the running pfwd (its `--admin-socket`).
+    #[arg(long, default_value = admin::DEFAULT_SOCKET)]
+    pub socket: PathBuf,
+
+    /// Print the daemon's answer as JSON.
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct TailArgs {
+    /// Admin socket of the running pfwd (its `--admin-socket`).
+    #[arg(long, default_value = admin::DEFAULT_SOCKET)]
+    pub socket: PathBuf,
+
+    /// Only sessions of the forward with this label.
+    #[arg(long)]
+    pub label: Option<String>,
+
+    /// Only sessions from this client, as an address or just its IP.
+    #[arg(long)]
+    pub peer: Option<String>,
+
+    /// Print each record as a JSON object per line, with the access log's fields.
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct TopArgs {
+    /// Admin socket of the running pfwd (its `--admin-socket`).
+    #[arg(long, default_value = admin::DEFAULT_SOCKET)]
+    pub socket: PathBuf,
+
+    /// Seconds between refreshes.
+    #[arg(long, default_value_t = 2)]
+    pub interval: u64,
+
+    /// Sessions listed at most.
+    #[arg(long, default_value_t = 20)]
+    pub limit: usize,
+}
+
+#[derive(Debug, Args)]
+pub struct NamespacesArgs {
+    /// Print JSON instead of a table.
+    #[arg(long)]
+    pub json: bool,
+
+    /// Directory of named namespaces to match against, like a forward's `netns_dir`.
+    #[arg(long, default_value = DEFAULT_NETNS_DIR)]
+    pub netns_dir: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct ExecArgs {
+    /// Namespace to run the command in, written like a forward's `namespace` (`ip netns` name,
+    /// `docker:<name>`, ...). Defaults to the namespace the forwards share; without one the
+    /// command runs on the host.
+    #[arg(long)]
+    pub namespace: Option<String>,
+
+    /// Command to run and its arguments, after `--`.
+    #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    pub command: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ReplayArgs {
+    /// Recording file written by a forward with `record_dir`.
+    pub recording: PathBuf,
+
+    /// Target to replay against (`host:port`, or any other forward `target` form).
+    #[arg(long)]
+    pub target: String,
+
+    /// Send chunks back to back instead of with the recorded timing.
+    #[arg(long)]
+    pub fast: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct ForwardInline(pub ForwardSpec);
 
@@ -64,15 +419,108 @@ impl FromStr for ForwardInline {
         if let Some(namespace) = map.remove("namespace") {
             spec.namespace = Some(namespace);
         }
+        if let Some(dir) = map.remove("netns_dir") {
+            spec.netns_dir = Some(PathBuf::from(dir));
+        }
         if let Some(path) = map.remove("setns_path") {
             spec.setns_path = Some(PathBuf::from(path));
         }
+        if let Some(pid) = map.remove("setns_pid") {
+            spec.setns_pid = Some(pid.parse().context("setns_pid must be a process id")?);
+        }
+        if let Some(mount) = map.remove("setns_mount") {
+            spec.setns_mount = Some(mount.parse().context("setns_mount must be true or false")?);
+        }
+        if let Some(user) = map.remove("setns_user") {
+            spec.setns_user = Some(user.parse().context("setns_user must be true or false")?);
+        }
         if let Some(uds) = map.remove("uds") {
             spec.uds = Some(PathBuf::from(uds));
         }
         if let Some(target) = map.remove("target") {
             spec.target = Some(target);
         }
+        if let Some(command) = map.remove("target_exec") {
+            spec.target_exec = Some(command);
+        }
+        if let Some(resolve) = map.remove("resolve_in_namespace") {
+            spec.resolve_in_namespace = Some(
+                resolve
+                    .parse()
+                    .context("resolve_in_namespace must be true or false")?,
+            );
+        }
+        if let Some(v6only) = map.remove("ipv6_only") {
+            spec.ipv6_only = Some(v6only.parse().context("ipv6_only must be true or false")?);
+        }
+        if let Some(ttl) = map.remove("target_resolve_ttl") {
+            spec.target_resolve_ttl_secs = Some(ttl.parse()?);
+        }
+        if let Some(mirror) = map.remove("mirror_target") {
+            spec.mirror_target = Some(mirror);
+        }
+        if let Some(dir) = map.remove("record_dir") {
+            spec.record_dir = Some(PathBuf::from(dir));
+        }
+        if let Some(latency) = map.remove("chaos_latency_ms") {
+            spec.chaos_latency_ms = Some(latency.parse()?);
+        }
+        if let Some(jitter) = map.remove("chaos_jitter_ms") {
+            spec.chaos_jitter_ms = Some(jitter.parse()?);
+        }
+        if let Some(probability) = map.remove("chaos_reset_probability") {
+            spec.chaos_reset_probability = Some(
+                probability
+                    .parse()
+                    .context("chaos_reset_probability must be a number")?,
+            );
+        }
+        if let Some(probability) = map.remove("chaos_drop_probability") {
+            spec.chaos_drop_probability = Some(
+                probability
+                    .parse()
+                    .context("chaos_drop_probability must be a number")?,
+            );
+        }
+        if let Some(rate) = map.remove("shape_rate") {
+            spec.shape_rate = Some(
+                rate.parse()
+                    .context("shape_rate must be bytes per second")?,
+            );
+        }
+        if let Some(burst) = map.remove("shape_burst") {
+            spec.shape_burst = Some(burst.parse().context("shape_burst must be a byte count")?);
+        }
+        if let Some(delay) = map.remove("shape_delay_ms") {
+            spec.shape_delay_ms = Some(delay.parse()?);
+        }
+        if let Some(jitter) = map.remove("shape_delay_jitter_ms") {
+            spec.shape_delay_jitter_ms = Some(jitter.parse()?);
+        }
+        if let Some(distribution) = map.remove("shape_delay_distribution") {
+            spec.shape_delay_distribution = Some(distribution.parse()?);
+        }
+        if let Some(command) = map.remove("on_up") {
+            spec.on_up = Some(command);
+        }
+        if let Some(command) = map.remove("on_down") {
+            spec.on_down = Some(command);
+        }
+        if let Some(command) = map.remove("on_connect_exec") {
+            spec.on_connect_exec = Some(command);
+        }
+        if let Some(script) = map.remove("on_accept_lua") {
+            spec.on_accept_lua = Some(PathBuf::from(script));
+        }
+        if let Some(module) = map.remove("filter_wasm") {
+            spec.filter_wasm = Some(PathBuf::from(module));
+        }
+        if let Some(size) = map.remove("target_pool") {
+            spec.target_pool = Some(size.parse().context("target_pool must be a count")?);
+        }
+        if let Some(balance) = map.remove("target_balance") {
+            spec.target_balance = Some(balance.parse()?);
+        }
         if let Some(mode) = map.remove("mode") {
             spec.mode = Some(parse_mode(&mode)?);
         }
@@ -94,6 +542,80 @@ impl FromStr for ForwardInline {
         if let Some(timeout) = map.remove("udp_idle_timeout") {
             spec.udp_idle_timeout_secs = Some(timeout.parse()?);
         }
+        if let Some(timeout) = map.remove("namespace_wait_timeout") {
+            spec.namespace_wait_timeout_secs = Some(timeout.parse()?);
+        }
+        if let Some(key) = map.remove("udp_session_key") {
+            spec.udp_session_key = Some(key.parse()?);
+        }
+        if let Some(mux) = map.remove("mux") {
+            spec.mux = Some(mux.parse().context("mux must be true or false")?);
+        }
+        if let Some(path) = map.remove("uds_ssh") {
+            spec.uds_ssh = Some(PathBuf::from(path));
+        }
+        if let Some(path) = map.remove("uds_tls") {
+            spec.uds_tls = Some(PathBuf::from(path));
+        }
+        if let Some(path) = map.remove("uds_http") {
+            spec.uds_http = Some(PathBuf::from(path));
+        }
+        if let Some(compress) = map.remove("uds_compress") {
+            spec.uds_compress = Some(compress.parse()?);
+        }
+        if let Some(session_id) = map.remove("uds_session_id") {
+            spec.uds_session_id = Some(
+                session_id
+                    .parse()
+                    .context("uds_session_id must be true or false")?,
+            );
+        }
+        if let Some(kind) = map.remove("uds_type") {
+            spec.uds_type = Some(kind.parse()?);
+        }
+        if let Some(fd_passing) = map.remove("fd_passing") {
+            spec.fd_passing = Some(
+                fd_passing
+                    .parse()
+                    .context("fd_passing must be true or false")?,
+            );
+        }
+        if let Some(listen) = map.remove("broadcast_listen") {
+            spec.broadcast_listen = Some(listen);
+        }
+        if let Some(target) = map.remove("broadcast_target") {
+            spec.broadcast_target = Some(target);
+        }
+        if let Some(ftp) = map.remove("ftp") {
+            spec.ftp = Some(ftp.parse().context("ftp must be true or false")?);
+        }
+        if let Some(mptcp) = map.remove("mptcp") {
+            spec.mptcp = Some(mptcp.parse().context("mptcp must be true or false")?);
+        }
+        if let Some(listen) = map.remove("dns_listen") {
+            spec.dns_listen = Some(listen);
+        }
+        if let Some(target) = map.remove("dns_target") {
+            spec.dns_target = Some(target);
+        }
+        if let Some(timeout) = map.remove("dns_timeout_ms") {
+            spec.dns_timeout_ms = Some(timeout.parse()?);
+        }
+        if let Some(entries) = map.remove("dns_cache") {
+            spec.dns_cache = Some(entries.parse()?);
+        }
+        if let Some(path) = map.remove("uds_listen") {
+            spec.uds_listen = Some(PathBuf::from(path));
+        }
+        if let Some(path) = map.remove("uds_target") {
+            spec.uds_target = Some(PathBuf::from(path));
+        }
+        if let Some(path) = map.remove("uds_mount_ns") {
+            spec.uds_mount_ns = Some(PathBuf::from(path));
+        }
+        if let Some(reverse) = map.remove("reverse") {
+            spec.reverse = Some(reverse.parse().context("reverse must be true or false")?);
+        }
 
         if !map.is_empty() {
             bail!(
@@ -119,6 +641,28 @@ pub struct FileConfig {
 pub struct Defaults {
     #[serde(default)]
     pub log_level: Option<String>,
+    /// `--log-output` takes precedence.
+    #[serde(default)]
+    pub log_output: Option<LogOutput>,
+    /// `--log-format` takes precedence.
+    #[serde(default)]
+    pub log_format: Option<LogFormat>,
+    #[serde(default)]
+    pub syslog_facility: Option<String>,
+    /// Syslog severity per log level, e.g. `{ info = "notice" }`, over the usual mapping.
+    #[serde(default)]
+    pub syslog_severity: BTreeMap<String, String>,
+    /// File the log is appended to; `--log-file` takes precedence.
+    #[serde(default)]
+    pub log_file: Option<PathBuf>,
+    /// Size past which the log file is rotated.
+    #[serde(default)]
+    pub log_file_max_bytes: Option<u64>,
+    #[serde(default)]
+    pub log_file_rotation: Option<LogRotation>,
+    /// Rotated log files kept.
+    #[serde(default)]
+    pub log_file_keep: Option<usize>,
     #[serde(default)]
     pub uds_dir: Option<PathBuf>,
     #[serde(default)]
@@ -129,10 +673,81 @@ pub struct Defaults {
     pub backlog: Option<u32>,
     #[serde(default)]
     pub udp_idle_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub namespace_wait_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub netns_dir: Option<PathBuf>,
+    /// Address of the Prometheus `/metrics` listener; `--metrics-listen` takes precedence.
+    #[serde(default)]
+    pub metrics_listen: Option<String>,
+    /// Address of the `/healthz` and `/readyz` listener; `--health-listen` takes precedence.
+    #[serde(default)]
+    pub health_listen: Option<String>,
+    /// OTLP collector session traces and metrics are exported to; `--otlp-endpoint` takes
+    /// precedence.
+    #[serde(default)]
+    pub otlp_endpoint: Option<String>,
+    #[serde(default)]
+    pub otlp_protocol: Option<OtlpProtocol>,
+    /// Resource attributes identifying this pfwd to the collector, on top of `service.name`.
+    #[serde(default)]
+    pub otlp_resource: BTreeMap<String, String>,
+    /// Seconds between metric pushes.
+    #[serde(default)]
+    pub otlp_metrics_interval_secs: Option<u64>,
+    /// statsd agent metrics are pushed to; `--statsd` takes precedence.
+    #[serde(default)]
+    pub statsd_addr: Option<String>,
+    /// Prefix of the statsd metric names (`pfwd` by default).
+    #[serde(default)]
+    pub statsd_prefix: Option<String>,
+    /// Seconds between statsd pushes.
+    #[serde(default)]
+    pub statsd_interval_secs: Option<u64>,
+    /// File or `fd:N` session records are appended to; `--access-log` takes precedence.
+    #[serde(default)]
+    pub access_log: Option<String>,
+    /// IPFIX collector session flows are exported to; `--ipfix-collector` takes precedence.
+    #[serde(default)]
+    pub ipfix_collector: Option<String>,
+    /// Directory forward captures are written to; `--capture-dir` takes precedence.
+    #[serde(default)]
+    pub capture_dir: Option<PathBuf>,
+    /// Size a capture file stops growing at.
+    #[serde(default)]
+    pub capture_max_bytes: Option<u64>,
+    /// Seconds a capture runs for.
+    #[serde(default)]
+    pub capture_max_secs: Option<u64>,
+    /// JSON file a status snapshot is written to periodically; `--status-file` takes precedence.
+    #[serde(default)]
+    pub status_file: Option<PathBuf>,
+    /// Seconds between status file writes.
+    #[serde(default)]
+    pub status_interval_secs: Option<u64>,
+    /// Unix socket the admin commands are served on; `--admin-socket` takes precedence.
+    #[serde(default)]
+    pub admin_socket: Option<PathBuf>,
+    /// Mode of the admin socket, 0600 by default. Other users never get access, since the
+    /// socket can reload and stop pfwd.
+    #[serde(default)]
+    pub admin_socket_mode: Option<u32>,
+    /// Owner of the admin socket, e.g. to let an operators' group in with mode 0660.
+    #[serde(default)]
+    pub admin_socket_owner: Option<Owner>,
+    /// Address the admin API is served on over HTTP; `--admin-listen` takes precedence.
+    #[serde(default)]
+    pub admin_listen: Option<String>,
+    /// File holding the bearer token the admin and gRPC APIs require.
+    #[serde(default)]
+    pub admin_token_file: Option<PathBuf>,
+    /// Address the gRPC control API is served on; `--grpc-listen` takes precedence.
+    #[serde(default)]
+    pub grpc_listen: Option<String>,
 }
 
 #[serde_as]
-#[derive(Debug, Deserialize, Default, Clone)]
+#[derive(Debug, Deserialize, Default, Clone, PartialEq)]
 pub struct ForwardSpec {
     #[serde(default)]
     pub label: Option<String>,
@@ -140,13 +755,69 @@ pub struct ForwardSpec {
     pub listen: Option<String>,
     #[serde(default)]
     pub namespace: Option<String>,
+    /// Directory named namespaces live in, for runtimes that do not use `/var/run/netns`.
+    #[serde(default)]
+    pub netns_dir: Option<PathBuf>,
     #[serde(default)]
     pub setns_path: Option<PathBuf>,
+    /// Join the network namespace of this process via `/proc/<pid>/ns/net`.
+    #[serde(default)]
+    pub setns_pid: Option<u32>,
+    /// Also join the namespaced process's mount namespace before binding `uds`.
+    #[serde(default)]
+    pub setns_mount: Option<bool>,
+    /// Read `owner` as ids inside the namespaced process's user namespace.
+    #[serde(default)]
+    pub setns_user: Option<bool>,
     #[serde(default)]
     pub uds: Option<PathBuf>,
     #[serde(default)]
     pub target: Option<String>,
     #[serde(default)]
+    pub target_exec: Option<String>,
+    #[serde(default)]
+    pub target_balance: Option<TargetBalance>,
+    #[serde(default)]
+    pub target_pool: Option<usize>,
+    #[serde(default)]
+    pub mirror_target: Option<String>,
+    #[serde(default)]
+    pub record_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub chaos_latency_ms: Option<u64>,
+    #[serde(default)]
+    pub chaos_jitter_ms: Option<u64>,
+    #[serde(default)]
+    pub chaos_reset_probability: Option<f64>,
+    #[serde(default)]
+    pub chaos_drop_probability: Option<f64>,
+    #[serde(default)]
+    pub filter_wasm: Option<PathBuf>,
+    #[serde(default)]
+    pub on_accept_lua: Option<PathBuf>,
+    #[serde(default)]
+    pub on_connect_exec: Option<String>,
+    #[serde(default)]
+    pub on_up: Option<String>,
+    #[serde(default)]
+    pub on_down: Option<String>,
+    #[serde(default)]
+    pub shape_rate: Option<u64>,
+    #[serde(default)]
+    pub shape_burst: Option<u64>,
+    #[serde(default)]
+    pub shape_delay_ms: Option<u64>,
+    #[serde(default)]
+    pub shape_delay_jitter_ms: Option<u64>,
+    #[serde(default)]
+    pub shape_delay_distribution: Option<ShapeDistribution>,
+    #[serde(default)]
+    pub resolve_in_namespace: Option<bool>,
+    #[serde(default)]
+    pub target_resolve_ttl_secs: Option<u64>,
+    #[serde(default)]
+    pub ipv6_only: Option<bool>,
+    #[serde(default)]
     pub mode: Option<u32>,
     #[serde(default)]
     pub owner: Option<Owner>,
@@ -158,14 +829,64 @@ pub struct ForwardSpec {
     pub udp_target: Option<String>,
     #[serde(default)]
     pub udp_idle_timeout_secs: Option<u64>,
+    /// Give up if the named namespace has not appeared this long after startup.
+    #[serde(default)]
+    pub namespace_wait_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub udp_session_key: Option<UdpSessionKey>,
+    #[serde(default)]
+    pub mux: Option<bool>,
+    #[serde(default)]
+    pub fd_passing: Option<bool>,
+    #[serde(default)]
+    pub uds_type: Option<UdsType>,
+    #[serde(default)]
+    pub uds_compress: Option<UdsCompress>,
+    #[serde(default)]
+    pub uds_session_id: Option<bool>,
+    #[serde(default)]
+    pub uds_ssh: Option<PathBuf>,
+    #[serde(default)]
+    pub uds_tls: Option<PathBuf>,
+    #[serde(default)]
+    pub uds_http: Option<PathBuf>,
+    #[serde(default)]
+    pub mptcp: Option<bool>,
+    #[serde(default)]
+    pub ftp: Option<bool>,
+    #[serde(default)]
+    pub broadcast_listen: Option<String>,
+    #[serde(default)]
+    pub broadcast_target: Option<String>,
+    #[serde(default)]
+    pub dns_listen: Option<String>,
+    #[serde(default)]
+    pub dns_target: Option<String>,
+    #[serde(default)]
+    pub dns_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub dns_cache: Option<u32>,
+    #[serde(default)]
+    pub uds_listen: Option<PathBuf>,
+    #[serde(default)]
+    pub uds_target: Option<PathBuf>,
+    #[serde(default)]
+    pub reverse: Option<bool>,
+    #[serde(default)]
+    pub uds_mount_ns: Option<PathBuf>,
 }
 
 impl ForwardSpec {
     pub fn apply_defaults(&mut self, defaults: &Defaults) {
-        if self.mode.is_none() {
+        // Abstract sockets have no file to chmod or chown.
+        let abstract_socket = [self.uds.as_deref(), self.uds_listen.as_deref()]
+            .into_iter()
+            .flatten()
+            .any(|path| uds::abstract_name(path).is_some());
+        if self.mode.is_none() && !abstract_socket {
             self.mode = defaults.mode;
         }
-        if self.owner.is_none() {
+        if self.owner.is_none() && !abstract_socket {
             self.owner = defaults.owner.clone();
         }
         if self.backlog.is_none() {
@@ -174,6 +895,12 @@ impl ForwardSpec {
         if self.udp_idle_timeout_secs.is_none() {
             self.udp_idle_timeout_secs = defaults.udp_idle_timeout_secs;
         }
+        if self.namespace_wait_timeout_secs.is_none() && self.netns_name().is_some() {
+            self.namespace_wait_timeout_secs = defaults.namespace_wait_timeout_secs;
+        }
+        if self.netns_dir.is_none() && (self.netns_name().is_some() || self.namespace_template()) {
+            self.netns_dir = defaults.netns_dir.clone();
+        }
         if self.uds.is_none()
             && let (Some(dir), Some(label)) = (defaults.uds_dir.as_ref(), self.label.as_ref())
         {
@@ -183,77 +910,755 @@ impl ForwardSpec {
 
     pub fn validate(&self) -> Result<()> {
         if self.listen.is_none()
-            && self.namespace.is_none()
-            && self.setns_path.is_none()
+            && !self.enters_namespace()
             && self.udp_listen.is_none()
+            && self.broadcast_listen.is_none()
+            && self.dns_listen.is_none()
+            && self.uds_listen.is_none()
+            && !self.requires_uds_endpoint()
         {
             bail!(
-                "forward spec must define a listener (`listen`, `udp_listen`) or namespace entry point"
+                "forward spec must define a listener (`listen`, `udp_listen`, `broadcast_listen`, `dns_listen`, `uds_listen`) or namespace entry point"
             );
         }
 
         if self.requires_uds_path() && self.uds.is_none() {
             bail!("missing uds path (set `uds` or provide defaults.uds_dir + label)");
         }
-        if self.requires_namespace_endpoint() && self.target.is_none() {
-            bail!("namespace endpoint requires `target` to be set");
+        if self.target.is_some() && self.target_exec.is_some() {
+            bail!("`target` and `target_exec` are mutually exclusive");
         }
-
-        if self.listen.is_some()
-            && !self.requires_host_uds_proxy()
-            && !self.requires_direct_tcp_proxy()
+        if self
+            .target_exec
+            .as_deref()
+            .is_some_and(|command| command.trim().is_empty())
         {
-            bail!(
-                "tcp listeners must set `uds` for UDS bridging or `target` for direct TCP proxying"
-            );
+            bail!("`target_exec` must name a command");
         }
-
-        if self.requires_direct_tcp_proxy()
-            && (self.namespace.is_some() || self.setns_path.is_some())
+        if self.target_balance.is_some()
+            && !self.target.as_deref().is_some_and(|target| {
+                !target.starts_with("ssh://") && !target.starts_with("vsock:")
+            })
         {
-            bail!("direct TCP proxy cannot mix with namespace settings");
+            bail!("`target_balance` needs a `target` that resolves through DNS");
         }
-
-        if self.requires_direct_tcp_proxy() && self.uds.is_some() {
-            bail!("direct TCP proxy should not define a UDS path");
+        if let Some(size) = self.target_pool {
+            if !(1..=MAX_TARGET_POOL).contains(&size) {
+                bail!("`target_pool` must be between 1 and {MAX_TARGET_POOL}");
+            }
+            let target = self.target.as_deref().unwrap_or_default();
+            if self.target.is_none()
+                || ["ssh://", "sctp://", "vsock:"]
+                    .iter()
+                    .any(|scheme| target.starts_with(scheme))
+            {
+                bail!("`target_pool` needs a tcp `target`");
+            }
+            if self.ftp() {
+                bail!("`target_pool` does not apply to ftp proxies");
+            }
         }
-
-        match (self.udp_listen.as_ref(), self.udp_target.as_ref()) {
-            (Some(_), Some(_)) => {}
-            (None, None) => {}
-            _ => bail!("udp proxies require both `udp_listen` and `udp_target`"),
+        if let Some(mirror) = self.mirror_target.as_deref() {
+            target::validate(mirror).context("invalid `mirror_target`")?;
+            if !self.relays_target() {
+                bail!(
+                    "`mirror_target` needs a spec that relays to its target (direct tcp proxy or UDS endpoint without `fd_passing`)"
+                );
+            }
         }
-
-        if let Some(timeout) = self.udp_idle_timeout_secs {
-            if timeout == 0 {
-                bail!("udp_idle_timeout_secs must be greater than zero");
+        if let Some(dir) = self.record_dir.as_deref() {
+            if !self.relays_target() {
+                bail!(
+                    "`record_dir` needs a spec that relays to its target (direct tcp proxy or UDS endpoint without `fd_passing`)"
+                );
+            }
+            if !dir.is_dir() {
+                bail!("`record_dir` {} is not a directory", dir.display());
             }
         }
-
+        if self.chaos() {
+            for (key, probability) in [
+                ("chaos_reset_probability", self.chaos_reset_probability),
+                ("chaos_drop_probability", self.chaos_drop_probability),
+            ] {
+                if probability.is_some_and(|p| !(0.0..=1.0).contains(&p)) {
+                    bail!("`{key}` must be between 0 and 1");
+                }
+            }
+            if !self.relays_target() {
+                bail!(
+                    "`chaos_*` options need a spec that relays to its target (direct tcp proxy or UDS endpoint without `fd_passing`)"
+                );
+            }
+        }
+        if let Some(module) = self.filter_wasm.as_deref() {
+            filter::validate(module)?;
+            if !self.relays_target() {
+                bail!(
+                    "`filter_wasm` needs a spec that relays to its target (direct tcp proxy or UDS endpoint without `fd_passing`)"
+                );
+            }
+        }
+        if let Some(script) = self.on_accept_lua.as_deref() {
+            lua::validate(script)?;
+            if !self.requires_direct_tcp_proxy() && !self.requires_host_uds_proxy() {
+                bail!("`on_accept_lua` needs a tcp listener (direct tcp proxy or host proxy)");
+            }
+        }
+        if let Some(command) = self.on_connect_exec.as_deref() {
+            if command.split_whitespace().next().is_none() {
+                bail!("`on_connect_exec` must not be empty");
+            }
+            if !self.requires_direct_tcp_proxy() && !self.requires_host_uds_proxy() {
+                bail!("`on_connect_exec` needs a tcp listener (direct tcp proxy or host proxy)");
+            }
+        }
+        for (key, command) in [("on_up", &self.on_up), ("on_down", &self.on_down)] {
+            if command
+                .as_deref()
+                .is_some_and(|command| command.split_whitespace().next().is_none())
+            {
+                bail!("`{key}` must not be empty");
+            }
+        }
+        if self.shaped() {
+            if self.shape_rate == Some(0) || self.shape_burst == Some(0) {
+                bail!("`shape_rate` and `shape_burst` must be greater than zero");
+            }
+            if self.shape_burst.is_some() && self.shape_rate.is_none() {
+                bail!("`shape_burst` needs `shape_rate`");
+            }
+            if self.shape_delay_distribution.is_some() && self.shape_delay_jitter_ms.is_none() {
+                bail!("`shape_delay_distribution` needs `shape_delay_jitter_ms`");
+            }
+            if !self.relays_target() {
+                bail!(
+                    "`shape_*` options need a spec that relays to its target (direct tcp proxy or UDS endpoint without `fd_passing`)"
+                );
+            }
+        }
+        if let Some(kind) = self.neutron_kind()
+            && !matches!(kind, "dhcp" | "router" | "all")
+        {
+            bail!(
+                "`namespace = \"neutron:{kind}\"` must be `neutron:dhcp`, `neutron:router`, or `neutron:all`"
+            );
+        }
+        if self.namespace_glob() == Some("") {
+            bail!("`namespace = \"glob:...\"` needs a pattern");
+        }
+        if self.namespace_template() {
+            let form = if self.neutron_kind().is_some() {
+                "neutron:..."
+            } else {
+                "glob:..."
+            };
+            let placeholders: &[&str] = if self.neutron_kind().is_some() {
+                &[NAMESPACE_PLACEHOLDER, UUID_PLACEHOLDER]
+            } else {
+                &[NAMESPACE_PLACEHOLDER]
+            };
+            if !self.uds.as_deref().is_some_and(|uds| {
+                let uds = uds.to_string_lossy();
+                placeholders
+                    .iter()
+                    .any(|placeholder| uds.contains(placeholder))
+            }) {
+                bail!(
+                    "`namespace = \"{form}\"` needs a `uds` path containing {} so each namespace gets its own socket",
+                    placeholders.join(" or ")
+                );
+            }
+            if (self.listen.is_some() && !self.reverse())
+                || self.udp_listen.is_some()
+                || self.dns_listen.is_some()
+                || self.broadcast_listen.is_some()
+                || self.uds_listen.is_some()
+            {
+                bail!(
+                    "`namespace = \"{form}\"` runs one endpoint per namespace, so it cannot own a host listener"
+                );
+            }
+        } else if let Some(jail) = self.jail() {
+            self.validate_jail(jail)?;
+        } else if let Some(namespace) = self.namespace.as_deref() {
+            ContainerRef::parse(namespace)?;
+        }
+        if let Some(pid) = self.setns_pid {
+            if self.namespace.is_some() || self.setns_path.is_some() {
+                bail!("`setns_pid` cannot be combined with `namespace` or `setns_path`");
+            }
+            if pid == 0 || !Path::new(&format!("/proc/{pid}")).exists() {
+                bail!("`setns_pid` process {pid} does not exist");
+            }
+        }
+        if self.namespace_wait_timeout_secs.is_some() && self.netns_name().is_none() {
+            bail!("`namespace_wait_timeout` only applies to a named `namespace`");
+        }
+        if self.netns_dir.is_some() && self.netns_name().is_none() && !self.namespace_template() {
+            bail!("`netns_dir` only applies to a named or templated `namespace`");
+        }
+        if self.resolve_in_namespace() && self.namespace.is_none() {
+            bail!("`resolve_in_namespace` needs a named `namespace` to find /etc/netns/<name>");
+        }
+        if self.requires_namespace_endpoint() && !self.has_target() {
+            bail!("namespace endpoint requires `target` to be set");
+        }
+
+        if self.listen.is_some()
+            && !self.requires_host_uds_proxy()
+            && !self.requires_direct_tcp_proxy()
+            && !self.requires_ftp_proxy()
+        {
+            bail!(
+                "tcp listeners must set `uds` for UDS bridging or `target` for direct TCP proxying"
+            );
+        }
+
+        if self.requires_direct_tcp_proxy() && self.enters_namespace() {
+            bail!("direct TCP proxy cannot mix with namespace settings");
+        }
+
+        if self.requires_direct_tcp_proxy() && self.uds.is_some() {
+            bail!("direct TCP proxy should not define a UDS path");
+        }
+
+        if self.udp_listen.is_some()
+            && !self.requires_udp_proxy()
+            && !self.requires_udp_host_proxy()
+            && !self.requires_udp_pipeline()
+        {
+            bail!(
+                "udp listeners must set `uds` for UDS bridging or `udp_target` for direct proxying"
+            );
+        }
+        if self.udp_target.is_some()
+            && !self.requires_udp_proxy()
+            && !self.requires_udp_namespace_endpoint()
+            && !self.requires_udp_pipeline()
+        {
+            bail!("udp_target requires `udp_listen` or a namespace to send from");
+        }
+
+        if self.uds.is_some() && self.uses_tcp_uds() && self.uses_udp_uds() {
+            bail!("a UDS path carries either stream (tcp) or datagram (udp) traffic, not both");
+        }
+
+        if self.udp_idle_timeout_secs == Some(0) {
+            bail!("udp_idle_timeout_secs must be greater than zero");
+        }
+
+        if self
+            .udp_session_key
+            .is_some_and(|key| key != UdpSessionKey::Address)
+            && !self.requires_udp_proxy()
+        {
+            bail!("`udp_session_key` only applies to direct udp proxies");
+        }
+
+        if let Some(target) = self.target.as_deref() {
+            target::validate(target).context("invalid `target`")?;
+        }
+        if let Some(listen) = self.listen.as_deref() {
+            validate_listen(listen).context("invalid `listen`")?;
+        }
+        if self.ipv6_only.is_some()
+            && !self
+                .listen
+                .as_deref()
+                .and_then(|listen| listen.parse::<HostPort>().ok())
+                .and_then(|addr| addr.socket_addr())
+                .is_some_and(|addr| addr.is_ipv6() && addr.ip().is_unspecified())
+        {
+            bail!("`ipv6_only` applies to a tcp `listen` on the IPv6 wildcard, e.g. [::]:2222");
+        }
+        if let Some(listen) = self.udp_listen.as_deref() {
+            MulticastListen::parse(listen)?;
+        }
+
+        match (
+            self.broadcast_listen.as_deref(),
+            self.broadcast_target.as_deref(),
+        ) {
+            (Some(listen), Some(target)) => {
+                BroadcastEndpoint::parse(listen)?;
+                BroadcastEndpoint::parse(target)?;
+            }
+            (None, None) => {}
+            _ => bail!("broadcast relays require both `broadcast_listen` and `broadcast_target`"),
+        }
+
+        if self.mptcp() {
+            if self.listen.is_none() && self.target.is_none() {
+                bail!("`mptcp` applies to tcp legs; set `listen` or `target`");
+            }
+            let non_tcp_leg = [self.listen.as_deref(), self.target.as_deref()]
+                .into_iter()
+                .flatten()
+                .any(|addr| sctp::strip_scheme(addr).is_some() || addr.starts_with("vsock:"));
+            if non_tcp_leg {
+                bail!("`mptcp` cannot be combined with sctp:// or vsock: addresses");
+            }
+        }
+
+        match (self.dns_listen.as_ref(), self.dns_target.as_ref()) {
+            (Some(_), Some(_)) | (None, None) => {}
+            _ => bail!("dns proxies require both `dns_listen` and `dns_target`"),
+        }
+        if self.dns_timeout_ms == Some(0) {
+            bail!("dns_timeout_ms must be greater than zero");
+        }
+
+        match (self.uds_listen.as_ref(), self.uds_target.as_ref()) {
+            (Some(listen), Some(target)) => {
+                if listen == target {
+                    bail!("`uds_listen` and `uds_target` must be different paths");
+                }
+                if self.enters_namespace() {
+                    bail!("uds relays run on the host and cannot mix with namespace settings");
+                }
+                if uds::abstract_name(listen).is_some()
+                    && (self.mode.is_some() || self.owner.is_some())
+                {
+                    bail!(
+                        "abstract `@name` sockets have no file, so `mode` and `owner` do not apply"
+                    );
+                }
+            }
+            (None, None) => {}
+            _ => bail!("uds relays require both `uds_listen` and `uds_target`"),
+        }
+
+        if self.reverse()
+            && !(self.enters_namespace() && self.listen.is_some() && self.uds.is_some())
+        {
+            bail!("`reverse` requires `namespace`/`setns_path`, `listen`, and `uds`");
+        }
+
+        if let Some(name) = self.uds.as_deref().and_then(uds::abstract_name) {
+            if name.is_empty() {
+                bail!("abstract uds names need at least one character after `@`");
+            }
+            if self.uses_udp_uds() {
+                bail!("abstract `@name` sockets are only supported for stream (tcp) bridges");
+            }
+            if self.uds_mount_ns.is_some() {
+                bail!("abstract `@name` sockets have no path, so `uds_mount_ns` does not apply");
+            }
+            if self.mode.is_some() || self.owner.is_some() {
+                bail!("abstract `@name` sockets have no file, so `mode` and `owner` do not apply");
+            }
+            if self.reverse() {
+                bail!(
+                    "abstract `@name` sockets are scoped to one network namespace and cannot carry reverse forwards"
+                );
+            }
+        }
+
+        if self.uds_mount_ns.is_some()
+            && !self.requires_namespace_endpoint()
+            && !self.requires_uds_endpoint()
+        {
+            bail!("`uds_mount_ns` only applies to specs that bind a stream `uds` listener");
+        }
+        if self.setns_mount() {
+            if self.uds_mount_ns.is_some() {
+                bail!("`setns_mount` and `uds_mount_ns` both pick a mount namespace; set one");
+            }
+            if !self.requires_namespace_endpoint() || uds::abstract_name(self.uds_path()).is_some()
+            {
+                bail!("`setns_mount` only applies to namespace endpoints that bind a `uds` path");
+            }
+            if !self.reached_via_process() {
+                bail!(
+                    "`setns_mount` needs a namespace reached through a process (`setns_pid`, `/proc/<pid>/ns/net`, or a container namespace)"
+                );
+            }
+        }
+        if self.setns_user() {
+            if !self.requires_namespace_endpoint() || uds::abstract_name(self.uds_path()).is_some()
+            {
+                bail!("`setns_user` only applies to namespace endpoints that bind a `uds` path");
+            }
+            if !self.reached_via_process() {
+                bail!(
+                    "`setns_user` needs a namespace reached through a process (`setns_pid`, `/proc/<pid>/ns/net`, or a container namespace)"
+                );
+            }
+        }
+
+        if self.muxed() && !self.uses_tcp_uds() {
+            bail!("`mux` only applies to tcp UDS bridges (host proxy or namespace endpoint)");
+        }
+
+        if self.ftp() {
+            if self.listen.is_none() || self.target.is_none() {
+                bail!("`ftp` proxies require `listen` and `target`");
+            }
+            if self.uds.is_some() || self.muxed() || self.reverse() || self.mptcp() {
+                bail!(
+                    "`ftp` proxies dial the target directly; drop `uds`, `mux`, `reverse`, and `mptcp`"
+                );
+            }
+            let target = self.target.as_deref().unwrap_or_default();
+            if target.contains("://") || target.starts_with("vsock:") || target.starts_with("srv:")
+            {
+                bail!("`ftp` targets must be plain host:port, got {target}");
+            }
+        }
+
+        if self.sniffs() {
+            if self.listen.is_none() {
+                bail!("`uds_ssh`, `uds_tls`, and `uds_http` route a `listen` socket; set `listen`");
+            }
+            if self.muxed() || self.fd_passing() || self.reverse() {
+                bail!(
+                    "protocol sniffing cannot be combined with `mux`, `fd_passing`, or `reverse`"
+                );
+            }
+        }
+
+        if self.uds_type() == UdsType::Seqpacket {
+            if !self.uses_tcp_uds() {
+                bail!(
+                    "`uds_type` only applies to tcp UDS bridges (host proxy or namespace endpoint)"
+                );
+            }
+            if self.muxed() || self.fd_passing() {
+                bail!("`uds_type = seqpacket` cannot be combined with `mux` or `fd_passing`");
+            }
+        }
+
+        if self.uds_compress() != UdsCompress::None {
+            compress::validate(self.uds_compress())?;
+            if !self.uses_tcp_uds() {
+                bail!(
+                    "`uds_compress` only applies to tcp UDS bridges (host proxy or namespace endpoint)"
+                );
+            }
+            if self.muxed()
+                || self.fd_passing()
+                || self.sniffs()
+                || self.uds_type() == UdsType::Seqpacket
+            {
+                bail!(
+                    "`uds_compress` cannot be combined with `mux`, `fd_passing`, sniffing, or seqpacket"
+                );
+            }
+        }
+
+        if self.uds_session_id() && !self.uses_tcp_uds() {
+            bail!(
+                "`uds_session_id` only applies to tcp UDS bridges (host proxy or namespace endpoint)"
+            );
+        }
+
+        if self.fd_passing() {
+            if !self.uses_tcp_uds() {
+                bail!(
+                    "`fd_passing` only applies to tcp UDS bridges (host proxy or namespace endpoint)"
+                );
+            }
+            if self.muxed() {
+                bail!("`fd_passing` and `mux` are mutually exclusive");
+            }
+            if self.target_exec.is_some() {
+                bail!("`fd_passing` needs a socket target, not `target_exec`");
+            }
+            if let Some(target) = self.target.as_deref()
+                && (target.starts_with("ssh://") || target.starts_with("vsock:"))
+            {
+                bail!("`fd_passing` needs a tcp or sctp target, got {target}");
+            }
+        }
+
         Ok(())
     }
 
     fn requires_uds_path(&self) -> bool {
-        self.requires_namespace_endpoint() || self.requires_host_uds_proxy()
+        self.requires_namespace_endpoint()
+            || self.requires_uds_endpoint()
+            || (self.requires_host_uds_proxy() && !self.sniffs())
+            || self.uses_udp_uds()
+    }
+
+    fn uses_tcp_uds(&self) -> bool {
+        self.requires_namespace_endpoint()
+            || self.requires_uds_endpoint()
+            || self.requires_host_uds_proxy()
+    }
+
+    fn uses_udp_uds(&self) -> bool {
+        self.requires_udp_namespace_endpoint() || self.requires_udp_host_proxy()
+    }
+
+    /// A jail's helper only opens TCP sockets in it: connections to a namespace endpoint's
+    /// `target`, and a reverse forward's listener.
+    fn validate_jail(&self, jail: &str) -> Result<()> {
+        if cfg!(not(target_os = "freebsd")) {
+            bail!("`namespace = \"jail:{jail}\"`: FreeBSD jails are only supported on FreeBSD");
+        }
+        if jail.is_empty() {
+            bail!("`namespace = \"jail:...\"` needs a jail name or id");
+        }
+        if self.setns_path.is_some() {
+            bail!("`setns_path` cannot be combined with a `jail:` namespace");
+        }
+        if !self.requires_namespace_endpoint() && !self.reverse() || self.uses_udp_uds() {
+            bail!(
+                "`jail:` namespaces carry namespace endpoints (`uds` and `target`) and reverse forwards"
+            );
+        }
+        if self.uds_mount_ns.is_some() || self.setns_mount() || self.setns_user() {
+            bail!("`jail:` namespaces have no mount or user namespace to join");
+        }
+        let plain = |addr: &str| addr.parse::<HostPort>().is_ok() && !addr.contains('/');
+        if self.reverse() {
+            if !self.listen.as_deref().is_some_and(plain) || self.mptcp() {
+                bail!("reverse forwards into a jail listen on a plain tcp `host:port`");
+            }
+        } else if self.target_exec.is_some() || !self.target.as_deref().is_some_and(plain) {
+            bail!("`jail:` endpoints dial a plain tcp `target` (`host:port`)");
+        }
+        Ok(())
+    }
+
+    /// Whether the stream leg ends at `target` or a `target_exec` handler.
+    fn has_target(&self) -> bool {
+        self.target.is_some() || self.target_exec.is_some()
+    }
+
+    /// Pattern of a `namespace = "glob:<pattern>"` template spec.
+    pub fn namespace_glob(&self) -> Option<&str> {
+        self.namespace.as_deref()?.strip_prefix("glob:")
+    }
+
+    /// Which Neutron namespaces a `namespace = "neutron:<kind>"` template spec follows.
+    pub fn neutron_kind(&self) -> Option<&str> {
+        self.namespace.as_deref()?.strip_prefix("neutron:")
+    }
+
+    /// Whether the spec is instantiated once per matching namespace (`glob:` or `neutron:`).
+    pub fn namespace_template(&self) -> bool {
+        self.namespace_glob().is_some() || self.neutron_kind().is_some()
+    }
+
+    /// Directory the spec's named or templated namespaces are looked up in.
+    pub fn netns_dir(&self) -> &Path {
+        self.netns_dir
+            .as_deref()
+            .unwrap_or(Path::new(DEFAULT_NETNS_DIR))
+    }
+
+    /// `ip netns` name the spec joins through its `netns_dir`, as opposed to a path, pid,
+    /// container, or pattern.
+    pub fn netns_name(&self) -> Option<&str> {
+        if self.setns_path.is_some() || self.namespace_template() {
+            return None;
+        }
+        let namespace = self.namespace.as_deref()?;
+        match ContainerRef::parse(namespace) {
+            Ok(None) => Some(namespace),
+            _ => None,
+        }
+    }
+
+    /// FreeBSD jail the spec reaches through a helper process (see [`crate::jail`]).
+    pub fn jail(&self) -> Option<&str> {
+        self.namespace.as_deref().and_then(jail::parse)
+    }
+
+    /// How the spec names the namespace it enters, for metrics and traces.
+    pub fn namespace_name(&self) -> Option<String> {
+        self.namespace
+            .clone()
+            .or_else(|| self.setns_path.as_ref().map(|p| p.display().to_string()))
+            .or_else(|| self.setns_pid.map(|pid| format!("/proc/{pid}/ns/net")))
+    }
+
+    pub fn enters_namespace(&self) -> bool {
+        self.namespace.is_some() || self.setns_path.is_some() || self.setns_pid.is_some()
     }
 
     pub fn requires_namespace_endpoint(&self) -> bool {
-        self.target.is_some() && (self.namespace.is_some() || self.setns_path.is_some())
+        self.has_target() && self.enters_namespace() && !self.reverse() && !self.ftp()
+    }
+
+    /// Unix listener bridging to `target` from the host namespace: either on its own, or as the
+    /// host side of a reverse forward whose TCP listener lives in the namespace.
+    pub fn requires_uds_endpoint(&self) -> bool {
+        self.has_target()
+            && self.uds.is_some()
+            && (self.reverse() || (self.listen.is_none() && !self.enters_namespace()))
     }
 
     pub fn requires_host_uds_proxy(&self) -> bool {
-        self.listen.is_some() && self.uds.is_some()
+        self.listen.is_some() && (self.uds.is_some() || self.sniffs())
+    }
+
+    /// Whether the host proxy picks a UDS backend per connection from its first bytes.
+    pub fn sniffs(&self) -> bool {
+        self.uds_ssh.is_some() || self.uds_tls.is_some() || self.uds_http.is_some()
     }
 
     pub fn requires_direct_tcp_proxy(&self) -> bool {
         self.listen.is_some()
             && self.uds.is_none()
-            && self.target.is_some()
+            && !self.sniffs()
+            && self.has_target()
             && !self.requires_namespace_endpoint()
+            && !self.ftp()
+    }
+
+    /// Whether sessions are relayed byte by byte to a target by this spec, which is where mirroring,
+    /// recording, and fault injection hook in.
+    pub fn relays_target(&self) -> bool {
+        (self.requires_direct_tcp_proxy()
+            || self.requires_namespace_endpoint()
+            || self.requires_uds_endpoint())
+            && !self.fd_passing()
+    }
+
+    /// FTP-aware proxy that rewrites data-channel addresses, dialing the target (and passive data
+    /// ports) from the spec's namespace when one is set.
+    pub fn requires_ftp_proxy(&self) -> bool {
+        self.ftp() && self.listen.is_some() && self.target.is_some()
+    }
+
+    /// Re-broadcast datagrams heard on one interface onto another, optionally inside a namespace.
+    pub fn requires_broadcast_relay(&self) -> bool {
+        self.broadcast_listen.is_some() && self.broadcast_target.is_some()
+    }
+
+    /// Unix listener relaying to another Unix socket, with its own owner and mode.
+    pub fn requires_uds_relay(&self) -> bool {
+        self.uds_listen.is_some() && self.uds_target.is_some()
+    }
+
+    pub fn requires_dns_proxy(&self) -> bool {
+        self.dns_listen.is_some() && self.dns_target.is_some()
     }
 
     pub fn requires_udp_proxy(&self) -> bool {
-        self.udp_listen.is_some() && self.udp_target.is_some()
+        self.udp_listen.is_some()
+            && self.udp_target.is_some()
+            && self.uds.is_none()
+            && !self.enters_namespace()
+    }
+
+    /// Host UDP listener relaying datagrams over a `SOCK_DGRAM` Unix socket.
+    pub fn requires_udp_host_proxy(&self) -> bool {
+        self.udp_listen.is_some() && self.uds.is_some()
+    }
+
+    /// Namespace-side receiver for datagrams relayed by a UDP host proxy.
+    pub fn requires_udp_namespace_endpoint(&self) -> bool {
+        self.udp_target.is_some() && self.enters_namespace() && !self.requires_udp_pipeline()
+    }
+
+    /// Host listener and namespace endpoint in one spec without a `uds` path; both legs run in
+    /// this process and share an anonymous datagram socketpair instead.
+    pub fn requires_udp_pipeline(&self) -> bool {
+        self.udp_listen.is_some()
+            && self.udp_target.is_some()
+            && self.enters_namespace()
+            && self.uds.is_none()
+    }
+
+    /// Whether the tcp listener runs inside the namespace and the uds endpoint on the host.
+    pub fn reverse(&self) -> bool {
+        self.reverse.unwrap_or(false)
+    }
+
+    pub fn setns_mount(&self) -> bool {
+        self.setns_mount.unwrap_or(false)
+    }
+
+    pub fn setns_user(&self) -> bool {
+        self.setns_user.unwrap_or(false)
+    }
+
+    /// Whether the namespace is joined through a process, whose other namespaces are then known.
+    fn reached_via_process(&self) -> bool {
+        self.setns_pid.is_some()
+            || self
+                .setns_path
+                .as_deref()
+                .is_some_and(|path| path.starts_with("/proc"))
+            || self
+                .namespace
+                .as_deref()
+                .is_some_and(|ns| matches!(ContainerRef::parse(ns), Ok(Some(_))))
+    }
+
+    /// Whether the UDS leg carries yamux-multiplexed sessions over one long-lived connection.
+    pub fn muxed(&self) -> bool {
+        self.mux.unwrap_or(false)
+    }
+
+    /// Whether threads that enter the namespace see `/etc/netns/<namespace>` over `/etc`.
+    pub fn resolve_in_namespace(&self) -> bool {
+        self.resolve_in_namespace.unwrap_or(false)
+    }
+
+    /// How long resolved target addresses are reused before the name is looked up again; zero
+    /// (the default) resolves on every connection.
+    pub fn target_resolve_ttl(&self) -> Duration {
+        Duration::from_secs(self.target_resolve_ttl_secs.unwrap_or(0))
+    }
+
+    pub fn target_balance(&self) -> TargetBalance {
+        self.target_balance.unwrap_or_default()
+    }
+
+    pub fn uds_compress(&self) -> UdsCompress {
+        self.uds_compress.unwrap_or_default()
+    }
+
+    /// Whether the host proxy sends each session's ID across the UDS for the namespace endpoint
+    /// to log under.
+    pub fn uds_session_id(&self) -> bool {
+        self.uds_session_id.unwrap_or(false)
+    }
+
+    pub fn uds_type(&self) -> UdsType {
+        self.uds_type.unwrap_or_default()
+    }
+
+    /// Whether the namespace endpoint hands connected target sockets to the host proxy over
+    /// SCM_RIGHTS instead of relaying bytes across the UDS.
+    pub fn fd_passing(&self) -> bool {
+        self.fd_passing.unwrap_or(false)
+    }
+
+    /// Whether any `chaos_*` fault injection option is set.
+    pub fn chaos(&self) -> bool {
+        self.chaos_latency_ms.is_some()
+            || self.chaos_jitter_ms.is_some()
+            || self.chaos_reset_probability.is_some()
+            || self.chaos_drop_probability.is_some()
+    }
+
+    /// Whether any `shape_*` link emulation option is set.
+    pub fn shaped(&self) -> bool {
+        self.shape_rate.is_some()
+            || self.shape_burst.is_some()
+            || self.shape_delay_ms.is_some()
+            || self.shape_delay_jitter_ms.is_some()
+            || self.shape_delay_distribution.is_some()
+    }
+
+    pub fn shape_delay_distribution(&self) -> ShapeDistribution {
+        self.shape_delay_distribution.unwrap_or_default()
+    }
+
+    pub fn ftp(&self) -> bool {
+        self.ftp.unwrap_or(false)
+    }
+
+    /// Whether tcp listeners and outbound target connections use Multipath TCP sockets.
+    pub fn mptcp(&self) -> bool {
+        self.mptcp.unwrap_or(false)
     }
 
     pub fn uds_path(&self) -> &Path {
@@ -263,10 +1668,252 @@ impl ForwardSpec {
     pub fn udp_idle_timeout(&self) -> Duration {
         Duration::from_secs(self.udp_idle_timeout_secs.unwrap_or(300))
     }
+
+    /// How long to wait for a named namespace at startup; `None` waits indefinitely.
+    pub fn namespace_wait_timeout(&self) -> Option<Duration> {
+        self.namespace_wait_timeout_secs.map(Duration::from_secs)
+    }
+
+    pub fn dns_timeout(&self) -> Duration {
+        Duration::from_millis(self.dns_timeout_ms.unwrap_or(2000))
+    }
+
+    pub fn udp_session_key(&self) -> UdpSessionKey {
+        self.udp_session_key.unwrap_or_default()
+    }
+}
+
+/// `listen` is `vsock:<cid>:<port>`, `sctp://ip:port`, or a tcp `host:port`.
+fn validate_listen(listen: &str) -> Result<()> {
+    sctp::validate(listen)?;
+    if VsockAddr::parse(listen)?.is_some() {
+        return Ok(());
+    }
+    match sctp::strip_scheme(listen) {
+        Some(addr) => {
+            if addr.parse::<HostPort>()?.socket_addr().is_none() {
+                bail!("sctp listen address {listen} must be ip:port");
+            }
+        }
+        None => {
+            listen.parse::<HostPort>()?;
+        }
+    }
+    Ok(())
+}
+
+/// Socket type of the stream UDS leg.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UdsType {
+    #[default]
+    Stream,
+    /// `SOCK_SEQPACKET`, for clients that rely on record boundaries.
+    Seqpacket,
+}
+
+impl UdsType {
+    pub fn socket_type(self) -> socket2::Type {
+        match self {
+            UdsType::Stream => socket2::Type::STREAM,
+            UdsType::Seqpacket => socket2::Type::SEQPACKET,
+        }
+    }
+}
+
+impl FromStr for UdsType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "stream" => Ok(Self::Stream),
+            "seqpacket" => Ok(Self::Seqpacket),
+            other => bail!("unknown uds_type `{other}` (expected stream or seqpacket)"),
+        }
+    }
+}
+
+/// Compression negotiated on each stream UDS session.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UdsCompress {
+    /// No handshake; bytes cross the UDS unchanged.
+    #[default]
+    None,
+    Zstd,
+}
+
+impl FromStr for UdsCompress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Self::None),
+            "zstd" => Ok(Self::Zstd),
+            other => bail!("unknown uds_compress `{other}` (expected none or zstd)"),
+        }
+    }
+}
+
+/// Destination of the log.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOutput {
+    #[default]
+    Stdout,
+    Syslog,
+    Journald,
+}
+
+impl FromStr for LogOutput {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "stdout" => Ok(Self::Stdout),
+            "syslog" => Ok(Self::Syslog),
+            "journald" => Ok(Self::Journald),
+            other => bail!("unknown log_output `{other}` (expected stdout, syslog, or journald)"),
+        }
+    }
+}
+
+/// Format of log lines.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            other => bail!("unknown log_format `{other}` (expected text or json)"),
+        }
+    }
+}
+
+/// Time-based rotation of the log file.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    #[default]
+    Never,
+    Hourly,
+    Daily,
+}
+
+/// Transport to the OTLP collector.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OtlpProtocol {
+    /// Protobuf over HTTP, usually on port 4318.
+    #[default]
+    Http,
+    /// gRPC, usually on port 4317.
+    Grpc,
+}
+
+impl FromStr for OtlpProtocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "http" => Ok(Self::Http),
+            "grpc" => Ok(Self::Grpc),
+            other => bail!("unknown otlp_protocol `{other}` (expected http or grpc)"),
+        }
+    }
+}
+
+/// Which resolved target address a new connection tries first.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TargetBalance {
+    /// Resolver order, so the first address takes every connection while it is reachable.
+    #[default]
+    First,
+    /// Start each connection one address further along than the previous one.
+    RoundRobin,
+    Random,
+}
+
+impl FromStr for TargetBalance {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "first" => Ok(Self::First),
+            "round_robin" => Ok(Self::RoundRobin),
+            "random" => Ok(Self::Random),
+            other => {
+                bail!("unknown target_balance `{other}` (expected first, round_robin, or random)")
+            }
+        }
+    }
+}
+
+/// How `shape_delay_jitter_ms` spreads the per-chunk delay around `shape_delay_ms`.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ShapeDistribution {
+    /// Anywhere within +/- the jitter, equally likely.
+    #[default]
+    Uniform,
+    /// Normal distribution with the jitter as standard deviation, clamped at zero delay.
+    Normal,
+}
+
+impl FromStr for ShapeDistribution {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "uniform" => Ok(Self::Uniform),
+            "normal" => Ok(Self::Normal),
+            other => {
+                bail!("unknown shape_delay_distribution `{other}` (expected uniform or normal)")
+            }
+        }
+    }
+}
+
+/// How the UDP proxy recognises datagrams that belong to an existing client session.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UdpSessionKey {
+    /// One session per client source address.
+    #[default]
+    Address,
+    /// Follow QUIC connection IDs so a client whose address changes keeps its upstream socket.
+    Quic,
+    /// Follow WireGuard session indices for the same reason.
+    Wireguard,
+}
+
+impl FromStr for UdpSessionKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "address" => Ok(Self::Address),
+            "quic" => Ok(Self::Quic),
+            "wireguard" => Ok(Self::Wireguard),
+            other => {
+                bail!("unknown udp_session_key `{other}` (expected address, quic, or wireguard)")
+            }
+        }
+    }
 }
 
 #[serde_as]
-#[derive(Debug, Deserialize, Clone, Default)]
+#[derive(Debug, Deserialize, Clone, Default, PartialEq, Eq)]
 pub struct Owner {
     #[serde_as(as = "DisplayFromStr")]
     pub uid: u32,
@@ -324,7 +1971,10 @@ fn parse_mode(value: &str) -> Result<u32> {
 }
 
 pub fn load_config(cli: &Cli) -> Result<(Defaults, Vec<ForwardSpec>)> {
-    let FileConfig { defaults, forward } = if let Some(path) = cli.config.as_ref() {
+    let FileConfig {
+        mut defaults,
+        forward,
+    } = if let Some(path) = cli.config.as_ref() {
         let data = fs::read_to_string(path)
             .with_context(|| format!("failed to read config file {}", path.display()))?;
         toml::from_str::<FileConfig>(&data)
@@ -333,12 +1983,191 @@ pub fn load_config(cli: &Cli) -> Result<(Defaults, Vec<ForwardSpec>)> {
         FileConfig::default()
     };
 
+    if let Some(output) = cli.log_output {
+        defaults.log_output = Some(output);
+    }
+    if let Some(format) = cli.log_format {
+        defaults.log_format = Some(format);
+    }
+    if defaults.log_format == Some(LogFormat::Json)
+        && defaults.log_output == Some(LogOutput::Journald)
+    {
+        bail!("`log_format = \"json\"` does not apply to journald, which keeps fields structured");
+    }
+    if let Some(facility) = cli.syslog_facility.clone() {
+        defaults.syslog_facility = Some(facility);
+    }
+    if matches!(
+        defaults.log_output,
+        Some(LogOutput::Syslog | LogOutput::Journald)
+    ) {
+        Priorities::new(
+            defaults.syslog_facility.as_deref(),
+            &defaults.syslog_severity,
+        )?;
+    } else if defaults.syslog_facility.is_some() || !defaults.syslog_severity.is_empty() {
+        bail!(
+            "`syslog_facility` and `syslog_severity` need `log_output` set to syslog or journald"
+        );
+    }
+    if let Some(path) = cli.log_file.clone() {
+        defaults.log_file = Some(path);
+    }
+    if defaults.log_file.is_some() {
+        if matches!(
+            defaults.log_output,
+            Some(LogOutput::Syslog | LogOutput::Journald)
+        ) {
+            bail!("`log_file` cannot be combined with `log_output` syslog or journald");
+        }
+    } else if defaults.log_file_max_bytes.is_some()
+        || defaults.log_file_rotation.is_some()
+        || defaults.log_file_keep.is_some()
+    {
+        bail!("`log_file_max_bytes`, `log_file_rotation`, and `log_file_keep` need a `log_file`");
+    }
+    if defaults.log_file_max_bytes == Some(0) {
+        bail!("`log_file_max_bytes` must be at least 1");
+    }
+    if let Some(listen) = cli.metrics_listen.clone() {
+        defaults.metrics_listen = Some(listen);
+    }
+    if let Some(listen) = defaults.metrics_listen.as_deref() {
+        listen
+            .parse::<SocketAddr>()
+            .with_context(|| format!("invalid metrics_listen address {listen}"))?;
+    }
+    if let Some(listen) = cli.health_listen.clone() {
+        defaults.health_listen = Some(listen);
+    }
+    if let Some(listen) = defaults.health_listen.as_deref() {
+        listen
+            .parse::<SocketAddr>()
+            .with_context(|| format!("invalid health_listen address {listen}"))?;
+    }
+    if let Some(endpoint) = cli.otlp_endpoint.clone() {
+        defaults.otlp_endpoint = Some(endpoint);
+    }
+    if let Some(protocol) = cli.otlp_protocol {
+        defaults.otlp_protocol = Some(protocol);
+    }
+    for attribute in &cli.otlp_resource {
+        let Some((key, value)) = attribute.split_once('=') else {
+            bail!("--otlp-resource expects key=value, got `{attribute}`");
+        };
+        defaults
+            .otlp_resource
+            .insert(key.to_string(), value.to_string());
+    }
+    if let Some(endpoint) = defaults.otlp_endpoint.as_deref() {
+        otel::validate(endpoint)?;
+    } else if defaults.otlp_protocol.is_some() || !defaults.otlp_resource.is_empty() {
+        bail!("`otlp_protocol` and `otlp_resource` need an `otlp_endpoint`");
+    }
+    if defaults.otlp_metrics_interval_secs == Some(0) {
+        bail!("`otlp_metrics_interval_secs` must be at least 1");
+    }
+    if let Some(addr) = cli.statsd_addr.clone() {
+        defaults.statsd_addr = Some(addr);
+    }
+    if let Some(addr) = defaults.statsd_addr.as_deref() {
+        addr.parse::<HostPort>()
+            .with_context(|| format!("invalid statsd_addr {addr}"))?;
+    } else if defaults.statsd_prefix.is_some() || defaults.statsd_interval_secs.is_some() {
+        bail!("`statsd_prefix` and `statsd_interval_secs` need a `statsd_addr`");
+    }
+    if defaults.statsd_interval_secs == Some(0) {
+        bail!("`statsd_interval_secs` must be at least 1");
+    }
+    if let Some(access_log) = cli.access_log.clone() {
+        defaults.access_log = Some(access_log);
+    }
+    if let Some(access_log) = defaults.access_log.as_deref() {
+        access_log::Destination::parse(access_log)?;
+    }
+    if let Some(collector) = cli.ipfix_collector.clone() {
+        defaults.ipfix_collector = Some(collector);
+    }
+    if let Some(collector) = defaults.ipfix_collector.as_deref() {
+        collector
+            .parse::<HostPort>()
+            .with_context(|| format!("invalid ipfix_collector {collector}"))?;
+    }
+    if let Some(dir) = cli.capture_dir.clone() {
+        defaults.capture_dir = Some(dir);
+    }
+    if let Some(dir) = defaults.capture_dir.as_deref() {
+        if !dir.is_dir() {
+            bail!("`capture_dir` {} is not a directory", dir.display());
+        }
+    } else if defaults.capture_max_bytes.is_some() || defaults.capture_max_secs.is_some() {
+        bail!("`capture_max_bytes` and `capture_max_secs` need a `capture_dir`");
+    }
+    if defaults.capture_max_secs == Some(0) {
+        bail!("`capture_max_secs` must be at least 1");
+    }
+    if let Some(path) = cli.status_file.clone() {
+        defaults.status_file = Some(path);
+    }
+    if defaults.status_file.is_none() && defaults.status_interval_secs.is_some() {
+        bail!("`status_interval_secs` needs a `status_file`");
+    }
+    if defaults.status_interval_secs == Some(0) {
+        bail!("`status_interval_secs` must be at least 1");
+    }
+    if let Some(path) = cli.admin_socket.clone() {
+        defaults.admin_socket = Some(path);
+    }
+    if defaults.admin_socket.is_none()
+        && (defaults.admin_socket_mode.is_some() || defaults.admin_socket_owner.is_some())
+    {
+        bail!("`admin_socket_mode` and `admin_socket_owner` need an `admin_socket`");
+    }
+    if defaults
+        .admin_socket_mode
+        .is_some_and(|mode| mode & 0o007 != 0)
+    {
+        bail!("`admin_socket_mode` must not give other users access");
+    }
+    if let Some(listen) = cli.admin_listen.clone() {
+        defaults.admin_listen = Some(listen);
+    }
+    if let Some(listen) = defaults.admin_listen.as_deref() {
+        listen
+            .parse::<SocketAddr>()
+            .with_context(|| format!("invalid admin_listen address {listen}"))?;
+        if defaults.admin_token_file.is_none() {
+            bail!("`admin_listen` needs an `admin_token_file`");
+        }
+    }
+    if let Some(listen) = cli.grpc_listen.clone() {
+        defaults.grpc_listen = Some(listen);
+    }
+    if let Some(listen) = defaults.grpc_listen.as_deref() {
+        grpc::validate(listen)?;
+        if defaults.admin_token_file.is_none() {
+            bail!("`grpc_listen` needs an `admin_token_file`");
+        }
+    }
+    if defaults.admin_token_file.is_some()
+        && defaults.admin_listen.is_none()
+        && defaults.grpc_listen.is_none()
+    {
+        bail!("`admin_token_file` needs an `admin_listen` or `grpc_listen`");
+    }
+
     let mut forwards = forward;
     forwards.extend(cli.inline_forwards.iter().map(|f| f.0.clone()));
 
-    for spec in forwards.iter_mut() {
+    for (index, spec) in forwards.iter_mut().enumerate() {
         spec.apply_defaults(&defaults);
-        spec.validate()?;
+        spec.validate().with_context(|| {
+            format!(
+                "forward #{} ({}) is invalid",
+                index + 1,
+                spec.label.as_deref().unwrap_or("unnamed")
+            )
+        })?;
     }
 
     Ok((defaults, forwards))
@@ -0,0 +1,438 @@
+//! Container runtimes as namespace sources: `namespace = "docker:<container>"`,
+//! `"podman:<container>"`, `"lxd:<container>"`, `"lxc:<container>"`, or
+//! `"k8s:<pod>[/<container>]"` resolves to the container's network namespace, looked up through
+//! the runtime each time a thread enters it, so a restarted container is picked up on the next
+//! attempt.
+//!
+//! Docker namespaces are reached through the init process's `/proc/<pid>/ns/net`. Podman reports
+//! the namespace file it created in `SandboxKey`, which for rootless containers lives under the
+//! owner's runtime dir (`/run/user/<uid>/netns`); containers without one (e.g. `network=host`
+//! or slirp4netns) fall back to the pid.
+//!
+//! Kubernetes pods are looked up on the local node through `crictl`, which talks CRI to
+//! containerd or CRI-O (`CONTAINER_RUNTIME_ENDPOINT` or its own config picks the socket). A pod
+//! resolves to its sandbox's netns file; naming a container resolves to that container's init
+//! process instead, which only differs for containers that do not share the pod network.
+//!
+//! LXD system containers are looked up through the LXD API (`$LXD_DIR/unix.socket`, the snap's
+//! socket, or `/var/lib/lxd/unix.socket`); plain liblxc containers through `lxc-info`. Both
+//! resolve to the container's init process.
+
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+
+const DOCKER_SOCKET: &str = "/var/run/docker.sock";
+const PODMAN_SOCKET: &str = "/run/podman/podman.sock";
+const CRICTL: &str = "crictl";
+const LXD_SOCKETS: [&str; 2] = [
+    "/var/snap/lxd/common/lxd/unix.socket",
+    "/var/lib/lxd/unix.socket",
+];
+const LXC_INFO: &str = "lxc-info";
+/// Bound on one API round trip; the runtime answers from memory, so this only trips when the
+/// daemon is wedged.
+const API_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Container reference parsed from a `namespace` value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContainerRef<'a> {
+    Docker(&'a str),
+    Podman(&'a str),
+    Lxd(&'a str),
+    Lxc(&'a str),
+    Kubernetes {
+        pod: &'a str,
+        container: Option<&'a str>,
+    },
+}
+
+impl<'a> ContainerRef<'a> {
+    /// `None` for plain `ip netns` names.
+    pub fn parse(namespace: &'a str) -> Result<Option<Self>> {
+        let Some((scheme, name)) = namespace.split_once(':') else {
+            return Ok(None);
+        };
+        let container = match scheme {
+            "docker" => ContainerRef::Docker(name),
+            "podman" => ContainerRef::Podman(name),
+            "lxd" => ContainerRef::Lxd(name),
+            "lxc" => ContainerRef::Lxc(name),
+            "k8s" => {
+                let (pod, container) = match name.split_once('/') {
+                    Some((pod, container)) => (pod, Some(container)),
+                    None => (name, None),
+                };
+                validate_name(pod).with_context(|| format!("invalid pod in `{namespace}`"))?;
+                if let Some(container) = container {
+                    validate_name(container)
+                        .with_context(|| format!("invalid container in `{namespace}`"))?;
+                }
+                return Ok(Some(ContainerRef::Kubernetes { pod, container }));
+            }
+            // Jails have no namespace file to join; see [`crate::jail`].
+            "jail" => bail!(
+                "`{namespace}` is a FreeBSD jail, which pfwd reaches through a helper process rather than by joining it"
+            ),
+            _ => return Ok(None),
+        };
+        validate_name(name).with_context(|| format!("invalid container in `{namespace}`"))?;
+        Ok(Some(container))
+    }
+
+    /// Network namespace file of the running container.
+    pub fn netns_path(&self) -> Result<PathBuf> {
+        match self {
+            ContainerRef::Podman(name) => {
+                let state = inspect("podman", &podman_socket(), name)?;
+                match state.sandbox_key {
+                    Some(key) if !key.as_os_str().is_empty() => Ok(key),
+                    _ => Ok(proc_ns(state.pid, "net")),
+                }
+            }
+            ContainerRef::Kubernetes {
+                pod,
+                container: None,
+            } => {
+                let sandbox = pod_sandbox(pod)?;
+                let info: CriInspect = crictl(&["inspectp", "--output", "json", &sandbox])?;
+                info.netns_path()
+                    .with_context(|| format!("CRI did not report a netns for pod `{pod}`"))
+            }
+            _ => self.init_pid().map(|pid| proc_ns(pid, "net")),
+        }
+    }
+
+    /// Init process of the container (the sandbox's pause process for a bare `k8s:<pod>`), whose
+    /// other namespaces `setns_mount` and `setns_user` use.
+    pub fn init_pid(&self) -> Result<u32> {
+        match self {
+            ContainerRef::Docker(name) => Ok(inspect("docker", &docker_socket(), name)?.pid),
+            ContainerRef::Podman(name) => Ok(inspect("podman", &podman_socket(), name)?.pid),
+            ContainerRef::Lxd(name) => lxd_pid(name),
+            ContainerRef::Lxc(name) => lxc_pid(name),
+            ContainerRef::Kubernetes { pod, container } => {
+                let sandbox = pod_sandbox(pod)?;
+                let Some(container) = container else {
+                    let info: CriInspect = crictl(&["inspectp", "--output", "json", &sandbox])?;
+                    return info
+                        .info
+                        .pid
+                        .filter(|&pid| pid != 0)
+                        .with_context(|| format!("CRI did not report a pid for pod `{pod}`"));
+                };
+                let id = pod_container(&sandbox, pod, container)?;
+                let info: CriInspect = crictl(&["inspect", "--output", "json", &id])?;
+                info.info.pid.filter(|&pid| pid != 0).with_context(|| {
+                    format!("CRI did not report a pid for container `{pod}/{container}`")
+                })
+            }
+        }
+    }
+}
+
+/// Container names and ids travel in API paths, so keep them to the characters runtimes allow.
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() {
+        bail!("container name is empty");
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+    {
+        bail!("container names may only contain letters, digits, `_`, `.`, and `-`");
+    }
+    Ok(())
+}
+
+fn proc_ns(pid: u32, kind: &str) -> PathBuf {
+    PathBuf::from(format!("/proc/{pid}/ns/{kind}"))
+}
+
+/// The part of `GET /containers/<name>/json` pfwd needs; podman serves the same shape on its
+/// Docker-compatible endpoint.
+#[derive(Deserialize)]
+struct InspectResponse {
+    #[serde(rename = "State")]
+    state: InspectState,
+    #[serde(rename = "NetworkSettings", default)]
+    network: Option<InspectNetwork>,
+}
+
+#[derive(Deserialize)]
+struct InspectState {
+    #[serde(rename = "Running")]
+    running: bool,
+    #[serde(rename = "Pid")]
+    pid: u32,
+}
+
+#[derive(Deserialize)]
+struct InspectNetwork {
+    #[serde(rename = "SandboxKey", default)]
+    sandbox_key: Option<PathBuf>,
+}
+
+struct Running {
+    pid: u32,
+    sandbox_key: Option<PathBuf>,
+}
+
+fn inspect(runtime: &str, socket: &Path, name: &str) -> Result<Running> {
+    let (status, body) = api_get(socket, &format!("/containers/{name}/json"))
+        .with_context(|| format!("{runtime} API at {} failed", socket.display()))?;
+    match status {
+        200 => {}
+        404 => bail!("{runtime} container `{name}` does not exist"),
+        _ => bail!("{runtime} API returned HTTP {status} for container `{name}`"),
+    }
+    let inspect: InspectResponse = serde_json::from_slice(&body)
+        .with_context(|| format!("unexpected {runtime} inspect response"))?;
+    if !inspect.state.running || inspect.state.pid == 0 {
+        bail!("{runtime} container `{name}` is not running");
+    }
+    Ok(Running {
+        pid: inspect.state.pid,
+        sandbox_key: inspect.network.and_then(|network| network.sandbox_key),
+    })
+}
+
+/// `DOCKER_HOST` when it names a unix socket, otherwise the default socket.
+fn docker_socket() -> PathBuf {
+    unix_socket_from_env("DOCKER_HOST").unwrap_or_else(|| PathBuf::from(DOCKER_SOCKET))
+}
+
+/// `CONTAINER_HOST` when it names a unix socket, then the rootless socket under
+/// `$XDG_RUNTIME_DIR` if that service is running, then the rootful one.
+fn podman_socket() -> PathBuf {
+    if let Some(socket) = unix_socket_from_env("CONTAINER_HOST") {
+        return socket;
+    }
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(|dir| Path::new(&dir).join("podman/podman.sock"))
+        .filter(|socket| socket.exists())
+        .unwrap_or_else(|| PathBuf::from(PODMAN_SOCKET))
+}
+
+fn unix_socket_from_env(var: &str) -> Option<PathBuf> {
+    std::env::var(var)
+        .ok()
+        .and_then(|host| host.strip_prefix("unix://").map(PathBuf::from))
+}
+
+/// `GET /1.0/instances/<name>/state`, wrapped in LXD's response envelope.
+#[derive(Deserialize)]
+struct LxdResponse {
+    metadata: LxdState,
+}
+
+#[derive(Deserialize)]
+struct LxdState {
+    status: String,
+    #[serde(default)]
+    pid: u32,
+}
+
+fn lxd_pid(name: &str) -> Result<u32> {
+    let socket = lxd_socket();
+    let (status, body) = api_get(&socket, &format!("/1.0/instances/{name}/state"))
+        .with_context(|| format!("lxd API at {} failed", socket.display()))?;
+    match status {
+        200 => {}
+        404 => bail!("lxd container `{name}` does not exist"),
+        _ => bail!("lxd API returned HTTP {status} for container `{name}`"),
+    }
+    let response: LxdResponse =
+        serde_json::from_slice(&body).context("unexpected lxd state response")?;
+    let state = response.metadata;
+    if state.status != "Running" || state.pid == 0 {
+        bail!("lxd container `{name}` is not running ({})", state.status);
+    }
+    Ok(state.pid)
+}
+
+/// `$LXD_DIR/unix.socket`, otherwise whichever of the snap and package sockets exists.
+fn lxd_socket() -> PathBuf {
+    if let Some(dir) = std::env::var_os("LXD_DIR") {
+        return Path::new(&dir).join("unix.socket");
+    }
+    LXD_SOCKETS
+        .iter()
+        .map(PathBuf::from)
+        .find(|socket| socket.exists())
+        .unwrap_or_else(|| PathBuf::from(LXD_SOCKETS[0]))
+}
+
+/// Init pid of a liblxc container from `lxc-info -H -p`, which prints nothing when it is stopped.
+fn lxc_pid(name: &str) -> Result<u32> {
+    let output = Command::new(LXC_INFO)
+        .args(["-H", "-p", "-n", name])
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("failed to run {LXC_INFO}"))?;
+    if !output.status.success() {
+        bail!(
+            "{LXC_INFO} failed for container `{name}`: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    match String::from_utf8_lossy(&output.stdout).trim().parse() {
+        Ok(pid) if pid != 0 => Ok(pid),
+        _ => bail!("lxc container `{name}` is not running"),
+    }
+}
+
+#[derive(Deserialize)]
+struct CriPods {
+    #[serde(default)]
+    items: Vec<CriObject>,
+}
+
+#[derive(Deserialize)]
+struct CriContainers {
+    #[serde(default)]
+    containers: Vec<CriObject>,
+}
+
+#[derive(Deserialize)]
+struct CriObject {
+    id: String,
+    metadata: CriMetadata,
+}
+
+#[derive(Deserialize)]
+struct CriMetadata {
+    name: String,
+    #[serde(default)]
+    namespace: String,
+}
+
+/// `crictl inspect`/`inspectp` output; `info` is runtime-specific, and both containerd and CRI-O
+/// include the pid and the OCI spec the sandbox was started with.
+#[derive(Deserialize)]
+struct CriInspect {
+    #[serde(default)]
+    info: CriInfo,
+}
+
+#[derive(Deserialize, Default)]
+struct CriInfo {
+    pid: Option<u32>,
+    #[serde(rename = "runtimeSpec")]
+    runtime_spec: Option<OciSpec>,
+}
+
+#[derive(Deserialize)]
+struct OciSpec {
+    linux: Option<OciLinux>,
+}
+
+#[derive(Deserialize)]
+struct OciLinux {
+    #[serde(default)]
+    namespaces: Vec<OciNamespace>,
+}
+
+#[derive(Deserialize)]
+struct OciNamespace {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    path: Option<PathBuf>,
+}
+
+impl CriInspect {
+    /// The sandbox's network namespace file, falling back to its pause process.
+    fn netns_path(self) -> Option<PathBuf> {
+        let from_spec = self
+            .info
+            .runtime_spec
+            .and_then(|spec| spec.linux)
+            .and_then(|linux| linux.namespaces.into_iter().find(|ns| ns.kind == "network"))
+            .and_then(|ns| ns.path)
+            .filter(|path| !path.as_os_str().is_empty());
+        from_spec.or_else(|| {
+            self.info
+                .pid
+                .filter(|&pid| pid != 0)
+                .map(|pid| proc_ns(pid, "net"))
+        })
+    }
+}
+
+/// Id of the ready sandbox for `pod`. `crictl --name` matches a regex, so filter exactly here;
+/// the same pod name in two Kubernetes namespaces is ambiguous.
+fn pod_sandbox(pod: &str) -> Result<String> {
+    let pods: CriPods = crictl(&["pods", "--state", "ready", "--output", "json"])?;
+    let mut matches = pods.items.into_iter().filter(|p| p.metadata.name == pod);
+    let Some(sandbox) = matches.next() else {
+        bail!("kubernetes pod `{pod}` is not running on this node");
+    };
+    if let Some(other) = matches.next() {
+        bail!(
+            "kubernetes pod `{pod}` exists in namespaces `{}` and `{}`",
+            sandbox.metadata.namespace,
+            other.metadata.namespace
+        );
+    }
+    Ok(sandbox.id)
+}
+
+fn pod_container(sandbox: &str, pod: &str, container: &str) -> Result<String> {
+    let containers: CriContainers = crictl(&[
+        "ps", "--pod", sandbox, "--state", "running", "--output", "json",
+    ])?;
+    containers
+        .containers
+        .into_iter()
+        .find(|c| c.metadata.name == container)
+        .map(|c| c.id)
+        .with_context(|| format!("container `{container}` of pod `{pod}` is not running"))
+}
+
+/// Run one `crictl` query and parse its JSON output.
+fn crictl<T: serde::de::DeserializeOwned>(args: &[&str]) -> Result<T> {
+    let output = Command::new(CRICTL)
+        .arg("--timeout")
+        .arg(format!("{}s", API_TIMEOUT.as_secs()))
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("failed to run {CRICTL}"))?;
+    if !output.status.success() {
+        bail!(
+            "{CRICTL} {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("unexpected {CRICTL} {} output", args[0]))
+}
+
+/// Minimal HTTP/1.0 GET over a Unix socket; the server closes the connection after the response,
+/// so the body is everything after the headers.
+fn api_get(socket: &Path, path: &str) -> Result<(u16, Vec<u8>)> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(API_TIMEOUT))?;
+    stream.set_write_timeout(Some(API_TIMEOUT))?;
+    stream.write_all(format!("GET {path} HTTP/1.0\r\nHost: localhost\r\n\r\n").as_bytes())?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let split = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .context("truncated HTTP response")?;
+    let head = std::str::from_utf8(&response[..split]).context("non-UTF-8 HTTP headers")?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .context("malformed HTTP status line")?;
+    Ok((status, response[split + 4..].to_vec()))
+}
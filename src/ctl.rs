@@ -0,0 +1,149 @@
+//! `pfwd status` and `pfwd reload`: one-shot requests to the running daemon over its [`admin`](crate::admin) socket,
+//! for scripts and operators who want an answer rather than a live view like `pfwd top`.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::admin::Client;
+use crate::config::{ReloadArgs, StatusArgs};
+use crate::top;
+
+#[derive(Deserialize)]
+struct Snapshot {
+    time_unix_ms: u64,
+    forwards: Vec<Forward>,
+    sessions: Vec<Session>,
+    errors: Vec<LastError>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Forward {
+    label: String,
+    namespace: Option<String>,
+    state: String,
+    listeners_bound: usize,
+    listeners_expected: usize,
+    consecutive_failures: u32,
+    failure: Option<String>,
+    /// Filled in from the snapshot's sessions.
+    #[serde(default)]
+    sessions: usize,
+}
+
+#[derive(Deserialize)]
+struct Session {
+    label: String,
+    namespace: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct LastError {
+    label: String,
+    namespace: Option<String>,
+    message: String,
+    time_unix_ms: u64,
+}
+
+/// The daemon's answer to `reload`.
+#[derive(Deserialize)]
+struct Reloaded {
+    added: Vec<String>,
+    removed: Vec<String>,
+    restarted: Vec<String>,
+    unchanged: usize,
+}
+
+/// What `pfwd status --json` prints.
+#[derive(Serialize)]
+struct Status {
+    forwards: Vec<Forward>,
+    errors: Vec<LastError>,
+}
+
+pub async fn status(args: &StatusArgs) -> Result<()> {
+    let mut client = Client::connect(&args.socket).await?;
+    let answer = client.call(&json!({ "command": "status" })).await?;
+    let snapshot: Snapshot =
+        serde_json::from_value(answer).context("unexpected status answer from pfwd")?;
+    let mut sessions: HashMap<(&str, Option<&str>), usize> = HashMap::new();
+    for session in &snapshot.sessions {
+        *sessions
+            .entry((&session.label, session.namespace.as_deref()))
+            .or_default() += 1;
+    }
+    let mut forwards = Vec::with_capacity(snapshot.forwards.len());
+    for mut forward in snapshot.forwards {
+        forward.sessions = sessions
+            .get(&(forward.label.as_str(), forward.namespace.as_deref()))
+            .copied()
+            .unwrap_or_default();
+        forwards.push(forward);
+    }
+    // Most recent first.
+    let mut errors = snapshot.errors;
+    errors.sort_by_key(|error| Reverse(error.time_unix_ms));
+    if args.json {
+        let status = Status { forwards, errors };
+        println!("{}", serde_json::to_string_pretty(&status)?);
+        return Ok(());
+    }
+
+    println!(
+        "{:<24} {:<20} {:<10} {:>9} {:>8} {:>8}  FAILURE",
+        "FORWARD", "NAMESPACE", "STATE", "LISTENERS", "SESSIONS", "FAILURES"
+    );
+    for forward in &forwards {
+        println!(
+            "{:<24} {:<20} {:<10} {:>9} {:>8} {:>8}  {}",
+            forward.label,
+            forward.namespace.as_deref().unwrap_or("-"),
+            forward.state,
+            format!("{}/{}", forward.listeners_bound, forward.listeners_expected),
+            forward.sessions,
+            forward.consecutive_failures,
+            forward.failure.as_deref().unwrap_or("-"),
+        );
+    }
+    if !errors.is_empty() {
+        println!(
+            "\n{:<24} {:<20} {:>8}  ERROR",
+            "FORWARD", "NAMESPACE", "AGO"
+        );
+        for error in &errors {
+            println!(
+                "{:<24} {:<20} {:>8}  {}",
+                error.label,
+                error.namespace.as_deref().unwrap_or("-"),
+                top::age(snapshot.time_unix_ms.saturating_sub(error.time_unix_ms)),
+                error.message,
+            );
+        }
+    }
+    Ok(())
+}
+
+pub async fn reload(args: &ReloadArgs) -> Result<()> {
+    let mut client = Client::connect(&args.socket).await?;
+    let answer = client.call(&json!({ "command": "reload" })).await?;
+    if args.json {
+        println!("{answer}");
+        return Ok(());
+    }
+    let reloaded: Reloaded =
+        serde_json::from_value(answer).context("unexpected reload answer from pfwd")?;
+    for (change, labels) in [
+        ("added", &reloaded.added),
+        ("removed", &reloaded.removed),
+        ("restarted", &reloaded.restarted),
+    ] {
+        for label in labels {
+            println!("{change:<9} {label}");
+        }
+    }
+    println!("{} unchanged", reloaded.unchanged);
+    Ok(())
+}
@@ -0,0 +1,106 @@
+//! `pfwd namespaces`: find the network namespaces on the host by scanning `/proc/*/ns/net` and
+//! grouping processes by namespace identity.
+//!
+//! Runtimes that never register their namespaces under `/var/run/netns` still leave them
+//! reachable through their processes, so every namespace is listed with a process a forward can
+//! join it through (`setns_pid`, or `setns_path = "/proc/<pid>/ns/net"`), along with any names it
+//! is mounted under.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::config::NamespacesArgs;
+use crate::netns::NetnsId;
+
+/// One network namespace and what refers to it.
+#[derive(Debug, Default, Serialize)]
+struct Namespace {
+    id: String,
+    /// Whether this is the namespace pfwd itself runs in.
+    current: bool,
+    /// Names it is mounted under in the netns directory.
+    names: Vec<String>,
+    /// Processes inside it, in pid order.
+    pids: Vec<u32>,
+    /// `comm` of the first process.
+    command: Option<String>,
+}
+
+pub fn list(args: &NamespacesArgs) -> Result<()> {
+    let namespaces = scan(&args.netns_dir)?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&namespaces)?);
+        return Ok(());
+    }
+    println!(
+        "{:<18} {:>5} {:>8} {:<16} NAMES",
+        "NETNS", "PROCS", "PID", "COMMAND"
+    );
+    for ns in &namespaces {
+        let mut names = ns.names.join(",");
+        if ns.current {
+            names = if names.is_empty() {
+                "(current)".to_string()
+            } else {
+                format!("{names} (current)")
+            };
+        }
+        println!(
+            "{:<18} {:>5} {:>8} {:<16} {names}",
+            ns.id,
+            ns.pids.len(),
+            ns.pids.first().map(u32::to_string).unwrap_or("-".into()),
+            ns.command.as_deref().unwrap_or("-"),
+        );
+    }
+    Ok(())
+}
+
+/// Every namespace with a process in it or a name in `netns_dir`, ordered by identity.
+fn scan(netns_dir: &Path) -> Result<Vec<Namespace>> {
+    let mut found: BTreeMap<NetnsId, Namespace> = BTreeMap::new();
+    for entry in fs::read_dir("/proc").context("failed to read /proc")? {
+        let entry = entry?;
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|n| n.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        // Processes exit, and others' namespaces may be off limits; skip what cannot be read.
+        let Ok(id) = NetnsId::of(&entry.path().join("ns/net")) else {
+            continue;
+        };
+        found.entry(id).or_default().pids.push(pid);
+    }
+    if let Ok(entries) = fs::read_dir(netns_dir) {
+        for entry in entries.flatten() {
+            let Ok(id) = NetnsId::of(&entry.path()) else {
+                continue;
+            };
+            let name = entry.file_name().to_string_lossy().into_owned();
+            found.entry(id).or_default().names.push(name);
+        }
+    }
+    let current = NetnsId::of(Path::new("/proc/self/ns/net")).ok();
+    Ok(found
+        .into_iter()
+        .map(|(id, mut ns)| {
+            ns.pids.sort_unstable();
+            ns.names.sort();
+            ns.id = id.to_string();
+            ns.current = Some(id) == current;
+            ns.command = ns.pids.first().and_then(|pid| {
+                fs::read_to_string(format!("/proc/{pid}/comm"))
+                    .ok()
+                    .map(|comm| comm.trim_end().to_string())
+            });
+            ns
+        })
+        .collect())
+}
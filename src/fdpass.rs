@@ -0,0 +1,81 @@
+//! Passing connected sockets between the namespace endpoint and the host proxy with SCM_RIGHTS.
+//!
+//! Each message is a single status byte. A successful hand-off carries the target socket as
+//! ancillary data; a failed one carries no descriptor and is followed by EOF.
+
+use std::io::{self, IoSlice, IoSliceMut};
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+
+use nix::cmsg_space;
+use nix::sys::socket::{ControlMessage, ControlMessageOwned, MsgFlags, recvmsg, sendmsg};
+use tokio::io::Interest;
+use tokio::net::UnixStream;
+
+const STATUS_OK: u8 = 0;
+const STATUS_FAILED: u8 = 1;
+
+/// Send `fd` to the peer of `stream`. The caller may close its copy afterwards.
+pub async fn send_fd(stream: &UnixStream, fd: BorrowedFd<'_>) -> io::Result<()> {
+    let fds = [fd.as_raw_fd()];
+    stream
+        .async_io(Interest::WRITABLE, || {
+            let iov = [IoSlice::new(&[STATUS_OK])];
+            let cmsg = [ControlMessage::ScmRights(&fds)];
+            sendmsg::<()>(
+                stream.as_raw_fd(),
+                &iov,
+                &cmsg,
+                MsgFlags::MSG_NOSIGNAL,
+                None,
+            )
+            .map_err(io::Error::from)
+        })
+        .await?;
+    Ok(())
+}
+
+/// Tell the peer no descriptor is coming for this connection.
+pub async fn send_failure(stream: &UnixStream) -> io::Result<()> {
+    stream
+        .async_io(Interest::WRITABLE, || {
+            let iov = [IoSlice::new(&[STATUS_FAILED])];
+            sendmsg::<()>(stream.as_raw_fd(), &iov, &[], MsgFlags::MSG_NOSIGNAL, None)
+                .map_err(io::Error::from)
+        })
+        .await?;
+    Ok(())
+}
+
+/// Receive the descriptor sent by [`send_fd`]; `None` when the peer reported a failure or closed
+/// the connection without sending one.
+pub async fn recv_fd(stream: &UnixStream) -> io::Result<Option<OwnedFd>> {
+    stream
+        .async_io(Interest::READABLE, || {
+            let mut status = [0u8; 1];
+            let mut iov = [IoSliceMut::new(&mut status)];
+            let mut cmsg_buf = cmsg_space!([RawFd; 1]);
+            let msg = recvmsg::<()>(
+                stream.as_raw_fd(),
+                &mut iov,
+                Some(&mut cmsg_buf),
+                MsgFlags::MSG_CMSG_CLOEXEC,
+            )
+            .map_err(io::Error::from)?;
+            let mut received = None;
+            for cmsg in msg.cmsgs().map_err(io::Error::from)? {
+                if let ControlMessageOwned::ScmRights(fds) = cmsg {
+                    for fd in fds {
+                        // SAFETY: the kernel just installed this descriptor in our table and
+                        // nothing else owns it.
+                        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+                        received.get_or_insert(fd);
+                    }
+                }
+            }
+            if msg.bytes == 0 || status[0] != STATUS_OK {
+                return Ok(None);
+            }
+            Ok(received)
+        })
+        .await
+}
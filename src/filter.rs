@@ -0,0 +1,237 @@
+//! Byte-stream filters run by the relay on every chunk, per direction.
+//!
+//! [`Filter`] is the extension point: one instance per session sees each chunk in order and
+//! returns what should be forwarded instead (possibly empty, to hold bytes back). [`FilterPlugin`]
+//! produces those instances for a forward. The only plugin shipped is `filter_wasm`, enabled with
+//! the `wasm` cargo feature, which runs a WebAssembly module per session. The module exports:
+//!
+//! ```text
+//! memory                                   linear memory the chunk is exchanged through
+//! alloc(len: i32) -> i32                   buffer of `len` bytes for the incoming chunk
+//! filter(direction, ptr, len: i32) -> i64  direction 0 = client→target, 1 = target→client;
+//!                                          returns (out_ptr << 32) | out_len
+//! ```
+//!
+//! A module instance lives as long as its session, so it can keep state across chunks and
+//! directions. Each call runs on a fuel budget so a runaway filter fails its session instead of
+//! running forever. The relay calls filters on tokio's blocking threads, so a call that uses its
+//! whole budget holds up only its own session.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Result, bail};
+
+use crate::config::ForwardSpec;
+
+/// Which way a chunk is flowing through the relay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ClientToTarget,
+    TargetToClient,
+}
+
+/// Per-session transformation of the bytes a relay forwards.
+pub trait Filter: Send {
+    fn filter(&mut self, direction: Direction, chunk: Vec<u8>) -> Result<Vec<u8>>;
+}
+
+/// Creates the [`Filter`] for each session of one forward.
+pub trait FilterPlugin: Send + Sync {
+    fn session(&self) -> Result<Box<dyn Filter>>;
+}
+
+/// Reject `filter_wasm` at config load when the feature is compiled out or the module is missing.
+pub fn validate(module: &Path) -> Result<()> {
+    if !cfg!(feature = "wasm") {
+        bail!("`filter_wasm` needs rebuilding pfwd with `--features wasm`");
+    }
+    if !module.is_file() {
+        bail!("`filter_wasm` module {} does not exist", module.display());
+    }
+    Ok(())
+}
+
+/// Load the spec's filter plugin, compiling its module once for all sessions.
+pub fn for_spec(spec: &ForwardSpec) -> Result<Option<Arc<dyn FilterPlugin>>> {
+    let Some(module) = spec.filter_wasm.as_deref() else {
+        return Ok(None);
+    };
+    #[cfg(feature = "wasm")]
+    {
+        Ok(Some(Arc::new(wasm::WasmPlugin::load(module)?)))
+    }
+    #[cfg(not(feature = "wasm"))]
+    {
+        // Config load already rejected this; keep the error if the spec came from elsewhere.
+        validate(module)?;
+        Ok(None)
+    }
+}
+
+#[cfg(feature = "wasm")]
+mod wasm {
+    use std::path::Path;
+
+    use anyhow::{Context, Result};
+    use wasmtime::{
+        Config, Engine, Instance, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc,
+    };
+
+    use super::{Direction, Filter, FilterPlugin};
+
+    /// Instructions (roughly) one `filter` call may execute.
+    const FUEL_PER_CALL: u64 = 50_000_000;
+    /// Linear memory one session's instance may grow to; `memory.grow` past it fails.
+    const MEMORY_PER_SESSION: usize = 64 * 1024 * 1024;
+
+    pub struct WasmPlugin {
+        engine: Engine,
+        module: Module,
+    }
+
+    impl WasmPlugin {
+        pub fn load(path: &Path) -> Result<Self> {
+            let mut config = Config::new();
+            config.consume_fuel(true);
+            let engine = Engine::new(&config)?;
+            let module = Module::from_file(&engine, path)
+                .map_err(anyhow::Error::from)
+                .with_context(|| format!("failed to load filter module {}", path.display()))?;
+            Ok(Self { engine, module })
+        }
+    }
+
+    impl FilterPlugin for WasmPlugin {
+        fn session(&self) -> Result<Box<dyn Filter>> {
+            let limits = StoreLimitsBuilder::new()
+                .memory_size(MEMORY_PER_SESSION)
+                .build();
+            let mut store = Store::new(&self.engine, limits);
+            store.limiter(|limits| limits);
+            store.set_fuel(FUEL_PER_CALL)?;
+            let instance = Instance::new(&mut store, &self.module, &[])
+                .map_err(anyhow::Error::from)
+                .context("failed to instantiate filter module")?;
+            let memory = instance
+                .get_memory(&mut store, "memory")
+                .context("filter module does not export `memory`")?;
+            let alloc = instance.get_typed_func(&mut store, "alloc")?;
+            let filter = instance.get_typed_func(&mut store, "filter")?;
+            Ok(Box::new(WasmFilter {
+                store,
+                memory,
+                alloc,
+                filter,
+            }))
+        }
+    }
+
+    struct WasmFilter {
+        store: Store<StoreLimits>,
+        memory: Memory,
+        alloc: TypedFunc<i32, i32>,
+        filter: TypedFunc<(i32, i32, i32), i64>,
+    }
+
+    impl Filter for WasmFilter {
+        fn filter(&mut self, direction: Direction, chunk: Vec<u8>) -> Result<Vec<u8>> {
+            self.store.set_fuel(FUEL_PER_CALL)?;
+            let len = i32::try_from(chunk.len())?;
+            let ptr = self.alloc.call(&mut self.store, len)?;
+            self.memory
+                .write(&mut self.store, ptr as u32 as usize, &chunk)
+                .context("filter `alloc` returned a buffer outside its memory")?;
+            let direction = match direction {
+                Direction::ClientToTarget => 0,
+                Direction::TargetToClient => 1,
+            };
+            let packed = self.filter.call(&mut self.store, (direction, ptr, len))? as u64;
+            let (out_ptr, out_len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+            let out = self
+                .memory
+                .data(&self.store)
+                .get(out_ptr..)
+                .and_then(|data| data.get(..out_len))
+                .context("filter returned a chunk outside its memory")?;
+            Ok(out.to_vec())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        /// The plugin for a module given as WAT.
+        fn plugin(wat: &str) -> WasmPlugin {
+            let mut config = Config::new();
+            config.consume_fuel(true);
+            let engine = Engine::new(&config).unwrap();
+            let module = Module::new(&engine, wat).unwrap();
+            WasmPlugin { engine, module }
+        }
+
+        /// A session of a module whose `filter` returns `(out_ptr, out_len)`.
+        fn session(out_ptr: i64, out_len: i64) -> Box<dyn Filter> {
+            let wat = format!(
+                r#"(module
+                    (memory (export "memory") 1)
+                    (func (export "alloc") (param i32) (result i32) i32.const 0)
+                    (func (export "filter") (param i32 i32 i32) (result i64)
+                        i64.const {}))"#,
+                (out_ptr << 32) | out_len
+            );
+            plugin(&wat).session().unwrap()
+        }
+
+        #[test]
+        fn limits_session_memory() {
+            // 2000 pages of 64 KiB are more than a session may have.
+            let large = plugin(
+                r#"(module
+                    (memory (export "memory") 2000)
+                    (func (export "alloc") (param i32) (result i32) i32.const 0)
+                    (func (export "filter") (param i32 i32 i32) (result i64) i64.const 0))"#,
+            );
+            assert!(large.session().is_err());
+            // Growing past the limit fails; this filter then passes one byte through.
+            let mut growing = plugin(
+                r#"(module
+                    (memory (export "memory") 1)
+                    (func (export "alloc") (param i32) (result i32) i32.const 0)
+                    (func (export "filter") (param i32 i32 i32) (result i64)
+                        (i64.extend_i32_u (i32.eq (memory.grow (i32.const 2000)) (i32.const -1)))))"#,
+            )
+            .session()
+            .unwrap();
+            let out = growing
+                .filter(Direction::ClientToTarget, b"x".to_vec())
+                .unwrap();
+            assert_eq!(out.len(), 1);
+        }
+
+        #[test]
+        fn passes_chunk_back() {
+            let mut filter = session(0, 5);
+            let out = filter
+                .filter(Direction::ClientToTarget, b"hello".to_vec())
+                .unwrap();
+            assert_eq!(out, b"hello");
+        }
+
+        #[test]
+        fn rejects_chunk_outside_memory() {
+            // One 64 KiB page: a 4 GiB chunk, or one running past the end, is refused unread.
+            assert!(
+                session(0, 0xffff_ffff)
+                    .filter(Direction::ClientToTarget, b"x".to_vec())
+                    .is_err()
+            );
+            assert!(
+                session(65_535, 2)
+                    .filter(Direction::TargetToClient, b"x".to_vec())
+                    .is_err()
+            );
+        }
+    }
+}
@@ -1,18 +1,800 @@
-use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet};
+use std::os::unix::net::UnixDatagram;
+
+use std::os::unix::process::ExitStatusExt;
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use futures::FutureExt;
+use futures::future::FusedFuture;
 use futures::stream::{FuturesUnordered, StreamExt};
-use tokio::task::JoinHandle;
+use serde::Serialize;
+use tokio::process::{Child, Command};
+use tokio::runtime::Handle;
+use tokio::signal;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{JoinHandle, spawn_blocking};
+use tokio::time::{Instant, sleep, timeout};
+use tracing::{debug, info, warn};
 
-use crate::config::ForwardSpec;
-use crate::pipeline::{self, ShutdownRx, host, namespace, tcp, udp};
+use crate::config::{Cli, Defaults, ExecArgs, ForwardSpec, load_config};
+use crate::container::ContainerRef;
+use crate::pipeline::capture::{Capture, Captured};
+use crate::pipeline::{
+    self, ShutdownRx, ShutdownTx, broadcast, dns, ftp, host, lifecycle, namespace, netns_watch,
+    pause, session, tcp, udp, udp_host, udp_namespace, uds_relay,
+};
+use crate::selector::Selector;
+use crate::statsd::{self, Statsd};
+use crate::{admin, admin_http, grpc, health, log_limit, metrics, netns, preflight, status};
 
-pub async fn run(specs: Vec<ForwardSpec>) -> Result<()> {
-    let (shutdown_tx, shutdown_rx) = pipeline::shutdown_channel();
+/// How often a drain looks whether the open sessions have ended.
+const DRAIN_POLL: Duration = Duration::from_millis(200);
+
+/// How long `add` waits for a new forward's listeners to bind before answering. A forward still
+/// waiting for its namespace by then is left starting.
+const BIND_WAIT: Duration = Duration::from_secs(2);
+
+/// How often `add` and `pfwd exec` look whether new forwards' listeners are bound.
+const BIND_POLL: Duration = Duration::from_millis(20);
+
+/// What the admin interfaces ask of the running daemon.
+pub enum Control {
+    /// Re-read the configuration and bring the forwards in line with it.
+    Reload(oneshot::Sender<Result<Reloaded>>),
+    /// Stop accepting on every forward, then exit once the open sessions have ended, or when the
+    /// deadline passes if one is given.
+    Drain(Option<Duration>),
+    /// Stop accepting on the selected forwards, and remove them once their open sessions have
+    /// ended, killing those left when the deadline passes if one is given.
+    DrainForward {
+        selector: Selector,
+        timeout: Option<Duration>,
+        reply: oneshot::Sender<Result<Drained>>,
+    },
+    /// Exit now, as on Ctrl-C.
+    Shutdown,
+    /// Start another forward, given before `[defaults]` apply; with `replace`, in place of the
+    /// forwards sharing its label.
+    Add {
+        spec: Box<ForwardSpec>,
+        replace: bool,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Stop the selected forwards, and with `kill_sessions` their open sessions too.
+    Remove {
+        selector: Selector,
+        kill_sessions: bool,
+        reply: oneshot::Sender<Result<Removed>>,
+    },
+    /// Start a packet capture of the selected forwards, or of every forward without a selector,
+    /// into the latest `capture_dir`.
+    Capture {
+        selector: Option<Selector>,
+        reply: oneshot::Sender<Result<Captured>>,
+    },
+}
+
+pub type ControlTx = mpsc::UnboundedSender<Control>;
+
+/// What a reload changed, by forward label.
+#[derive(Default, Serialize)]
+pub struct Reloaded {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Forwards whose spec changed, stopped and started again.
+    pub restarted: Vec<String>,
+    pub unchanged: usize,
+}
+
+/// What removing forwards stopped.
+#[derive(Serialize)]
+pub struct Removed {
+    /// The labels of the forwards removed.
+    pub labels: Vec<String>,
+    pub forwards: usize,
+    pub sessions_killed: usize,
+}
+
+/// What draining forwards started on.
+#[derive(Serialize)]
+pub struct Drained {
+    /// The labels of the forwards draining.
+    pub labels: Vec<String>,
+    /// Sessions still open on them.
+    pub sessions: usize,
+}
+
+pub async fn run(specs: Vec<ForwardSpec>, defaults: &Defaults, cli: &Cli) -> Result<()> {
+    let (shutdown_tx, mut shutdown_rx) = pipeline::shutdown_channel();
     let signal_handle = pipeline::spawn_shutdown_listener(shutdown_tx.clone());
+    let (control_tx, mut control_rx) = mpsc::unbounded_channel();
+    let mut exporters: FuturesUnordered<JoinHandle<Result<()>>> = FuturesUnordered::new();
+    enqueue_exporters(&mut exporters, defaults, Some(control_tx), &shutdown_rx);
+    // Forwards added at runtime inherit the latest `[defaults]`.
+    let mut defaults = defaults.clone();
+    let mut forwards = Forwards::new();
+    for spec in specs {
+        forwards.start(spec);
+    }
+
+    // Set once draining, to the time the open sessions are given up on, if any.
+    let mut draining: Option<Option<Instant>> = None;
+    // Forwards drained alone, by label, with the time their sessions are killed, if any.
+    let mut draining_forwards: BTreeMap<String, Option<Instant>> = BTreeMap::new();
+    let res = loop {
+        tokio::select! {
+            Some(res) = exporters.next() => {
+                if let Err(err) = res.map_err(anyhow::Error::from).and_then(|res| res) {
+                    break Err(err);
+                }
+            }
+            res = forwards.ended() => {
+                if let Err(err) = res {
+                    break Err(err);
+                }
+            }
+            Some(control) = control_rx.recv() => match control {
+                Control::Reload(reply) => {
+                    let res = match draining {
+                        Some(_) => Err(anyhow::anyhow!("pfwd is draining")),
+                        None => reload(&mut forwards, &mut defaults, cli).await,
+                    };
+                    let _ = reply.send(res);
+                }
+                Control::Add { spec, replace, reply } => {
+                    let res = match (draining, &spec.label) {
+                        (Some(_), _) => Err(anyhow::anyhow!("pfwd is draining")),
+                        (None, Some(label)) if draining_forwards.contains_key(label) => {
+                            Err(anyhow::anyhow!("forward {label} is draining"))
+                        }
+                        (None, _) => add(&mut forwards, &defaults, *spec, replace).await,
+                    };
+                    let _ = reply.send(res);
+                }
+                Control::Remove { selector, kill_sessions, reply } => {
+                    let _ = reply.send(remove(&mut forwards, &selector, kill_sessions).await);
+                }
+                Control::Capture { selector, reply } => {
+                    let res = Capture::from_defaults(&defaults)
+                        .context("captures need a `capture_dir`")
+                        .and_then(|capture| capture.start_selected(selector.as_ref()));
+                    let _ = reply.send(res);
+                }
+                Control::Drain(timeout) => {
+                    if draining.is_none() {
+                        info!(
+                            sessions = session::open_count(),
+                            "draining: no longer accepting; exiting once open sessions end"
+                        );
+                        if let Err(err) = forwards.stop_all().await {
+                            warn!(error = %err, "forward failed while draining");
+                        }
+                        draining = Some(timeout.map(|timeout| Instant::now() + timeout));
+                    }
+                }
+                Control::DrainForward { selector, timeout, reply } => {
+                    let res = drain_forward(&mut forwards, &selector).await;
+                    if let Ok(drained) = &res {
+                        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+                        for label in &drained.labels {
+                            draining_forwards.insert(label.clone(), deadline);
+                        }
+                    }
+                    let _ = reply.send(res);
+                }
+                Control::Shutdown => {
+                    info!("shutdown requested over the admin socket");
+                    break Ok(());
+                }
+            },
+            _ = sleep(DRAIN_POLL), if draining.is_some() || !draining_forwards.is_empty() => {
+                draining_forwards.retain(|label, deadline| !drained(label, *deadline));
+                if draining.is_none() {
+                    continue;
+                }
+                let open = session::open_count();
+                if open == 0 {
+                    info!("drained; shutting down");
+                    break Ok(());
+                }
+                if draining.flatten().is_some_and(|deadline| Instant::now() >= deadline) {
+                    info!(sessions = open, "drain deadline passed; shutting down");
+                    break Ok(());
+                }
+            }
+            res = shutdown_rx.changed() => {
+                if res.is_err() || *shutdown_rx.borrow() {
+                    break Ok(());
+                }
+            }
+        }
+    };
+
+    let _ = shutdown_tx.send(true);
+    let stopped = forwards.stop_all().await;
+    let exported = join_all(exporters, &shutdown_tx).await;
+
+    signal_handle.abort();
+    let _ = signal_handle.await;
+    lifecycle::finished().await;
+
+    res.and(stopped).and(exported)
+}
+
+/// A configured forward, run on its own shutdown channel so it can be stopped alone.
+struct Running {
+    spec: ForwardSpec,
+    shutdown: ShutdownTx,
+    /// `None` once a contained forward has failed.
+    handle: Option<JoinHandle<Result<()>>>,
+    /// Started at runtime, by `add` or `reload`: its failure is logged and shown in its status
+    /// rather than stopping pfwd, and it stays listed until removed or reloaded.
+    contained: bool,
+}
+
+/// The configured forwards, in the order they were started.
+struct Forwards {
+    running: BTreeMap<u64, Running>,
+    next_id: u64,
+    /// Told a forward's ID when its tasks end, whether stopped or by themselves.
+    done_tx: mpsc::UnboundedSender<u64>,
+    done_rx: mpsc::UnboundedReceiver<u64>,
+    /// The forward [`Forwards::ended`] was waiting for when it was last cancelled.
+    ending: Option<u64>,
+}
+
+impl Forwards {
+    fn new() -> Self {
+        let (done_tx, done_rx) = mpsc::unbounded_channel();
+        Self {
+            running: BTreeMap::new(),
+            next_id: 0,
+            done_tx,
+            done_rx,
+            ending: None,
+        }
+    }
+
+    fn start(&mut self, spec: ForwardSpec) {
+        self.spawn(spec, false);
+    }
+
+    /// Start `spec` at runtime, where it must not take the other forwards down with it.
+    fn start_contained(&mut self, spec: ForwardSpec) -> u64 {
+        self.spawn(spec, true)
+    }
+
+    fn spawn(&mut self, spec: ForwardSpec, contained: bool) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let (shutdown_tx, shutdown_rx) = pipeline::shutdown_channel();
+        let mut tasks = FuturesUnordered::new();
+        enqueue_tasks(&mut tasks, spec.clone(), shutdown_rx);
+        let shutdown = shutdown_tx.clone();
+        let done = self.done_tx.clone();
+        let handle = tokio::spawn(async move {
+            let res = join_all(tasks, &shutdown_tx).await;
+            let _ = done.send(id);
+            res
+        });
+        self.running.insert(
+            id,
+            Running {
+                spec,
+                shutdown,
+                handle: Some(handle),
+                contained,
+            },
+        );
+        id
+    }
+
+    /// Wait for a forward to end by itself, and return how it ended. Errors of the forwards
+    /// configured at startup stop pfwd, as they did before any forward could be stopped alone;
+    /// those of contained forwards are recorded instead.
+    async fn ended(&mut self) -> Result<()> {
+        loop {
+            let id = match self.ending {
+                Some(id) => id,
+                None => {
+                    let Some(id) = self.done_rx.recv().await else {
+                        return std::future::pending().await;
+                    };
+                    self.ending = Some(id);
+                    id
+                }
+            };
+            // Stopped forwards were waited for already.
+            let Some(handle) = self
+                .running
+                .get_mut(&id)
+                .and_then(|running| running.handle.as_mut())
+            else {
+                self.ending = None;
+                continue;
+            };
+            // The handle stays in place while this waits, so a caller that gives up on `ended`
+            // leaves the result for the next call.
+            let res = handle
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|res| res);
+            self.ending = None;
+            let running = self.running.get_mut(&id).expect("forward is running");
+            running.handle = None;
+            match res {
+                Err(err) if running.contained => {
+                    let label = running.spec.label.as_deref().unwrap_or("unnamed");
+                    warn!(label, error = %err, "forward failed");
+                    status::record_failed(&running.spec, &err);
+                }
+                res => {
+                    self.running.remove(&id);
+                    return res;
+                }
+            }
+        }
+    }
+
+    /// Wait for the forward `id`, just started, to bind its listeners, for up to [`BIND_WAIT`]. If
+    /// its tasks fail first, it is dropped and their error returned.
+    async fn bound(&mut self, id: u64) -> Result<()> {
+        let deadline = Instant::now() + BIND_WAIT;
+        loop {
+            let Some(running) = self.running.get(&id) else {
+                return Ok(());
+            };
+            if running.handle.as_ref().is_some_and(JoinHandle::is_finished) {
+                let running = self.running.remove(&id).expect("forward is running");
+                if !running.spec.namespace_template() {
+                    status::forget(&running.spec, listeners(&running.spec));
+                }
+                let handle = running.handle.expect("forward has not failed");
+                return handle.await?;
+            }
+            if listening(&running.spec) || Instant::now() >= deadline {
+                return Ok(());
+            }
+            sleep(BIND_POLL).await;
+        }
+    }
+
+    /// Stop the forward `id` and wait for its tasks to end, returning its spec. Its listeners
+    /// close, while sessions it accepted carry on.
+    async fn stop(&mut self, id: u64) -> Option<ForwardSpec> {
+        let running = self.running.remove(&id)?;
+        let _ = running.shutdown.send(true);
+        let label = running.spec.label.as_deref().unwrap_or("unnamed");
+        // A failed forward's tasks have ended already.
+        if let Some(handle) = running.handle {
+            match handle.await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => warn!(label, error = %err, "forward failed while stopping"),
+                Err(join_err) => warn!(label, error = %join_err, "forward failed while stopping"),
+            }
+        }
+        if !running.spec.namespace_template() {
+            status::forget(&running.spec, listeners(&running.spec));
+        }
+        Some(running.spec)
+    }
+
+    /// IDs of the forwards `selector` picks, with their distinct labels.
+    fn selected(&self, selector: &Selector) -> (Vec<u64>, Vec<String>) {
+        let mut ids = Vec::new();
+        let mut labels = BTreeSet::new();
+        for (&id, running) in &self.running {
+            if let Some(label) = running.spec.label.as_deref()
+                && selector.matches(label)
+            {
+                ids.push(id);
+                labels.insert(label.to_string());
+            }
+        }
+        (ids, labels.into_iter().collect())
+    }
+
+    /// Stop every forward together, returning the first failure.
+    async fn stop_all(&mut self) -> Result<()> {
+        let running = std::mem::take(&mut self.running);
+        for forward in running.values() {
+            let _ = forward.shutdown.send(true);
+        }
+        let mut first_err = None;
+        for forward in running.into_values() {
+            if let Some(handle) = forward.handle
+                && let Err(err) = handle
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|res| res)
+            {
+                first_err.get_or_insert(err);
+            }
+            if !forward.spec.namespace_template() {
+                status::forget(&forward.spec, listeners(&forward.spec));
+            }
+        }
+        first_err.map_or(Ok(()), Err)
+    }
+}
+
+/// Re-read the configuration `cli` points at and bring `forwards` in line with it: forwards no
+/// longer configured are stopped, new ones started, and changed ones restarted. Unchanged forwards
+/// keep running, sessions and all. Forwards pick up the `[defaults]` they inherit; the rest of
+/// `[defaults]`, such as the exporters, keeps its settings until pfwd restarts.
+async fn reload(forwards: &mut Forwards, defaults: &mut Defaults, cli: &Cli) -> Result<Reloaded> {
+    let (reloaded_defaults, mut specs) = load_config(cli)?;
+    let mut stale = Vec::new();
+    let mut unchanged = 0;
+    for (&id, running) in &forwards.running {
+        match specs.iter().position(|spec| *spec == running.spec) {
+            // Failed forwards are started again.
+            Some(index) if running.handle.is_some() => {
+                specs.remove(index);
+                unchanged += 1;
+            }
+            _ => stale.push(id),
+        }
+    }
+    preflight::check(&specs)?;
+    *defaults = reloaded_defaults;
+
+    let label = |spec: &ForwardSpec| spec.label.clone().unwrap_or_else(|| "unnamed".to_string());
+    let mut removed = Vec::new();
+    for id in stale {
+        if let Some(spec) = forwards.stop(id).await {
+            removed.push(label(&spec));
+        }
+    }
+    let mut reloaded = Reloaded {
+        unchanged,
+        ..Reloaded::default()
+    };
+    for spec in specs {
+        let label = label(&spec);
+        match removed.iter().position(|old| *old == label) {
+            Some(index) => {
+                removed.remove(index);
+                reloaded.restarted.push(label);
+            }
+            None => reloaded.added.push(label),
+        }
+        forwards.start_contained(spec);
+    }
+    reloaded.removed = removed;
+    info!(
+        added = ?reloaded.added,
+        removed = ?reloaded.removed,
+        restarted = ?reloaded.restarted,
+        unchanged,
+        "configuration reloaded"
+    );
+    Ok(reloaded)
+}
+
+/// Serve or push the forward metrics where the defaults ask for them, dump them on `SIGUSR1` or
+/// into the status file, answer health probes, the admin socket, and the admin and gRPC APIs, log
+/// forward state changes, summarize suppressed repeated warnings, and capture forwards on
+/// `SIGUSR2`. `control` is where the admin interfaces send requests that change forwards or stop
+/// pfwd, where they are supported.
+fn enqueue_exporters(
+    tasks: &mut FuturesUnordered<JoinHandle<Result<()>>>,
+    defaults: &Defaults,
+    control: Option<ControlTx>,
+    shutdown: &ShutdownRx,
+) {
+    tasks.push(status::spawn_dump_on_signal(shutdown.clone()));
+    tasks.push(status::spawn_state_watch(shutdown.clone()));
+    tasks.push(log_limit::spawn_flush(shutdown.clone()));
+    if let Some(listen) = defaults.metrics_listen.clone() {
+        tasks.push(metrics::spawn(listen, shutdown.clone()));
+    }
+    if let Some(listen) = defaults.health_listen.clone() {
+        tasks.push(health::spawn(listen, shutdown.clone()));
+    }
+    if let Some(addr) = defaults.statsd_addr.clone() {
+        let statsd = Statsd {
+            addr,
+            prefix: defaults.statsd_prefix.clone().unwrap_or("pfwd".into()),
+            interval: defaults
+                .statsd_interval_secs
+                .map_or(statsd::DEFAULT_INTERVAL, Duration::from_secs),
+        };
+        tasks.push(statsd.spawn(shutdown.clone()));
+    }
+    if let Some(capture) = Capture::from_defaults(defaults) {
+        tasks.push(capture.spawn_on_signal(shutdown.clone()));
+    }
+    if let Some(path) = defaults.status_file.clone() {
+        let every = defaults
+            .status_interval_secs
+            .map_or(status::DEFAULT_INTERVAL, Duration::from_secs);
+        tasks.push(status::spawn_file_writer(path, every, shutdown.clone()));
+    }
+    if let Some(path) = defaults.admin_socket.clone() {
+        let socket = admin::Socket {
+            path,
+            owner: defaults.admin_socket_owner.clone(),
+            mode: defaults.admin_socket_mode,
+        };
+        tasks.push(admin::spawn(socket, control.clone(), shutdown.clone()));
+    }
+    if let (Some(listen), Some(token_file)) = (
+        defaults.admin_listen.clone(),
+        defaults.admin_token_file.clone(),
+    ) {
+        tasks.push(admin_http::spawn(
+            listen,
+            token_file,
+            control.clone(),
+            shutdown.clone(),
+        ));
+    }
+    if let (Some(listen), Some(token_file)) = (
+        defaults.grpc_listen.clone(),
+        defaults.admin_token_file.clone(),
+    ) {
+        tasks.push(grpc::spawn(listen, token_file, control, shutdown.clone()));
+    }
+}
+
+/// Start `spec` with `defaults` applied, after the checks a configured forward gets. Its label
+/// must be unique, so it can be removed again, unless it is to `replace` the forwards labelled so.
+async fn add(
+    forwards: &mut Forwards,
+    defaults: &Defaults,
+    mut spec: ForwardSpec,
+    replace: bool,
+) -> Result<()> {
+    let label = spec
+        .label
+        .clone()
+        .context("a forward added at runtime needs a `label`")?;
+    spec.apply_defaults(defaults);
+    spec.validate()
+        .with_context(|| format!("forward {label} is invalid"))?;
+    let (existing, _) = forwards.selected(&Selector::Label(label.clone()));
+    if !replace && !existing.is_empty() {
+        bail!("a forward labelled {label} is already running");
+    }
+    preflight::check(std::slice::from_ref(&spec))?;
+    for id in existing {
+        forwards.stop(id).await;
+    }
+    let id = forwards.start_contained(spec);
+    forwards
+        .bound(id)
+        .await
+        .with_context(|| format!("forward {label} failed to start"))?;
+    info!(label, replace, "forward added");
+    Ok(())
+}
+
+/// Whether every listener `spec`'s forward expects is bound.
+fn listening(spec: &ForwardSpec) -> bool {
+    let namespace = spec.namespace_name();
+    status::forwards().iter().any(|forward| {
+        spec.label.as_deref() == Some(forward.label.as_str())
+            && forward.namespace == namespace
+            && forward.listeners_bound >= forward.listeners_expected
+    })
+}
+
+/// Stop accepting on every forward `selector` picks, leaving their sessions to finish.
+async fn drain_forward(forwards: &mut Forwards, selector: &Selector) -> Result<Drained> {
+    let (ids, labels) = forwards.selected(selector);
+    if ids.is_empty() {
+        bail!("no forward matches {selector}");
+    }
+    for id in ids {
+        forwards.stop(id).await;
+    }
+    let mut total = 0;
+    for label in &labels {
+        pause::resume(label);
+        let sessions = session::count_labelled(label);
+        info!(label, sessions, "draining forward: no longer accepting");
+        total += sessions;
+    }
+    Ok(Drained {
+        labels,
+        sessions: total,
+    })
+}
+
+/// Whether the forward labelled `label` is done draining, either because its sessions have ended
+/// or because `deadline` passed and those left were killed.
+fn drained(label: &str, deadline: Option<Instant>) -> bool {
+    let open = session::count_labelled(label);
+    if open == 0 {
+        info!(label, "forward drained");
+        return true;
+    }
+    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        let killed = session::kill_labelled(label);
+        info!(
+            label,
+            sessions_killed = killed,
+            "forward drain deadline passed"
+        );
+        return true;
+    }
+    false
+}
+
+/// Stop every forward `selector` picks, and with `kill_sessions` every session they have open.
+/// Otherwise the sessions carry on until they end.
+async fn remove(
+    forwards: &mut Forwards,
+    selector: &Selector,
+    kill_sessions: bool,
+) -> Result<Removed> {
+    let (ids, labels) = forwards.selected(selector);
+    if ids.is_empty() {
+        bail!("no forward matches {selector}");
+    }
+    for &id in &ids {
+        forwards.stop(id).await;
+    }
+    let mut total_killed = 0;
+    for label in &labels {
+        pause::resume(label);
+        let sessions_killed = if kill_sessions {
+            session::kill_labelled(label)
+        } else {
+            0
+        };
+        info!(label, sessions_killed, "forward removed");
+        total_killed += sessions_killed;
+    }
+    Ok(Removed {
+        labels,
+        forwards: ids.len(),
+        sessions_killed: total_killed,
+    })
+}
+
+/// How long `pfwd exec` waits for every forward to bind before it starts the command anyway.
+const EXEC_STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `pfwd exec`: bring up `specs`, run the command in its namespace, and tear the forwards down
+/// when it exits. Returns the exit code to leave with, mirroring the command's.
+pub async fn exec(specs: Vec<ForwardSpec>, defaults: &Defaults, args: ExecArgs) -> Result<i32> {
+    let namespace = exec_namespace(&specs, defaults, args.namespace)?;
+    let (shutdown_tx, shutdown_rx) = pipeline::shutdown_channel();
     let mut tasks: FuturesUnordered<JoinHandle<Result<()>>> = FuturesUnordered::new();
+    enqueue_exporters(&mut tasks, defaults, None, &shutdown_rx);
     for spec in specs {
         enqueue_tasks(&mut tasks, spec, shutdown_rx.clone());
     }
+    let forwards = join_all(tasks, &shutdown_tx).fuse();
+    tokio::pin!(forwards);
+    tokio::select! {
+        res = &mut forwards => res?,
+        res = timeout(EXEC_STARTUP_TIMEOUT, all_listening()) => {
+            if res.is_err() {
+                let pending: Vec<String> =
+                    status::pending().into_iter().map(|forward| forward.label).collect();
+                warn!(
+                    ?pending,
+                    timeout = ?EXEC_STARTUP_TIMEOUT,
+                    "forwards are not listening yet; starting the command anyway"
+                );
+            }
+        }
+    }
 
+    let mut child = spawn_command(namespace, args.command).await?;
+    // Ctrl-C reaches the command through the terminal; pfwd waits for it to exit instead.
+    let signal_handle = tokio::spawn(async {
+        while signal::ctrl_c().await.is_ok() {
+            debug!("ctrl_c received; waiting for the command to exit");
+        }
+    });
+    let res = tokio::select! {
+        status = child.wait() => status.map_err(anyhow::Error::from),
+        res = &mut forwards, if !forwards.is_terminated() => {
+            let err = res.err().unwrap_or_else(|| anyhow::anyhow!("forwards stopped"));
+            warn!(error = %err, "forward failed; stopping the command");
+            let _ = child.start_kill();
+            let _ = child.wait().await;
+            Err(err)
+        }
+    };
+    let _ = shutdown_tx.send(true);
+    if !forwards.is_terminated() {
+        forwards.await?;
+    }
+    signal_handle.abort();
+    lifecycle::finished().await;
+
+    let status = res?;
+    info!(%status, "command exited; forwards stopped");
+    Ok(status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1))
+}
+
+/// Resolve once every configured forward has bound all its listeners.
+async fn all_listening() {
+    while !status::pending().is_empty() {
+        sleep(BIND_POLL).await;
+    }
+}
+
+/// Spec describing where `pfwd exec` runs its command: `--namespace`, else the namespace every
+/// forward names, else the host.
+fn exec_namespace(
+    specs: &[ForwardSpec],
+    defaults: &Defaults,
+    namespace: Option<String>,
+) -> Result<Option<ForwardSpec>> {
+    let namespace = match namespace {
+        Some(namespace) => namespace,
+        None => {
+            let mut named = specs.iter().filter_map(|spec| spec.namespace.as_deref());
+            let Some(first) = named.next() else {
+                return Ok(None);
+            };
+            if named.any(|other| other != first) {
+                bail!("forwards use several namespaces; choose one with `--namespace`");
+            }
+            first.to_string()
+        }
+    };
+    // A forward naming the same namespace says where to find it, else `[defaults]` does.
+    let netns_dir = specs
+        .iter()
+        .find(|spec| spec.namespace.as_deref() == Some(namespace.as_str()))
+        .and_then(|spec| spec.netns_dir.clone())
+        .or_else(|| defaults.netns_dir.clone());
+    let mut spec = ForwardSpec {
+        namespace: Some(namespace),
+        netns_dir,
+        ..ForwardSpec::default()
+    };
+    if spec.namespace_template() {
+        bail!("`pfwd exec` needs one namespace, not a `glob:` or `neutron:` template");
+    }
+    ContainerRef::parse(spec.namespace.as_deref().unwrap_or_default())?;
+    // Like `ip netns exec`, show the command the namespace's `/etc/netns` files.
+    spec.resolve_in_namespace = Some(spec.netns_name().is_some());
+    Ok(Some(spec))
+}
+
+/// Start the command from a throwaway thread that has joined the namespace, so the child inherits
+/// it without the runtime's threads ever leaving the host namespace.
+async fn spawn_command(namespace: Option<ForwardSpec>, argv: Vec<String>) -> Result<Child> {
+    let handle = Handle::current();
+    spawn_blocking(move || {
+        std::thread::Builder::new()
+            .name("pfwd-exec".into())
+            .spawn(move || {
+                let _runtime = handle.enter();
+                if let Some(spec) = namespace.as_ref() {
+                    netns::maybe_enter(spec)?;
+                }
+                let (program, args) = argv.split_first().context("no command given")?;
+                Command::new(program)
+                    .args(args)
+                    .kill_on_drop(true)
+                    .spawn()
+                    .with_context(|| format!("failed to run {program}"))
+            })?
+            .join()
+            .map_err(|_| anyhow::anyhow!("exec thread panicked"))?
+    })
+    .await?
+}
+
+/// Wait for every task, signalling `shutdown_tx` at the first failure so the others wind down, and
+/// return that failure.
+pub async fn join_all(
+    mut tasks: FuturesUnordered<JoinHandle<Result<()>>>,
+    shutdown_tx: &ShutdownTx,
+) -> Result<()> {
     let mut first_err: Option<anyhow::Error> = None;
     while let Some(result) = tasks.next().await {
         match result {
@@ -28,9 +810,6 @@ pub async fn run(specs: Vec<ForwardSpec>) -> Result<()> {
         }
     }
 
-    signal_handle.abort();
-    let _ = signal_handle.await;
-
     if let Some(err) = first_err {
         return Err(err);
     }
@@ -43,7 +822,45 @@ fn enqueue_tasks(
     spec: ForwardSpec,
     shutdown_rx: ShutdownRx,
 ) {
-    if spec.requires_namespace_endpoint() {
+    // Template instances come and go with their namespaces; readiness waits for the rest.
+    if !spec.namespace_template() {
+        status::expect(&spec, listeners(&spec));
+    }
+    // Specs living in an `ip netns` namespace start once it exists and stop when it goes away.
+    if spec.namespace_template() || spec.netns_name().is_some() {
+        tasks.push(netns_watch::spawn(spec, shutdown_rx));
+        return;
+    }
+    enqueue_instance(tasks, spec, shutdown_rx);
+}
+
+/// Listeners [`enqueue_instance`] opens for `spec`: one per task, except the UDP namespace
+/// endpoint's datagram socket.
+fn listeners(spec: &ForwardSpec) -> usize {
+    [
+        spec.requires_namespace_endpoint() || spec.requires_uds_endpoint(),
+        spec.requires_host_uds_proxy(),
+        spec.requires_direct_tcp_proxy(),
+        spec.requires_ftp_proxy(),
+        spec.requires_uds_relay(),
+        spec.requires_udp_host_proxy(),
+        spec.requires_dns_proxy(),
+        spec.requires_broadcast_relay(),
+        spec.requires_udp_pipeline(),
+        spec.requires_udp_proxy(),
+    ]
+    .into_iter()
+    .filter(|&needed| needed)
+    .count()
+}
+
+/// Spawn the tasks a spec needs, now.
+pub fn enqueue_instance(
+    tasks: &mut FuturesUnordered<JoinHandle<Result<()>>>,
+    spec: ForwardSpec,
+    shutdown_rx: ShutdownRx,
+) {
+    if spec.requires_namespace_endpoint() || spec.requires_uds_endpoint() {
         tasks.push(namespace::spawn(spec.clone(), shutdown_rx.clone()));
     }
     if spec.requires_host_uds_proxy() {
@@ -52,6 +869,43 @@ fn enqueue_tasks(
     if spec.requires_direct_tcp_proxy() {
         tasks.push(tcp::spawn(spec.clone(), shutdown_rx.clone()));
     }
+    if spec.requires_ftp_proxy() {
+        tasks.push(ftp::spawn(spec.clone(), shutdown_rx.clone()));
+    }
+    if spec.requires_uds_relay() {
+        tasks.push(uds_relay::spawn(spec.clone(), shutdown_rx.clone()));
+    }
+    if spec.requires_udp_namespace_endpoint() {
+        tasks.push(udp_namespace::spawn(spec.clone(), shutdown_rx.clone()));
+    }
+    if spec.requires_udp_host_proxy() {
+        tasks.push(udp_host::spawn(spec.clone(), shutdown_rx.clone()));
+    }
+    if spec.requires_dns_proxy() {
+        tasks.push(dns::spawn(spec.clone(), shutdown_rx.clone()));
+    }
+    if spec.requires_broadcast_relay() {
+        tasks.push(broadcast::spawn(spec.clone(), shutdown_rx.clone()));
+    }
+    if spec.requires_udp_pipeline() {
+        match UnixDatagram::pair() {
+            Ok((host_end, namespace_end)) => {
+                tasks.push(udp_namespace::spawn_paired(
+                    spec.clone(),
+                    namespace_end,
+                    shutdown_rx.clone(),
+                ));
+                tasks.push(udp_host::spawn_paired(
+                    spec.clone(),
+                    host_end,
+                    shutdown_rx.clone(),
+                ));
+            }
+            Err(err) => tasks.push(tokio::spawn(async move {
+                Err(anyhow::Error::new(err).context("failed to create udp socketpair"))
+            })),
+        }
+    }
     if spec.requires_udp_proxy() {
         tasks.push(udp::spawn(spec, shutdown_rx));
     }
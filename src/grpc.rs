@@ -0,0 +1,943 @@
+//! `grpc_listen`: the gRPC control API, enabled with the `grpc` cargo feature, for controllers
+//! that manage a fleet of pfwd agents programmatically.
+//!
+//! The service is `pfwd.admin.v1.Admin`, described in `proto/admin.proto` for clients to generate
+//! stubs from. It covers what the [`admin`](crate::admin) socket does, plus adding, replacing, and
+//! removing forwards: `ListForwards`, `AddForward`, `UpdateForward`, `RemoveForward`,
+//! `PauseForward`, `ResumeForward`, `GetLogFilter`, `SetLogFilter`, `ListSessions`, `WatchSessions`, `KillSession`, `GetStats`, `CaptureForward`, `Reload`, `Drain`, and `Shutdown`. Forwards are
+//! given in the `--forward` key=value grammar and must be labelled; [`forward`](crate::forward)
+//! applies `[defaults]` and the usual checks before starting them. `WatchSessions` streams the
+//! [`access_log`](crate::access_log) records until the client cancels it.
+//!
+//! Every call needs `authorization: Bearer <token>` metadata, with the token read from
+//! `admin_token_file`, and is refused with `UNAUTHENTICATED` otherwise.
+//!
+//! The messages are written out with `prost` rather than generated, so building needs no
+//! `protoc`; keep them in step with the `.proto` file.
+
+use anyhow::{Result, bail};
+#[cfg(feature = "grpc")]
+pub use imp::spawn;
+
+/// Reject `grpc_listen` at config load when the feature is compiled out or the address is bad.
+pub fn validate(listen: &str) -> Result<()> {
+    if !cfg!(feature = "grpc") {
+        bail!("`grpc_listen` needs rebuilding pfwd with `--features grpc`");
+    }
+    if listen.parse::<std::net::SocketAddr>().is_err() {
+        bail!("invalid grpc_listen address {listen}");
+    }
+    Ok(())
+}
+
+/// Stand-in when the feature is compiled out; config load rejects `grpc_listen` before it is
+/// started.
+#[cfg(not(feature = "grpc"))]
+pub fn spawn(
+    listen: String,
+    _token_file: std::path::PathBuf,
+    _control: Option<crate::forward::ControlTx>,
+    _shutdown: crate::pipeline::ShutdownRx,
+) -> tokio::task::JoinHandle<Result<()>> {
+    tokio::spawn(async move {
+        validate(&listen)?;
+        bail!("gRPC support is not compiled in")
+    })
+}
+
+#[cfg(feature = "grpc")]
+mod imp {
+    use std::convert::Infallible;
+    use std::path::PathBuf;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use anyhow::{Context as _, Result, bail};
+    use futures::stream::{self, StreamExt};
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use hyper_util::server::conn::auto;
+    use hyper_util::service::TowerToHyperService;
+    use tokio::net::TcpListener;
+    use tokio::sync::broadcast::Receiver;
+    use tokio::sync::broadcast::error::RecvError;
+    use tokio::sync::oneshot;
+    use tokio::task::JoinHandle;
+    use tonic::body::Body;
+    use tonic::codegen::{BoxFuture, BoxStream, Context, Poll, Service, StdError, http};
+    use tonic::server::Grpc;
+    use tonic::{Request, Response, Status};
+    use tonic_prost::ProstCodec;
+    use tracing::{debug, info};
+
+    use super::proto::*;
+    use crate::access_log::{self, Followed};
+    use crate::admin;
+    use crate::admin_http::same;
+    use crate::config::ForwardInline;
+    use crate::forward::{Control, ControlTx};
+    use crate::pipeline::pause::{self, Pause};
+    use crate::pipeline::{ShutdownRx, session};
+    use crate::selector::Selector;
+    use crate::status;
+    use crate::{log_level, metrics};
+
+    const SERVICE: &str = "/pfwd.admin.v1.Admin/";
+
+    /// Serve the API on `listen` until shutdown, to clients presenting the token in `token_file`.
+    /// Requests that change forwards go to `control`.
+    pub fn spawn(
+        listen: String,
+        token_file: PathBuf,
+        control: Option<ControlTx>,
+        mut shutdown: ShutdownRx,
+    ) -> JoinHandle<Result<()>> {
+        tokio::spawn(async move {
+            let token = std::fs::read_to_string(&token_file)
+                .with_context(|| format!("failed to read admin token {}", token_file.display()))?
+                .trim()
+                .to_string();
+            if token.is_empty() {
+                bail!("admin token file {} is empty", token_file.display());
+            }
+            let listener = TcpListener::bind(&listen)
+                .await
+                .with_context(|| format!("failed to bind grpc listener {listen}"))?;
+            info!(%listen, "gRPC API listening");
+            let router = Router {
+                authorization: format!("Bearer {token}"),
+                control,
+            };
+            loop {
+                tokio::select! {
+                    res = shutdown.changed() => {
+                        if res.is_err() || *shutdown.borrow() {
+                            return Ok(());
+                        }
+                    }
+                    accept_res = listener.accept() => {
+                        let (stream, peer) = accept_res?;
+                        let service = TowerToHyperService::new(router.clone());
+                        tokio::spawn(async move {
+                            let res = auto::Builder::new(TokioExecutor::new())
+                                .http2_only()
+                                .serve_connection(TokioIo::new(stream), service)
+                                .await;
+                            if let Err(err) = res {
+                                debug!(%peer, error = %err, "gRPC connection failed");
+                            }
+                        });
+                    }
+                }
+            }
+        })
+    }
+
+    /// Dispatches each call to its method, as `tonic-build` generated servers do.
+    #[derive(Clone)]
+    struct Router {
+        /// The `authorization` value callers must send.
+        authorization: String,
+        control: Option<ControlTx>,
+    }
+
+    impl<B> Service<http::Request<B>> for Router
+    where
+        B: tonic::codegen::Body + Send + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+    {
+        type Response = http::Response<Body>;
+        type Error = Infallible;
+        type Future = BoxFuture<Self::Response, Infallible>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<B>) -> Self::Future {
+            let router = self.clone();
+            Box::pin(async move { Ok(router.route(request).await) })
+        }
+    }
+
+    impl Router {
+        async fn route<B>(self, request: http::Request<B>) -> http::Response<Body>
+        where
+            B: tonic::codegen::Body + Send + 'static,
+            B::Error: Into<StdError> + Send + 'static,
+        {
+            let authorized = request
+                .headers()
+                .get("authorization")
+                .is_some_and(|value| same(value.as_bytes(), self.authorization.as_bytes()));
+            if !authorized {
+                return Status::unauthenticated("missing or wrong bearer token").into_http();
+            }
+            let Some(method) = request
+                .uri()
+                .path()
+                .strip_prefix(SERVICE)
+                .map(str::to_string)
+            else {
+                return Status::unimplemented("unknown service").into_http();
+            };
+            let control = self.control;
+            match method.as_str() {
+                "ListForwards" => unary(request, |_: Empty| async { Ok(forward_list()) }).await,
+                "AddForward" => {
+                    unary(request, move |add: ForwardRequest| {
+                        add_forward(control.clone(), add, false)
+                    })
+                    .await
+                }
+                "UpdateForward" => {
+                    unary(request, move |add: ForwardRequest| {
+                        add_forward(control.clone(), add, true)
+                    })
+                    .await
+                }
+                "RemoveForward" => {
+                    unary(request, move |remove: RemoveRequest| {
+                        remove_forward(control.clone(), remove)
+                    })
+                    .await
+                }
+                "ListSessions" => unary(request, |_: Empty| async { Ok(session_list()) }).await,
+                "WatchSessions" => {
+                    streaming(request, |watch: WatchRequest| async {
+                        Ok(watch_sessions(watch))
+                    })
+                    .await
+                }
+                "PauseForward" => {
+                    unary(request, |pause: PauseRequest| async {
+                        pause_forward(pause)
+                    })
+                    .await
+                }
+                "ResumeForward" => {
+                    unary(request, |resume: ResumeRequest| async {
+                        resume_forward(resume)
+                    })
+                    .await
+                }
+                "GetLogFilter" => unary(request, |_: Empty| async { log_filter(None) }).await,
+                "SetLogFilter" => {
+                    unary(request, |set: LogFilter| async {
+                        log_filter(Some(set.filter))
+                    })
+                    .await
+                }
+                "KillSession" => {
+                    unary(request, |kill: KillRequest| async { kill_session(kill) }).await
+                }
+                "GetStats" => unary(request, |_: Empty| async { Ok(stats()) }).await,
+                "CaptureForward" => {
+                    unary(request, move |capture: CaptureRequest| {
+                        capture_forward(control.clone(), capture)
+                    })
+                    .await
+                }
+                "Reload" => unary(request, move |_: Empty| reload(control.clone())).await,
+                "Drain" => {
+                    unary(request, move |drain: DrainRequest| {
+                        let control = control.clone();
+                        async move {
+                            let timeout = drain.timeout_secs.map(Duration::from_secs);
+                            let Some(selector) = selector(drain.label, drain.selector)? else {
+                                send(control.as_ref(), "Drain", Control::Drain(timeout))?;
+                                return Ok(DrainReply {
+                                    sessions: session::open_count() as u64,
+                                    labels: Vec::new(),
+                                });
+                            };
+                            let (reply, drained) = oneshot::channel();
+                            let request = Control::DrainForward {
+                                selector,
+                                timeout,
+                                reply,
+                            };
+                            send(control.as_ref(), "Drain", request)?;
+                            let drained = answer(drained).await?;
+                            Ok(DrainReply {
+                                sessions: drained.sessions as u64,
+                                labels: drained.labels,
+                            })
+                        }
+                    })
+                    .await
+                }
+                "Shutdown" => {
+                    unary(request, move |_: Empty| {
+                        let res = send(control.as_ref(), "Shutdown", Control::Shutdown);
+                        async move { res.map(|()| Empty {}) }
+                    })
+                    .await
+                }
+                _ => Status::unimplemented(format!("unknown method {method}")).into_http(),
+            }
+        }
+    }
+
+    /// Answer a unary call with `handle`.
+    async fn unary<B, Req, Resp, F, Fut>(
+        request: http::Request<B>,
+        handle: F,
+    ) -> http::Response<Body>
+    where
+        B: tonic::codegen::Body + Send + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+        Req: prost::Message + Default + Send + 'static,
+        Resp: prost::Message + Send + 'static,
+        F: FnMut(Req) -> Fut,
+        Fut: Future<Output = Result<Resp, Status>> + Send + 'static,
+    {
+        let mut handle = handle;
+        let method = Method(move |request: Request<Req>| handle(request.into_inner()));
+        Grpc::new(ProstCodec::default())
+            .unary(method, request)
+            .await
+    }
+
+    /// Answer a server-streaming call with the stream `handle` returns.
+    async fn streaming<B, Req, Resp, F, Fut>(
+        request: http::Request<B>,
+        handle: F,
+    ) -> http::Response<Body>
+    where
+        B: tonic::codegen::Body + Send + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+        Req: prost::Message + Default + Send + 'static,
+        Resp: prost::Message + Send + 'static,
+        F: FnMut(Req) -> Fut,
+        Fut: Future<Output = Result<BoxStream<Resp>, Status>> + Send + 'static,
+    {
+        let mut handle = handle;
+        let method = Method(move |request: Request<Req>| handle(request.into_inner()));
+        Grpc::new(ProstCodec::default())
+            .server_streaming(method, request)
+            .await
+    }
+
+    /// A closure as the tower service tonic's [`Grpc`] calls for each request.
+    struct Method<F>(F);
+
+    impl<F, Req, Resp, Fut> Service<Request<Req>> for Method<F>
+    where
+        F: FnMut(Request<Req>) -> Fut,
+        Fut: Future<Output = Result<Resp, Status>> + Send + 'static,
+    {
+        type Response = Response<Resp>;
+        type Error = Status;
+        type Future = BoxFuture<Response<Resp>, Status>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Status>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: Request<Req>) -> Self::Future {
+            let res = (self.0)(request);
+            Box::pin(async move { res.await.map(Response::new) })
+        }
+    }
+
+    fn send(control: Option<&ControlTx>, method: &str, request: Control) -> Result<(), Status> {
+        control
+            .ok_or_else(|| {
+                Status::failed_precondition(format!("{method} is not available under `pfwd exec`"))
+            })?
+            .send(request)
+            .map_err(|_| Status::unavailable("pfwd is shutting down"))
+    }
+
+    /// Wait for the answer to a request sent with [`send`].
+    async fn answer<T>(reply: oneshot::Receiver<Result<T>>) -> Result<T, Status> {
+        reply
+            .await
+            .map_err(|_| Status::unavailable("pfwd is shutting down"))?
+            .map_err(|err| Status::failed_precondition(format!("{err:#}")))
+    }
+
+    async fn add_forward(
+        control: Option<ControlTx>,
+        add: ForwardRequest,
+        replace: bool,
+    ) -> Result<Empty, Status> {
+        let ForwardInline(spec) = ForwardInline::from_str(&add.spec)
+            .map_err(|err| Status::invalid_argument(format!("{err:#}")))?;
+        let (reply, added) = oneshot::channel();
+        let method = if replace {
+            "UpdateForward"
+        } else {
+            "AddForward"
+        };
+        send(
+            control.as_ref(),
+            method,
+            Control::Add {
+                spec: Box::new(spec),
+                replace,
+                reply,
+            },
+        )?;
+        answer(added).await?;
+        Ok(Empty {})
+    }
+
+    async fn remove_forward(
+        control: Option<ControlTx>,
+        remove: RemoveRequest,
+    ) -> Result<RemoveReply, Status> {
+        let label = Some(remove.label).filter(|label| !label.is_empty());
+        let selector = selector(label, remove.selector)?
+            .ok_or_else(|| Status::invalid_argument("give a label or a selector"))?;
+        let (reply, removed) = oneshot::channel();
+        let request = Control::Remove {
+            selector,
+            kill_sessions: remove.kill_sessions,
+            reply,
+        };
+        send(control.as_ref(), "RemoveForward", request)?;
+        let removed = answer(removed).await?;
+        Ok(RemoveReply {
+            removed: removed.forwards as u64,
+            sessions_killed: removed.sessions_killed as u64,
+            labels: removed.labels,
+        })
+    }
+
+    async fn capture_forward(
+        control: Option<ControlTx>,
+        capture: CaptureRequest,
+    ) -> Result<CaptureReply, Status> {
+        let selector = selector(capture.label, capture.selector)?;
+        let (reply, captured) = oneshot::channel();
+        send(
+            control.as_ref(),
+            "CaptureForward",
+            Control::Capture { selector, reply },
+        )?;
+        let captured = answer(captured).await?;
+        Ok(CaptureReply {
+            captures: captured
+                .captures
+                .into_iter()
+                .map(|started| CaptureFile {
+                    label: started.label,
+                    namespace: started.namespace,
+                    path: started.path.display().to_string(),
+                })
+                .collect(),
+            failed: captured
+                .failed
+                .into_iter()
+                .map(|failed| CaptureFailure {
+                    label: failed.label,
+                    namespace: failed.namespace,
+                    error: failed.error,
+                })
+                .collect(),
+        })
+    }
+
+    async fn reload(control: Option<ControlTx>) -> Result<ReloadReply, Status> {
+        let (reply, reloaded) = oneshot::channel();
+        send(control.as_ref(), "Reload", Control::Reload(reply))?;
+        let reloaded = answer(reloaded).await?;
+        Ok(ReloadReply {
+            added: reloaded.added,
+            removed: reloaded.removed,
+            restarted: reloaded.restarted,
+            unchanged: reloaded.unchanged as u64,
+        })
+    }
+
+    fn pause_forward(request: PauseRequest) -> Result<PauseReply, Status> {
+        let label = Some(request.label).filter(|label| !label.is_empty());
+        let selector = selector(label, request.selector)?
+            .ok_or_else(|| Status::invalid_argument("give a label or a selector"))?;
+        let forwards = status::forwards();
+        let labels = selector.select(forwards.iter().map(|forward| forward.label.as_str()));
+        if labels.is_empty() {
+            return Err(Status::not_found(format!("no forward matches {selector}")));
+        }
+        let mode = if request.reject {
+            Pause::Reject
+        } else {
+            Pause::Queue
+        };
+        for label in &labels {
+            pause::pause(label, mode);
+            info!(label, reject = request.reject, "forward paused");
+        }
+        Ok(PauseReply { labels })
+    }
+
+    fn resume_forward(request: ResumeRequest) -> Result<PauseReply, Status> {
+        let label = Some(request.label).filter(|label| !label.is_empty());
+        let selector = selector(label, request.selector)?
+            .ok_or_else(|| Status::invalid_argument("give a label or a selector"))?;
+        let labels = selector.select(pause::labels().iter().map(String::as_str));
+        if labels.is_empty() {
+            return Err(Status::failed_precondition(format!(
+                "no paused forward matches {selector}"
+            )));
+        }
+        for label in &labels {
+            pause::resume(label);
+            info!(label, "forward resumed");
+        }
+        Ok(PauseReply { labels })
+    }
+
+    /// The forwards a request picks by `label` or `selector`, if either is given.
+    fn selector(
+        label: Option<String>,
+        selector: Option<String>,
+    ) -> Result<Option<Selector>, Status> {
+        Selector::from_args(label, selector)
+            .map_err(|err| Status::invalid_argument(format!("{err:#}")))
+    }
+
+    /// Set the log filter to `filter`, if given, and answer with the one in effect.
+    fn log_filter(filter: Option<String>) -> Result<LogFilter, Status> {
+        if let Some(filter) = filter {
+            let from = log_level::current().map_err(|err| Status::internal(format!("{err:#}")))?;
+            log_level::set(&filter).map_err(|err| Status::invalid_argument(format!("{err:#}")))?;
+            info!(from, to = filter, "log filter changed");
+        }
+        Ok(LogFilter {
+            filter: log_level::current().map_err(|err| Status::internal(format!("{err:#}")))?,
+        })
+    }
+
+    fn kill_session(kill: KillRequest) -> Result<KillReply, Status> {
+        let sessions_killed = match (kill.id, kill.session_id) {
+            (Some(id), None) => usize::from(session::kill_id(id)),
+            (None, Some(session_id)) => session::kill_session_id(
+                session::parse_id(&session_id)
+                    .map_err(|err| Status::invalid_argument(format!("{err:#}")))?,
+            ),
+            _ => {
+                return Err(Status::invalid_argument(
+                    "KillSession needs one of `id` and `session_id`",
+                ));
+            }
+        };
+        if sessions_killed == 0 {
+            return Err(Status::not_found("no such open session"));
+        }
+        Ok(KillReply {
+            sessions_killed: sessions_killed as u64,
+        })
+    }
+
+    fn forward_list() -> ForwardList {
+        ForwardList {
+            forwards: status::forwards()
+                .into_iter()
+                .map(|forward| Forward {
+                    label: forward.label,
+                    namespace: forward.namespace,
+                    state: forward.state.as_str().to_string(),
+                    listeners_bound: forward.listeners_bound as u64,
+                    listeners_expected: forward.listeners_expected as u64,
+                    consecutive_failures: forward.consecutive_failures,
+                    failure: forward.failure,
+                })
+                .collect(),
+        }
+    }
+
+    fn session_list() -> SessionList {
+        SessionList {
+            sessions: session::live()
+                .into_iter()
+                .map(|live| Session {
+                    id: live.id,
+                    session_id: live.session_id,
+                    label: live.label,
+                    peer: live.peer,
+                    namespace: live.namespace,
+                    target: live.target,
+                    started_unix_ms: live.started_unix_ms,
+                    duration_ms: live.duration_ms,
+                    bytes_client_to_target: live.bytes_client_to_target,
+                    bytes_target_to_client: live.bytes_target_to_client,
+                })
+                .collect(),
+        }
+    }
+
+    fn stats() -> Stats {
+        Stats {
+            legs: metrics::samples()
+                .into_iter()
+                .map(|sample| Leg {
+                    errors: metrics::ErrorKind::ALL
+                        .iter()
+                        .map(|kind| kind.as_str().to_string())
+                        .zip(sample.errors)
+                        .collect(),
+                    namespace: Some(sample.namespace).filter(|namespace| !namespace.is_empty()),
+                    label: sample.label,
+                    leg: sample.leg.to_string(),
+                    sessions_accepted: sample.sessions_accepted,
+                    sessions_active: sample.sessions_active,
+                    bytes_client_to_target: sample.bytes_client_to_target,
+                    bytes_target_to_client: sample.bytes_target_to_client,
+                    connect_errors: sample.connect_errors,
+                    uds_retries: sample.uds_retries,
+                    udp_sessions: sample.udp_sessions,
+                    udp_sessions_created: sample.udp_sessions_created,
+                    udp_sessions_expired: sample.udp_sessions_expired,
+                    datagrams_client_to_target: sample.datagrams_client_to_target,
+                    datagrams_target_to_client: sample.datagrams_target_to_client,
+                    datagrams_dropped: sample.datagrams_dropped,
+                    client_tcp_retransmits: sample.client_tcp_retransmits,
+                    target_tcp_retransmits: sample.target_tcp_retransmits,
+                })
+                .collect(),
+        }
+    }
+
+    /// Access log records matching `watch` from now on, with a `lagged` event in place of any the
+    /// client fell too far behind to get.
+    fn watch_sessions(watch: WatchRequest) -> BoxStream<SessionEvent> {
+        stream::unfold((access_log::follow(), watch), next_event).boxed()
+    }
+
+    async fn next_event(
+        (mut records, watch): (Receiver<Arc<Followed>>, WatchRequest),
+    ) -> Option<(
+        Result<SessionEvent, Status>,
+        (Receiver<Arc<Followed>>, WatchRequest),
+    )> {
+        let (label, peer) = (watch.label.as_deref(), watch.peer.as_deref());
+        let event = loop {
+            match records.recv().await {
+                Ok(record) if admin::matches(&record, label, peer) => {
+                    break serde_json::from_str(&record.line).map_err(|err| {
+                        Status::internal(format!("unreadable access log record: {err}"))
+                    });
+                }
+                Ok(_) => continue,
+                Err(RecvError::Lagged(missed)) => {
+                    break Ok(SessionEvent {
+                        event: "lagged".to_string(),
+                        missed: Some(missed),
+                        ..SessionEvent::default()
+                    });
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        };
+        Some((event, (records, watch)))
+    }
+}
+
+/// The messages of `proto/admin.proto`.
+#[cfg(feature = "grpc")]
+mod proto {
+    use std::collections::BTreeMap;
+
+    use serde::Deserialize;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Empty {}
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Forward {
+        #[prost(string, tag = "1")]
+        pub label: String,
+        #[prost(string, optional, tag = "2")]
+        pub namespace: Option<String>,
+        #[prost(string, tag = "3")]
+        pub state: String,
+        #[prost(uint64, tag = "4")]
+        pub listeners_bound: u64,
+        #[prost(uint64, tag = "5")]
+        pub listeners_expected: u64,
+        #[prost(uint32, tag = "6")]
+        pub consecutive_failures: u32,
+        #[prost(string, optional, tag = "7")]
+        pub failure: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ForwardList {
+        #[prost(message, repeated, tag = "1")]
+        pub forwards: Vec<Forward>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ForwardRequest {
+        /// The forward in the `--forward` key=value grammar.
+        #[prost(string, tag = "1")]
+        pub spec: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct RemoveRequest {
+        #[prost(string, tag = "1")]
+        pub label: String,
+        /// Kill the forwards' open sessions too, rather than letting them finish.
+        #[prost(bool, tag = "2")]
+        pub kill_sessions: bool,
+        /// `label=<name>` or `label~=<pattern>`, in place of `label`.
+        #[prost(string, optional, tag = "3")]
+        pub selector: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct RemoveReply {
+        #[prost(uint64, tag = "1")]
+        pub removed: u64,
+        #[prost(uint64, tag = "2")]
+        pub sessions_killed: u64,
+        #[prost(string, repeated, tag = "3")]
+        pub labels: Vec<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Session {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        #[prost(string, optional, tag = "2")]
+        pub session_id: Option<String>,
+        #[prost(string, tag = "3")]
+        pub label: String,
+        #[prost(string, optional, tag = "4")]
+        pub peer: Option<String>,
+        #[prost(string, optional, tag = "5")]
+        pub namespace: Option<String>,
+        #[prost(string, optional, tag = "6")]
+        pub target: Option<String>,
+        #[prost(uint64, tag = "7")]
+        pub started_unix_ms: u64,
+        #[prost(uint64, tag = "8")]
+        pub duration_ms: u64,
+        #[prost(uint64, tag = "9")]
+        pub bytes_client_to_target: u64,
+        #[prost(uint64, tag = "10")]
+        pub bytes_target_to_client: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SessionList {
+        #[prost(message, repeated, tag = "1")]
+        pub sessions: Vec<Session>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct WatchRequest {
+        #[prost(string, optional, tag = "1")]
+        pub label: Option<String>,
+        /// A whole address or just its IP.
+        #[prost(string, optional, tag = "2")]
+        pub peer: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PauseRequest {
+        #[prost(string, tag = "1")]
+        pub label: String,
+        /// Accept and close new connections, rather than leaving them in the backlog.
+        #[prost(bool, tag = "2")]
+        pub reject: bool,
+        /// `label=<name>` or `label~=<pattern>`, in place of `label`.
+        #[prost(string, optional, tag = "3")]
+        pub selector: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ResumeRequest {
+        #[prost(string, tag = "1")]
+        pub label: String,
+        /// `label=<name>` or `label~=<pattern>`, in place of `label`.
+        #[prost(string, optional, tag = "2")]
+        pub selector: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PauseReply {
+        /// The labels of the forwards paused or resumed.
+        #[prost(string, repeated, tag = "1")]
+        pub labels: Vec<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct LogFilter {
+        /// As for `--log-level`, e.g. `info,pfwd::pipeline::tcp=debug`.
+        #[prost(string, tag = "1")]
+        pub filter: String,
+    }
+
+    /// One of `id` or `session_id`.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct KillRequest {
+        #[prost(uint64, optional, tag = "1")]
+        pub id: Option<u64>,
+        #[prost(string, optional, tag = "2")]
+        pub session_id: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct KillReply {
+        #[prost(uint64, tag = "1")]
+        pub sessions_killed: u64,
+    }
+
+    /// An access log record, read straight from its JSON.
+    #[derive(Clone, PartialEq, prost::Message, Deserialize)]
+    #[serde(default)]
+    pub struct SessionEvent {
+        /// `open`, `close`, or `lagged`.
+        #[prost(string, tag = "1")]
+        pub event: String,
+        #[prost(uint64, tag = "2")]
+        pub time_unix_ms: u64,
+        #[prost(uint64, tag = "3")]
+        pub id: u64,
+        #[prost(string, tag = "4")]
+        pub label: String,
+        #[prost(string, optional, tag = "5")]
+        pub peer: Option<String>,
+        #[prost(string, optional, tag = "6")]
+        pub namespace: Option<String>,
+        #[prost(string, optional, tag = "7")]
+        pub target: Option<String>,
+        #[prost(uint64, optional, tag = "8")]
+        pub duration_ms: Option<u64>,
+        #[prost(uint64, optional, tag = "9")]
+        pub bytes_client_to_target: Option<u64>,
+        #[prost(uint64, optional, tag = "10")]
+        pub bytes_target_to_client: Option<u64>,
+        #[prost(string, optional, tag = "11")]
+        pub reason: Option<String>,
+        /// Records skipped because the client fell behind, on `lagged`.
+        #[prost(uint64, optional, tag = "12")]
+        pub missed: Option<u64>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Leg {
+        #[prost(string, tag = "1")]
+        pub label: String,
+        #[prost(string, optional, tag = "2")]
+        pub namespace: Option<String>,
+        #[prost(string, tag = "3")]
+        pub leg: String,
+        #[prost(uint64, tag = "4")]
+        pub sessions_accepted: u64,
+        #[prost(int64, tag = "5")]
+        pub sessions_active: i64,
+        #[prost(uint64, tag = "6")]
+        pub bytes_client_to_target: u64,
+        #[prost(uint64, tag = "7")]
+        pub bytes_target_to_client: u64,
+        #[prost(uint64, tag = "8")]
+        pub connect_errors: u64,
+        #[prost(uint64, tag = "9")]
+        pub uds_retries: u64,
+        #[prost(int64, tag = "10")]
+        pub udp_sessions: i64,
+        #[prost(uint64, tag = "11")]
+        pub udp_sessions_created: u64,
+        #[prost(uint64, tag = "12")]
+        pub udp_sessions_expired: u64,
+        #[prost(uint64, tag = "13")]
+        pub datagrams_client_to_target: u64,
+        #[prost(uint64, tag = "14")]
+        pub datagrams_target_to_client: u64,
+        #[prost(uint64, tag = "15")]
+        pub datagrams_dropped: u64,
+        #[prost(uint64, tag = "16")]
+        pub client_tcp_retransmits: u64,
+        #[prost(uint64, tag = "17")]
+        pub target_tcp_retransmits: u64,
+        /// Errors by kind, as in `/metrics`.
+        #[prost(btree_map = "string, uint64", tag = "18")]
+        pub errors: BTreeMap<String, u64>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Stats {
+        #[prost(message, repeated, tag = "1")]
+        pub legs: Vec<Leg>,
+    }
+
+    /// Neither `label` nor `selector` captures every forward.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CaptureRequest {
+        #[prost(string, optional, tag = "1")]
+        pub label: Option<String>,
+        /// `label=<name>` or `label~=<pattern>`, in place of `label`.
+        #[prost(string, optional, tag = "2")]
+        pub selector: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CaptureFile {
+        #[prost(string, tag = "1")]
+        pub label: String,
+        #[prost(string, optional, tag = "2")]
+        pub namespace: Option<String>,
+        /// The pcap file the capture is written to.
+        #[prost(string, tag = "3")]
+        pub path: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CaptureFailure {
+        #[prost(string, tag = "1")]
+        pub label: String,
+        #[prost(string, optional, tag = "2")]
+        pub namespace: Option<String>,
+        #[prost(string, tag = "3")]
+        pub error: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CaptureReply {
+        #[prost(message, repeated, tag = "1")]
+        pub captures: Vec<CaptureFile>,
+        /// Forwards whose capture did not start, e.g. because one was already running.
+        #[prost(message, repeated, tag = "2")]
+        pub failed: Vec<CaptureFailure>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ReloadReply {
+        #[prost(string, repeated, tag = "1")]
+        pub added: Vec<String>,
+        #[prost(string, repeated, tag = "2")]
+        pub removed: Vec<String>,
+        #[prost(string, repeated, tag = "3")]
+        pub restarted: Vec<String>,
+        #[prost(uint64, tag = "4")]
+        pub unchanged: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DrainRequest {
+        #[prost(uint64, optional, tag = "1")]
+        pub timeout_secs: Option<u64>,
+        /// Drain only the forwards labelled so.
+        #[prost(string, optional, tag = "2")]
+        pub label: Option<String>,
+        /// Drain only the forwards picked by `label=<name>` or `label~=<pattern>`.
+        #[prost(string, optional, tag = "3")]
+        pub selector: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DrainReply {
+        /// Sessions still open when the drain began, of the drained forwards if some were picked.
+        #[prost(uint64, tag = "1")]
+        pub sessions: u64,
+        /// The labels of the forwards drained, if some were picked.
+        #[prost(string, repeated, tag = "2")]
+        pub labels: Vec<String>,
+    }
+}
@@ -0,0 +1,88 @@
+//! Happy Eyeballs (RFC 8305) connection racing for targets that resolve to several addresses.
+//!
+//! Resolved addresses are interleaved by family, IPv6 first, and attempted in that order. A new
+//! attempt starts whenever the previous one fails or has been pending for
+//! [`CONNECTION_ATTEMPT_DELAY`]; the first connection to complete wins and the rest are dropped.
+//! Resolution itself goes through the system resolver, which returns A and AAAA records together.
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::StreamExt;
+use futures::stream::FuturesUnordered;
+use tokio::time::sleep;
+use tracing::debug;
+
+/// Recommended default from RFC 8305 §5.
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Order addresses for racing: alternate IPv6 and IPv4, starting with whichever family the
+/// resolver returned first, keeping the resolver's order within a family.
+pub fn interleave(addrs: impl IntoIterator<Item = SocketAddr>) -> Vec<SocketAddr> {
+    let addrs: Vec<SocketAddr> = addrs.into_iter().collect();
+    let first_v6 = addrs.first().is_none_or(SocketAddr::is_ipv6);
+    let (mut primary, mut secondary): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_v6);
+    primary.reverse();
+    secondary.reverse();
+    let mut ordered = Vec::with_capacity(primary.len() + secondary.len());
+    loop {
+        match (primary.pop(), secondary.pop()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Race `connect` across `addrs` (already ordered, see [`interleave`]), staggering attempts by
+/// [`CONNECTION_ATTEMPT_DELAY`]. Returns the last error when every attempt fails.
+pub async fn race<T, F, Fut>(addrs: Vec<SocketAddr>, connect: F) -> io::Result<T>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut pending = addrs.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = None;
+
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(addr) => attempts.push(attempt(addr, &connect)),
+                None => {
+                    return Err(last_err.unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to")
+                    }));
+                }
+            }
+        }
+        tokio::select! {
+            Some((addr, res)) = attempts.next() => match res {
+                Ok(stream) => return Ok(stream),
+                Err(err) => {
+                    debug!(%addr, error = %err, "connection attempt failed");
+                    last_err = Some(err);
+                    if let Some(next) = pending.next() {
+                        attempts.push(attempt(next, &connect));
+                    }
+                }
+            },
+            _ = sleep(CONNECTION_ATTEMPT_DELAY), if pending.len() > 0 => {
+                if let Some(next) = pending.next() {
+                    attempts.push(attempt(next, &connect));
+                }
+            }
+        }
+    }
+}
+
+async fn attempt<T, F, Fut>(addr: SocketAddr, connect: &F) -> (SocketAddr, io::Result<T>)
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    (addr, connect(addr).await)
+}
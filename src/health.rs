@@ -0,0 +1,50 @@
+//! `health_listen`: probes for systemd, Kubernetes, and load balancers.
+//!
+//! `GET /healthz` answers `200` while the process serves requests at all. `GET /readyz` answers
+//! `200` once every configured forward has bound all its listeners, and `503` listing the ones
+//! still missing otherwise: while a namespace has not appeared, or a listener is rebinding after
+//! its namespace was recreated. Instances of `glob:` and `neutron:` templates come and go with
+//! their namespaces and do not count.
+
+use anyhow::Result;
+use tokio::task::JoinHandle;
+
+use crate::pipeline::ShutdownRx;
+use crate::{http, status};
+
+pub fn spawn(listen: String, shutdown: ShutdownRx) -> JoinHandle<Result<()>> {
+    http::spawn(
+        "health",
+        listen,
+        "text/plain; charset=utf-8",
+        route,
+        shutdown,
+    )
+}
+
+fn route(path: &[u8]) -> Option<(&'static str, String)> {
+    match path {
+        b"/healthz" => Some(("200 OK", "ok\n".to_string())),
+        b"/readyz" => Some(readiness()),
+        _ => None,
+    }
+}
+
+fn readiness() -> (&'static str, String) {
+    let pending = status::pending();
+    if pending.is_empty() {
+        return ("200 OK", "ready\n".to_string());
+    }
+    let mut body = String::from("not ready\n");
+    for forward in pending {
+        let namespace = forward
+            .namespace
+            .map(|ns| format!(" in {ns}"))
+            .unwrap_or_default();
+        body += &format!(
+            "{}{namespace}: {} of {} listeners bound\n",
+            forward.label, forward.listeners_bound, forward.listeners_expected
+        );
+    }
+    ("503 Service Unavailable", body)
+}
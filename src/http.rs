@@ -0,0 +1,177 @@
+//! The minimal HTTP/1.x server behind `/metrics` and the health probes: one `GET` per connection,
+//! answered from a routing function and closed. The admin API reads and answers its requests with
+//! the same helpers.
+
+use anyhow::{Context, Result, bail};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, timeout};
+use tracing::{debug, info};
+
+use crate::pipeline::ShutdownRx;
+
+/// How long a client may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Size past which a request line and headers are refused.
+const MAX_HEAD: usize = 8192;
+/// Size past which a request body is refused.
+const MAX_BODY: usize = 64 * 1024;
+
+/// Status line and body for a `GET` of a path, or `None` for 404.
+pub type Route = fn(&[u8]) -> Option<(&'static str, String)>;
+
+/// Serve `route` on `listen` until shutdown. `name` says what the listener is for in logs, and
+/// `content_type` labels every response.
+pub fn spawn(
+    name: &'static str,
+    listen: String,
+    content_type: &'static str,
+    route: Route,
+    mut shutdown: ShutdownRx,
+) -> JoinHandle<Result<()>> {
+    tokio::spawn(async move {
+        let listener = TcpListener::bind(&listen)
+            .await
+            .with_context(|| format!("failed to bind {name} listener {listen}"))?;
+        info!(%listen, "{name} endpoint listening");
+        loop {
+            tokio::select! {
+                res = shutdown.changed() => {
+                    if res.is_err() || *shutdown.borrow() {
+                        return Ok(());
+                    }
+                }
+                accept_res = listener.accept() => {
+                    let (stream, peer) = accept_res?;
+                    tokio::spawn(async move {
+                        if let Err(err) = respond(stream, content_type, route).await {
+                            debug!(%peer, error = %err, "{name} request failed");
+                        }
+                    });
+                }
+            }
+        }
+    })
+}
+
+/// Answer one request and close the connection.
+async fn respond(mut stream: TcpStream, content_type: &str, route: Route) -> Result<()> {
+    let request = read_request(&mut stream).await?;
+    let (status, body) = match request.method.as_str() {
+        // Probes may add a query string; no route looks at it.
+        "GET" => {
+            route(request.path.as_bytes()).unwrap_or(("404 Not Found", "not found\n".to_string()))
+        }
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+    };
+    write_response(&mut stream, status, content_type, &[], body.as_bytes()).await
+}
+
+/// An HTTP request as far as pfwd's endpoints look at it.
+pub struct Request {
+    pub method: String,
+    /// The path without its query string.
+    pub path: String,
+    pub query: Option<String>,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Value of the header `name`, whatever its case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Read a request's head and, if it has a `Content-Length`, its body.
+pub async fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    let head_len = timeout(REQUEST_TIMEOUT, async {
+        loop {
+            if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                return Ok(end + 4);
+            }
+            if request.len() >= MAX_HEAD {
+                bail!("request head too long");
+            }
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                // Answer what arrived, as a request without headers.
+                return Ok(request.len());
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+    })
+    .await
+    .context("request timed out")??;
+    let head = String::from_utf8_lossy(&request[..head_len]).into_owned();
+    let mut lines = head.split("\r\n");
+    let mut parts = lines.next().unwrap_or_default().split(' ');
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default();
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (target.to_string(), None),
+    };
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect();
+    let mut request = Request {
+        method,
+        path,
+        query,
+        headers,
+        body: request[head_len..].to_vec(),
+    };
+    let length: usize = match request.header("content-length") {
+        Some(length) => length.parse().context("invalid Content-Length")?,
+        None => 0,
+    };
+    if length > MAX_BODY {
+        bail!("request body too long");
+    }
+    timeout(REQUEST_TIMEOUT, async {
+        while request.body.len() < length {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                bail!("request body cut short");
+            }
+            request.body.extend_from_slice(&buf[..n]);
+        }
+        request.body.truncate(length);
+        Ok(())
+    })
+    .await
+    .context("request timed out")??;
+    Ok(request)
+}
+
+/// Write a whole response with `status`, `content_type`, and any `headers`, then close the
+/// connection.
+pub async fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<()> {
+    let mut response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        body.len()
+    );
+    for (name, value) in headers {
+        response += &format!("{name}: {value}\r\n");
+    }
+    response += "\r\n";
+    stream.write_all(response.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
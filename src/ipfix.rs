@@ -0,0 +1,279 @@
+//! IPFIX flow export (`ipfix_collector`): a pair of flow records per proxied session, sent over UDP
+//! to a collector so proxied traffic shows up in existing flow-analysis tooling.
+//!
+//! A session becomes one record per direction: client to target, and target to client with the
+//! addresses swapped. Records carry the addresses and ports as far as they are IP, the protocol,
+//! the bytes, the start and end, and the forward's `label` as `applicationName`. UDP sessions also
+//! carry their datagrams as packets; pfwd does not see the segments of a stream, so stream records
+//! have no packet count. Each leg exports its own records, and legs with no IP address on either
+//! end (a UDS relayed to a UDS) export none.
+//!
+//! Templates go out with the first message and again every [`TEMPLATE_REFRESH`], since a collector
+//! that restarts over UDP has no other way to learn them.
+
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, bail};
+use tracing::warn;
+
+use crate::log_limit;
+
+/// How often templates are sent again.
+const TEMPLATE_REFRESH: Duration = Duration::from_secs(60);
+
+const VERSION: u16 = 10;
+const TEMPLATE_SET: u16 = 2;
+/// Templates are numbered from here, see [`template_id`].
+const FIRST_TEMPLATE: u16 = 256;
+/// Field length of a variable-length element in a template.
+const VARIABLE: u16 = 65535;
+/// Longer labels are cut to this many octets.
+const MAX_LABEL: usize = 254;
+
+// Information element ids (IANA IPFIX registry).
+const OCTET_DELTA_COUNT: u16 = 1;
+const PACKET_DELTA_COUNT: u16 = 2;
+const PROTOCOL_IDENTIFIER: u16 = 4;
+const SOURCE_TRANSPORT_PORT: u16 = 7;
+const SOURCE_IPV4_ADDRESS: u16 = 8;
+const DESTINATION_TRANSPORT_PORT: u16 = 11;
+const DESTINATION_IPV4_ADDRESS: u16 = 12;
+const SOURCE_IPV6_ADDRESS: u16 = 27;
+const DESTINATION_IPV6_ADDRESS: u16 = 28;
+const APPLICATION_NAME: u16 = 96;
+const FLOW_START_MILLISECONDS: u16 = 152;
+const FLOW_END_MILLISECONDS: u16 = 153;
+
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+static EXPORTER: OnceLock<Exporter> = OnceLock::new();
+
+struct Exporter {
+    socket: UdpSocket,
+    collector: String,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Data records sent so far, which the next message header carries.
+    sequence: u32,
+    templates_sent: Option<Instant>,
+}
+
+/// Export flows to `collector` for the rest of the process; sessions before this are not exported.
+pub fn init(collector: &str) -> Result<()> {
+    let addr = collector
+        .to_socket_addrs()
+        .with_context(|| format!("failed to resolve ipfix_collector {collector}"))?
+        .next()
+        .with_context(|| format!("ipfix_collector {collector} has no address"))?;
+    let local: SocketAddr = if addr.is_ipv6() {
+        "[::]:0".parse().expect("valid address")
+    } else {
+        "0.0.0.0:0".parse().expect("valid address")
+    };
+    let socket = UdpSocket::bind(local).context("failed to bind ipfix socket")?;
+    socket.connect(addr)?;
+    // Sessions close on runtime threads; a full socket buffer drops the message instead.
+    socket.set_nonblocking(true)?;
+    let exporter = Exporter {
+        socket,
+        collector: collector.to_string(),
+        state: Mutex::new(State {
+            sequence: 0,
+            templates_sent: None,
+        }),
+    };
+    if EXPORTER.set(exporter).is_err() {
+        bail!("ipfix exporter already started");
+    }
+    Ok(())
+}
+
+/// Whether flows are exported, so callers can skip gathering them.
+pub fn enabled() -> bool {
+    EXPORTER.get().is_some()
+}
+
+/// One finished session, seen from its client.
+pub struct Flow<'a> {
+    pub label: &'a str,
+    pub protocol: u8,
+    /// Client address, where it is an IP one.
+    pub client: Option<SocketAddr>,
+    /// Target address, where it is an IP one.
+    pub target: Option<SocketAddr>,
+    pub started_unix_ms: u64,
+    pub bytes_client_to_target: u64,
+    pub bytes_target_to_client: u64,
+    /// Datagrams each way, for UDP sessions.
+    pub packets: Option<(u64, u64)>,
+}
+
+/// Export `flow`'s two records, if flows are exported and either end is an IP address.
+pub fn export(flow: Flow) {
+    let Some(exporter) = EXPORTER.get() else {
+        return;
+    };
+    if flow.client.is_none() && flow.target.is_none() {
+        return;
+    }
+    let ended = SystemTime::now();
+    let (client_to_target, target_to_client) = flow.packets.unzip();
+    let records = [
+        Record {
+            source: flow.client,
+            destination: flow.target,
+            bytes: flow.bytes_client_to_target,
+            packets: client_to_target,
+        },
+        Record {
+            source: flow.target,
+            destination: flow.client,
+            bytes: flow.bytes_target_to_client,
+            packets: target_to_client,
+        },
+    ];
+    let v6 = [flow.client, flow.target]
+        .iter()
+        .flatten()
+        .any(SocketAddr::is_ipv6);
+    let template = template_id(v6, flow.packets.is_some());
+
+    let mut data = Vec::new();
+    for record in &records {
+        record.encode(&flow, v6, ended, &mut data);
+    }
+    let mut state = exporter.state.lock().expect("ipfix state poisoned");
+    let mut message = Vec::with_capacity(512);
+    message.extend_from_slice(&[0; 16]);
+    if state
+        .templates_sent
+        .is_none_or(|at| at.elapsed() >= TEMPLATE_REFRESH)
+    {
+        append_set(&mut message, TEMPLATE_SET, &templates());
+        state.templates_sent = Some(Instant::now());
+    }
+    append_set(&mut message, template, &data);
+    let export_secs = unix_millis(ended) / 1000;
+    let length = message.len() as u16;
+    message[0..2].copy_from_slice(&VERSION.to_be_bytes());
+    message[2..4].copy_from_slice(&length.to_be_bytes());
+    message[4..8].copy_from_slice(&(export_secs as u32).to_be_bytes());
+    message[8..12].copy_from_slice(&state.sequence.to_be_bytes());
+    // Observation domain 0: pfwd is a single exporting process.
+    message[12..16].copy_from_slice(&0u32.to_be_bytes());
+    state.sequence = state.sequence.wrapping_add(records.len() as u32);
+    if let Err(err) = exporter.socket.send(&message) {
+        // A collector that is down refuses the datagrams; flows are lost like on any UDP exporter.
+        if let Some(suppressed) = log_limit::allow("failed to send ipfix flow", &exporter.collector)
+        {
+            warn!(collector = %exporter.collector, error = %err, suppressed, "failed to send ipfix flow");
+        }
+    }
+}
+
+/// One direction of a flow.
+struct Record {
+    source: Option<SocketAddr>,
+    destination: Option<SocketAddr>,
+    bytes: u64,
+    packets: Option<u64>,
+}
+
+impl Record {
+    /// Append the record in the field order of [`fields`].
+    fn encode(&self, flow: &Flow, v6: bool, ended: SystemTime, out: &mut Vec<u8>) {
+        for addr in [self.source, self.destination] {
+            // An end that is not IP is left as the unspecified address.
+            match (v6, addr.map(|addr| addr.ip())) {
+                (false, None) => out.extend_from_slice(&[0; 4]),
+                (false, Some(IpAddr::V4(ip))) => out.extend_from_slice(&ip.octets()),
+                (false, Some(IpAddr::V6(_))) => unreachable!("v4 template chosen for a v6 address"),
+                (true, None) => out.extend_from_slice(&[0; 16]),
+                (true, Some(IpAddr::V4(ip))) => {
+                    out.extend_from_slice(&ip.to_ipv6_mapped().octets())
+                }
+                (true, Some(IpAddr::V6(ip))) => out.extend_from_slice(&ip.octets()),
+            }
+        }
+        for addr in [self.source, self.destination] {
+            out.extend_from_slice(&addr.map_or(0, |addr| addr.port()).to_be_bytes());
+        }
+        out.push(flow.protocol);
+        out.extend_from_slice(&self.bytes.to_be_bytes());
+        if let Some(packets) = self.packets {
+            out.extend_from_slice(&packets.to_be_bytes());
+        }
+        out.extend_from_slice(&flow.started_unix_ms.to_be_bytes());
+        out.extend_from_slice(&unix_millis(ended).to_be_bytes());
+        // Variable length with a one-octet length, which caps it below 255 octets.
+        let mut end = flow.label.len().min(MAX_LABEL);
+        while !flow.label.is_char_boundary(end) {
+            end -= 1;
+        }
+        out.push(end as u8);
+        out.extend_from_slice(&flow.label.as_bytes()[..end]);
+    }
+}
+
+/// Template numbers: IPv4 or IPv6 addresses, with or without a packet count.
+fn template_id(v6: bool, packets: bool) -> u16 {
+    FIRST_TEMPLATE + u16::from(v6) * 2 + u16::from(packets)
+}
+
+/// Information elements and lengths of a template, in record order.
+fn fields(v6: bool, packets: bool) -> Vec<(u16, u16)> {
+    let mut fields = if v6 {
+        vec![(SOURCE_IPV6_ADDRESS, 16), (DESTINATION_IPV6_ADDRESS, 16)]
+    } else {
+        vec![(SOURCE_IPV4_ADDRESS, 4), (DESTINATION_IPV4_ADDRESS, 4)]
+    };
+    fields.extend([
+        (SOURCE_TRANSPORT_PORT, 2),
+        (DESTINATION_TRANSPORT_PORT, 2),
+        (PROTOCOL_IDENTIFIER, 1),
+        (OCTET_DELTA_COUNT, 8),
+    ]);
+    if packets {
+        fields.push((PACKET_DELTA_COUNT, 8));
+    }
+    fields.extend([
+        (FLOW_START_MILLISECONDS, 8),
+        (FLOW_END_MILLISECONDS, 8),
+        (APPLICATION_NAME, VARIABLE),
+    ]);
+    fields
+}
+
+/// Template records for all four templates.
+fn templates() -> Vec<u8> {
+    let mut out = Vec::new();
+    for v6 in [false, true] {
+        for packets in [false, true] {
+            let fields = fields(v6, packets);
+            out.extend_from_slice(&template_id(v6, packets).to_be_bytes());
+            out.extend_from_slice(&(fields.len() as u16).to_be_bytes());
+            for (id, length) in fields {
+                out.extend_from_slice(&id.to_be_bytes());
+                out.extend_from_slice(&length.to_be_bytes());
+            }
+        }
+    }
+    out
+}
+
+fn append_set(message: &mut Vec<u8>, id: u16, body: &[u8]) {
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&((body.len() + 4) as u16).to_be_bytes());
+    message.extend_from_slice(body);
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
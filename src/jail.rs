@@ -0,0 +1,287 @@
+//! FreeBSD jails as namespace sources: `namespace = "jail:<name|jid>"`.
+//!
+//! `jail_attach(2)` moves the whole process into a jail for good, so pfwd cannot join one on a
+//! thread the way it joins a Linux network namespace. A forward into a jail starts a helper
+//! instead: a copy of pfwd (`pfwd jail-helper <jail>`) that attaches to the jail and then opens
+//! sockets there on request, namely connections to a namespace endpoint's `target` and the TCP
+//! listener of a reverse forward. A socket stays in the jail it was created in, so the helper
+//! hands it back with SCM_RIGHTS (see [`crate::fdpass`]) and the rest of the pipeline runs
+//! unchanged in pfwd itself. Names resolve inside the jail, with its own `resolv.conf`.
+//!
+//! Every request travels on a socket pair of its own, whose helper end is passed over the
+//! helper's stdin, so slow connects do not queue behind each other and an abandoned request
+//! leaves nothing behind. The request is a length-prefixed JSON message. The reply is an
+//! [`fdpass`] status byte carrying the socket; a failure is followed by a length-prefixed error
+//! message. The helper exits once pfwd closes its stdin, i.e. when the forward stops.
+
+use std::io;
+use std::os::fd::{AsFd, OwnedFd};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::process::Stdio;
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UnixStream};
+use tokio::process::{Child, Command};
+use tracing::{debug, info};
+
+use crate::fdpass;
+use crate::pipeline::listener::bind_tcp;
+
+const SCHEME: &str = "jail:";
+/// Upper bound on a request or error message, which hold an address or a line of text.
+const MAX_MESSAGE: u32 = 64 * 1024;
+
+/// Jail named by a `namespace = "jail:<name|jid>"` value.
+pub fn parse(namespace: &str) -> Option<&str> {
+    namespace.strip_prefix(SCHEME)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request {
+    /// Connect to `target` (`host:port`).
+    Connect { target: String },
+    /// Bind a TCP listener on `listen`.
+    Listen {
+        listen: String,
+        ipv6_only: Option<bool>,
+    },
+}
+
+/// pfwd's end of a helper attached to one jail. Dropping it stops the helper.
+#[derive(Debug)]
+pub struct Helper {
+    jail: String,
+    control: UnixStream,
+    _child: Option<Child>,
+}
+
+impl Helper {
+    /// Start a helper attached to `jail`. Must run on the runtime.
+    pub fn start(jail: &str) -> Result<Self> {
+        let (ours, theirs) =
+            StdUnixStream::pair().context("failed to create jail helper channel")?;
+        let exe = std::env::current_exe().context("failed to locate the pfwd binary")?;
+        let child = Command::new(exe)
+            .args(["jail-helper", jail])
+            .stdin(Stdio::from(OwnedFd::from(theirs)))
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("failed to start the helper for jail {jail}"))?;
+        info!(jail, pid = child.id(), "jail helper started");
+        Self::over(jail, ours, Some(child))
+    }
+
+    fn over(jail: &str, control: StdUnixStream, child: Option<Child>) -> Result<Self> {
+        control.set_nonblocking(true)?;
+        Ok(Self {
+            jail: jail.to_string(),
+            control: UnixStream::from_std(control)?,
+            _child: child,
+        })
+    }
+
+    /// Connect to `target` from inside the jail.
+    pub async fn connect(&self, target: &str) -> Result<TcpStream> {
+        let request = Request::Connect {
+            target: target.to_string(),
+        };
+        let fd = self
+            .request(&request)
+            .await
+            .with_context(|| format!("failed to connect {target} in jail {}", self.jail))?;
+        let stream = std::net::TcpStream::from(fd);
+        stream.set_nonblocking(true)?;
+        Ok(TcpStream::from_std(stream)?)
+    }
+
+    /// Bind a TCP listener on `listen` inside the jail.
+    pub async fn listen(&self, listen: &str, ipv6_only: Option<bool>) -> Result<TcpListener> {
+        let request = Request::Listen {
+            listen: listen.to_string(),
+            ipv6_only,
+        };
+        let fd = self
+            .request(&request)
+            .await
+            .with_context(|| format!("failed to listen on {listen} in jail {}", self.jail))?;
+        let listener = std::net::TcpListener::from(fd);
+        listener.set_nonblocking(true)?;
+        Ok(TcpListener::from_std(listener)?)
+    }
+
+    async fn request(&self, request: &Request) -> Result<OwnedFd> {
+        let (ours, theirs) = UnixStream::pair().context("failed to create jail request channel")?;
+        fdpass::send_fd(&self.control, theirs.as_fd())
+            .await
+            .context("jail helper is gone")?;
+        drop(theirs);
+        let mut channel = ours;
+        write_message(&mut channel, &serde_json::to_vec(request)?).await?;
+        if let Some(fd) = fdpass::recv_fd(&channel).await? {
+            return Ok(fd);
+        }
+        let message = read_message(&mut channel)
+            .await
+            .context("jail helper exited")?;
+        bail!("{}", String::from_utf8_lossy(&message))
+    }
+}
+
+/// `pfwd jail-helper`: attach to `jail`, then answer pfwd's requests until it closes stdin.
+pub async fn serve(jail: &str) -> Result<()> {
+    attach(jail)?;
+    let control = StdUnixStream::from(
+        io::stdin()
+            .as_fd()
+            .try_clone_to_owned()
+            .context("failed to take the jail helper channel")?,
+    );
+    control.set_nonblocking(true)?;
+    serve_control(UnixStream::from_std(control)?).await
+}
+
+/// Answer each request channel passed over `control`, concurrently, until `control` closes.
+async fn serve_control(control: UnixStream) -> Result<()> {
+    while let Some(fd) = fdpass::recv_fd(&control).await? {
+        let channel = StdUnixStream::from(fd);
+        channel.set_nonblocking(true)?;
+        let channel = UnixStream::from_std(channel)?;
+        tokio::spawn(async move {
+            if let Err(err) = answer(channel).await {
+                debug!(error = %err, "jail helper request failed");
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn answer(mut channel: UnixStream) -> Result<()> {
+    let request: Request = serde_json::from_slice(&read_message(&mut channel).await?)
+        .context("malformed jail helper request")?;
+    let socket = match request {
+        Request::Connect { target } => TcpStream::connect(&target)
+            .await
+            .and_then(TcpStream::into_std)
+            .map(OwnedFd::from)
+            .map_err(anyhow::Error::from),
+        Request::Listen { listen, ipv6_only } => bind_tcp(&listen, false, ipv6_only)
+            .and_then(|listener| Ok(OwnedFd::from(listener.into_std()?))),
+    };
+    match socket {
+        Ok(fd) => fdpass::send_fd(&channel, fd.as_fd()).await?,
+        Err(err) => {
+            fdpass::send_failure(&channel).await?;
+            write_message(&mut channel, format!("{err:#}").as_bytes()).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn write_message(channel: &mut UnixStream, message: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(message.len())
+        .ok()
+        .filter(|&len| len <= MAX_MESSAGE)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "jail message too long"))?;
+    channel.write_u32(len).await?;
+    channel.write_all(message).await
+}
+
+async fn read_message(channel: &mut UnixStream) -> io::Result<Vec<u8>> {
+    let len = channel.read_u32().await?;
+    if len > MAX_MESSAGE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "jail message too long",
+        ));
+    }
+    let mut message = vec![0; len as usize];
+    channel.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+/// Move this process into `jail`, named or by id.
+#[cfg(target_os = "freebsd")]
+fn attach(jail: &str) -> Result<()> {
+    use std::ffi::CString;
+
+    use nix::libc;
+
+    let jid = match jail.parse::<libc::c_int>() {
+        Ok(jid) => jid,
+        Err(_) => {
+            let name = CString::new(jail).context("jail name contains a NUL byte")?;
+            // SAFETY: `name` is a NUL-terminated string that outlives the call.
+            let jid = unsafe { libc::jail_getid(name.as_ptr()) };
+            if jid < 0 {
+                return Err(io::Error::last_os_error())
+                    .with_context(|| format!("jail {jail} not found"));
+            }
+            jid
+        }
+    };
+    // SAFETY: takes a plain jail id and touches no memory of ours.
+    if unsafe { libc::jail_attach(jid) } != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("jail_attach failed for jail {jail}"));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "freebsd"))]
+fn attach(jail: &str) -> Result<()> {
+    bail!("jail {jail}: FreeBSD jails are only supported on FreeBSD");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A helper serving requests in this process, without attaching to a jail.
+    fn local_helper() -> Helper {
+        let (ours, theirs) = StdUnixStream::pair().unwrap();
+        theirs.set_nonblocking(true).unwrap();
+        let theirs = UnixStream::from_std(theirs).unwrap();
+        tokio::spawn(serve_control(theirs));
+        Helper::over("test", ours, None).unwrap()
+    }
+
+    #[tokio::test]
+    async fn connects_through_the_helper() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = target.local_addr().unwrap();
+        let helper = local_helper();
+        let (mut stream, accepted) = tokio::join!(
+            async { helper.connect(&addr.to_string()).await.unwrap() },
+            async { target.accept().await.unwrap().0 }
+        );
+        let mut accepted = accepted;
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        accepted.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn reports_connect_failures() {
+        let unused = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = unused.local_addr().unwrap();
+        drop(unused);
+        let err = local_helper().connect(&addr.to_string()).await.unwrap_err();
+        let message = format!("{err:#}");
+        assert!(message.contains(&addr.to_string()), "{message}");
+        assert!(message.contains("refused"), "{message}");
+    }
+
+    #[tokio::test]
+    async fn listens_through_the_helper() {
+        let helper = local_helper();
+        let listener = helper.listen("127.0.0.1:0", None).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        assert_eq!(client.unwrap().local_addr().unwrap(), accepted.unwrap().1);
+    }
+}
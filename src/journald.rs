@@ -0,0 +1,188 @@
+//! `--log-output journald`: tracing events in journald's native protocol, with their fields kept
+//! as journal fields instead of flattened into the text.
+//!
+//! Every field of an event and of the spans it happened in becomes an upper-case journal field,
+//! so `journalctl -u pfwd LABEL=ssh-qdhcp-123` or `NAMESPACE=qdhcp-...` selects a forward's lines.
+//! `MESSAGE` still reads like the stdout log, and `PRIORITY`/`SYSLOG_FACILITY` follow
+//! `syslog_severity` and `syslog_facility`.
+
+use std::fmt::{self, Write as _};
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::Context as LayerContext;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::syslog::Priorities;
+
+const SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Sends events to the journal.
+pub struct Journald {
+    socket: UnixDatagram,
+    priorities: Priorities,
+}
+
+impl Journald {
+    pub fn connect(priorities: Priorities) -> Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket
+            .connect(SOCKET)
+            .with_context(|| format!("failed to connect to journald at {SOCKET}"))?;
+        Ok(Self { socket, priorities })
+    }
+
+    fn send(&self, payload: &[u8]) {
+        // Like syslog, a restarted journald gets one reconnect; there is nowhere to report failure.
+        if self.socket.send(payload).is_err() && self.socket.connect(Path::new(SOCKET)).is_ok() {
+            let _ = self.socket.send(payload);
+        }
+    }
+}
+
+/// Fields recorded on a span, kept in its extensions until its events need them.
+struct SpanFields(Vec<(String, String)>);
+
+/// Collects fields by journal name, and the `message` separately.
+#[derive(Default)]
+struct Fields {
+    message: String,
+    fields: Vec<(String, String)>,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.fields
+                .push((field.name().to_string(), value.to_string()));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{value:?}");
+        } else {
+            self.fields
+                .push((field.name().to_string(), format!("{value:?}")));
+        }
+    }
+}
+
+impl<S> Layer<S> for Journald
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: LayerContext<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        span.extensions_mut().insert(SpanFields(fields.fields));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: LayerContext<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = Fields::default();
+        values.record(&mut fields);
+        let mut extensions = span.extensions_mut();
+        if let Some(SpanFields(known)) = extensions.get_mut::<SpanFields>() {
+            for (name, value) in fields.fields {
+                match known.iter_mut().find(|(n, _)| *n == name) {
+                    Some((_, old)) => *old = value,
+                    None => known.push((name, value)),
+                }
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: LayerContext<'_, S>) {
+        let meta = event.metadata();
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+
+        // Readable text like the stdout log: the message, then the event's own fields.
+        let mut message = fields.message;
+        for (name, value) in &fields.fields {
+            let _ = write!(message, " {name}={value}");
+        }
+        let mut payload = Vec::new();
+        append(&mut payload, "MESSAGE", &message);
+        append(
+            &mut payload,
+            "PRIORITY",
+            &self.priorities.severity(*meta.level()).to_string(),
+        );
+        append(
+            &mut payload,
+            "SYSLOG_FACILITY",
+            &self.priorities.facility.to_string(),
+        );
+        append(&mut payload, "SYSLOG_IDENTIFIER", "pfwd");
+        // `TARGET` is left to the forward target that session records carry.
+        append(&mut payload, "TRACING_TARGET", meta.target());
+        if let (Some(file), Some(line)) = (meta.file(), meta.line()) {
+            append(&mut payload, "CODE_FILE", file);
+            append(&mut payload, "CODE_LINE", &line.to_string());
+        }
+        // Outer spans first, so an inner span's or the event's own value of a field comes last.
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                append(&mut payload, "SPAN", span.name());
+                if let Some(SpanFields(span_fields)) = span.extensions().get::<SpanFields>() {
+                    for (name, value) in span_fields {
+                        append(&mut payload, &field_name(name), value);
+                    }
+                }
+            }
+        }
+        for (name, value) in &fields.fields {
+            append(&mut payload, &field_name(name), value);
+        }
+        self.send(&payload);
+    }
+}
+
+/// Journal field names are upper-case letters, digits, and underscores, and may not start with an
+/// underscore (reserved for trusted fields) or a digit.
+fn field_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let name = name.trim_start_matches('_');
+    if name.starts_with(|c: char| c.is_ascii_digit()) || name.is_empty() {
+        format!("F_{name}")
+    } else {
+        name.to_string()
+    }
+}
+
+/// One field in the native protocol: `NAME=value\n`, or the length-prefixed form for values with a
+/// newline.
+fn append(payload: &mut Vec<u8>, name: &str, value: &str) {
+    payload.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        payload.push(b'\n');
+        payload.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        payload.push(b'=');
+    }
+    payload.extend_from_slice(value.as_bytes());
+    payload.push(b'\n');
+}
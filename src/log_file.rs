@@ -0,0 +1,146 @@
+//! `--log-file`: the log appended to a file that pfwd rotates itself, for hosts without a log
+//! shipper or logrotate.
+//!
+//! The file is rotated once it would grow past `log_file_max_bytes`, or when the UTC hour or day
+//! changes with `log_file_rotation`. Rotated files shift up as `<path>.1`, `<path>.2`, ... and
+//! only the newest `log_file_keep` are kept.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::config::LogRotation;
+
+/// Rotated files kept unless `log_file_keep` says otherwise.
+pub const DEFAULT_KEEP: usize = 5;
+
+pub struct LogFile {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    rotation: LogRotation,
+    keep: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    file: File,
+    size: u64,
+    /// Rotation period the file was opened in.
+    period: u64,
+}
+
+impl LogFile {
+    pub fn open(
+        path: PathBuf,
+        max_bytes: Option<u64>,
+        rotation: LogRotation,
+        keep: usize,
+    ) -> Result<Self> {
+        let file =
+            append(&path).with_context(|| format!("failed to open log file {}", path.display()))?;
+        let metadata = file.metadata()?;
+        let size = metadata.len();
+        // A file left from an earlier run belongs to the period it was last written in.
+        let period = period(
+            rotation,
+            metadata.modified().unwrap_or_else(|_| SystemTime::now()),
+        );
+        Ok(Self {
+            path,
+            max_bytes,
+            rotation,
+            keep,
+            state: Mutex::new(State { file, size, period }),
+        })
+    }
+
+    fn write_record(&self, buf: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().expect("log file poisoned");
+        let period = period(self.rotation, SystemTime::now());
+        let full = self
+            .max_bytes
+            .is_some_and(|max| state.size > 0 && state.size + buf.len() as u64 > max);
+        if full || period != state.period {
+            // Keep writing to the old file if rotating fails rather than losing lines.
+            if let Err(err) = self.rotate(&mut state, period) {
+                let _ = writeln!(
+                    state.file,
+                    "failed to rotate log file {}: {err}",
+                    self.path.display()
+                );
+            }
+        }
+        state.file.write_all(buf)?;
+        state.size += buf.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&self, state: &mut State, period: u64) -> io::Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(numbered(&self.path, self.keep));
+            for n in (1..self.keep).rev() {
+                let from = numbered(&self.path, n);
+                if from.exists() {
+                    fs::rename(&from, numbered(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&self.path, numbered(&self.path, 1))?;
+        }
+        state.file = append(&self.path)?;
+        state.size = 0;
+        state.period = period;
+        Ok(())
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+/// Hours or days since the epoch at `time`, or always 0 without time-based rotation.
+fn period(rotation: LogRotation, time: SystemTime) -> u64 {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    match rotation {
+        LogRotation::Never => 0,
+        LogRotation::Hourly => secs / 3600,
+        LogRotation::Daily => secs / 86400,
+    }
+}
+
+/// Writes one formatted event; the formatter hands it over in a single write.
+pub struct Writer<'a>(&'a LogFile);
+
+impl Write for Writer<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_record(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogFile {
+    type Writer = Writer<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Writer(self)
+    }
+}
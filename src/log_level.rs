@@ -0,0 +1,42 @@
+//! Changing the log filter while pfwd runs, from the [`admin`](crate::admin) interfaces, so a live
+//! issue can be debugged without a restart.
+//!
+//! The filter takes the same directives as `--log-level` and `RUST_LOG`: a level for everything,
+//! e.g. `debug`, or per target, e.g. `info,pfwd::pipeline::tcp=trace`. It applies to the log
+//! output only; what `otlp_endpoint` exports is unaffected.
+
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result, anyhow};
+use tracing_subscriber::{EnvFilter, Registry, reload};
+
+pub type Filter = reload::Layer<EnvFilter, Registry>;
+
+static HANDLE: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// The log output's filter, starting out as `filter`, made changeable through [`set`].
+pub fn install(filter: EnvFilter) -> Filter {
+    let (filter, handle) = reload::Layer::new(filter);
+    let _ = HANDLE.set(handle);
+    filter
+}
+
+/// The directives the log output is filtered with now.
+pub fn current() -> Result<String> {
+    handle()?
+        .with_current(ToString::to_string)
+        .map_err(|err| anyhow!("failed to read the log filter: {err}"))
+}
+
+/// Filter the log output with `directives` from now on.
+pub fn set(directives: &str) -> Result<()> {
+    let filter = EnvFilter::try_new(directives)
+        .with_context(|| format!("invalid log filter {directives}"))?;
+    handle()?
+        .reload(filter)
+        .map_err(|err| anyhow!("failed to change the log filter: {err}"))
+}
+
+fn handle() -> Result<&'static reload::Handle<EnvFilter, Registry>> {
+    HANDLE.get().context("logging is not set up")
+}
@@ -0,0 +1,89 @@
+//! Repeated warnings collapsed into summaries, so one dead namespace or misbehaving client cannot
+//! flood the log with a line per retry or datagram.
+//!
+//! A warning is identified by its message and a key saying what it is about (a UDS path, a target,
+//! the error text). The first one is logged as usual; repeats within [`WINDOW`] are only counted.
+//! The next one after that is logged with `suppressed` set to the count skipped, and if none comes,
+//! a `repeated warnings suppressed` line reports them instead.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tokio::task::JoinHandle;
+use tokio::time::{MissedTickBehavior, interval};
+use tracing::warn;
+
+use crate::pipeline::ShutdownRx;
+
+/// How long repeats of a logged warning are only counted.
+const WINDOW: Duration = Duration::from_secs(30);
+
+struct Entry {
+    logged: Option<Instant>,
+    suppressed: u64,
+}
+
+type Entries = HashMap<(&'static str, String), Entry>;
+
+static ENTRIES: LazyLock<Mutex<Entries>> = LazyLock::new(Default::default);
+
+/// Whether to log `message` about `key` now, with how many repeats were skipped since it last was.
+pub fn allow(message: &'static str, key: impl Display) -> Option<u64> {
+    let mut entries = ENTRIES.lock().expect("log limit poisoned");
+    let entry = entries.entry((message, key.to_string())).or_insert(Entry {
+        logged: None,
+        suppressed: 0,
+    });
+    if entry.logged.is_some_and(|at| at.elapsed() < WINDOW) {
+        entry.suppressed += 1;
+        return None;
+    }
+    entry.logged = Some(Instant::now());
+    Some(std::mem::take(&mut entry.suppressed))
+}
+
+/// Report repeats no later warning has reported, and forget quiet warnings, every [`WINDOW`]; at
+/// shutdown, report everything still counted.
+pub fn spawn_flush(mut shutdown: ShutdownRx) -> JoinHandle<Result<()>> {
+    tokio::spawn(async move {
+        let mut ticks = interval(WINDOW);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                res = shutdown.changed() => {
+                    if res.is_err() || *shutdown.borrow() {
+                        flush(true);
+                        return Ok(());
+                    }
+                }
+                _ = ticks.tick() => flush(false),
+            }
+        }
+    })
+}
+
+/// Report and forget warnings quiet for a whole [`WINDOW`], or all of them when `all` is set.
+fn flush(all: bool) {
+    let mut expired = Vec::new();
+    ENTRIES
+        .lock()
+        .expect("log limit poisoned")
+        .retain(|(message, key), entry| {
+            if !all && entry.logged.is_some_and(|at| at.elapsed() < WINDOW) {
+                return true;
+            }
+            if entry.suppressed > 0 {
+                expired.push((*message, key.clone(), entry.suppressed));
+            }
+            false
+        });
+    for (message, key, suppressed) in expired {
+        warn!(
+            warning = message,
+            key, suppressed, "repeated warnings suppressed"
+        );
+    }
+}
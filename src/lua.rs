@@ -0,0 +1,178 @@
+//! Lua policy hook (`on_accept_lua`), enabled with the `lua` cargo feature.
+//!
+//! The script is loaded once per forward and must define a global `on_accept(conn)`. `conn` has
+//! `peer` (the client address), `label`, and `namespace` (nil for host-side specs). Returning
+//! `nil` or `true` accepts the session, `false` rejects it, and a string sends the session to that
+//! target instead of the spec's own.
+//!
+//! Each run of the script, at load and per connection, may execute about
+//! [`INSTRUCTIONS_PER_CALL`] Lua instructions before it is stopped with an error, which rejects the
+//! connection. Admission runs it on tokio's blocking threads, so a slow script holds up only the
+//! clients waiting on it.
+
+use std::path::Path;
+use std::sync::Arc;
+#[cfg(feature = "lua")]
+use std::sync::atomic::{AtomicU32, Ordering::Relaxed};
+#[cfg(feature = "lua")]
+use std::sync::{Mutex, MutexGuard};
+
+use anyhow::{Result, bail};
+
+use crate::pipeline::admission::Verdict;
+
+/// Reject `on_accept_lua` at config load when the feature is compiled out or the script is missing.
+pub fn validate(script: &Path) -> Result<()> {
+    if !cfg!(feature = "lua") {
+        bail!("`on_accept_lua` needs rebuilding pfwd with `--features lua`");
+    }
+    if !script.is_file() {
+        bail!("`on_accept_lua` script {} does not exist", script.display());
+    }
+    Ok(())
+}
+
+/// Lua instructions (roughly) one run of the script may execute.
+#[cfg_attr(not(feature = "lua"), allow(dead_code))]
+const INSTRUCTIONS_PER_CALL: u32 = 10_000_000;
+/// How often the instruction budget is checked.
+#[cfg(feature = "lua")]
+const INSTRUCTIONS_PER_CHECK: u32 = 1_000;
+
+/// Connection attributes handed to the script.
+#[cfg_attr(not(feature = "lua"), allow(dead_code))]
+pub struct Connection {
+    pub peer: String,
+    pub label: Option<Arc<str>>,
+    pub namespace: Option<Arc<str>>,
+}
+
+#[cfg(feature = "lua")]
+pub struct LuaHook {
+    lua: mlua::Lua,
+    /// Instruction checks left to the run in progress; held locked for the whole run.
+    budget: Mutex<Arc<AtomicU32>>,
+}
+
+#[cfg(feature = "lua")]
+impl LuaHook {
+    pub fn load(script: &Path) -> Result<Self> {
+        use anyhow::Context;
+
+        let source = std::fs::read_to_string(script)
+            .with_context(|| format!("failed to read {}", script.display()))?;
+        let lua = mlua::Lua::new();
+        let left = Arc::new(AtomicU32::new(0));
+        let checks = left.clone();
+        lua.set_hook(
+            mlua::HookTriggers::new().every_nth_instruction(INSTRUCTIONS_PER_CHECK),
+            move |_, _| {
+                if checks
+                    .fetch_update(Relaxed, Relaxed, |left| left.checked_sub(1))
+                    .is_err()
+                {
+                    return Err(mlua::Error::runtime("instruction limit exceeded"));
+                }
+                Ok(mlua::VmState::Continue)
+            },
+        )?;
+        let hook = Self {
+            lua,
+            budget: Mutex::new(left),
+        };
+        let budget = hook.start_run();
+        hook.lua
+            .load(source)
+            .set_name(script.display().to_string())
+            .exec()
+            .with_context(|| format!("failed to run {}", script.display()))?;
+        drop(budget);
+        hook.lua
+            .globals()
+            .get::<mlua::Function>("on_accept")
+            .with_context(|| format!("{} does not define `on_accept`", script.display()))?;
+        Ok(hook)
+    }
+
+    /// Give one run of the script its full budget, for as long as the guard is held.
+    fn start_run(&self) -> MutexGuard<'_, Arc<AtomicU32>> {
+        let budget = self.budget.lock().expect("lua budget poisoned");
+        budget.store(INSTRUCTIONS_PER_CALL / INSTRUCTIONS_PER_CHECK, Relaxed);
+        budget
+    }
+
+    /// Run `on_accept` for `conn`. This blocks for as long as the script runs.
+    pub fn on_accept(&self, conn: &Connection) -> Result<Verdict> {
+        let _budget = self.start_run();
+        let table = self.lua.create_table()?;
+        table.set("peer", conn.peer.as_str())?;
+        table.set("label", conn.label.as_deref())?;
+        table.set("namespace", conn.namespace.as_deref())?;
+        let on_accept: mlua::Function = self.lua.globals().get("on_accept")?;
+        match on_accept.call::<mlua::Value>(table)? {
+            mlua::Value::Nil | mlua::Value::Boolean(true) => Ok(Verdict::Accept),
+            mlua::Value::Boolean(false) => Ok(Verdict::Reject),
+            mlua::Value::String(target) => Ok(Verdict::Target(target.to_str()?.to_string())),
+            other => bail!("`on_accept` returned a {}", other.type_name()),
+        }
+    }
+}
+
+/// Stand-in when the feature is compiled out; config load rejects `on_accept_lua` before any is
+/// needed.
+#[cfg(not(feature = "lua"))]
+pub enum LuaHook {}
+
+#[cfg(not(feature = "lua"))]
+impl LuaHook {
+    pub fn load(script: &Path) -> Result<Self> {
+        validate(script)?;
+        bail!("lua support is not compiled in")
+    }
+
+    pub fn on_accept(&self, _conn: &Connection) -> Result<Verdict> {
+        match *self {}
+    }
+}
+
+#[cfg(all(test, feature = "lua"))]
+mod tests {
+    use super::*;
+
+    fn load(source: &str) -> Result<LuaHook> {
+        let path = std::env::temp_dir().join(format!("pfwd-lua-{}.lua", std::process::id()));
+        std::fs::write(&path, source).unwrap();
+        let hook = LuaHook::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        hook
+    }
+
+    fn conn() -> Connection {
+        Connection {
+            peer: "192.0.2.1:40000".to_string(),
+            label: Some(Arc::from("web")),
+            namespace: None,
+        }
+    }
+
+    #[test]
+    fn stops_a_looping_script() {
+        let hook = load("function on_accept(conn) while true do end end").unwrap();
+        let err = hook.on_accept(&conn()).unwrap_err();
+        assert!(format!("{err:#}").contains("instruction limit"), "{err:#}");
+        // The next connection gets a fresh budget.
+        assert!(hook.on_accept(&conn()).is_err());
+    }
+
+    #[test]
+    fn gives_each_run_its_own_budget() {
+        let hook = load(
+            "function on_accept(conn) for i = 1, 100000 do end return conn.label == 'web' end",
+        )
+        .unwrap();
+        for _ in 0..200 {
+            assert_eq!(hook.on_accept(&conn()).unwrap(), Verdict::Accept);
+        }
+        assert!(load("while true do end function on_accept() end").is_err());
+    }
+}
@@ -1,40 +1,214 @@
+mod access_log;
+mod addr;
+mod admin;
+mod admin_http;
+mod compress;
 mod config;
+mod container;
+mod ctl;
+mod discover;
+mod fdpass;
+mod filter;
 mod forward;
+mod grpc;
+mod happy_eyeballs;
+mod health;
+mod http;
+mod ipfix;
+mod jail;
+mod journald;
+mod log_file;
+mod log_level;
+mod log_limit;
+mod lua;
+mod metrics;
+mod mptcp;
 mod netns;
+mod otel;
 mod pipeline;
+mod preflight;
+mod sctp;
+mod selector;
+mod seqpacket;
+mod srv;
+mod ssh;
+mod statsd;
+mod status;
+mod syslog;
+mod tail;
+mod target;
+mod tcp_info;
+mod top;
 mod uds;
+mod userns;
+mod vsock;
+mod yamux;
 
 use anyhow::Result;
 use clap::{CommandFactory, Parser};
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{EnvFilter, Layer};
 
-use crate::config::{Cli, load_config};
+use crate::config::{Cli, Command, LogFormat, LogOutput, load_config};
+use crate::journald::Journald;
+use crate::log_file::LogFile;
+use crate::syslog::{Priorities, Syslog};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     maybe_print_long_help();
-    let cli = Cli::parse();
+    let mut cli = Cli::parse();
+    let exec = match cli.command.take() {
+        Some(Command::Replay(args)) => {
+            // The target's responses go to stdout, so logs stay on stderr.
+            init_tracing(
+                cli.log_level.as_deref().or(Some("info")),
+                LogSink::Stream(BoxMakeWriter::new(std::io::stderr)),
+                cli.log_format.unwrap_or_default(),
+                None,
+            );
+            return pipeline::record::replay(args).await;
+        }
+        Some(Command::Namespaces(args)) => return discover::list(&args),
+        Some(Command::Top(args)) => return top::run(&args).await,
+        Some(Command::Tail(args)) => return tail::run(&args).await,
+        Some(Command::Status(args)) => return ctl::status(&args).await,
+        Some(Command::Reload(args)) => return ctl::reload(&args).await,
+        Some(Command::JailHelper(args)) => return jail::serve(&args.jail).await,
+        Some(Command::Exec(args)) => Some(args),
+        None => None,
+    };
     let (defaults, specs) = load_config(&cli)?;
     let chosen_level = cli.log_level.as_deref().or(defaults.log_level.as_deref());
-    init_tracing(chosen_level);
+    let log_format = defaults.log_format.unwrap_or_default();
+    preflight::check(&specs)?;
+    if let Some(access_log) = defaults.access_log.as_deref() {
+        access_log::init(access_log)?;
+    }
+    if let Some(collector) = defaults.ipfix_collector.as_deref() {
+        ipfix::init(collector)?;
+    }
+    let (exporter, otel_layer) = match defaults.otlp_endpoint {
+        Some(_) => otel::Exporter::start(&defaults).map(|(e, l)| (Some(e), Some(l)))?,
+        None => (None, None),
+    };
+    let priorities = || {
+        Priorities::new(
+            defaults.syslog_facility.as_deref(),
+            &defaults.syslog_severity,
+        )
+    };
+    let daemon = match defaults.log_output.unwrap_or_default() {
+        LogOutput::Stdout => match defaults.log_file.clone() {
+            Some(path) => Some(LogSink::File(LogFile::open(
+                path,
+                defaults.log_file_max_bytes,
+                defaults.log_file_rotation.unwrap_or_default(),
+                defaults.log_file_keep.unwrap_or(log_file::DEFAULT_KEEP),
+            )?)),
+            None => None,
+        },
+        LogOutput::Syslog => Some(LogSink::Syslog(Syslog::connect(priorities()?)?)),
+        LogOutput::Journald => Some(LogSink::Journald(Journald::connect(priorities()?)?)),
+    };
+    if let Some(args) = exec {
+        // The command owns stdout.
+        init_tracing(
+            chosen_level,
+            daemon.unwrap_or_else(|| LogSink::Stream(BoxMakeWriter::new(std::io::stderr))),
+            log_format,
+            otel_layer,
+        );
+        let res = forward::exec(specs, &defaults, args).await;
+        if let Some(exporter) = exporter {
+            exporter.shutdown();
+        }
+        std::process::exit(res?);
+    }
+    init_tracing(
+        chosen_level,
+        daemon.unwrap_or_else(|| LogSink::Stream(BoxMakeWriter::new(std::io::stdout))),
+        log_format,
+        otel_layer,
+    );
     if specs.is_empty() {
         tracing::warn!("no forward entries configured");
         return Ok(());
     }
-    forward::run(specs).await?;
-    Ok(())
+    let res = forward::run(specs, &defaults, &cli).await;
+    // Flush the last sessions' spans.
+    if let Some(exporter) = exporter {
+        exporter.shutdown();
+    }
+    res
+}
+
+/// Where the log goes: a file or log daemon when configured, otherwise stdout or stderr.
+enum LogSink {
+    Stream(BoxMakeWriter),
+    File(LogFile),
+    Syslog(Syslog),
+    Journald(Journald),
+}
+
+fn init_tracing(
+    level: Option<&str>,
+    sink: LogSink,
+    format: LogFormat,
+    otel: Option<otel::BoxLayer>,
+) {
+    let log = match sink {
+        LogSink::Stream(writer) => fmt_layer(writer, format, false, true),
+        LogSink::File(file) => fmt_layer(file, format, true, true),
+        // The daemon stamps the time.
+        LogSink::Syslog(syslog) => fmt_layer(syslog, format, true, false),
+        LogSink::Journald(journald) => journald.boxed(),
+    };
+    let log = log
+        .with_filter(log_level::install(tracing_filter(level)))
+        .boxed();
+    let layers: Vec<otel::BoxLayer> = std::iter::once(log).chain(otel).collect();
+    tracing_subscriber::registry().with(layers).init();
+}
+
+/// The fmt layer writing to `writer` in `format`. `plain` leaves out colour codes, which would end
+/// up in files, and `timed` the timestamp.
+fn fmt_layer<W>(writer: W, format: LogFormat, plain: bool, timed: bool) -> otel::BoxLayer
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let fmt = tracing_subscriber::fmt::layer().with_writer(writer);
+    let fmt = if plain { fmt.with_ansi(false) } else { fmt };
+    match (format, timed) {
+        // Event fields go at the top level rather than under `fields`. The module path is left out,
+        // since many events have a `target` field of their own that it would clash with.
+        (LogFormat::Json, true) => fmt.json().flatten_event(true).with_target(false).boxed(),
+        (LogFormat::Json, false) => fmt
+            .json()
+            .flatten_event(true)
+            .with_target(false)
+            .without_time()
+            .boxed(),
+        (LogFormat::Text, true) => fmt.boxed(),
+        (LogFormat::Text, false) => fmt.without_time().boxed(),
+    }
 }
 
-fn init_tracing(level: Option<&str>) {
-    let filter = level
+fn tracing_filter(level: Option<&str>) -> EnvFilter {
+    level
         .map(EnvFilter::new)
-        .unwrap_or_else(EnvFilter::from_default_env);
-    tracing_subscriber::fmt().with_env_filter(filter).init();
+        .unwrap_or_else(EnvFilter::from_default_env)
 }
 
 fn maybe_print_long_help() {
-    if std::env::args_os().any(|arg| arg == "-h") {
-        let mut cmd = Cli::command();
+    let mut cmd = Cli::command();
+    // Subcommands have short help of their own; the long text is for the forward options.
+    let subcommand = std::env::args_os()
+        .skip(1)
+        .any(|arg| cmd.get_subcommands().any(|sub| arg == sub.get_name()));
+    if !subcommand && std::env::args_os().any(|arg| arg == "-h") {
         cmd.print_long_help().expect("failed to print help");
         println!();
         std::process::exit(0);
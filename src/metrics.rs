@@ -0,0 +1,563 @@
+//! Per-forward counters, exported in the Prometheus text format on `metrics_listen` and pushed over
+//! OTLP (`otlp_endpoint`) or statsd (`statsd_addr`).
+//!
+//! Each leg of a forward (the host proxy, the namespace endpoint, ...) registers a [`Metrics`]
+//! under the forward's `label` and namespace the first time its task starts, so a session crossing
+//! both legs of one process is not counted twice. Endpoints that rebind, or restart with their
+//! namespace, keep counting into the same series. Counters are plain atomics updated from the
+//! session tasks, so serving a scrape never waits on a forward.
+//!
+//! Successful target and UDS connects are also timed into fixed-bucket histograms, served on
+//! `metrics_listen` only, to alert on slow namespace services, as are the round-trip times of TCP
+//! clients and targets when their sessions close.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde::Serialize;
+use tokio::task::JoinHandle;
+
+use crate::config::ForwardSpec;
+use crate::pipeline::ShutdownRx;
+use crate::tcp_info::TcpInfo;
+use crate::{http, status};
+
+/// Upper bounds of the latency buckets, in seconds.
+const BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0,
+];
+
+type Registry = BTreeMap<(String, String, &'static str), Arc<Metrics>>;
+
+static REGISTRY: LazyLock<Mutex<Registry>> = LazyLock::new(Default::default);
+
+/// Counters of one forward.
+#[derive(Default)]
+pub struct Metrics {
+    /// Forward the counters belong to, which connect failures are also reported against.
+    label: String,
+    namespace: Option<String>,
+    sessions_accepted: AtomicU64,
+    sessions_active: AtomicI64,
+    bytes_client_to_target: AtomicU64,
+    bytes_target_to_client: AtomicU64,
+    connect_errors: AtomicU64,
+    uds_retries: AtomicU64,
+    udp_sessions: AtomicI64,
+    udp_sessions_created: AtomicU64,
+    udp_sessions_expired: AtomicU64,
+    datagrams_client_to_target: AtomicU64,
+    datagrams_target_to_client: AtomicU64,
+    datagrams_dropped: AtomicU64,
+    client_tcp_retransmits: AtomicU64,
+    target_tcp_retransmits: AtomicU64,
+    target_connect: Histogram,
+    uds_connect: Histogram,
+    client_tcp_rtt: Histogram,
+    target_tcp_rtt: Histogram,
+    errors: [AtomicU64; ErrorKind::ALL.len()],
+}
+
+/// Which way a datagram was relayed.
+#[derive(Clone, Copy)]
+pub enum Direction {
+    ClientToTarget,
+    TargetToClient,
+}
+
+/// What went wrong, for `pfwd_errors_total`.
+#[derive(Clone, Copy)]
+pub enum ErrorKind {
+    /// A listener could not be bound.
+    Bind,
+    /// The other leg's UDS could not be connected to.
+    UdsConnect,
+    /// The target could not be connected to.
+    TargetConnect,
+    /// A session failed after connecting.
+    Relay,
+    /// Any of the above, when it was a timeout.
+    Timeout,
+}
+
+impl ErrorKind {
+    pub const ALL: [ErrorKind; 5] = [
+        ErrorKind::Bind,
+        ErrorKind::UdsConnect,
+        ErrorKind::TargetConnect,
+        ErrorKind::Relay,
+        ErrorKind::Timeout,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::Bind => "bind",
+            ErrorKind::UdsConnect => "uds_connect",
+            ErrorKind::TargetConnect => "target_connect",
+            ErrorKind::Relay => "relay",
+            ErrorKind::Timeout => "timeout",
+        }
+    }
+
+    /// `self`, or [`ErrorKind::Timeout`] if `err` was caused by one.
+    fn classify(self, err: &anyhow::Error) -> Self {
+        let timed_out = err.chain().any(|cause| {
+            cause.is::<tokio::time::error::Elapsed>()
+                || cause
+                    .downcast_ref::<io::Error>()
+                    .is_some_and(|err| err.kind() == io::ErrorKind::TimedOut)
+        });
+        if timed_out { ErrorKind::Timeout } else { self }
+    }
+}
+
+/// Latencies counted per bucket of [`BUCKETS`], each observation in the first that fits.
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, latency: Duration) {
+        let secs = latency.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|&le| secs <= le) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    /// Counters for the `leg` of `spec`, shared with every task running that leg.
+    pub fn for_spec(spec: &ForwardSpec, leg: &'static str) -> Arc<Self> {
+        let label = spec.label.clone().unwrap_or_else(|| "unnamed".to_string());
+        let namespace = spec.namespace_name();
+        REGISTRY
+            .lock()
+            .expect("metrics registry poisoned")
+            .entry((label.clone(), namespace.clone().unwrap_or_default(), leg))
+            .or_insert_with(|| {
+                Arc::new(Metrics {
+                    label,
+                    namespace,
+                    ..Default::default()
+                })
+            })
+            .clone()
+    }
+
+    /// Label of the forward the counters belong to.
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Count an accepted session, which stays active until the guard is dropped.
+    pub fn session(self: &Arc<Self>) -> ActiveGuard {
+        self.sessions_accepted.fetch_add(1, Ordering::Relaxed);
+        self.sessions_active.fetch_add(1, Ordering::Relaxed);
+        ActiveGuard {
+            metrics: self.clone(),
+            udp: false,
+        }
+    }
+
+    /// Count a UDP client session, which stays open until the guard is dropped.
+    pub fn udp_session(self: &Arc<Self>) -> ActiveGuard {
+        self.udp_sessions_created.fetch_add(1, Ordering::Relaxed);
+        self.udp_sessions.fetch_add(1, Ordering::Relaxed);
+        ActiveGuard {
+            metrics: self.clone(),
+            udp: true,
+        }
+    }
+
+    /// Add the bytes one session relayed in each direction.
+    pub fn add_bytes(&self, client_to_target: u64, target_to_client: u64) {
+        self.bytes_client_to_target
+            .fetch_add(client_to_target, Ordering::Relaxed);
+        self.bytes_target_to_client
+            .fetch_add(target_to_client, Ordering::Relaxed);
+    }
+
+    /// Count a UDP session closed for being idle.
+    pub fn udp_session_expired(&self) {
+        self.udp_sessions_expired.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a datagram of `len` payload bytes relayed in `direction`.
+    pub fn datagram(&self, direction: Direction, len: usize) {
+        let (datagrams, bytes) = match direction {
+            Direction::ClientToTarget => (
+                &self.datagrams_client_to_target,
+                &self.bytes_client_to_target,
+            ),
+            Direction::TargetToClient => (
+                &self.datagrams_target_to_client,
+                &self.bytes_target_to_client,
+            ),
+        };
+        datagrams.fetch_add(1, Ordering::Relaxed);
+        bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Count a datagram that could not be relayed either way.
+    pub fn datagram_dropped(&self) {
+        self.datagrams_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Add the `TCP_INFO` of a closed session's client and target sockets, where they are TCP.
+    pub fn tcp_info(&self, client: Option<&TcpInfo>, target: Option<&TcpInfo>) {
+        for (info, retransmits, rtt) in [
+            (client, &self.client_tcp_retransmits, &self.client_tcp_rtt),
+            (target, &self.target_tcp_retransmits, &self.target_tcp_rtt),
+        ] {
+            if let Some(info) = info {
+                retransmits.fetch_add(info.retransmits.into(), Ordering::Relaxed);
+                rtt.observe(info.rtt);
+            }
+        }
+    }
+
+    /// Count `err` under `kind`, or as a timeout.
+    pub fn error(&self, kind: ErrorKind, err: &anyhow::Error) {
+        self.errors[kind.classify(err) as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a listener that failed to bind.
+    pub fn bind_error(&self, err: &anyhow::Error) {
+        self.error(ErrorKind::Bind, err);
+    }
+
+    /// Count a failed connect to the target or, with `kind` [`ErrorKind::UdsConnect`], the UDS.
+    pub fn connect_error(&self, kind: ErrorKind, err: &anyhow::Error) {
+        self.connect_errors.fetch_add(1, Ordering::Relaxed);
+        self.error(kind, err);
+        status::record_failure(&self.label, self.namespace.as_deref());
+    }
+
+    /// Await a connect to the target, timing it if it succeeds and counting a connect error if not.
+    pub async fn target_connect<T>(&self, connect: impl Future<Output = Result<T>>) -> Result<T> {
+        Self::timed(connect, &self.target_connect)
+            .await
+            .inspect_err(|err| self.connect_error(ErrorKind::TargetConnect, err))
+    }
+
+    /// Like [`Metrics::target_connect`], for the UDS of the other leg, retries included.
+    pub async fn uds_connect<T>(&self, connect: impl Future<Output = Result<T>>) -> Result<T> {
+        Self::timed(connect, &self.uds_connect)
+            .await
+            .inspect_err(|err| self.connect_error(ErrorKind::UdsConnect, err))
+    }
+
+    async fn timed<T>(
+        connect: impl Future<Output = Result<T>>,
+        histogram: &Histogram,
+    ) -> Result<T> {
+        let started = Instant::now();
+        let res = connect.await;
+        if res.is_ok() {
+            histogram.observe(started.elapsed());
+        }
+        res
+    }
+
+    pub fn uds_retry(&self) {
+        self.uds_retries.fetch_add(1, Ordering::Relaxed);
+        status::record_failure(&self.label, self.namespace.as_deref());
+    }
+}
+
+/// Keeps a session counted as active.
+pub struct ActiveGuard {
+    metrics: Arc<Metrics>,
+    udp: bool,
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        let gauge = if self.udp {
+            &self.metrics.udp_sessions
+        } else {
+            &self.metrics.sessions_active
+        };
+        gauge.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Values of one leg's counters at the time they were read.
+#[derive(Serialize)]
+pub struct Sample {
+    pub label: String,
+    /// Empty outside a namespace, which serializes as `null`.
+    #[serde(serialize_with = "empty_as_null")]
+    pub namespace: String,
+    pub leg: &'static str,
+    pub sessions_accepted: u64,
+    pub sessions_active: i64,
+    pub bytes_client_to_target: u64,
+    pub bytes_target_to_client: u64,
+    pub connect_errors: u64,
+    pub uds_retries: u64,
+    pub udp_sessions: i64,
+    pub udp_sessions_created: u64,
+    pub udp_sessions_expired: u64,
+    pub datagrams_client_to_target: u64,
+    pub datagrams_target_to_client: u64,
+    pub datagrams_dropped: u64,
+    pub client_tcp_retransmits: u64,
+    pub target_tcp_retransmits: u64,
+    /// Errors by [`ErrorKind`], in the order of [`ErrorKind::ALL`].
+    #[serde(serialize_with = "by_kind")]
+    pub errors: [u64; ErrorKind::ALL.len()],
+}
+
+fn by_kind<S: serde::Serializer>(
+    errors: &[u64; ErrorKind::ALL.len()],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(ErrorKind::ALL.iter().map(|kind| kind.as_str()).zip(errors))
+}
+
+fn empty_as_null<S: serde::Serializer>(value: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        "" => serializer.serialize_none(),
+        value => serializer.serialize_some(value),
+    }
+}
+
+/// Every leg's counters as they stand, for exporters that push them.
+pub fn samples() -> Vec<Sample> {
+    let registry = REGISTRY.lock().expect("metrics registry poisoned");
+    registry
+        .iter()
+        .map(|((label, namespace, leg), m)| Sample {
+            label: label.clone(),
+            namespace: namespace.clone(),
+            leg,
+            sessions_accepted: m.sessions_accepted.load(Ordering::Relaxed),
+            sessions_active: m.sessions_active.load(Ordering::Relaxed),
+            bytes_client_to_target: m.bytes_client_to_target.load(Ordering::Relaxed),
+            bytes_target_to_client: m.bytes_target_to_client.load(Ordering::Relaxed),
+            connect_errors: m.connect_errors.load(Ordering::Relaxed),
+            uds_retries: m.uds_retries.load(Ordering::Relaxed),
+            udp_sessions: m.udp_sessions.load(Ordering::Relaxed),
+            udp_sessions_created: m.udp_sessions_created.load(Ordering::Relaxed),
+            udp_sessions_expired: m.udp_sessions_expired.load(Ordering::Relaxed),
+            datagrams_client_to_target: m.datagrams_client_to_target.load(Ordering::Relaxed),
+            datagrams_target_to_client: m.datagrams_target_to_client.load(Ordering::Relaxed),
+            datagrams_dropped: m.datagrams_dropped.load(Ordering::Relaxed),
+            client_tcp_retransmits: m.client_tcp_retransmits.load(Ordering::Relaxed),
+            target_tcp_retransmits: m.target_tcp_retransmits.load(Ordering::Relaxed),
+            errors: m.errors.each_ref().map(|n| n.load(Ordering::Relaxed)),
+        })
+        .collect()
+}
+
+/// Every forward's counters in the Prometheus text exposition format.
+pub fn render() -> String {
+    type Read = fn(&Metrics) -> String;
+    const FAMILIES: &[(&str, &str, &str, Read)] = &[
+        (
+            "pfwd_sessions_accepted_total",
+            "counter",
+            "Sessions accepted.",
+            |m| m.sessions_accepted.load(Ordering::Relaxed).to_string(),
+        ),
+        (
+            "pfwd_sessions_active",
+            "gauge",
+            "Sessions currently open.",
+            |m| m.sessions_active.load(Ordering::Relaxed).to_string(),
+        ),
+        (
+            "pfwd_connect_errors_total",
+            "counter",
+            "Failed connections to the target.",
+            |m| m.connect_errors.load(Ordering::Relaxed).to_string(),
+        ),
+        (
+            "pfwd_uds_retries_total",
+            "counter",
+            "Connection attempts to a UDS that did not exist yet.",
+            |m| m.uds_retries.load(Ordering::Relaxed).to_string(),
+        ),
+        (
+            "pfwd_udp_sessions",
+            "gauge",
+            "UDP client sessions currently open.",
+            |m| m.udp_sessions.load(Ordering::Relaxed).to_string(),
+        ),
+        (
+            "pfwd_udp_sessions_created_total",
+            "counter",
+            "UDP client sessions opened.",
+            |m| m.udp_sessions_created.load(Ordering::Relaxed).to_string(),
+        ),
+        (
+            "pfwd_udp_sessions_expired_total",
+            "counter",
+            "UDP client sessions closed for being idle.",
+            |m| m.udp_sessions_expired.load(Ordering::Relaxed).to_string(),
+        ),
+        (
+            "pfwd_udp_datagrams_dropped_total",
+            "counter",
+            "UDP datagrams that could not be relayed.",
+            |m| m.datagrams_dropped.load(Ordering::Relaxed).to_string(),
+        ),
+        (
+            "pfwd_client_tcp_retransmits_total",
+            "counter",
+            "Segments retransmitted to TCP clients, counted when their sessions close.",
+            |m| m.client_tcp_retransmits.load(Ordering::Relaxed).to_string(),
+        ),
+        (
+            "pfwd_target_tcp_retransmits_total",
+            "counter",
+            "Segments retransmitted to TCP targets, counted when their sessions close.",
+            |m| m.target_tcp_retransmits.load(Ordering::Relaxed).to_string(),
+        ),
+    ];
+    let registry = REGISTRY.lock().expect("metrics registry poisoned");
+    let mut out = String::new();
+    for (name, kind, help, read) in FAMILIES {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+        for ((label, namespace, leg), metrics) in registry.iter() {
+            let _ = writeln!(
+                out,
+                "{name}{{{}}} {}",
+                labels(label, namespace, leg, None),
+                read(metrics)
+            );
+        }
+    }
+    let name = "pfwd_bytes_total";
+    let _ = writeln!(
+        out,
+        "# HELP {name} Bytes relayed, by direction.\n# TYPE {name} counter"
+    );
+    for ((label, namespace, leg), metrics) in registry.iter() {
+        for (direction, counter) in [
+            ("client_to_target", &metrics.bytes_client_to_target),
+            ("target_to_client", &metrics.bytes_target_to_client),
+        ] {
+            let _ = writeln!(
+                out,
+                "{name}{{{}}} {}",
+                labels(label, namespace, leg, Some(direction)),
+                counter.load(Ordering::Relaxed)
+            );
+        }
+    }
+    let name = "pfwd_udp_datagrams_total";
+    let _ = writeln!(
+        out,
+        "# HELP {name} UDP datagrams relayed, by direction.\n# TYPE {name} counter"
+    );
+    for ((label, namespace, leg), metrics) in registry.iter() {
+        for (direction, counter) in [
+            ("client_to_target", &metrics.datagrams_client_to_target),
+            ("target_to_client", &metrics.datagrams_target_to_client),
+        ] {
+            let _ = writeln!(
+                out,
+                "{name}{{{}}} {}",
+                labels(label, namespace, leg, Some(direction)),
+                counter.load(Ordering::Relaxed)
+            );
+        }
+    }
+    let name = "pfwd_errors_total";
+    let _ = writeln!(out, "# HELP {name} Errors, by kind.\n# TYPE {name} counter");
+    for ((label, namespace, leg), metrics) in registry.iter() {
+        for (kind, counter) in ErrorKind::ALL.iter().zip(&metrics.errors) {
+            let _ = writeln!(
+                out,
+                "{name}{{{},kind=\"{}\"}} {}",
+                labels(label, namespace, leg, None),
+                kind.as_str(),
+                counter.load(Ordering::Relaxed)
+            );
+        }
+    }
+    type Pick = fn(&Metrics) -> &Histogram;
+    const HISTOGRAMS: &[(&str, &str, Pick)] = &[
+        (
+            "pfwd_target_connect_seconds",
+            "Time to connect to the target.",
+            |m| &m.target_connect,
+        ),
+        (
+            "pfwd_uds_connect_seconds",
+            "Time to connect to the other leg's UDS, including retries.",
+            |m| &m.uds_connect,
+        ),
+        (
+            "pfwd_client_tcp_rtt_seconds",
+            "Smoothed round-trip time to TCP clients when their sessions closed.",
+            |m| &m.client_tcp_rtt,
+        ),
+        (
+            "pfwd_target_tcp_rtt_seconds",
+            "Smoothed round-trip time to TCP targets when their sessions closed.",
+            |m| &m.target_tcp_rtt,
+        ),
+    ];
+    for (name, help, pick) in HISTOGRAMS {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} histogram");
+        for ((label, namespace, leg), metrics) in registry.iter() {
+            let histogram = pick(metrics);
+            let labels = labels(label, namespace, leg, None);
+            let mut cumulative = 0;
+            for (le, bucket) in BUCKETS.iter().zip(&histogram.buckets) {
+                cumulative += bucket.load(Ordering::Relaxed);
+                let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{le}\"}} {cumulative}");
+            }
+            let count = histogram.count.load(Ordering::Relaxed);
+            let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {count}");
+            let _ = writeln!(out, "{name}_sum{{{labels}}} {sum}");
+            let _ = writeln!(out, "{name}_count{{{labels}}} {count}");
+        }
+    }
+    out
+}
+
+fn labels(label: &str, namespace: &str, leg: &str, direction: Option<&str>) -> String {
+    let mut out = format!(
+        "label=\"{}\",namespace=\"{}\",leg=\"{leg}\"",
+        escape(label),
+        escape(namespace)
+    );
+    if let Some(direction) = direction {
+        let _ = write!(out, ",direction=\"{direction}\"");
+    }
+    out
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve `GET /metrics` on `listen` until shutdown.
+pub fn spawn(listen: String, shutdown: ShutdownRx) -> JoinHandle<Result<()>> {
+    http::spawn(
+        "metrics",
+        listen,
+        "text/plain; version=0.0.4",
+        |path| (path == b"/metrics").then(|| ("200 OK", render())),
+        shutdown,
+    )
+}
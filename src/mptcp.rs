@@ -0,0 +1,54 @@
+//! Multipath TCP sockets for forwards with `mptcp = true`.
+//!
+//! Listeners and outbound connections are created with `IPPROTO_MPTCP` so multipath-capable peers
+//! can add subflows; peers without MPTCP get plain TCP from the kernel automatically. When the
+//! kernel itself lacks MPTCP, sockets fall back to ordinary TCP and that is logged once.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpSocket, TcpStream};
+use tracing::warn;
+
+static FALLBACK_LOGGED: AtomicBool = AtomicBool::new(false);
+
+/// Stream socket using MPTCP when the kernel supports it, TCP otherwise.
+pub fn stream_socket(addr: SocketAddr) -> Result<Socket> {
+    let domain = Domain::for_address(addr);
+    #[cfg(target_os = "linux")]
+    match Socket::new(domain, Type::STREAM, Some(Protocol::MPTCP)) {
+        Ok(socket) => return Ok(socket),
+        Err(err) if mptcp_unsupported(&err) => {
+            if !FALLBACK_LOGGED.swap(true, Ordering::Relaxed) {
+                warn!(error = %err, "kernel does not support MPTCP; falling back to TCP");
+            }
+        }
+        Err(err) => return Err(err).context("failed to create mptcp socket"),
+    }
+    #[cfg(not(target_os = "linux"))]
+    if !FALLBACK_LOGGED.swap(true, Ordering::Relaxed) {
+        warn!("MPTCP is only supported on Linux; falling back to TCP");
+    }
+    Socket::new(domain, Type::STREAM, Some(Protocol::TCP)).context("failed to create tcp socket")
+}
+
+#[cfg(target_os = "linux")]
+fn mptcp_unsupported(err: &io::Error) -> bool {
+    use nix::libc::{EAFNOSUPPORT, EINVAL, ENOPROTOOPT, EPROTONOSUPPORT};
+    matches!(
+        err.raw_os_error(),
+        Some(EPROTONOSUPPORT | ENOPROTOOPT | EINVAL | EAFNOSUPPORT)
+    )
+}
+
+/// Connect to one resolved address; see [`crate::happy_eyeballs`] for picking among several.
+pub async fn connect(remote: SocketAddr) -> io::Result<TcpStream> {
+    let socket = stream_socket(remote).map_err(io::Error::other)?;
+    socket.set_nonblocking(true)?;
+    TcpSocket::from_std_stream(socket.into())
+        .connect(remote)
+        .await
+}
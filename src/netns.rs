@@ -1,42 +1,315 @@
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
 
-#[cfg(target_os = "linux")]
-use anyhow::{Context, Result};
-#[cfg(not(target_os = "linux"))]
-use anyhow::{Result, bail};
+use anyhow::{Context, Result, bail};
+use tracing::debug;
 
-use crate::config::ForwardSpec;
+use crate::config::{ForwardSpec, Owner};
+use crate::container::ContainerRef;
+use crate::userns;
 
-#[cfg(target_os = "linux")]
-pub fn maybe_enter(spec: &ForwardSpec) -> Result<()> {
-    use nix::sched::{CloneFlags, setns};
-    use std::fs::File;
+/// Per-namespace overrides for `/etc`, the same layout `ip netns exec` uses.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+const NETNS_ETC_DIR: &str = "/etc/netns";
 
-    let Some(path) = desired_netns_path(spec) else {
+pub fn maybe_enter(spec: &ForwardSpec) -> Result<()> {
+    let Some(path) = desired_netns_path(spec)? else {
         return Ok(());
     };
+    enter(&path, etc_overlay(spec))
+}
 
-    let file = File::open(&path)
-        .with_context(|| format!("failed to open namespace file {}", path.display()))?;
-    setns(&file, CloneFlags::CLONE_NEWNET)
+/// Join the namespace at `path` on the calling thread, then overlay `/etc/netns/<etc_overlay>`.
+/// The namespace file comes from [`open_cached`], and the thread's namespace is checked against
+/// it afterwards, so a file swapped between lookup and `setns()` is caught rather than joined.
+#[cfg(target_os = "linux")]
+pub fn enter(path: &Path, etc_overlay: Option<&str>) -> Result<()> {
+    use nix::sched::{CloneFlags, setns};
+
+    let (file, id) = open_cached(path)?;
+    setns(&*file, CloneFlags::CLONE_NEWNET)
         .with_context(|| format!("setns failed for {}", path.display()))?;
+    let joined = current_id()?;
+    if joined != id {
+        bail!(
+            "joined {joined} instead of {id} for {}; the namespace changed while entering it",
+            path.display()
+        );
+    }
+    debug!(path = %path.display(), %id, "entered network namespace");
+    if let Some(name) = etc_overlay {
+        overlay_netns_etc(name)?;
+    }
     Ok(())
 }
 
+/// Namespace files opened so far, by path. Entries are reused while the path still leads to the
+/// same namespace, and dropped once it does not, so a cached fd does not keep a deleted
+/// namespace alive for long.
+static OPEN_NAMESPACES: LazyLock<Mutex<OpenNamespaces>> = LazyLock::new(Default::default);
+
+type OpenNamespaces = HashMap<PathBuf, (NetnsId, Arc<File>)>;
+
+/// Open file for the namespace `path` leads to now, with its identity.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn open_cached(path: &Path) -> Result<(Arc<File>, NetnsId)> {
+    let current = NetnsId::of(path)
+        .with_context(|| format!("failed to open namespace file {}", path.display()))?;
+    let mut open = OPEN_NAMESPACES.lock().expect("namespace cache poisoned");
+    if let Some((id, file)) = open.get(path)
+        && *id == current
+    {
+        return Ok((file.clone(), *id));
+    }
+    let file = File::open(path)
+        .with_context(|| format!("failed to open namespace file {}", path.display()))?;
+    let id = NetnsId::of_file(&file)?;
+    // Opening a new namespace is rare; use it to drop entries whose namespace has gone.
+    open.retain(|path, (id, _)| NetnsId::of(path).is_ok_and(|now| now == *id));
+    let file = Arc::new(file);
+    open.insert(path.to_path_buf(), (id, file.clone()));
+    Ok((file, id))
+}
+
+/// Drop the cached file for `path`, e.g. once its namespace has been deleted.
+pub fn forget(path: &Path) {
+    OPEN_NAMESPACES
+        .lock()
+        .expect("namespace cache poisoned")
+        .remove(path);
+}
+
 #[cfg(not(target_os = "linux"))]
-pub fn maybe_enter(spec: &ForwardSpec) -> Result<()> {
-    if spec.namespace.is_some() || spec.setns_path.is_some() {
-        bail!("network namespaces are unsupported on this platform");
+pub fn enter(_path: &Path, _etc_overlay: Option<&str>) -> Result<()> {
+    bail!("network namespaces are unsupported on this platform");
+}
+
+/// Namespace name whose `/etc/netns` entry the spec resolves names with, if any.
+pub fn etc_overlay(spec: &ForwardSpec) -> Option<&str> {
+    spec.namespace
+        .as_deref()
+        .filter(|_| spec.resolve_in_namespace())
+}
+
+/// Bind-mount `/etc/netns/<name>/*` over `/etc` in a private mount namespace for this thread, as
+/// `ip netns exec` does, so name resolution uses the namespace's own `resolv.conf` and `hosts`.
+/// Threads the caller spawns afterwards (e.g. the runtime's resolver threads) inherit the view.
+#[cfg(target_os = "linux")]
+fn overlay_netns_etc(name: &str) -> Result<()> {
+    use nix::mount::{MsFlags, mount};
+    use nix::sched::{CloneFlags, unshare};
+
+    let dir = Path::new(NETNS_ETC_DIR).join(name);
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    // Threads share filesystem attributes, which a new mount namespace cannot.
+    unshare(CloneFlags::CLONE_FS | CloneFlags::CLONE_NEWNS)
+        .context("failed to unshare mount namespace")?;
+    // Keep the bind mounts below from propagating back to the host.
+    mount(
+        None::<&str>,
+        "/",
+        None::<&str>,
+        MsFlags::MS_SLAVE | MsFlags::MS_REC,
+        None::<&str>,
+    )
+    .context("failed to make / a slave mount")?;
+    for entry in
+        std::fs::read_dir(&dir).with_context(|| format!("failed to read {}", dir.display()))?
+    {
+        let entry = entry?;
+        let target = Path::new("/etc").join(entry.file_name());
+        mount(
+            Some(&entry.path()),
+            &target,
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        )
+        .with_context(|| {
+            format!(
+                "failed to bind {} over {}",
+                entry.path().display(),
+                target.display()
+            )
+        })?;
     }
     Ok(())
 }
 
-#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
-fn desired_netns_path(spec: &ForwardSpec) -> Option<PathBuf> {
+/// Namespace file to join: `setns_path`, the namespace of `setns_pid`, the container's namespace
+/// for `<runtime>:<name>`, or the `ip netns` entry for a plain name.
+pub fn desired_netns_path(spec: &ForwardSpec) -> Result<Option<PathBuf>> {
     if let Some(path) = spec.setns_path.as_ref() {
-        return Some(path.clone());
+        return Ok(Some(path.clone()));
     }
-    spec.namespace
-        .as_ref()
-        .map(|ns| PathBuf::from("/var/run/netns").join(ns))
+    if let Some(pid) = spec.setns_pid {
+        let proc = PathBuf::from(format!("/proc/{pid}"));
+        if !proc.exists() {
+            bail!("`setns_pid` process {pid} has exited");
+        }
+        return Ok(Some(proc.join("ns/net")));
+    }
+    let Some(ns) = spec.namespace.as_deref() else {
+        return Ok(None);
+    };
+    if let Some(container) = ContainerRef::parse(ns)? {
+        return container.netns_path().map(Some);
+    }
+    Ok(Some(spec.netns_dir().join(ns)))
+}
+
+/// Mount namespace a namespace endpoint binds its `uds` in: `uds_mount_ns` as configured, or
+/// with `setns_mount` the one of the process whose network namespace the spec joins. May block on
+/// a container runtime's API.
+pub fn desired_mount_ns(spec: &ForwardSpec) -> Result<Option<PathBuf>> {
+    if let Some(path) = spec.uds_mount_ns.as_ref() {
+        return Ok(Some(path.clone()));
+    }
+    if !spec.setns_mount() {
+        return Ok(None);
+    }
+    Ok(namespace_pid(spec)?.map(|pid| PathBuf::from(format!("/proc/{pid}/ns/mnt"))))
+}
+
+/// Owner a namespace endpoint's `uds` is given: `owner` as configured, or with `setns_user` the
+/// host ids the namespaced process's user namespace sees as `owner`. May block like
+/// [`desired_mount_ns`].
+pub fn desired_owner(spec: &ForwardSpec) -> Result<Option<Owner>> {
+    if !spec.setns_user() {
+        return Ok(spec.owner.clone());
+    }
+    let pid =
+        namespace_pid(spec)?.context("`setns_user` needs a namespace reached through a process")?;
+    userns::host_owner(pid, spec.owner.as_ref()).map(Some)
+}
+
+/// Process whose network namespace the spec joins, when it is reached through one: `setns_pid`, a
+/// `/proc/<pid>/ns/net` `setns_path`, or a container's init process.
+pub fn namespace_pid(spec: &ForwardSpec) -> Result<Option<u32>> {
+    if let Some(pid) = spec.setns_pid {
+        return Ok(Some(pid));
+    }
+    if let Some(path) = spec.setns_path.as_deref() {
+        let pid = path
+            .strip_prefix("/proc")
+            .ok()
+            .and_then(|rest| rest.iter().next())
+            .and_then(|pid| pid.to_str()?.parse().ok());
+        return Ok(pid);
+    }
+    match spec
+        .namespace
+        .as_deref()
+        .map(ContainerRef::parse)
+        .transpose()?
+    {
+        Some(Some(container)) => container.init_pid().map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Identity of a network namespace: the device and inode `stat` reports for its nsfs file. A
+/// namespace deleted and recreated under the same name gets a new inode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NetnsId {
+    dev: u64,
+    ino: u64,
+}
+
+impl NetnsId {
+    pub fn of(path: &Path) -> std::io::Result<Self> {
+        Ok(Self::from_metadata(&std::fs::metadata(path)?))
+    }
+
+    fn of_file(file: &File) -> std::io::Result<Self> {
+        Ok(Self::from_metadata(&file.metadata()?))
+    }
+
+    fn from_metadata(meta: &std::fs::Metadata) -> Self {
+        Self {
+            dev: meta.dev(),
+            ino: meta.ino(),
+        }
+    }
+}
+
+impl fmt::Display for NetnsId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "net:[{}]", self.ino)
+    }
+}
+
+/// Namespace the calling thread is in.
+pub fn current_id() -> Result<NetnsId> {
+    NetnsId::of(Path::new("/proc/thread-self/ns/net"))
+        .context("failed to stat /proc/thread-self/ns/net")
+}
+
+/// Namespace the spec resolves to right now, or `None` if it cannot be resolved (e.g. the
+/// namespace is gone or its container is stopped). May block on a container runtime's API.
+pub fn resolve_id(spec: &ForwardSpec) -> Option<NetnsId> {
+    let path = desired_netns_path(spec).ok().flatten()?;
+    NetnsId::of(&path).ok()
+}
+
+/// Run `f` on a short-lived thread that has joined the spec's namespace, leaving the caller's
+/// thread untouched. Sockets created by `f` stay attached to that namespace after it returns.
+pub fn within<T, F>(spec: &ForwardSpec, f: F) -> Result<T>
+where
+    T: Send,
+    F: FnOnce() -> Result<T> + Send,
+{
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                maybe_enter(spec)?;
+                f()
+            })
+            .join()
+            .expect("namespace worker thread panicked")
+    })
+}
+
+/// Run `f` on a short-lived thread that has joined the mount namespace at `mount_ns` (e.g.
+/// `/proc/<pid>/ns/mnt`), so paths resolve the way processes in that namespace see them. The
+/// thread keeps the caller's network namespace.
+#[cfg(target_os = "linux")]
+pub fn within_mount_ns<T, F>(mount_ns: &Path, f: F) -> Result<T>
+where
+    T: Send,
+    F: FnOnce() -> Result<T> + Send,
+{
+    use nix::sched::{CloneFlags, setns, unshare};
+    use std::fs::File;
+
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                let file = File::open(mount_ns).with_context(|| {
+                    format!("failed to open mount namespace {}", mount_ns.display())
+                })?;
+                // Threads share filesystem attributes, which setns(CLONE_NEWNS) refuses.
+                unshare(CloneFlags::CLONE_FS).context("failed to unshare filesystem attributes")?;
+                setns(&file, CloneFlags::CLONE_NEWNS)
+                    .with_context(|| format!("setns failed for {}", mount_ns.display()))?;
+                f()
+            })
+            .join()
+            .expect("mount namespace worker thread panicked")
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn within_mount_ns<T, F>(_mount_ns: &Path, _f: F) -> Result<T>
+where
+    T: Send,
+    F: FnOnce() -> Result<T> + Send,
+{
+    bail!("mount namespaces are unsupported on this platform");
 }
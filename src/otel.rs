@@ -0,0 +1,313 @@
+//! OTLP export (`otlp_endpoint`), enabled with the `otel` cargo feature.
+//!
+//! Traces: only spans under the [`session::TARGET`](crate::pipeline::session::TARGET) target are
+//! exported, one `session` span per proxied connection with `uds_connect`, `target_connect`, and
+//! `relay` children. Listener and namespace spans stay in the logs.
+//!
+//! Metrics: the counters behind `/metrics` are read every `otlp_metrics_interval_secs` and pushed
+//! as `pfwd.*` instruments with the same `label`, `namespace`, and `leg` attributes, for
+//! deployments that have a collector but nothing scraping.
+//!
+//! Both go out over OTLP/HTTP or gRPC (`otlp_protocol`) in batches, from the exporters' own threads.
+
+use anyhow::{Result, bail};
+use tracing_subscriber::Layer;
+use tracing_subscriber::registry::Registry;
+
+use crate::config::Defaults;
+
+pub type BoxLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Reject `otlp_endpoint` at config load when the feature is compiled out or the URL is not one.
+pub fn validate(endpoint: &str) -> Result<()> {
+    if !cfg!(feature = "otel") {
+        bail!("`otlp_endpoint` needs rebuilding pfwd with `--features otel`");
+    }
+    if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+        bail!("`otlp_endpoint` {endpoint} must be an http:// or https:// URL");
+    }
+    Ok(())
+}
+
+/// Batches spans and metrics to the collector until shut down.
+#[cfg(feature = "otel")]
+pub struct Exporter {
+    tracer_provider: opentelemetry_sdk::trace::SdkTracerProvider,
+    meter_provider: opentelemetry_sdk::metrics::SdkMeterProvider,
+}
+
+#[cfg(feature = "otel")]
+impl Exporter {
+    /// Seconds between metric pushes unless `otlp_metrics_interval_secs` says otherwise.
+    const DEFAULT_METRICS_INTERVAL_SECS: u64 = 60;
+
+    /// Exporters sending to the collector at `otlp_endpoint`, and the tracing layer that feeds
+    /// spans to it. For HTTP the endpoint is the base URL; the signal paths are appended.
+    pub fn start(defaults: &Defaults) -> Result<(Self, BoxLayer)> {
+        use std::time::Duration;
+
+        use anyhow::Context;
+        use opentelemetry::KeyValue;
+        use opentelemetry::trace::TracerProvider as _;
+        use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig};
+        use opentelemetry_sdk::Resource;
+        use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+        use opentelemetry_sdk::trace::SdkTracerProvider;
+        use tracing::Level;
+        use tracing_subscriber::filter::Targets;
+
+        use crate::config::OtlpProtocol;
+        use crate::pipeline::session;
+
+        let endpoint = defaults
+            .otlp_endpoint
+            .as_deref()
+            .context("otlp_endpoint missing")?
+            .trim_end_matches('/');
+        let resource = Resource::builder()
+            .with_service_name("pfwd")
+            .with_attributes(
+                defaults
+                    .otlp_resource
+                    .iter()
+                    .map(|(key, value)| KeyValue::new(key.clone(), value.clone())),
+            )
+            .build();
+
+        let protocol = defaults.otlp_protocol.unwrap_or_default();
+        let (spans, metrics) = match protocol {
+            OtlpProtocol::Http => (
+                SpanExporter::builder()
+                    .with_http()
+                    .with_endpoint(format!("{endpoint}/v1/traces"))
+                    .build(),
+                MetricExporter::builder()
+                    .with_http()
+                    .with_endpoint(format!("{endpoint}/v1/metrics"))
+                    .build(),
+            ),
+            OtlpProtocol::Grpc => (
+                SpanExporter::builder()
+                    .with_tonic()
+                    .with_endpoint(endpoint)
+                    .build(),
+                MetricExporter::builder()
+                    .with_tonic()
+                    .with_endpoint(endpoint)
+                    .build(),
+            ),
+        };
+        let tracer_provider = SdkTracerProvider::builder()
+            .with_batch_exporter(spans.context("failed to build OTLP span exporter")?)
+            .with_resource(resource.clone())
+            .build();
+        let interval = defaults
+            .otlp_metrics_interval_secs
+            .unwrap_or(Self::DEFAULT_METRICS_INTERVAL_SECS);
+        let reader =
+            PeriodicReader::builder(metrics.context("failed to build OTLP metric exporter")?)
+                .with_interval(Duration::from_secs(interval))
+                .build();
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(reader)
+            .with_resource(resource)
+            .build();
+        register_instruments(&meter_provider);
+
+        let layer = tracing_opentelemetry::layer()
+            .with_tracer(tracer_provider.tracer("pfwd"))
+            .with_filter(Targets::new().with_target(session::TARGET, Level::INFO));
+        Ok((
+            Self {
+                tracer_provider,
+                meter_provider,
+            },
+            Box::new(layer),
+        ))
+    }
+
+    /// Push the last metrics and flush the spans still buffered.
+    pub fn shutdown(self) {
+        if let Err(err) = self.meter_provider.shutdown() {
+            tracing::warn!(error = %err, "failed to flush OTLP metrics");
+        }
+        if let Err(err) = self.tracer_provider.shutdown() {
+            tracing::warn!(error = %err, "failed to flush OTLP spans");
+        }
+    }
+}
+
+/// Observable instruments reading [`metrics::samples`](crate::metrics::samples) at every push.
+#[cfg(feature = "otel")]
+fn register_instruments(provider: &opentelemetry_sdk::metrics::SdkMeterProvider) {
+    use opentelemetry::KeyValue;
+    use opentelemetry::metrics::MeterProvider as _;
+
+    use crate::metrics::{self, ErrorKind, Sample};
+
+    fn attributes(sample: &Sample) -> Vec<KeyValue> {
+        vec![
+            KeyValue::new("label", sample.label.clone()),
+            KeyValue::new("namespace", sample.namespace.clone()),
+            KeyValue::new("leg", sample.leg),
+        ]
+    }
+
+    /// Name, unit, description, and the field read.
+    type Instrument<T> = (&'static str, &'static str, &'static str, fn(&Sample) -> T);
+
+    let meter = provider.meter("pfwd");
+    let counters: [Instrument<u64>; 8] = [
+        (
+            "pfwd.sessions.accepted",
+            "{session}",
+            "Sessions accepted.",
+            |s| s.sessions_accepted,
+        ),
+        (
+            "pfwd.connect_errors",
+            "{error}",
+            "Failed connections to the target.",
+            |s| s.connect_errors,
+        ),
+        (
+            "pfwd.uds_retries",
+            "{attempt}",
+            "Connection attempts to a UDS that did not exist yet.",
+            |s| s.uds_retries,
+        ),
+        (
+            "pfwd.udp.sessions.created",
+            "{session}",
+            "UDP client sessions opened.",
+            |s| s.udp_sessions_created,
+        ),
+        (
+            "pfwd.udp.sessions.expired",
+            "{session}",
+            "UDP client sessions closed for being idle.",
+            |s| s.udp_sessions_expired,
+        ),
+        (
+            "pfwd.udp.datagrams.dropped",
+            "{datagram}",
+            "UDP datagrams that could not be relayed.",
+            |s| s.datagrams_dropped,
+        ),
+        (
+            "pfwd.client.tcp.retransmits",
+            "{segment}",
+            "Segments retransmitted to TCP clients, counted when their sessions close.",
+            |s| s.client_tcp_retransmits,
+        ),
+        (
+            "pfwd.target.tcp.retransmits",
+            "{segment}",
+            "Segments retransmitted to TCP targets, counted when their sessions close.",
+            |s| s.target_tcp_retransmits,
+        ),
+    ];
+    for (name, unit, description, read) in counters {
+        meter
+            .u64_observable_counter(name)
+            .with_unit(unit)
+            .with_description(description)
+            .with_callback(move |observer| {
+                for sample in metrics::samples() {
+                    observer.observe(read(&sample), &attributes(&sample));
+                }
+            })
+            .build();
+    }
+    let gauges: [Instrument<i64>; 2] = [
+        (
+            "pfwd.sessions.active",
+            "{session}",
+            "Sessions currently open.",
+            |s| s.sessions_active,
+        ),
+        (
+            "pfwd.udp.sessions",
+            "{session}",
+            "UDP client sessions currently open.",
+            |s| s.udp_sessions,
+        ),
+    ];
+    for (name, unit, description, read) in gauges {
+        meter
+            .i64_observable_gauge(name)
+            .with_unit(unit)
+            .with_description(description)
+            .with_callback(move |observer| {
+                for sample in metrics::samples() {
+                    observer.observe(read(&sample), &attributes(&sample));
+                }
+            })
+            .build();
+    }
+    meter
+        .u64_observable_counter("pfwd.bytes")
+        .with_unit("By")
+        .with_description("Bytes relayed, by direction.")
+        .with_callback(|observer| {
+            for sample in metrics::samples() {
+                for (direction, bytes) in [
+                    ("client_to_target", sample.bytes_client_to_target),
+                    ("target_to_client", sample.bytes_target_to_client),
+                ] {
+                    let mut attributes = attributes(&sample);
+                    attributes.push(KeyValue::new("direction", direction));
+                    observer.observe(bytes, &attributes);
+                }
+            }
+        })
+        .build();
+    meter
+        .u64_observable_counter("pfwd.udp.datagrams")
+        .with_unit("{datagram}")
+        .with_description("UDP datagrams relayed, by direction.")
+        .with_callback(|observer| {
+            for sample in metrics::samples() {
+                for (direction, datagrams) in [
+                    ("client_to_target", sample.datagrams_client_to_target),
+                    ("target_to_client", sample.datagrams_target_to_client),
+                ] {
+                    let mut attributes = attributes(&sample);
+                    attributes.push(KeyValue::new("direction", direction));
+                    observer.observe(datagrams, &attributes);
+                }
+            }
+        })
+        .build();
+    meter
+        .u64_observable_counter("pfwd.errors")
+        .with_unit("{error}")
+        .with_description("Errors, by kind.")
+        .with_callback(|observer| {
+            for sample in metrics::samples() {
+                for (kind, errors) in ErrorKind::ALL.iter().zip(sample.errors) {
+                    let mut attributes = attributes(&sample);
+                    attributes.push(KeyValue::new("kind", kind.as_str()));
+                    observer.observe(errors, &attributes);
+                }
+            }
+        })
+        .build();
+}
+
+/// Stand-in when the feature is compiled out; config load rejects `otlp_endpoint` before any is
+/// started.
+#[cfg(not(feature = "otel"))]
+pub enum Exporter {}
+
+#[cfg(not(feature = "otel"))]
+impl Exporter {
+    pub fn start(defaults: &Defaults) -> Result<(Self, BoxLayer)> {
+        validate(defaults.otlp_endpoint.as_deref().unwrap_or_default())?;
+        bail!("OpenTelemetry support is not compiled in")
+    }
+
+    pub fn shutdown(self) {
+        match self {}
+    }
+}
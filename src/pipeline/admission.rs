@@ -0,0 +1,142 @@
+//! Per-connection admission, decided right after a listener accepts a client and before anything
+//! is dialed. Hooks run in the session's task, with `on_accept_lua` on tokio's blocking threads, so
+//! a slow one delays only its own client.
+//!
+//! `on_accept_lua` runs first and may re-target the session; `on_connect_exec` then runs a command
+//! whose exit status gates it. A hook that fails rejects the session: policy that cannot be
+//! evaluated must not let clients through.
+
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use tokio::process::Command;
+use tokio::task::spawn_blocking;
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+use crate::config::ForwardSpec;
+use crate::lua::{self, LuaHook};
+use crate::pipeline::listener::PeerAddr;
+
+/// Outcome of the admission hooks for one connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    Reject,
+    /// Accept, but dial this target instead of the spec's.
+    #[cfg_attr(not(feature = "lua"), allow(dead_code))]
+    Target(String),
+}
+
+/// How long an `on_connect_exec` command may take before the connection is rejected.
+const EXEC_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Admission hooks configured on one forward.
+#[derive(Clone)]
+pub struct Admission {
+    label: Option<Arc<str>>,
+    namespace: Option<Arc<str>>,
+    lua: Option<Arc<LuaHook>>,
+    exec: Option<Arc<str>>,
+}
+
+impl Admission {
+    pub fn for_spec(spec: &ForwardSpec) -> Result<Self> {
+        let lua = spec
+            .on_accept_lua
+            .as_deref()
+            .map(LuaHook::load)
+            .transpose()?
+            .map(Arc::new);
+        let namespace = spec
+            .namespace
+            .as_deref()
+            .map(Arc::from)
+            .or_else(|| {
+                spec.setns_path
+                    .as_deref()
+                    .map(Path::to_string_lossy)
+                    .map(Arc::from)
+            })
+            .or_else(|| {
+                spec.setns_pid
+                    .map(|pid| Arc::from(format!("/proc/{pid}/ns/net")))
+            });
+        Ok(Self {
+            label: spec.label.as_deref().map(Arc::from),
+            namespace,
+            lua,
+            exec: spec.on_connect_exec.as_deref().map(Arc::from),
+        })
+    }
+
+    /// Run the hooks for a client connecting from `peer`.
+    pub async fn check(&self, peer: &PeerAddr) -> Verdict {
+        let mut verdict = Verdict::Accept;
+        if let Some(hook) = self.lua.as_ref() {
+            let conn = lua::Connection {
+                peer: peer.to_string(),
+                label: self.label.clone(),
+                namespace: self.namespace.clone(),
+            };
+            let hook = hook.clone();
+            verdict = spawn_blocking(move || hook.on_accept(&conn))
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|res| res)
+                .unwrap_or_else(|err| {
+                    warn!(%peer, error = %err, "on_accept_lua failed; rejecting");
+                    Verdict::Reject
+                });
+        }
+        if verdict == Verdict::Reject {
+            return verdict;
+        }
+        if let Some(command) = self.exec.as_deref() {
+            match self.run_exec(command, peer).await {
+                Ok(true) => {}
+                Ok(false) => return Verdict::Reject,
+                Err(err) => {
+                    warn!(%peer, error = %err, "on_connect_exec failed; rejecting");
+                    return Verdict::Reject;
+                }
+            }
+        }
+        verdict
+    }
+
+    /// Run `on_connect_exec` for one connection; a zero exit status admits it. The command line is
+    /// split on whitespace without shell quoting, like `target_exec`.
+    async fn run_exec(&self, command: &str, peer: &PeerAddr) -> Result<bool> {
+        let mut argv = command.split_whitespace();
+        let program = argv.next().context("on_connect_exec command is empty")?;
+        let mut cmd = Command::new(program);
+        cmd.args(argv)
+            .env("PFWD_PEER", peer.to_string())
+            .env("PFWD_LABEL", self.label.as_deref().unwrap_or_default())
+            .env(
+                "PFWD_NAMESPACE",
+                self.namespace.as_deref().unwrap_or_default(),
+            )
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true);
+        if let PeerAddr::Inet(addr) = peer {
+            cmd.env("PFWD_PEER_IP", addr.ip().to_string())
+                .env("PFWD_PEER_PORT", addr.port().to_string());
+        }
+        let mut child = cmd
+            .spawn()
+            .with_context(|| format!("failed to spawn on_connect_exec command {program}"))?;
+        let Ok(status) = timeout(EXEC_TIMEOUT, child.wait()).await else {
+            bail!("on_connect_exec command {program} did not finish within {EXEC_TIMEOUT:?}");
+        };
+        let status = status?;
+        debug!(%peer, %status, "on_connect_exec finished");
+        Ok(status.success())
+    }
+}
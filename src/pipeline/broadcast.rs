@@ -0,0 +1,163 @@
+//! Broadcast relay: datagrams heard on one interface are re-broadcast on another, optionally inside
+//! a network namespace, for discovery protocols (DHCP, game lobbies) that only speak to
+//! `255.255.255.255`.
+//!
+//! Every re-broadcast is remembered for a short window by sending port and payload digest. A relay
+//! that hears one of those datagrams again (its own echo, or a mirror relay sending it back) drops
+//! it instead of bouncing it between interfaces forever.
+
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket as StdUdpSocket};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, bail};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, instrument, warn};
+
+use crate::config::ForwardSpec;
+use crate::pipeline::ShutdownRx;
+use crate::pipeline::lifecycle;
+use crate::pipeline::udp::MAX_DATAGRAM;
+use crate::{log_limit, netns};
+
+/// How long a re-broadcast datagram is remembered for loop detection.
+const LOOP_WINDOW: Duration = Duration::from_secs(2);
+
+static LOOP_GUARD: LazyLock<LoopGuard> = LazyLock::new(LoopGuard::default);
+
+/// IPv4 address and port, optionally pinned to an interface: `255.255.255.255:67@br0`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BroadcastEndpoint {
+    pub addr: SocketAddrV4,
+    pub interface: Option<String>,
+}
+
+impl BroadcastEndpoint {
+    pub fn parse(value: &str) -> Result<Self> {
+        let (addr, interface) = match value.split_once('@') {
+            Some((addr, interface)) if !interface.is_empty() => (addr, Some(interface)),
+            Some(_) => bail!("missing interface name after `@` in {value}"),
+            None => (value, None),
+        };
+        let addr = addr
+            .parse::<SocketAddrV4>()
+            .with_context(|| format!("broadcast endpoints must be ipv4 addr:port, got {addr}"))?;
+        Ok(Self {
+            addr,
+            interface: interface.map(str::to_string),
+        })
+    }
+
+    /// Broadcast-capable socket bound to `local` and, when configured, to the interface.
+    fn bind(&self, local: SocketAddrV4) -> Result<StdUdpSocket> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))
+            .context("failed to create broadcast socket")?;
+        socket.set_broadcast(true)?;
+        socket.set_reuse_address(true)?;
+        if let Some(name) = self.interface.as_deref() {
+            socket
+                .bind_device(Some(name.as_bytes()))
+                .with_context(|| format!("failed to bind broadcast socket to {name}"))?;
+        }
+        socket
+            .bind(&SocketAddr::V4(local).into())
+            .with_context(|| format!("failed to bind broadcast socket {local}"))?;
+        socket.set_nonblocking(true)?;
+        Ok(socket.into())
+    }
+}
+
+pub fn spawn(spec: ForwardSpec, shutdown: ShutdownRx) -> JoinHandle<Result<()>> {
+    tokio::spawn(async move { broadcast_loop(spec, shutdown).await })
+}
+
+#[instrument(skip_all, fields(broadcast_listen = spec.broadcast_listen.as_deref().unwrap_or_default()))]
+async fn broadcast_loop(spec: ForwardSpec, mut shutdown: ShutdownRx) -> Result<()> {
+    let listen = BroadcastEndpoint::parse(
+        spec.broadcast_listen
+            .as_deref()
+            .context("broadcast relay requires broadcast_listen")?,
+    )?;
+    let target = BroadcastEndpoint::parse(
+        spec.broadcast_target
+            .as_deref()
+            .context("broadcast relay requires broadcast_target")?,
+    )?;
+    let listener = UdpSocket::from_std(listen.bind(listen.addr)?)?;
+    // The sending socket belongs to wherever the target interface lives, which may be the
+    // spec's namespace.
+    let sender = netns::within(&spec, || {
+        target.bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+    })?;
+    let sender = UdpSocket::from_std(sender)?;
+    let sender_port = sender.local_addr()?.port();
+    info!(
+        listen = %listen.addr,
+        listen_interface = listen.interface.as_deref().unwrap_or("any"),
+        target = %target.addr,
+        target_interface = target.interface.as_deref().unwrap_or("any"),
+        "broadcast relay listening"
+    );
+    let _lifecycle = lifecycle::up(&spec, listen.addr).await;
+
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        tokio::select! {
+            biased;
+            res = shutdown.changed() => {
+                if res.is_err() || *shutdown.borrow() {
+                    info!(listen = %listen.addr, "shutdown received; stopping broadcast relay");
+                    break;
+                }
+            }
+            recv = listener.recv_from(&mut buf) => {
+                let (len, source) = recv?;
+                let payload = &buf[..len];
+                let digest = digest(payload);
+                if LOOP_GUARD.is_echo(source, digest) {
+                    debug!(%source, "dropping datagram this process already re-broadcast");
+                    continue;
+                }
+                LOOP_GUARD.record(sender_port, digest);
+                if let Err(err) = sender.send_to(payload, target.addr).await
+                    && let Some(suppressed) = log_limit::allow("failed to re-broadcast datagram", target.addr)
+                {
+                    warn!(%source, target = %target.addr, error = %err, suppressed, "failed to re-broadcast datagram");
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn digest(payload: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    payload.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Re-broadcasts sent by every relay in this process, keyed by sending port and payload digest.
+#[derive(Default)]
+struct LoopGuard {
+    sent: Mutex<HashMap<(u16, u64), Instant>>,
+}
+
+impl LoopGuard {
+    fn record(&self, port: u16, digest: u64) {
+        let now = Instant::now();
+        let mut sent = self.sent.lock().expect("loop guard poisoned");
+        sent.retain(|_, at| now.duration_since(*at) < LOOP_WINDOW);
+        sent.insert((port, digest), now);
+    }
+
+    fn is_echo(&self, source: SocketAddr, digest: u64) -> bool {
+        let sent = self.sent.lock().expect("loop guard poisoned");
+        sent.get(&(source.port(), digest))
+            .is_some_and(|at| at.elapsed() < LOOP_WINDOW)
+    }
+}
@@ -0,0 +1,453 @@
+//! Packet capture per forward (`capture_dir`): the stream sessions of a forward written to a pcap
+//! file as synthesized TCP, for debugging protocol issues without tcpdump inside a namespace.
+//!
+//! pfwd only sees the bytes a session relays, not the packets that carried them, so each session
+//! becomes a made-up TCP connection between its client and target. A handshake opens it, every
+//! chunk read from the client or written to it becomes a segment with sequence numbers that add
+//! up, and FINs close it. Wireshark's "Follow TCP Stream" then shows the session's bytes. Ends that
+//! are not IP addresses (a UDS, a target given by name) are given addresses from `192.0.2.0/24`.
+//!
+//! A capture covers the sessions that start while it runs, across every leg of the forward, and
+//! stops after `capture_max_secs` or before the file would grow past `capture_max_bytes`. One is
+//! started by `SIGUSR2`, for every forward, or by the admin `capture` command, for the forwards it
+//! picks.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, bail};
+use serde::Serialize;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, sleep_until};
+use tracing::{info, warn};
+
+use crate::config::Defaults;
+use crate::pipeline::ShutdownRx;
+use crate::selector::Selector;
+use crate::status;
+
+/// File size limit unless `capture_max_bytes` says otherwise.
+pub const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+/// Duration unless `capture_max_secs` says otherwise.
+pub const DEFAULT_MAX_DURATION: Duration = Duration::from_secs(60);
+
+/// pcap link type for packets that start with their IP header.
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65_535;
+/// Largest payload put in one synthesized segment, keeping packets under [`SNAPLEN`].
+const MAX_SEGMENT: usize = 65_000;
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+/// Packets queued for a capture file before the sessions adding to it stop being captured.
+const CAPTURE_QUEUE: usize = 1024;
+/// Addresses for session ends that are not IP.
+const UNKNOWN_CLIENT: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+const UNKNOWN_TARGET: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 2);
+
+type Captures = HashMap<(String, Option<String>), mpsc::Sender<Packet>>;
+
+/// Running captures by forward label and namespace.
+static CAPTURES: LazyLock<Mutex<Captures>> = LazyLock::new(Default::default);
+
+/// Where captures go and when they stop.
+#[derive(Clone)]
+pub struct Capture {
+    pub dir: PathBuf,
+    pub max_bytes: u64,
+    pub max_duration: Duration,
+}
+
+/// What a `capture` command started, by forward.
+#[derive(Default, Serialize)]
+pub struct Captured {
+    pub captures: Vec<Started>,
+    pub failed: Vec<Failed>,
+}
+
+#[derive(Serialize)]
+pub struct Started {
+    pub label: String,
+    pub namespace: Option<String>,
+    pub path: PathBuf,
+}
+
+/// A forward whose capture did not start, e.g. because one was already running.
+#[derive(Serialize)]
+pub struct Failed {
+    pub label: String,
+    pub namespace: Option<String>,
+    pub error: String,
+}
+
+impl Capture {
+    /// Captures as `defaults` configure them, if they give a `capture_dir`.
+    pub fn from_defaults(defaults: &Defaults) -> Option<Self> {
+        Some(Self {
+            dir: defaults.capture_dir.clone()?,
+            max_bytes: defaults.capture_max_bytes.unwrap_or(DEFAULT_MAX_BYTES),
+            max_duration: defaults
+                .capture_max_secs
+                .map_or(DEFAULT_MAX_DURATION, Duration::from_secs),
+        })
+    }
+
+    /// Start capturing the forward `label` in `namespace`, returning the file it is written to.
+    /// A forward has one capture at a time.
+    pub fn start(&self, label: &str, namespace: Option<&str>) -> Result<PathBuf> {
+        let mut captures = CAPTURES.lock().expect("capture registry poisoned");
+        let key = (label.to_string(), namespace.map(str::to_string));
+        if captures.get(&key).is_some_and(|tx| !tx.is_closed()) {
+            bail!("forward {label} is already being captured");
+        }
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let name = match namespace {
+            Some(namespace) => format!("{label}-{namespace}-{started}.pcap"),
+            None => format!("{label}-{started}.pcap"),
+        };
+        let path = self.dir.join(name.replace('/', "_"));
+        let (tx, rx) = mpsc::channel(CAPTURE_QUEUE);
+        tokio::spawn(write(
+            path.clone(),
+            rx,
+            self.max_bytes,
+            Instant::now() + self.max_duration,
+        ));
+        captures.insert(key, tx);
+        Ok(path)
+    }
+
+    /// Start capturing the forwards `selector` picks, or every forward without one.
+    pub fn start_selected(&self, selector: Option<&Selector>) -> Result<Captured> {
+        let forwards = status::forwards();
+        let mut picked = forwards
+            .iter()
+            .filter(|forward| selector.is_none_or(|selector| selector.matches(&forward.label)))
+            .peekable();
+        if let Some(selector) = selector
+            && picked.peek().is_none()
+        {
+            bail!("no forward matches {selector}");
+        }
+        let mut captured = Captured::default();
+        for forward in picked {
+            let label = forward.label.clone();
+            let namespace = forward.namespace.clone();
+            match self.start(&label, namespace.as_deref()) {
+                Ok(path) => {
+                    info!(label, namespace, path = %path.display(), "capture started");
+                    captured.captures.push(Started {
+                        label,
+                        namespace,
+                        path,
+                    });
+                }
+                Err(err) => {
+                    warn!(label, namespace, error = %err, "failed to start capture");
+                    captured.failed.push(Failed {
+                        label,
+                        namespace,
+                        error: format!("{err:#}"),
+                    });
+                }
+            }
+        }
+        Ok(captured)
+    }
+
+    /// Capture every forward on each `SIGUSR2` until shutdown.
+    pub fn spawn_on_signal(self, mut shutdown: ShutdownRx) -> JoinHandle<Result<()>> {
+        tokio::spawn(async move {
+            let mut usr2 =
+                signal(SignalKind::user_defined2()).context("failed to listen for SIGUSR2")?;
+            loop {
+                tokio::select! {
+                    res = shutdown.changed() => {
+                        if res.is_err() || *shutdown.borrow() {
+                            return Ok(());
+                        }
+                    }
+                    _ = usr2.recv() => {
+                        self.start_selected(None)?;
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// One synthesized packet and when its bytes were relayed.
+struct Packet {
+    time: SystemTime,
+    data: Vec<u8>,
+}
+
+/// Write packets to `path` until the deadline, or until the next one would take the file past
+/// `max_bytes`.
+async fn write(path: PathBuf, mut rx: mpsc::Receiver<Packet>, max_bytes: u64, deadline: Instant) {
+    let (mut bytes, mut packets) = (0u64, 0u64);
+    let res: Result<()> = async {
+        // Captures hold the sessions' plaintext, so only pfwd's user may read them.
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .await
+            .context("failed to create capture file")?;
+        // Unbuffered, so the file holds every packet written even if pfwd exits mid-capture.
+        let mut out = file;
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        out.write_all(&header).await?;
+        bytes += header.len() as u64;
+        loop {
+            let packet = tokio::select! {
+                packet = rx.recv() => packet,
+                _ = sleep_until(deadline) => None,
+            };
+            let Some(packet) = packet else {
+                break;
+            };
+            let record = 16 + packet.data.len() as u64;
+            if bytes + record > max_bytes {
+                break;
+            }
+            let time = packet.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let len = packet.data.len() as u32;
+            let mut buf = Vec::with_capacity(record as usize);
+            buf.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+            buf.extend_from_slice(&time.subsec_micros().to_le_bytes());
+            buf.extend_from_slice(&len.to_le_bytes());
+            buf.extend_from_slice(&len.to_le_bytes());
+            buf.extend_from_slice(&packet.data);
+            out.write_all(&buf).await?;
+            bytes += record;
+            packets += 1;
+        }
+        Ok(())
+    }
+    .await;
+    // Sessions still writing to the capture see it closed from here on.
+    drop(rx);
+    match res {
+        Ok(()) => info!(path = %path.display(), bytes, packets, "capture finished"),
+        Err(err) => warn!(path = %path.display(), error = %format!("{err:#}"), "capture failed"),
+    }
+}
+
+/// One session of a captured forward, turned into packets as it relays.
+pub struct Stream {
+    /// `None` once the capture has ended or fallen behind.
+    tx: Option<mpsc::Sender<Packet>>,
+    client: SocketAddr,
+    target: SocketAddr,
+    /// Next sequence number of each side.
+    client_seq: u32,
+    target_seq: u32,
+}
+
+/// The session `id` of forward `label` in `namespace`, if the forward is being captured. `peer`
+/// and `target` are the session's ends as configured or accepted.
+pub fn stream(
+    label: &str,
+    namespace: Option<&str>,
+    id: u64,
+    peer: Option<&str>,
+    target: Option<&str>,
+) -> Option<Stream> {
+    let tx = {
+        let captures = CAPTURES.lock().expect("capture registry poisoned");
+        if captures.is_empty() {
+            return None;
+        }
+        let tx = captures.get(&(label.to_string(), namespace.map(str::to_string)))?;
+        if tx.is_closed() {
+            return None;
+        }
+        tx.clone()
+    };
+    // Sessions with made-up ends still get a port of their own, so their streams stay apart.
+    let port = 1024 + (id % 64_000) as u16;
+    let client = peer
+        .and_then(|peer| peer.parse().ok())
+        .unwrap_or(SocketAddr::new(UNKNOWN_CLIENT.into(), port));
+    let target = target
+        .and_then(|target| target.parse().ok())
+        .unwrap_or(SocketAddr::new(UNKNOWN_TARGET.into(), 0));
+    let mut stream = Stream {
+        tx: Some(tx),
+        client,
+        target,
+        client_seq: 0,
+        target_seq: 0,
+    };
+    stream.segment(true, TCP_SYN, &[]);
+    stream.client_seq = 1;
+    stream.segment(false, TCP_SYN | TCP_ACK, &[]);
+    stream.target_seq = 1;
+    stream.segment(true, TCP_ACK, &[]);
+    Some(stream)
+}
+
+impl Stream {
+    /// Bytes read from the client.
+    pub fn client_to_target(&mut self, data: &[u8]) {
+        self.data(true, data);
+    }
+
+    /// Bytes written to the client.
+    pub fn target_to_client(&mut self, data: &[u8]) {
+        self.data(false, data);
+    }
+
+    fn data(&mut self, from_client: bool, data: &[u8]) {
+        for chunk in data.chunks(MAX_SEGMENT) {
+            self.segment(from_client, TCP_PSH | TCP_ACK, chunk);
+            let seq = if from_client {
+                &mut self.client_seq
+            } else {
+                &mut self.target_seq
+            };
+            *seq = seq.wrapping_add(chunk.len() as u32);
+        }
+    }
+
+    /// Queue one segment from the client or the target at the current sequence numbers.
+    fn segment(&mut self, from_client: bool, flags: u8, payload: &[u8]) {
+        let Some(tx) = self.tx.as_ref() else {
+            return;
+        };
+        let (source, destination, seq, ack) = if from_client {
+            (self.client, self.target, self.client_seq, self.target_seq)
+        } else {
+            (self.target, self.client, self.target_seq, self.client_seq)
+        };
+        // The SYN is the only segment without an acknowledgement.
+        let ack = if flags & TCP_ACK != 0 { ack } else { 0 };
+        let data = packet(source, destination, seq, ack, flags, payload);
+        let packet = Packet {
+            time: SystemTime::now(),
+            data,
+        };
+        if let Err(err) = tx.try_send(packet) {
+            if matches!(err, mpsc::error::TrySendError::Full(_)) {
+                warn!("capture fell behind; no longer capturing this session");
+            }
+            self.tx = None;
+        }
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        self.segment(true, TCP_FIN | TCP_ACK, &[]);
+        self.client_seq = self.client_seq.wrapping_add(1);
+        self.segment(false, TCP_FIN | TCP_ACK, &[]);
+        self.target_seq = self.target_seq.wrapping_add(1);
+        self.segment(true, TCP_ACK, &[]);
+    }
+}
+
+/// An IP packet carrying one TCP segment, IPv4 if both ends are and IPv6 otherwise.
+fn packet(
+    source: SocketAddr,
+    destination: SocketAddr,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut tcp = Vec::with_capacity(20 + payload.len());
+    tcp.extend_from_slice(&source.port().to_be_bytes());
+    tcp.extend_from_slice(&destination.port().to_be_bytes());
+    tcp.extend_from_slice(&seq.to_be_bytes());
+    tcp.extend_from_slice(&ack.to_be_bytes());
+    tcp.push(5 << 4);
+    tcp.push(flags);
+    tcp.extend_from_slice(&u16::MAX.to_be_bytes());
+    tcp.extend_from_slice(&[0; 4]);
+    tcp.extend_from_slice(payload);
+
+    let mut pseudo = Vec::with_capacity(40);
+    let mut out = Vec::with_capacity(40 + tcp.len());
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            pseudo.extend_from_slice(&src.octets());
+            pseudo.extend_from_slice(&dst.octets());
+            pseudo.extend_from_slice(&[0, 6]);
+            pseudo.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+
+            out.extend_from_slice(&[0x45, 0]);
+            out.extend_from_slice(&((20 + tcp.len()) as u16).to_be_bytes());
+            // Identification 0, don't fragment, TTL 64, TCP, checksum filled in below.
+            out.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+            out.extend_from_slice(&src.octets());
+            out.extend_from_slice(&dst.octets());
+            let checksum = checksum(&[&out]);
+            out[10..12].copy_from_slice(&checksum.to_be_bytes());
+        }
+        (src, dst) => {
+            let src = to_ipv6(src).octets();
+            let dst = to_ipv6(dst).octets();
+            pseudo.extend_from_slice(&src);
+            pseudo.extend_from_slice(&dst);
+            pseudo.extend_from_slice(&(tcp.len() as u32).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, 6]);
+
+            out.extend_from_slice(&[0x60, 0, 0, 0]);
+            out.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+            // Next header TCP, hop limit 64.
+            out.extend_from_slice(&[6, 64]);
+            out.extend_from_slice(&src);
+            out.extend_from_slice(&dst);
+        }
+    }
+    let checksum = checksum(&[&pseudo, &tcp]);
+    tcp[16..18].copy_from_slice(&checksum.to_be_bytes());
+    out.extend_from_slice(&tcp);
+    out
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// The Internet checksum over `parts` laid end to end; every part but the last has even length.
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        for word in part.chunks(2) {
+            let word = match word {
+                [hi, lo] => u16::from_be_bytes([*hi, *lo]),
+                [hi] => u16::from_be_bytes([*hi, 0]),
+                _ => unreachable!(),
+            };
+            sum += u32::from(word);
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
@@ -0,0 +1,142 @@
+//! Fault injection (`chaos_*` options) and link shaping (`shape_*`, see
+//! [`crate::pipeline::shape`]) applied by the relay's delay line (see [`crate::pipeline::relay`]).
+//!
+//! Each direction of a session gets its own [`Line`]: chunks are optionally thinned byte by byte,
+//! held back until the direction's token bucket allows them, and scheduled for the (latency +
+//! jitter + shaping delay) deadline. Deadlines never move backwards, so bytes stay in order and
+//! jitter shows up as bursty throughput. A reset aborts the whole session and, where the target
+//! leg is a socket, closes it with an RST.
+
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+use tokio::io;
+use tokio::time::Instant;
+
+use crate::config::ForwardSpec;
+use crate::pipeline::shape::{Shaping, TokenBucket};
+use crate::srv::random_u64;
+
+/// Faults and shaping applied to every session of one forward.
+#[derive(Debug, Clone, Copy)]
+pub struct Chaos {
+    latency: Duration,
+    jitter: Duration,
+    reset_probability: f64,
+    drop_probability: f64,
+    shaping: Option<Shaping>,
+}
+
+impl Chaos {
+    /// `None` when the spec sets no `chaos_*` or `shape_*` option, so sessions take the plain
+    /// relay.
+    pub fn for_spec(spec: &ForwardSpec) -> Option<Self> {
+        if !spec.chaos() && !spec.shaped() {
+            return None;
+        }
+        Some(Self {
+            latency: Duration::from_millis(spec.chaos_latency_ms.unwrap_or(0)),
+            jitter: Duration::from_millis(spec.chaos_jitter_ms.unwrap_or(0)),
+            reset_probability: spec.chaos_reset_probability.unwrap_or(0.0),
+            drop_probability: spec.chaos_drop_probability.unwrap_or(0.0),
+            shaping: Shaping::for_spec(spec),
+        })
+    }
+
+    /// State for one direction of one session.
+    pub fn line(&self) -> Line {
+        Line {
+            chaos: *self,
+            rng: Rng::new(),
+            bucket: self.shaping.and_then(|shaping| shaping.bucket()),
+            last_due: Instant::now(),
+        }
+    }
+}
+
+/// One direction's faults and shaping state.
+pub struct Line {
+    chaos: Chaos,
+    rng: Rng,
+    bucket: Option<TokenBucket>,
+    last_due: Instant,
+}
+
+impl Line {
+    /// Roll for a reset and drop bytes from `chunk`.
+    pub fn mangle(&mut self, chunk: Vec<u8>) -> io::Result<Vec<u8>> {
+        if self.rng.chance(self.chaos.reset_probability) {
+            return Err(io::Error::other(InjectedReset));
+        }
+        if self.chaos.drop_probability <= 0.0 {
+            return Ok(chunk);
+        }
+        let drop_probability = self.chaos.drop_probability;
+        let rng = &mut self.rng;
+        Ok(chunk
+            .into_iter()
+            .filter(|_| !rng.chance(drop_probability))
+            .collect())
+    }
+
+    /// Wait for the token bucket to admit `len` bytes and return when they may be written.
+    pub async fn schedule(&mut self, len: usize) -> Instant {
+        if let Some(bucket) = self.bucket.as_mut() {
+            bucket.take(len).await;
+        }
+        let mut delay = self.chaos.latency + self.rng.up_to(self.chaos.jitter);
+        if let Some(shaping) = self.chaos.shaping.as_ref() {
+            delay += shaping.delay(&mut self.rng);
+        }
+        self.last_due = (Instant::now() + delay).max(self.last_due);
+        self.last_due
+    }
+}
+
+/// Whether `err` is a reset [`Line::mangle`] decided on, rather than a real one.
+pub fn is_injected_reset(err: &io::Error) -> bool {
+    err.get_ref()
+        .is_some_and(|inner| inner.is::<InjectedReset>())
+}
+
+#[derive(Debug)]
+struct InjectedReset;
+
+impl fmt::Display for InjectedReset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("injected reset")
+    }
+}
+
+impl Error for InjectedReset {}
+
+/// xorshift64* generator; fault decisions are made per byte, which is too hot for `random_u64`.
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new() -> Self {
+        Self(random_u64() | 1)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform in `[0, 1)`.
+    pub(crate) fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub(crate) fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.unit() < probability
+    }
+
+    /// Uniform in `[0, max]`.
+    pub(crate) fn up_to(&mut self, max: Duration) -> Duration {
+        max.mul_f64(self.unit())
+    }
+}
@@ -0,0 +1,348 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, bail};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket, lookup_host};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tracing::{debug, info, instrument, warn};
+
+use crate::config::ForwardSpec;
+use crate::log_limit;
+use crate::pipeline::ShutdownRx;
+use crate::pipeline::dns_cache::{DnsCache, HEADER_LEN, is_truncated, servfail, udp_limit};
+use crate::pipeline::lifecycle;
+use crate::pipeline::namespace::spawn_in_namespace;
+use crate::pipeline::udp::MAX_DATAGRAM;
+
+/// Queries waiting for the upstream worker before new ones are refused.
+const REQUEST_QUEUE: usize = 1024;
+
+/// One query handed from the listeners to the upstream worker.
+struct DnsRequest {
+    query: Vec<u8>,
+    /// Client asked over TCP, so the upstream is queried over TCP directly.
+    tcp: bool,
+    /// Largest response the client takes over UDP.
+    udp_limit: usize,
+    reply: oneshot::Sender<Result<Vec<u8>>>,
+}
+
+/// DNS-aware proxy answering on UDP and TCP `dns_listen`. Queries are sent to `dns_target` by an
+/// upstream worker, which runs inside the spec's namespace when one is configured so the target
+/// can be a namespace-local resolver such as the dnsmasq in a `qdhcp-*` namespace.
+pub fn spawn(spec: ForwardSpec, shutdown: ShutdownRx) -> JoinHandle<Result<()>> {
+    let (requests_tx, requests_rx) = mpsc::channel(REQUEST_QUEUE);
+    let upstream = if spec.enters_namespace() {
+        spawn_in_namespace(spec.clone(), shutdown.clone(), |spec, shutdown| {
+            upstream_loop(spec, requests_rx, shutdown)
+        })
+    } else {
+        tokio::spawn(upstream_loop(spec.clone(), requests_rx, shutdown.clone()))
+    };
+    tokio::spawn(async move {
+        let mut upstream = upstream;
+        tokio::select! {
+            res = dns_listener_loop(spec, requests_tx, shutdown) => res,
+            res = &mut upstream => res?,
+        }
+    })
+}
+
+#[derive(Clone)]
+struct Resolver {
+    requests: mpsc::Sender<DnsRequest>,
+    timeout: Duration,
+    cache: Option<Arc<Mutex<DnsCache>>>,
+}
+
+impl Resolver {
+    /// Answer `query` from the cache or the upstream, falling back to SERVFAIL when the upstream
+    /// fails or does not answer in time.
+    async fn resolve(&self, query: Vec<u8>, tcp: bool) -> Result<Vec<u8>> {
+        let udp_limit = if tcp { usize::MAX } else { udp_limit(&query) };
+        if let Some(cache) = self.cache.as_ref()
+            && let Some(hit) = cache.lock().expect("dns cache poisoned").get(&query)
+            && hit.len() <= udp_limit
+        {
+            return Ok(hit);
+        }
+
+        let (reply, response) = oneshot::channel();
+        let request = DnsRequest {
+            query: query.clone(),
+            tcp,
+            udp_limit,
+            reply,
+        };
+        self.requests
+            .try_send(request)
+            .map_err(|_| anyhow::anyhow!("dns upstream queue is full"))?;
+        let response = match timeout(self.timeout, response).await {
+            Ok(Ok(Ok(answer))) => answer,
+            Ok(Ok(Err(err))) => {
+                if let Some(suppressed) = log_limit::allow("dns upstream query failed", &err) {
+                    warn!(error = %err, suppressed, "dns upstream query failed");
+                }
+                return servfail(&query).context("malformed dns query");
+            }
+            Ok(Err(_)) => bail!("dns upstream worker went away"),
+            Err(_) => {
+                debug!(
+                    timeout_ms = self.timeout.as_millis() as u64,
+                    "dns query timed out"
+                );
+                return servfail(&query).context("malformed dns query");
+            }
+        };
+        if let Some(cache) = self.cache.as_ref() {
+            cache
+                .lock()
+                .expect("dns cache poisoned")
+                .insert(&query, &response);
+        }
+        Ok(response)
+    }
+}
+
+#[instrument(skip_all, fields(dns_listen = spec.dns_listen.as_deref().unwrap_or_default()))]
+async fn dns_listener_loop(
+    spec: ForwardSpec,
+    requests: mpsc::Sender<DnsRequest>,
+    mut shutdown: ShutdownRx,
+) -> Result<()> {
+    let listen_addr = spec
+        .dns_listen
+        .as_ref()
+        .context("dns proxy requires dns_listen address")?;
+    let udp = Arc::new(
+        UdpSocket::bind(listen_addr)
+            .await
+            .with_context(|| format!("failed to bind dns udp listener {}", listen_addr))?,
+    );
+    let tcp = TcpListener::bind(listen_addr)
+        .await
+        .with_context(|| format!("failed to bind dns tcp listener {}", listen_addr))?;
+    let resolver = Resolver {
+        requests,
+        timeout: spec.dns_timeout(),
+        cache: spec
+            .dns_cache
+            .filter(|&entries| entries > 0)
+            .map(|entries| Arc::new(Mutex::new(DnsCache::new(entries as usize)))),
+    };
+    info!(
+        %listen_addr,
+        target = spec.dns_target.as_deref().unwrap_or_default(),
+        timeout_ms = resolver.timeout.as_millis() as u64,
+        cache_entries = spec.dns_cache.unwrap_or(0),
+        "dns proxy listening"
+    );
+    let _lifecycle = lifecycle::up(&spec, listen_addr).await;
+
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        tokio::select! {
+            biased;
+            res = shutdown.changed() => {
+                if res.is_err() || *shutdown.borrow() {
+                    info!(%listen_addr, "shutdown received; stopping dns proxy");
+                    break;
+                }
+            }
+            recv = udp.recv_from(&mut buf) => {
+                let (len, client) = recv?;
+                if len < HEADER_LEN {
+                    debug!(%client, len, "dropping dns query shorter than its header");
+                    continue;
+                }
+                let query = buf[..len].to_vec();
+                let resolver = resolver.clone();
+                let udp = udp.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = answer_udp(&resolver, &udp, query, client).await
+                        && let Some(suppressed) = log_limit::allow("dns udp query failed", &err)
+                    {
+                        warn!(%client, error = %err, suppressed, "dns udp query failed");
+                    }
+                });
+            }
+            accept_res = tcp.accept() => {
+                let (stream, peer) = accept_res?;
+                let resolver = resolver.clone();
+                let shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = serve_tcp(&resolver, stream, shutdown).await {
+                        warn!(%peer, error = %err, "dns tcp session failed");
+                    }
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn answer_udp(
+    resolver: &Resolver,
+    udp: &UdpSocket,
+    query: Vec<u8>,
+    client: SocketAddr,
+) -> Result<()> {
+    let response = resolver.resolve(query, false).await?;
+    udp.send_to(&response, client).await?;
+    Ok(())
+}
+
+/// Serve length-prefixed queries on one TCP connection until the client closes it.
+async fn serve_tcp(
+    resolver: &Resolver,
+    mut stream: TcpStream,
+    mut shutdown: ShutdownRx,
+) -> Result<()> {
+    stream.set_nodelay(true).ok();
+    loop {
+        let query = tokio::select! {
+            biased;
+            _ = shutdown.changed() => return Ok(()),
+            query = read_tcp_message(&mut stream) => query?,
+        };
+        let Some(query) = query else {
+            return Ok(());
+        };
+        if query.len() < HEADER_LEN {
+            debug!(
+                len = query.len(),
+                "dropping dns query shorter than its header"
+            );
+            continue;
+        }
+        let response = resolver.resolve(query, true).await?;
+        write_tcp_message(&mut stream, &response).await?;
+    }
+}
+
+async fn upstream_loop(
+    spec: ForwardSpec,
+    mut requests: mpsc::Receiver<DnsRequest>,
+    mut shutdown: ShutdownRx,
+) -> Result<()> {
+    let target = spec
+        .dns_target
+        .clone()
+        .context("dns proxy requires dns_target address")?;
+    // The resolver answers SERVFAIL after this too; the exchange must not outlive it.
+    let query_timeout = spec.dns_timeout();
+    loop {
+        tokio::select! {
+            biased;
+            res = shutdown.changed() => {
+                if res.is_err() || *shutdown.borrow() {
+                    break;
+                }
+            }
+            request = requests.recv() => {
+                let Some(request) = request else { break };
+                let target = target.clone();
+                tokio::spawn(async move {
+                    let answer = timeout(query_timeout, exchange(&target, &request))
+                        .await
+                        .unwrap_or_else(|_| Err(anyhow!("dns target {target} did not answer in time")));
+                    let _ = request.reply.send(answer);
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Query the upstream over UDP, retrying over TCP when the answer comes back truncated. The full
+/// TCP answer is used when it fits the client's UDP limit; otherwise the truncated one is passed on
+/// so the client retries over TCP itself.
+async fn exchange(target: &str, request: &DnsRequest) -> Result<Vec<u8>> {
+    if request.tcp {
+        return exchange_tcp(target, &request.query).await;
+    }
+    let response = exchange_udp(target, &request.query).await?;
+    if !is_truncated(&response) {
+        return Ok(response);
+    }
+    debug!(%target, "dns answer truncated; retrying over tcp");
+    match exchange_tcp(target, &request.query).await {
+        Ok(full) if full.len() <= request.udp_limit => Ok(full),
+        Ok(_) => Ok(response),
+        Err(err) => {
+            debug!(%target, error = %err, "dns tcp retry failed; returning truncated answer");
+            Ok(response)
+        }
+    }
+}
+
+pub(crate) async fn exchange_udp(target: &str, query: &[u8]) -> Result<Vec<u8>> {
+    let target_addr = lookup_host(target)
+        .await
+        .with_context(|| format!("failed to resolve dns target {}", target))?
+        .next()
+        .with_context(|| format!("dns target {} resolved to no addresses", target))?;
+    let bind_addr = if target_addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(bind_addr)
+        .await
+        .context("failed to bind dns upstream socket")?;
+    socket
+        .connect(target_addr)
+        .await
+        .with_context(|| format!("failed to connect dns target {}", target))?;
+    socket.send(query).await?;
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        // Ignore stray datagrams that do not answer this query's ID.
+        if len >= 2 && buf[..2] == query[..2] {
+            buf.truncate(len);
+            return Ok(buf);
+        }
+    }
+}
+
+pub(crate) async fn exchange_tcp(target: &str, query: &[u8]) -> Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(target)
+        .await
+        .with_context(|| format!("failed to connect dns target {} over tcp", target))?;
+    stream.set_nodelay(true).ok();
+    write_tcp_message(&mut stream, query).await?;
+    read_tcp_message(&mut stream)
+        .await?
+        .context("dns target closed the tcp connection without answering")
+}
+
+/// Read one two-byte length-prefixed DNS message; `None` on a clean EOF between messages.
+async fn read_tcp_message(stream: &mut TcpStream) -> Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 2];
+    match stream.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let len = usize::from(u16::from_be_bytes(len));
+    if len == 0 {
+        bail!("zero-length dns message");
+    }
+    let mut msg = vec![0u8; len];
+    stream.read_exact(&mut msg).await?;
+    Ok(Some(msg))
+}
+
+async fn write_tcp_message(stream: &mut TcpStream, msg: &[u8]) -> Result<()> {
+    let len = u16::try_from(msg.len()).context("dns message too large for tcp framing")?;
+    let mut framed = Vec::with_capacity(msg.len() + 2);
+    framed.extend_from_slice(&len.to_be_bytes());
+    framed.extend_from_slice(msg);
+    stream.write_all(&framed).await?;
+    Ok(())
+}
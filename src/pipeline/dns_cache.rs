@@ -0,0 +1,214 @@
+//! DNS wire-format inspection and a small response cache for the DNS proxy.
+//!
+//! Parsing stops at what the proxy needs: the question section (cache key), the client's EDNS UDP
+//! payload size, and the offset of every record TTL so cached answers can be aged on the way out.
+//! Names are skipped, never decompressed.
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+pub(crate) const HEADER_LEN: usize = 12;
+const TYPE_OPT: u16 = 41;
+const FLAG_TC: u8 = 0x02;
+const RCODE_NOERROR: u8 = 0;
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// UDP payload limit for clients that do not advertise EDNS.
+pub(crate) const CLASSIC_UDP_LIMIT: usize = 512;
+
+struct Record {
+    rtype: u16,
+    class: u16,
+    ttl: u32,
+    ttl_offset: usize,
+}
+
+struct Message {
+    question: Range<usize>,
+    question_count: u16,
+    records: Vec<Record>,
+}
+
+pub(crate) fn read_u16(msg: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(msg.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+pub(crate) fn read_u32(msg: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(msg.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+pub(crate) fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(pos)?;
+        match len & 0xC0 {
+            0x00 if len == 0 => return Some(pos + 1),
+            0x00 => pos += 1 + usize::from(len),
+            0xC0 => return Some(pos + 2),
+            _ => return None,
+        }
+    }
+}
+
+fn parse(msg: &[u8]) -> Option<Message> {
+    if msg.len() < HEADER_LEN {
+        return None;
+    }
+    let question_count = read_u16(msg, 4)?;
+    let record_count = [6, 8, 10]
+        .iter()
+        .map(|&at| read_u16(msg, at).map(usize::from))
+        .sum::<Option<usize>>()?;
+
+    let mut pos = HEADER_LEN;
+    for _ in 0..question_count {
+        pos = skip_name(msg, pos)? + 4;
+    }
+    let question = HEADER_LEN..pos;
+
+    let mut records = Vec::with_capacity(record_count);
+    for _ in 0..record_count {
+        pos = skip_name(msg, pos)?;
+        let rtype = read_u16(msg, pos)?;
+        let class = read_u16(msg, pos + 2)?;
+        let ttl = read_u32(msg, pos + 4)?;
+        let rdlength = usize::from(read_u16(msg, pos + 8)?);
+        records.push(Record {
+            rtype,
+            class,
+            ttl,
+            ttl_offset: pos + 4,
+        });
+        pos += 10 + rdlength;
+    }
+    (pos <= msg.len()).then_some(Message {
+        question,
+        question_count,
+        records,
+    })
+}
+
+pub(crate) fn is_truncated(msg: &[u8]) -> bool {
+    msg.get(2).is_some_and(|flags| flags & FLAG_TC != 0)
+}
+
+fn rcode(msg: &[u8]) -> Option<u8> {
+    msg.get(3).map(|flags| flags & 0x0F)
+}
+
+/// Largest UDP response the querying client accepts, from its EDNS OPT record if present.
+pub(crate) fn udp_limit(query: &[u8]) -> usize {
+    parse(query)
+        .and_then(|msg| msg.records.into_iter().find(|r| r.rtype == TYPE_OPT))
+        .map_or(CLASSIC_UDP_LIMIT, |opt| {
+            usize::from(opt.class).max(CLASSIC_UDP_LIMIT)
+        })
+}
+
+/// SERVFAIL answer echoing the query's ID and question, sent when the upstream does not answer in
+/// time.
+pub(crate) fn servfail(query: &[u8]) -> Option<Vec<u8>> {
+    let msg = parse(query)?;
+    let mut response = query[..msg.question.end].to_vec();
+    response[2] = (response[2] | 0x80) & !FLAG_TC; // QR, keep opcode and RD
+    response[3] = 0x80 | RCODE_SERVFAIL; // RA
+    response[6..HEADER_LEN].fill(0);
+    Some(response)
+}
+
+/// Responses keyed by their (case-folded) question, aged by TTL and capped at `capacity` entries.
+pub(crate) struct DnsCache {
+    capacity: usize,
+    entries: HashMap<Vec<u8>, CacheEntry>,
+}
+
+struct CacheEntry {
+    response: Vec<u8>,
+    ttl_offsets: Vec<(usize, u32)>,
+    stored: Instant,
+    expires: Instant,
+}
+
+impl DnsCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn get(&mut self, query: &[u8]) -> Option<Vec<u8>> {
+        let key = cache_key(query)?;
+        let entry = self.entries.get(&key)?;
+        let now = Instant::now();
+        if now >= entry.expires {
+            self.entries.remove(&key);
+            return None;
+        }
+        let elapsed = u32::try_from(now.duration_since(entry.stored).as_secs()).unwrap_or(u32::MAX);
+        let mut response = entry.response.clone();
+        response[..2].copy_from_slice(&query[..2]);
+        for &(offset, ttl) in &entry.ttl_offsets {
+            let aged = ttl.saturating_sub(elapsed);
+            response[offset..offset + 4].copy_from_slice(&aged.to_be_bytes());
+        }
+        Some(response)
+    }
+
+    /// Cache a complete NOERROR/NXDOMAIN answer for as long as its shortest record TTL.
+    pub(crate) fn insert(&mut self, query: &[u8], response: &[u8]) {
+        if is_truncated(response)
+            || !matches!(rcode(response), Some(RCODE_NOERROR | RCODE_NXDOMAIN))
+        {
+            return;
+        }
+        let (Some(key), Some(msg)) = (cache_key(query), parse(response)) else {
+            return;
+        };
+        let ttl_offsets: Vec<(usize, u32)> = msg
+            .records
+            .iter()
+            .filter(|record| record.rtype != TYPE_OPT)
+            .map(|record| (record.ttl_offset, record.ttl))
+            .collect();
+        let Some(min_ttl) = ttl_offsets.iter().map(|&(_, ttl)| ttl).min() else {
+            return;
+        };
+        if min_ttl == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            self.entries.retain(|_, entry| entry.expires > now);
+            if self.entries.len() >= self.capacity
+                && let Some(evict) = self.entries.keys().next().cloned()
+            {
+                self.entries.remove(&evict);
+            }
+        }
+        self.entries.insert(
+            key,
+            CacheEntry {
+                response: response.to_vec(),
+                ttl_offsets,
+                stored: now,
+                expires: now + Duration::from_secs(u64::from(min_ttl)),
+            },
+        );
+    }
+}
+
+/// Single-question queries only; the key is the question section with the name's ASCII case
+/// folded.
+fn cache_key(query: &[u8]) -> Option<Vec<u8>> {
+    let msg = parse(query)?;
+    if msg.question_count != 1 {
+        return None;
+    }
+    let mut key = query[msg.question.clone()].to_vec();
+    let name_len = key.len() - 4;
+    key[..name_len].make_ascii_lowercase();
+    Some(key)
+}
@@ -0,0 +1,572 @@
+//! FTP-aware proxy: the control connection is relayed line by line so data-channel addresses can
+//! be rewritten, and every data channel gets a short-lived forward of its own.
+//!
+//! * Passive mode: `227`/`229` replies from the server are answered with a one-shot listener on the
+//!   client-facing side, which dials the server's data port from the target's namespace.
+//! * Active mode: `PORT`/`EPRT` commands from the client are replaced with a one-shot listener in
+//!   the target's namespace, which dials the client's data port from the host.
+//!
+//! Data channels only ever connect the control connection's two ends. A `PORT`/`EPRT` naming any
+//! address but the client's own is refused, so a client cannot bounce server data to another host,
+//! and the one-shot listeners drop connections from anyone but the client (passive) or the server
+//! (active). Passive replies are dialed at the server's control address, whatever they advertise.
+//!
+//! Control connections protected with `AUTH TLS` cannot be inspected and break data channels.
+
+use std::net::TcpStream as StdTcpStream;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener as StdTcpListener};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::task::{JoinHandle, spawn_blocking};
+use tokio::time::{Instant, timeout, timeout_at};
+use tracing::{debug, info, instrument, warn};
+
+use crate::config::ForwardSpec;
+use crate::netns;
+use crate::pipeline::lifecycle;
+use crate::pipeline::listener::bind_tcp;
+use crate::pipeline::pause::Gate;
+use crate::pipeline::{ShutdownRx, copy_bidirectional};
+
+/// How long a data-channel listener waits for its single connection.
+const DATA_ACCEPT_TIMEOUT: Duration = Duration::from_secs(30);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Control lines longer than this are relayed in pieces of this size and never rewritten.
+const MAX_LINE: usize = 8192;
+
+pub fn spawn(spec: ForwardSpec, shutdown: ShutdownRx) -> JoinHandle<Result<()>> {
+    tokio::spawn(async move { ftp_proxy_loop(spec, shutdown).await })
+}
+
+#[instrument(skip_all, fields(listen = spec.listen.as_deref().unwrap_or_default(), target = spec.target.as_deref().unwrap_or_default()))]
+async fn ftp_proxy_loop(spec: ForwardSpec, mut shutdown: ShutdownRx) -> Result<()> {
+    let listen_addr = spec
+        .listen
+        .clone()
+        .context("listen address missing for ftp proxy")?;
+    let listener = bind_tcp(&listen_addr, false, spec.ipv6_only)?;
+    let spec = Arc::new(spec);
+    info!(%listen_addr, namespaced = spec.enters_namespace(), "ftp proxy listening");
+    let _lifecycle = lifecycle::up(&spec, &listen_addr).await;
+    let mut gate = Gate::for_spec(&spec);
+
+    loop {
+        tokio::select! {
+            biased;
+            res = shutdown.changed() => {
+                if res.is_err() || *shutdown.borrow() {
+                    info!(%listen_addr, "shutdown received; stopping ftp proxy");
+                    break;
+                }
+            }
+            accept_res = gate.accept(|| listener.accept()) => {
+                let (client, peer) = accept_res?;
+                let spec = spec.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = serve_control(client, spec).await {
+                        warn!(%peer, error = %err, "ftp session failed");
+                    }
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn serve_control(client: TcpStream, spec: Arc<ForwardSpec>) -> Result<()> {
+    let target = spec.target.clone().context("ftp proxy requires target")?;
+    let server = connect_in_namespace(spec.clone(), target.clone()).await?;
+    // IPv4 clients of a dual-stack listener show up as v4-mapped addresses.
+    let client_local = client.local_addr()?;
+    let client_peer = client.peer_addr()?.ip().to_canonical();
+    let server_local = server.local_addr()?;
+    let server_peer = server.peer_addr()?;
+    let (client_read, client_write) = client.into_split();
+    let (server_read, server_write) = server.into_split();
+    let session = Arc::new(Session {
+        spec,
+        client_local: SocketAddr::new(client_local.ip().to_canonical(), client_local.port()),
+        client_peer,
+        server_local,
+        server_peer,
+        client: Mutex::new(client_write),
+    });
+
+    let upstream = {
+        let session = session.clone();
+        async move {
+            let server_write = Mutex::new(server_write);
+            relay_lines(client_read, &server_write, |line| {
+                session.rewrite_command(line)
+            })
+            .await?;
+            server_write.lock().await.shutdown().await.ok();
+            anyhow::Ok(())
+        }
+    };
+    let downstream = {
+        let session = session.clone();
+        async move {
+            relay_lines(server_read, &session.client, |line| {
+                session.rewrite_reply(line)
+            })
+            .await?;
+            session.client.lock().await.shutdown().await.ok();
+            anyhow::Ok(())
+        }
+    };
+    tokio::try_join!(upstream, downstream)?;
+    Ok(())
+}
+
+/// Copy CRLF-terminated lines from `reader` to `writer`, letting `rewrite` replace any line, or
+/// drop it by answering `None`.
+async fn relay_lines<R, W, F, Fut>(reader: R, writer: &Mutex<W>, rewrite: F) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<Option<String>>>,
+{
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    // Set while the rest of a line too long to rewrite is still coming.
+    let mut overlong = false;
+    loop {
+        line.clear();
+        let read = (&mut reader)
+            .take(MAX_LINE as u64)
+            .read_until(b'\n', &mut line)
+            .await?;
+        if read == 0 {
+            return Ok(());
+        }
+        let complete = line.ends_with(b"\n");
+        let out = match std::str::from_utf8(&line) {
+            Ok(text) if complete && !overlong => match rewrite(text.to_string()).await? {
+                Some(out) => out.into_bytes(),
+                None => continue,
+            },
+            _ => line.clone(),
+        };
+        overlong = !complete;
+        writer.lock().await.write_all(&out).await?;
+    }
+}
+
+struct Session {
+    spec: Arc<ForwardSpec>,
+    /// Our address as the client sees it; passive-mode listeners are bound here.
+    client_local: SocketAddr,
+    /// The client's address, the only one its data channels may connect to or come from.
+    client_peer: IpAddr,
+    /// Our address as the server sees it; active-mode listeners are bound here.
+    server_local: SocketAddr,
+    server_peer: SocketAddr,
+    /// Shared by the relayed replies and those the proxy answers itself.
+    client: Mutex<OwnedWriteHalf>,
+}
+
+impl Session {
+    /// Replace `PORT`/`EPRT` with a listener the server can reach from its namespace. One naming
+    /// another host than the client is answered with `500` and never reaches the server.
+    async fn rewrite_command(&self, line: String) -> Result<Option<String>> {
+        let trimmed = line.trim_end();
+        let (verb, arg) = trimmed.split_once(' ').unwrap_or((trimmed, ""));
+        let verb = verb.to_ascii_uppercase();
+        let client_data = match verb.as_str() {
+            "PORT" => parse_port_tuple(arg),
+            "EPRT" => parse_eprt(arg),
+            _ => return Ok(Some(line)),
+        };
+        let Some(client_data) = client_data else {
+            return Ok(Some(line));
+        };
+        if client_data.ip().to_canonical() != self.client_peer {
+            warn!(client = %self.client_peer, data = %client_data, "ftp data address refused: not the client's");
+            let refusal = format!("500 Illegal {verb} command.\r\n");
+            self.client
+                .lock()
+                .await
+                .write_all(refusal.as_bytes())
+                .await?;
+            return Ok(None);
+        }
+        let bind = SocketAddr::new(self.server_local.ip(), 0);
+        let listener = bind_in_namespace(self.spec.clone(), bind).await?;
+        let local = listener.local_addr()?;
+        debug!(client = %client_data, via = %local, "active ftp data channel");
+        let server = self.server_peer.ip();
+        tokio::spawn(forward_once(listener, server, None, client_data));
+        Ok(Some(match (verb.as_str(), local.ip()) {
+            ("PORT", IpAddr::V4(ip)) => format!("PORT {}\r\n", port_tuple(ip, local.port())),
+            _ => format!("EPRT {}\r\n", eprt(local)),
+        }))
+    }
+
+    /// Replace `227`/`229` replies with a host-side listener that reaches the server's data port.
+    /// The port is dialed at the server's control address, whatever address a `227` names. A `227`
+    /// cannot name an IPv6 listener, so on an IPv6 control connection it is relayed as it is.
+    async fn rewrite_reply(&self, line: String) -> Result<Option<String>> {
+        let client_ip = self.client_local.ip().to_canonical();
+        let server_port = if line.starts_with("227") {
+            if client_ip.is_ipv6() {
+                debug!("227 reply on an ipv6 control connection relayed unchanged");
+                return Ok(Some(line));
+            }
+            parse_pasv_reply(&line).map(|addr| addr.port())
+        } else if line.starts_with("229") {
+            parse_epsv_reply(&line)
+        } else {
+            return Ok(Some(line));
+        };
+        let Some(server_port) = server_port else {
+            return Ok(Some(line));
+        };
+        let server_data = SocketAddr::new(self.server_peer.ip(), server_port);
+        let listener = TcpListener::bind(SocketAddr::new(client_ip, 0)).await?;
+        let local = listener.local_addr()?;
+        debug!(server = %server_data, via = %local, "passive ftp data channel");
+        let client = self.client_peer;
+        tokio::spawn(forward_once(
+            listener,
+            client,
+            Some(self.spec.clone()),
+            server_data,
+        ));
+        Ok(Some(match local.ip() {
+            IpAddr::V4(ip) if line.starts_with("227") => format!(
+                "227 Entering Passive Mode ({}).\r\n",
+                port_tuple(ip, local.port())
+            ),
+            _ => format!(
+                "229 Entering Extended Passive Mode (|||{}|)\r\n",
+                local.port()
+            ),
+        }))
+    }
+}
+
+/// Accept one data connection from `from` on `listener` and bridge it to `dest`, dialed from
+/// `netns` (the target's namespace) or from the host when `None`. Connections from other hosts
+/// are closed.
+async fn forward_once(
+    listener: TcpListener,
+    from: IpAddr,
+    netns: Option<Arc<ForwardSpec>>,
+    dest: SocketAddr,
+) {
+    let res = async {
+        let deadline = Instant::now() + DATA_ACCEPT_TIMEOUT;
+        let mut inbound = loop {
+            let (inbound, peer) = timeout_at(deadline, listener.accept())
+                .await
+                .context("ftp data connection never arrived")??;
+            if peer.ip().to_canonical() == from {
+                break inbound;
+            }
+            warn!(%peer, expected = %from, "ftp data connection refused: wrong peer");
+        };
+        drop(listener);
+        let mut outbound = match netns {
+            Some(spec) => connect_in_namespace(spec, dest.to_string()).await?,
+            None => timeout(CONNECT_TIMEOUT, TcpStream::connect(dest))
+                .await
+                .context("ftp data connect timed out")??,
+        };
+        copy_bidirectional(&mut inbound, &mut outbound)
+            .await
+            .map(|_| ())
+    }
+    .await;
+    if let Err(err) = res {
+        warn!(%dest, error = %err, "ftp data channel failed");
+    }
+}
+
+async fn connect_in_namespace(spec: Arc<ForwardSpec>, target: String) -> Result<TcpStream> {
+    let stream = spawn_blocking(move || {
+        netns::within(&spec, || {
+            let addr = std::net::ToSocketAddrs::to_socket_addrs(&target)?
+                .next()
+                .with_context(|| format!("{target} resolved to no addresses"))?;
+            StdTcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+                .with_context(|| format!("failed to connect {target}"))
+        })
+    })
+    .await??;
+    stream.set_nonblocking(true)?;
+    Ok(TcpStream::from_std(stream)?)
+}
+
+async fn bind_in_namespace(spec: Arc<ForwardSpec>, addr: SocketAddr) -> Result<TcpListener> {
+    let listener = spawn_blocking(move || {
+        netns::within(&spec, || {
+            StdTcpListener::bind(addr).with_context(|| format!("failed to bind {addr}"))
+        })
+    })
+    .await??;
+    listener.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(listener)?)
+}
+
+/// `h1,h2,h3,h4,p1,p2` as used by `PORT` and `227` replies.
+fn parse_port_tuple(text: &str) -> Option<SocketAddr> {
+    let fields: Vec<u8> = text
+        .trim()
+        .split(',')
+        .map(|field| field.trim().parse().ok())
+        .collect::<Option<_>>()?;
+    let [a, b, c, d, hi, lo] = fields.as_slice() else {
+        return None;
+    };
+    let ip = Ipv4Addr::new(*a, *b, *c, *d);
+    Some(SocketAddr::new(ip.into(), u16::from_be_bytes([*hi, *lo])))
+}
+
+fn port_tuple(ip: Ipv4Addr, port: u16) -> String {
+    let [a, b, c, d] = ip.octets();
+    let [hi, lo] = port.to_be_bytes();
+    format!("{a},{b},{c},{d},{hi},{lo}")
+}
+
+/// `227 Entering Passive Mode (h1,h2,h3,h4,p1,p2).`; the tuple may appear without parentheses.
+fn parse_pasv_reply(line: &str) -> Option<SocketAddr> {
+    let start = line[3..].find(|c: char| c.is_ascii_digit())? + 3;
+    let end = line[start..]
+        .find(|c: char| !(c.is_ascii_digit() || c == ','))
+        .map_or(line.len(), |end| start + end);
+    parse_port_tuple(&line[start..end])
+}
+
+/// `229 Entering Extended Passive Mode (|||port|)`.
+fn parse_epsv_reply(line: &str) -> Option<u16> {
+    let open = line.find('(')?;
+    let inner = &line[open + 1..line[open..].find(')')? + open];
+    let delim = inner.chars().next()?;
+    inner.trim_matches(delim).parse().ok()
+}
+
+/// `|proto|addr|port|` as used by `EPRT`.
+fn parse_eprt(arg: &str) -> Option<SocketAddr> {
+    let delim = arg.chars().next()?;
+    let mut fields = arg[delim.len_utf8()..].split(delim);
+    let _proto = fields.next()?;
+    let ip: IpAddr = fields.next()?.parse().ok()?;
+    let port = fields.next()?.parse().ok()?;
+    Some(SocketAddr::new(ip, port))
+}
+
+fn eprt(addr: SocketAddr) -> String {
+    let proto = if addr.is_ipv4() { 1 } else { 2 };
+    format!("|{proto}|{}|{}|", addr.ip(), addr.port())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+    use tokio::net::TcpSocket;
+
+    use super::*;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    /// A session whose client is a socket on 127.0.0.1, returned for reading what the proxy
+    /// answers it, with the server's control connection coming from `server_peer`.
+    async fn session(server_peer: SocketAddr) -> (Session, TcpStream) {
+        let listener = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (accepted, peer) = listener.accept().await.unwrap();
+        let (_, client_write) = accepted.into_split();
+        let session = Session {
+            spec: Arc::new(ForwardSpec::default()),
+            client_local: listener.local_addr().unwrap(),
+            client_peer: peer.ip(),
+            server_local: SocketAddr::new(LOCALHOST, 2121),
+            server_peer,
+            client: Mutex::new(client_write),
+        };
+        (session, client)
+    }
+
+    async fn connect_from(from: IpAddr, to: SocketAddr) -> TcpStream {
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.bind(SocketAddr::new(from, 0)).unwrap();
+        socket.connect(to).await.unwrap()
+    }
+
+    #[test]
+    fn port_tuple_round_trips() {
+        let addr: SocketAddr = "192.0.2.7:50021".parse().unwrap();
+        let IpAddr::V4(ip) = addr.ip() else {
+            unreachable!()
+        };
+        assert_eq!(port_tuple(ip, addr.port()), "192,0,2,7,195,101");
+        assert_eq!(parse_port_tuple("192,0,2,7,195,101"), Some(addr));
+        assert_eq!(parse_port_tuple(" 192, 0, 2, 7, 195, 101 "), Some(addr));
+        assert_eq!(parse_port_tuple("192,0,2,7,195"), None);
+        assert_eq!(parse_port_tuple("192,0,2,7,195,101,1"), None);
+        assert_eq!(parse_port_tuple("192,0,2,300,195,101"), None);
+    }
+
+    #[test]
+    fn parses_passive_replies() {
+        let addr: SocketAddr = "10.0.0.5:1025".parse().unwrap();
+        assert_eq!(
+            parse_pasv_reply("227 Entering Passive Mode (10,0,0,5,4,1).\r\n"),
+            Some(addr)
+        );
+        assert_eq!(parse_pasv_reply("227 =10,0,0,5,4,1\r\n"), Some(addr));
+        assert_eq!(parse_pasv_reply("227 Entering Passive Mode.\r\n"), None);
+        assert_eq!(
+            parse_epsv_reply("229 Entering Extended Passive Mode (|||6446|)\r\n"),
+            Some(6446)
+        );
+        assert_eq!(
+            parse_epsv_reply("229 Entering Extended Passive Mode (!!!6446!)"),
+            Some(6446)
+        );
+        assert_eq!(
+            parse_epsv_reply("229 Entering Extended Passive Mode\r\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn eprt_round_trips() {
+        for addr in ["192.0.2.7:50021", "[2001:db8::1]:50021"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            assert_eq!(parse_eprt(&eprt(addr)), Some(addr));
+        }
+        assert_eq!(
+            eprt("[2001:db8::1]:21".parse().unwrap()),
+            "|2|2001:db8::1|21|"
+        );
+        assert_eq!(parse_eprt("|1|not-an-ip|21|"), None);
+        assert_eq!(parse_eprt("|1|192.0.2.7|"), None);
+    }
+
+    #[tokio::test]
+    async fn relays_other_commands_as_they_are() {
+        let (session, _client) = session(SocketAddr::new(LOCALHOST, 21)).await;
+        let line = "RETR file.txt\r\n".to_string();
+        assert_eq!(
+            session.rewrite_command(line.clone()).await.unwrap(),
+            Some(line)
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_data_addresses_of_other_hosts() {
+        let (session, mut client) = session(SocketAddr::new(LOCALHOST, 21)).await;
+        for line in ["PORT 10,0,0,9,0,22\r\n", "EPRT |1|10.0.0.9|22|\r\n"] {
+            let rewritten = session.rewrite_command(line.to_string()).await.unwrap();
+            assert_eq!(rewritten, None);
+        }
+        let mut answer = [0; 64];
+        let mut read = 0;
+        while !answer[..read].ends_with(b"EPRT command.\r\n") {
+            read += client.read(&mut answer[read..]).await.unwrap();
+        }
+        assert_eq!(
+            &answer[..read],
+            b"500 Illegal PORT command.\r\n500 Illegal EPRT command.\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn relays_overlong_lines_in_pieces_without_rewriting() {
+        let long = "x".repeat(3 * MAX_LINE);
+        let input = format!("{long}\r\nNOOP\r\n");
+        let (mut source, reader) = duplex(MAX_LINE);
+        let (writer, mut sink) = duplex(4 * MAX_LINE);
+        let writer = Mutex::new(writer);
+        let relay = relay_lines(reader, &writer, |line| async move {
+            assert!(line.len() <= MAX_LINE);
+            Ok(Some(format!("<{}>", line.trim_end())))
+        });
+        let feed = async {
+            source.write_all(input.as_bytes()).await.unwrap();
+            drop(source);
+        };
+        let ((), res) = tokio::join!(feed, relay);
+        res.unwrap();
+        drop(writer);
+        let mut out = String::new();
+        sink.read_to_string(&mut out).await.unwrap();
+        assert_eq!(out, format!("{long}\r\n<NOOP>"));
+    }
+
+    #[tokio::test]
+    async fn relays_227_on_ipv6_unchanged() {
+        let (mut session, _client) = session(SocketAddr::new(LOCALHOST, 21)).await;
+        session.client_local = "[::1]:21".parse().unwrap();
+        let reply = "227 Entering Passive Mode (192,0,2,7,4,1).\r\n".to_string();
+        assert_eq!(
+            session.rewrite_reply(reply.clone()).await.unwrap(),
+            Some(reply)
+        );
+    }
+
+    #[tokio::test]
+    async fn rewrites_the_clients_data_address() {
+        let (session, _client) = session(SocketAddr::new(LOCALHOST, 21)).await;
+        let rewritten = session
+            .rewrite_command("PORT 127,0,0,1,0,22\r\n".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        let via = parse_port_tuple(rewritten.strip_prefix("PORT ").unwrap().trim_end()).unwrap();
+        assert_eq!(via.ip(), session.server_local.ip());
+        assert_ne!(via.port(), 22);
+    }
+
+    #[tokio::test]
+    async fn dials_passive_data_at_the_servers_address() {
+        let server_data = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+        let port = server_data.local_addr().unwrap().port();
+        let (session, _client) = session(SocketAddr::new(LOCALHOST, 21)).await;
+        // The server advertises an address the proxy must not dial.
+        let [hi, lo] = port.to_be_bytes();
+        let reply = format!("227 Entering Passive Mode (192,0,2,7,{hi},{lo}).\r\n");
+        let rewritten = session.rewrite_reply(reply).await.unwrap().unwrap();
+        let via = parse_pasv_reply(&rewritten).unwrap();
+        assert_eq!(via.ip(), session.client_local.ip());
+
+        let _data = connect_from(LOCALHOST, via).await;
+        timeout(Duration::from_secs(5), server_data.accept())
+            .await
+            .expect("server data port was not dialed")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn data_listener_drops_other_peers() {
+        let dest = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+        let listener = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+        let via = listener.local_addr().unwrap();
+        tokio::spawn(forward_once(
+            listener,
+            LOCALHOST,
+            None,
+            dest.local_addr().unwrap(),
+        ));
+
+        let mut intruder = connect_from(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)), via).await;
+        let mut buf = [0; 1];
+        assert_eq!(intruder.read(&mut buf).await.unwrap(), 0);
+        let _client = connect_from(LOCALHOST, via).await;
+        timeout(Duration::from_secs(5), dest.accept())
+            .await
+            .expect("data channel was not bridged")
+            .unwrap();
+    }
+}
@@ -1,22 +1,41 @@
+use std::os::fd::AsFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use tokio::io;
-use tokio::net::{TcpListener, TcpStream, UnixStream};
-use tokio::task::JoinHandle;
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::Mutex;
+use tokio::task::{JoinHandle, spawn_blocking};
 use tokio::time::sleep;
-use tracing::{info, instrument, warn};
+use tracing::{Instrument, debug, info, instrument, warn};
 
-use crate::config::ForwardSpec;
-use crate::pipeline::{ShutdownRx, copy_bidirectional};
+use crate::config::{ForwardSpec, UdsCompress, UdsType};
+use crate::metrics::Metrics;
+use crate::pipeline::ShutdownRx;
+use crate::pipeline::admission::{Admission, Verdict};
+use crate::pipeline::lifecycle;
+use crate::pipeline::listener::{ClientStream, StreamListener};
+use crate::pipeline::namespace::spawn_rebinding;
+use crate::pipeline::pause::Gate;
+use crate::pipeline::session::{self, Session};
+use crate::pipeline::sniff::{SniffRoutes, sniff};
+use crate::uds::UdsConnection;
+use crate::{compress, fdpass, jail, log_limit, netns, uds, yamux};
 
 const UDS_RETRY_INITIAL_DELAY: Duration = Duration::from_millis(100);
 const UDS_RETRY_MAX_DELAY: Duration = Duration::from_secs(2);
 
 /// Listens for TCP clients on the host and tunnels each session through the configured Unix
-/// Domain Socket. This corresponds to the "host proxy" leg in the docs.
+/// Domain Socket. This corresponds to the "host proxy" leg in the docs. Reverse forwards run the
+/// same loop inside the namespace, so namespace clients reach the host-side endpoint.
 pub fn spawn(spec: ForwardSpec, shutdown: ShutdownRx) -> JoinHandle<Result<()>> {
-    tokio::spawn(async move { host_proxy_loop(spec, shutdown).await })
+    if spec.reverse() && spec.jail().is_none() {
+        spawn_rebinding(spec, shutdown, host_proxy_loop)
+    } else {
+        tokio::spawn(async move { host_proxy_loop(spec, shutdown).await })
+    }
 }
 
 #[instrument(skip_all, fields(listen = spec.listen.as_deref().unwrap_or_default()))]
@@ -25,10 +44,42 @@ async fn host_proxy_loop(spec: ForwardSpec, mut shutdown: ShutdownRx) -> Result<
         .listen
         .as_ref()
         .context("listen address missing for host proxy")?;
-    let listener = TcpListener::bind(listen_addr)
-        .await
-        .with_context(|| format!("failed to bind {}", listen_addr))?;
-    info!(%listen_addr, "host proxy listening");
+    let metrics = Metrics::for_spec(&spec, "host_proxy");
+    // The helper only has to outlive the bind: the listener stays in the jail it was created in.
+    let listener = match spec.jail().filter(|_| spec.reverse()) {
+        Some(jail) => jail::Helper::start(jail)?
+            .listen(listen_addr, spec.ipv6_only)
+            .await
+            .map(StreamListener::Tcp),
+        None => StreamListener::bind(listen_addr, spec.mptcp(), spec.ipv6_only),
+    }
+    .inspect_err(|err| metrics.bind_error(err))?;
+    let endpoint = Endpoint {
+        uds: spec.uds.clone().unwrap_or_default(),
+        kind: spec.uds_type(),
+        compress: spec.uds_compress(),
+        session_id: spec.uds_session_id(),
+        netns: spec.enters_namespace().then(|| Arc::new(spec.clone())),
+        metrics,
+    };
+    let routes = spec
+        .sniffs()
+        .then(|| Arc::new(SniffRoutes::from_spec(&spec)));
+    let mux = spec
+        .muxed()
+        .then(|| Arc::new(MuxConnector::new(endpoint.clone())));
+    let fd_passing = spec.fd_passing();
+    let admission = Admission::for_spec(&spec)?;
+    info!(
+        %listen_addr,
+        muxed = mux.is_some(),
+        fd_passing,
+        sniffing = routes.is_some(),
+        reverse = spec.reverse(),
+        "host proxy listening"
+    );
+    let _lifecycle = lifecycle::up(&spec, listen_addr).await;
+    let mut gate = Gate::for_spec(&spec);
     loop {
         tokio::select! {
             biased;
@@ -38,14 +89,40 @@ async fn host_proxy_loop(spec: ForwardSpec, mut shutdown: ShutdownRx) -> Result<
                     break;
                 }
             }
-            accept_res = listener.accept() => {
+            accept_res = gate.accept(|| listener.accept()) => {
                 let (tcp, peer) = accept_res?;
-                let uds = spec.uds_path().to_path_buf();
+                let endpoint = endpoint.clone();
+                let mux = mux.clone();
+                let routes = routes.clone();
+                let admission = admission.clone();
+                let session = Session::new(&spec, Some(&peer));
+                let span = session.span();
                 tokio::spawn(async move {
-                    if let Err(err) = bridge_tcp_to_unix(tcp, uds).await {
-                        warn!(peer = %peer, error = %err, "session failed");
+                    match admission.check(&peer).await {
+                        Verdict::Accept => {}
+                        Verdict::Reject => {
+                            info!(peer = %peer, "connection rejected by admission hook");
+                            session.reject(&endpoint.uds.display().to_string());
+                            return;
+                        }
+                        Verdict::Target(_) => {
+                            warn!(peer = %peer, "admission hook returned a target, but host proxies have none to override; rejecting");
+                            session.reject(&endpoint.uds.display().to_string());
+                            return;
+                        }
                     }
-                });
+                    let _active = endpoint.metrics.session();
+                    let target = endpoint.uds.display().to_string();
+                    let res = session.run(&target, async {
+                        match (routes, mux) {
+                            (Some(routes), _) => bridge_sniffed(tcp, &routes, endpoint).await,
+                            (None, Some(mux)) => bridge_tcp_to_mux(tcp, &mux).await,
+                            (None, None) if fd_passing => bridge_tcp_to_passed_fd(tcp, &endpoint).await,
+                            (None, None) => bridge_tcp_to_unix(tcp, &endpoint).await,
+                        }
+                    }).await;
+                    session.close(&target, &res);
+                }.instrument(span));
             }
         }
     }
@@ -54,26 +131,153 @@ async fn host_proxy_loop(spec: ForwardSpec, mut shutdown: ShutdownRx) -> Result<
 
 /// Establish a Unix stream to the namespace endpoint and ferry traffic between it and the original
 /// TCP client.
-async fn bridge_tcp_to_unix(mut tcp: TcpStream, uds: std::path::PathBuf) -> Result<()> {
+async fn bridge_tcp_to_unix(mut tcp: ClientStream, endpoint: &Endpoint) -> Result<(u64, u64)> {
     tcp.set_nodelay(true).ok();
+    let mut unix = endpoint.connect().await?;
+    if endpoint.session_id {
+        session::send_id(&mut unix).await?;
+    }
+    let mut unix = compress::offer(unix, endpoint.compress).await?;
+    let res = session::relay(&mut tcp, &mut unix, &endpoint.metrics).await;
+    session::record_tcp_info(tcp.socket_fd(), None, &endpoint.metrics);
+    res
+}
+
+/// Route the client to the backend for the protocol its first bytes announce.
+async fn bridge_sniffed(
+    tcp: ClientStream,
+    routes: &SniffRoutes,
+    endpoint: Endpoint,
+) -> Result<(u64, u64)> {
+    let protocol = sniff(&tcp).await?;
+    let Some(uds) = routes.route(protocol) else {
+        bail!("no uds backend for {protocol:?} clients and no default `uds`");
+    };
+    debug!(?protocol, uds = %uds.display(), "routing sniffed connection");
+    let endpoint = Endpoint {
+        uds: uds.to_path_buf(),
+        ..endpoint
+    };
+    bridge_tcp_to_unix(tcp, &endpoint).await
+}
+
+/// Open a yamux stream on the shared Unix connection and ferry the TCP client through it.
+async fn bridge_tcp_to_mux(mut tcp: ClientStream, mux: &MuxConnector) -> Result<(u64, u64)> {
+    tcp.set_nodelay(true).ok();
+    let mut stream = mux.open().await?;
+    if mux.endpoint.session_id {
+        session::send_id(&mut stream).await?;
+    }
+    let res = session::relay(&mut tcp, &mut stream, &mux.endpoint.metrics).await;
+    session::record_tcp_info(tcp.socket_fd(), None, &mux.endpoint.metrics);
+    res
+}
+
+/// Ask the namespace endpoint for a connected target socket over SCM_RIGHTS and copy between it and
+/// the client directly, so bytes no longer cross the UDS.
+async fn bridge_tcp_to_passed_fd(mut tcp: ClientStream, endpoint: &Endpoint) -> Result<(u64, u64)> {
+    tcp.set_nodelay(true).ok();
+    let mut unix = endpoint.connect_stream().await?;
+    if endpoint.session_id {
+        session::send_id(&mut unix).await?;
+    }
+    let fd = fdpass::recv_fd(&unix)
+        .await
+        .context("failed to receive target socket from namespace endpoint")?
+        .context("namespace endpoint could not connect to its target")?;
+    let upstream = std::net::TcpStream::from(fd);
+    upstream.set_nonblocking(true)?;
+    let mut upstream = TcpStream::from_std(upstream)?;
+    drop(unix);
+    let res = session::relay(&mut tcp, &mut upstream, &endpoint.metrics).await;
+    session::record_tcp_info(tcp.socket_fd(), Some(upstream.as_fd()), &endpoint.metrics);
+    res
+}
+
+/// The namespace endpoint's socket as the host proxy dials it.
+#[derive(Clone)]
+struct Endpoint {
+    uds: PathBuf,
+    kind: UdsType,
+    compress: UdsCompress,
+    /// Whether each session's stream starts with its ID (`uds_session_id`).
+    session_id: bool,
+    /// Spec whose namespace an abstract `@name` socket lives in; abstract sockets are scoped to a
+    /// network namespace, so they are dialed from inside it.
+    netns: Option<Arc<ForwardSpec>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Endpoint {
+    async fn connect(&self) -> Result<UdsConnection> {
+        let connect = connect_with_backoff(
+            &self.uds,
+            self.kind,
+            self.netns.as_ref(),
+            Some(&self.metrics),
+        )
+        .instrument(session::uds_connect_span(&self.uds.display()));
+        self.metrics.uds_connect(connect).await
+    }
+
+    /// Stream connection for the modes that need a `UnixStream` (mux, fd passing).
+    async fn connect_stream(&self) -> Result<UnixStream> {
+        match self.connect().await? {
+            UdsConnection::Stream(stream) => Ok(stream),
+            UdsConnection::Seqpacket(_) => bail!("this mode needs a stream uds"),
+        }
+    }
+}
+
+/// Connect to a stream UDS, backing off while the socket file (or abstract name) does not exist
+/// yet. Retries are counted in `metrics` when given.
+pub(crate) async fn connect_uds(
+    uds: &Path,
+    netns: Option<&Arc<ForwardSpec>>,
+    metrics: Option<&Metrics>,
+) -> Result<UnixStream> {
+    match connect_with_backoff(uds, UdsType::Stream, netns, metrics).await? {
+        UdsConnection::Stream(stream) => Ok(stream),
+        UdsConnection::Seqpacket(_) => unreachable!("dialed a stream socket"),
+    }
+}
+
+async fn connect_with_backoff(
+    uds: &Path,
+    kind: UdsType,
+    netns: Option<&Arc<ForwardSpec>>,
+    metrics: Option<&Metrics>,
+) -> Result<UdsConnection> {
     let mut delay = UDS_RETRY_INITIAL_DELAY;
     let mut attempts = 0u32;
-    let mut unix = loop {
-        match UnixStream::connect(&uds).await {
+    loop {
+        match dial_uds(uds, kind, netns).await {
             Ok(stream) => {
                 if attempts > 0 {
                     info!(uds = %uds.display(), attempts, "uds became available");
                 }
-                break stream;
+                return Ok(stream);
             }
-            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            Err(err)
+                if err.kind() == io::ErrorKind::NotFound
+                    || (err.kind() == io::ErrorKind::ConnectionRefused
+                        && uds::abstract_name(uds).is_some()) =>
+            {
                 attempts += 1;
-                warn!(
-                    uds = %uds.display(),
-                    attempts,
-                    wait_ms = delay.as_millis() as u64,
-                    "uds not found; backing off"
-                );
+                if let Some(metrics) = metrics {
+                    metrics.uds_retry();
+                }
+                if let Some(suppressed) =
+                    log_limit::allow("uds not found; backing off", uds.display())
+                {
+                    warn!(
+                        uds = %uds.display(),
+                        attempts,
+                        wait_ms = delay.as_millis() as u64,
+                        suppressed,
+                        "uds not found; backing off"
+                    );
+                }
                 sleep(delay).await;
                 delay = delay.saturating_mul(2);
                 if delay > UDS_RETRY_MAX_DELAY {
@@ -82,7 +286,69 @@ async fn bridge_tcp_to_unix(mut tcp: TcpStream, uds: std::path::PathBuf) -> Resu
             }
             Err(err) => return Err(err.into()),
         }
+    }
+}
+
+async fn dial_uds(
+    uds: &Path,
+    kind: UdsType,
+    netns: Option<&Arc<ForwardSpec>>,
+) -> io::Result<UdsConnection> {
+    let is_abstract = uds::abstract_name(uds).is_some();
+    if kind == UdsType::Stream && !is_abstract {
+        return UnixStream::connect(uds).await.map(UdsConnection::Stream);
+    }
+    let socket = match netns.filter(|_| is_abstract) {
+        Some(spec) => {
+            let spec = spec.clone();
+            let uds = uds.to_path_buf();
+            spawn_blocking(move || {
+                netns::within(&spec, || Ok(uds::connect_socket(&uds, kind)))
+                    .map_err(io::Error::other)?
+            })
+            .await
+            .map_err(io::Error::other)??
+        }
+        None => uds::connect_socket(uds, kind)?,
     };
-    copy_bidirectional(&mut tcp, &mut unix).await?;
-    Ok(())
+    UdsConnection::from_socket(socket, kind)
+}
+
+/// Holds the long-lived yamux session to the namespace endpoint, reconnecting lazily whenever the
+/// previous connection has gone away.
+struct MuxConnector {
+    endpoint: Endpoint,
+    session: Mutex<Option<yamux::Session>>,
+}
+
+impl MuxConnector {
+    fn new(endpoint: Endpoint) -> Self {
+        Self {
+            endpoint,
+            session: Mutex::new(None),
+        }
+    }
+
+    async fn open(&self) -> Result<io::DuplexStream> {
+        if let Some(existing) = self.session.lock().await.as_ref()
+            && !existing.is_closed()
+        {
+            return existing.open();
+        }
+        // Dial without the lock, so waiting on an endpoint that is down only holds up this
+        // session, as it would without `mux`.
+        let unix = self.endpoint.connect_stream().await?;
+        let mut session = self.session.lock().await;
+        // Another session reconnected first; share its connection and drop this one.
+        if let Some(existing) = session.as_ref()
+            && !existing.is_closed()
+        {
+            return existing.open();
+        }
+        info!(uds = %self.endpoint.uds.display(), "established multiplexed uds session");
+        let fresh = yamux::Session::new(unix, yamux::Mode::Client);
+        let stream = fresh.open();
+        *session = Some(fresh);
+        stream
+    }
 }
@@ -0,0 +1,165 @@
+//! `on_up` / `on_down` hooks: commands run when one of a forward's listeners has bound and when
+//! that listener is torn down again, e.g. to open a firewall port or register with service
+//! discovery.
+//!
+//! Each listener a forward opens fires its own pair, with `PFWD_LISTEN` naming the address or
+//! socket path. Commands inherit the listener's context, so for a namespace endpoint they run
+//! inside the namespace. Like `target_exec`, command lines are split on whitespace without shell
+//! quoting. A failing hook is logged and does not stop the forward.
+//!
+//! The listener waits for `on_up` before it accepts; `on_down` runs in the background, since it
+//! fires while the listener is dropped. Either is killed if it runs past [`HOOK_TIMEOUT`], so a hung
+//! hook cannot stall the forward, or the namespace runtime it shares with its neighbours. pfwd
+//! waits for the `on_down` hooks still running before it exits (see [`finished`]).
+
+use std::fmt::Display;
+use std::io;
+use std::process::{ExitStatus, Stdio};
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use tokio::process::{Child, Command};
+use tokio::runtime::Handle;
+use tokio::sync::watch;
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+use crate::config::ForwardSpec;
+use crate::status::{self, Registration};
+
+/// How long a hook may run before it is killed.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of `on_down` hooks running in the background.
+static RUNNING: LazyLock<watch::Sender<usize>> = LazyLock::new(|| watch::Sender::new(0));
+
+/// Counts a background hook in [`RUNNING`] until dropped.
+struct Running;
+
+impl Running {
+    fn start() -> Self {
+        RUNNING.send_modify(|running| *running += 1);
+        Running
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.send_modify(|running| *running -= 1);
+    }
+}
+
+/// Wait until no `on_down` hook is running in the background. Each is killed past
+/// [`HOOK_TIMEOUT`], so this returns within that long of the last listener going down.
+pub async fn finished() {
+    let _ = RUNNING.subscribe().wait_for(|&running| running == 0).await;
+}
+
+/// Holds a listener's `on_down` until it is dropped along with the listener's loop, and keeps it
+/// in the [`status`] listing meanwhile.
+pub struct Lifecycle {
+    down: Option<Hook>,
+    _status: Registration,
+}
+
+struct Hook {
+    command: String,
+    label: String,
+    listen: String,
+}
+
+impl Hook {
+    /// Start the command on the calling thread, so it inherits the thread's namespace.
+    fn spawn(&self, event: &str) -> Option<Child> {
+        let mut argv = self.command.split_whitespace();
+        let program = argv.next()?;
+        let child = Command::new(program)
+            .args(argv)
+            .env("PFWD_EVENT", event)
+            .env("PFWD_LABEL", &self.label)
+            .env("PFWD_LISTEN", &self.listen)
+            .stdin(Stdio::null())
+            .spawn();
+        match child {
+            Ok(child) => Some(child),
+            Err(err) => {
+                warn!(event, listen = %self.listen, error = %err, "failed to run lifecycle hook");
+                None
+            }
+        }
+    }
+
+    /// Run to completion, killing the command past [`HOOK_TIMEOUT`].
+    async fn run(&self, event: &str) {
+        let Some(mut child) = self.spawn(event) else {
+            return;
+        };
+        self.wait(event, &mut child).await;
+    }
+
+    /// Run in the background, for teardown, which cannot wait.
+    fn run_detached(self, event: &'static str) {
+        if Handle::try_current().is_err() {
+            warn!(event, listen = %self.listen, "lifecycle hook skipped: runtime is gone");
+            return;
+        }
+        let Some(mut child) = self.spawn(event) else {
+            return;
+        };
+        let running = Running::start();
+        tokio::spawn(async move {
+            self.wait(event, &mut child).await;
+            drop(running);
+        });
+    }
+
+    async fn wait(&self, event: &str, child: &mut Child) {
+        let status = match timeout(HOOK_TIMEOUT, child.wait()).await {
+            Ok(status) => status,
+            Err(_) => {
+                let _ = child.start_kill();
+                warn!(event, listen = %self.listen, timeout = ?HOOK_TIMEOUT, "lifecycle hook timed out; killed it");
+                return;
+            }
+        };
+        self.report(event, status);
+    }
+
+    fn report(&self, event: &str, status: io::Result<ExitStatus>) {
+        match status {
+            Ok(status) if status.success() => {
+                debug!(event, listen = %self.listen, "lifecycle hook finished");
+            }
+            Ok(status) => {
+                warn!(event, listen = %self.listen, %status, "lifecycle hook failed");
+            }
+            Err(err) => {
+                warn!(event, listen = %self.listen, error = %err, "failed to run lifecycle hook");
+            }
+        }
+    }
+}
+
+/// Run the spec's `on_up` for a listener that just bound `listen`, and arm its `on_down`.
+pub async fn up(spec: &ForwardSpec, listen: impl Display) -> Lifecycle {
+    let hook = |command: &String| Hook {
+        command: command.clone(),
+        label: spec.label.clone().unwrap_or_default(),
+        listen: listen.to_string(),
+    };
+    if let Some(on_up) = spec.on_up.as_ref() {
+        hook(on_up).run("up").await;
+    }
+    Lifecycle {
+        down: spec.on_down.as_ref().map(hook),
+        _status: status::register(spec, listen.to_string()),
+    }
+}
+
+impl Drop for Lifecycle {
+    fn drop(&mut self) {
+        if let Some(down) = self.down.take() {
+            down.run_detached("down");
+        }
+    }
+}
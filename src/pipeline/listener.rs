@@ -0,0 +1,174 @@
+//! Accept side of stream forwards: TCP (optionally MPTCP or SCTP) or AF_VSOCK.
+
+use std::fmt;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::fd::{AsFd, BorrowedFd};
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::addr::HostPort;
+use crate::vsock::{VsockAddr, VsockListener, VsockStream};
+use crate::{mptcp, sctp};
+
+/// Accept backlog for stream listeners.
+const STREAM_BACKLOG: u32 = 1024;
+
+/// TCP (or MPTCP, see [`crate::mptcp`]) listener built through socket2 so IPv6 sockets get
+/// `IPV6_V6ONLY` set explicitly: off, making `[::]` dual-stack, unless `ipv6_only` is true. The
+/// result then does not depend on the `net.ipv6.bindv6only` sysctl.
+pub fn bind_tcp(listen: &str, mptcp: bool, ipv6_only: Option<bool>) -> Result<TcpListener> {
+    let local = match listen.parse::<HostPort>()? {
+        HostPort::Ip(addr) => addr,
+        named => named
+            .to_string()
+            .to_socket_addrs()
+            .with_context(|| format!("failed to resolve listen address {listen}"))?
+            .next()
+            .with_context(|| format!("listen address {listen} resolved to no addresses"))?,
+    };
+    let socket = if mptcp {
+        mptcp::stream_socket(local)?
+    } else {
+        Socket::new(
+            Domain::for_address(local),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )
+        .context("failed to create tcp socket")?
+    };
+    if local.is_ipv6() {
+        socket
+            .set_only_v6(ipv6_only.unwrap_or(false))
+            .context("failed to set IPV6_V6ONLY")?;
+    }
+    socket.set_reuse_address(true)?;
+    socket
+        .bind(&local.into())
+        .with_context(|| format!("failed to bind {listen}"))?;
+    socket.listen(STREAM_BACKLOG.try_into().unwrap_or(i32::MAX))?;
+    socket.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+pub enum StreamListener {
+    Tcp(TcpListener),
+    Vsock(VsockListener),
+}
+
+impl StreamListener {
+    /// Bind `listen`; `sctp://` addresses get an SCTP listener, `vsock:` addresses an AF_VSOCK
+    /// one, and tcp addresses go through [`bind_tcp`].
+    pub fn bind(listen: &str, mptcp: bool, ipv6_only: Option<bool>) -> Result<Self> {
+        if let Some(addr) = VsockAddr::parse(listen)? {
+            return Ok(Self::Vsock(VsockListener::bind(addr, STREAM_BACKLOG)?));
+        }
+        if let Some(addr) = sctp::strip_scheme(listen) {
+            return Ok(Self::Tcp(sctp::bind_listener(addr, STREAM_BACKLOG)?));
+        }
+        Ok(Self::Tcp(bind_tcp(listen, mptcp, ipv6_only)?))
+    }
+
+    pub async fn accept(&self) -> io::Result<(ClientStream, PeerAddr)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((ClientStream::Tcp(stream), PeerAddr::Inet(peer)))
+            }
+            Self::Vsock(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((ClientStream::Vsock(stream), PeerAddr::Vsock(peer)))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum PeerAddr {
+    Inet(SocketAddr),
+    Vsock(VsockAddr),
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Inet(addr) => addr.fmt(f),
+            PeerAddr::Vsock(addr) => addr.fmt(f),
+        }
+    }
+}
+
+/// Accepted client connection.
+pub enum ClientStream {
+    Tcp(TcpStream),
+    Vsock(VsockStream),
+}
+
+impl ClientStream {
+    /// Copy queued bytes into `buf` without consuming them, waiting until at least one arrives.
+    pub async fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Tcp(tcp) => tcp.peek(buf).await,
+            ClientStream::Vsock(vsock) => vsock.peek(buf).await,
+        }
+    }
+
+    /// Socket of a TCP client, for [`record_tcp_info`](crate::pipeline::session::record_tcp_info).
+    pub fn socket_fd(&self) -> Option<BorrowedFd<'_>> {
+        match self {
+            ClientStream::Tcp(tcp) => Some(tcp.as_fd()),
+            ClientStream::Vsock(_) => None,
+        }
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(tcp) => tcp.set_nodelay(nodelay),
+            ClientStream::Vsock(_) => Ok(()),
+        }
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(tcp) => Pin::new(tcp).poll_read(cx, buf),
+            ClientStream::Vsock(vsock) => Pin::new(vsock).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Tcp(tcp) => Pin::new(tcp).poll_write(cx, buf),
+            ClientStream::Vsock(vsock) => Pin::new(vsock).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(tcp) => Pin::new(tcp).poll_flush(cx),
+            ClientStream::Vsock(vsock) => Pin::new(vsock).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(tcp) => Pin::new(tcp).poll_shutdown(cx),
+            ClientStream::Vsock(vsock) => Pin::new(vsock).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Shadow traffic: copy the client→target byte stream of each session to a `mirror_target`.
+//!
+//! Mirroring is fire-and-forget. Each session gets its own connection to the mirror target, fed
+//! from a bounded queue; whatever the mirror sends back is discarded. When the mirror falls behind
+//! and the queue fills up, that session stops being mirrored instead of slowing the real one.
+
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+use crate::config::ForwardSpec;
+use crate::target::Connector;
+
+/// Chunks (one per read from the client, at most 8 KiB with the relay's copy buffer) queued for
+/// the mirror before the session stops being mirrored.
+const MIRROR_QUEUE: usize = 1024;
+
+/// Dials the mirror target for each session of one forward.
+#[derive(Clone)]
+pub struct Mirror {
+    connector: Connector,
+}
+
+impl Mirror {
+    pub fn for_spec(spec: &ForwardSpec) -> Option<Self> {
+        let target = spec.mirror_target.clone()?;
+        Some(Self {
+            connector: Connector::new(target, spec.mptcp()),
+        })
+    }
+
+    /// Start mirroring one session and return the sender its client bytes go to.
+    fn start(&self) -> mpsc::Sender<Vec<u8>> {
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(MIRROR_QUEUE);
+        let connector = self.connector.clone();
+        tokio::spawn(async move {
+            let stream = match connector.connect().await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    debug!(mirror = connector.target(), error = %err, "mirror connect failed");
+                    return;
+                }
+            };
+            let (mut reader, mut writer) = io::split(stream);
+            let discard = tokio::spawn(async move {
+                io::copy(&mut reader, &mut io::sink()).await.ok();
+            });
+            while let Some(chunk) = rx.recv().await {
+                if writer.write_all(&chunk).await.is_err() {
+                    break;
+                }
+            }
+            writer.shutdown().await.ok();
+            discard.await.ok();
+        });
+        tx
+    }
+}
+
+/// Client-side stream whose reads are also queued for the mirror, if any.
+pub struct Tapped<S> {
+    inner: S,
+    mirror: Option<mpsc::Sender<Vec<u8>>>,
+}
+
+/// Wrap the client leg of a session; without a mirror the stream is passed through untouched.
+pub fn tap<S>(inner: S, mirror: Option<&Mirror>) -> Tapped<S> {
+    Tapped {
+        inner,
+        mirror: mirror.map(Mirror::start),
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tapped<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(tx)) = (&res, this.mirror.as_ref()) {
+            let read = &buf.filled()[before..];
+            if read.is_empty() {
+                this.mirror = None;
+            } else if let Err(err) = tx.try_send(read.to_vec()) {
+                if matches!(err, mpsc::error::TrySendError::Full(_)) {
+                    debug!("mirror fell behind; no longer mirroring this session");
+                }
+                this.mirror = None;
+            }
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tapped<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -1,7 +1,30 @@
+pub mod admission;
+pub mod broadcast;
+pub mod capture;
+pub mod chaos;
+pub mod dns;
+pub mod dns_cache;
+pub mod ftp;
 pub mod host;
+pub mod lifecycle;
+pub mod listener;
+pub mod mirror;
+pub mod multicast;
 pub mod namespace;
+pub mod netns_runtime;
+pub mod netns_watch;
+pub mod pause;
+pub mod record;
+pub mod relay;
+pub mod session;
+pub mod shape;
+pub mod sniff;
 pub mod tcp;
 pub mod udp;
+pub mod udp_cid;
+pub mod udp_host;
+pub mod udp_namespace;
+pub mod uds_relay;
 
 use anyhow::Result;
 use tokio::signal;
@@ -30,11 +53,12 @@ pub fn spawn_shutdown_listener(shutdown: ShutdownTx) -> JoinHandle<()> {
     })
 }
 
-pub async fn copy_bidirectional<A, B>(a: &mut A, b: &mut B) -> Result<()>
+/// Copy between `a` and `b` until both directions are done; returns the bytes moved `a` → `b`
+/// and `b` → `a`.
+pub async fn copy_bidirectional<A, B>(a: &mut A, b: &mut B) -> Result<(u64, u64)>
 where
     A: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
     B: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
 {
-    tokio::io::copy_bidirectional(a, b).await?;
-    Ok(())
+    Ok(tokio::io::copy_bidirectional(a, b).await?)
 }
@@ -0,0 +1,95 @@
+//! Multicast-group UDP listeners, written as `group:port@interface` (e.g. `239.1.1.1:5353@eth0`).
+//!
+//! The socket binds the wildcard address on the group's port and joins the group on the named
+//! interface, so datagrams sent to the group reach the proxy while responses it sends back to
+//! clients leave with an ordinary unicast source address.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{Context, Result, bail};
+use nix::net::if_::if_nametoindex;
+use socket2::{Domain, InterfaceIndexOrAddress, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MulticastListen {
+    pub group: SocketAddr,
+    pub interface: Option<String>,
+}
+
+impl MulticastListen {
+    /// Returns `Ok(None)` for ordinary (unicast or hostname) listen addresses.
+    pub fn parse(listen: &str) -> Result<Option<Self>> {
+        let (addr, interface) = match listen.split_once('@') {
+            Some((addr, interface)) if !interface.is_empty() => (addr, Some(interface)),
+            Some(_) => bail!("missing interface name after `@` in {listen}"),
+            None => (listen, None),
+        };
+        let group = match addr.parse::<SocketAddr>() {
+            Ok(group) => group,
+            Err(_) if interface.is_none() => return Ok(None),
+            Err(err) => return Err(err).with_context(|| format!("invalid multicast group {addr}")),
+        };
+        if !group.ip().is_multicast() {
+            if interface.is_some() {
+                bail!(
+                    "{addr} is not a multicast group; `@interface` only applies to multicast listeners"
+                );
+            }
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            group,
+            interface: interface.map(str::to_string),
+        }))
+    }
+
+    pub fn bind(&self) -> Result<UdpSocket> {
+        let ifindex = match self.interface.as_deref() {
+            Some(name) => if_nametoindex(name)
+                .with_context(|| format!("unknown interface {name} for multicast listener"))?,
+            None => 0,
+        };
+        let domain = Domain::for_address(self.group);
+        let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))
+            .context("failed to create multicast socket")?;
+        // Other listeners (e.g. a local mDNS responder) commonly hold the same group port.
+        socket.set_reuse_address(true)?;
+        socket.set_reuse_port(true)?;
+        let wildcard: IpAddr = match self.group.ip() {
+            IpAddr::V4(group) => {
+                socket
+                    .join_multicast_v4_n(&group, &InterfaceIndexOrAddress::Index(ifindex))
+                    .with_context(|| format!("failed to join multicast group {group}"))?;
+                Ipv4Addr::UNSPECIFIED.into()
+            }
+            IpAddr::V6(group) => {
+                socket.set_only_v6(true)?;
+                socket
+                    .join_multicast_v6(&group, ifindex)
+                    .with_context(|| format!("failed to join multicast group {group}"))?;
+                Ipv6Addr::UNSPECIFIED.into()
+            }
+        };
+        if let Some(name) = self.interface.as_deref() {
+            socket
+                .bind_device(Some(name.as_bytes()))
+                .with_context(|| format!("failed to bind multicast listener to {name}"))?;
+        }
+        socket
+            .bind(&SocketAddr::new(wildcard, self.group.port()).into())
+            .with_context(|| format!("failed to bind multicast listener {}", self.group))?;
+        socket.set_nonblocking(true)?;
+        Ok(UdpSocket::from_std(socket.into())?)
+    }
+}
+
+/// Bind a UDP listener, joining a multicast group when `listen` names one.
+pub async fn bind_udp_listener(listen: &str) -> Result<UdpSocket> {
+    match MulticastListen::parse(listen)? {
+        Some(multicast) => multicast.bind(),
+        None => UdpSocket::bind(listen)
+            .await
+            .with_context(|| format!("failed to bind udp listener {}", listen)),
+    }
+}
@@ -1,45 +1,215 @@
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result};
-use tokio::net::{TcpStream, UnixStream};
-use tokio::runtime::Builder;
+use anyhow::{Context, Result, bail};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::UnixStream;
 use tokio::task::{JoinHandle, spawn_blocking};
-use tracing::{info, warn};
+use tokio::time::interval;
+use tracing::{Instrument, info};
 
-use crate::config::ForwardSpec;
-use crate::netns;
-use crate::pipeline::{ShutdownRx, copy_bidirectional};
-use crate::uds::{BoundUnixListener, bind_listener};
+use crate::config::{ForwardSpec, UdsCompress};
+use crate::metrics::Metrics;
+use crate::netns::NetnsId;
+use crate::pipeline::lifecycle;
+use crate::pipeline::netns_runtime::NamespaceRuntime;
+use crate::pipeline::pause::Gate;
+use crate::pipeline::relay::Relay;
+use crate::pipeline::session::{self, Session};
+use crate::pipeline::{self, ShutdownRx, ShutdownTx};
+use crate::target::Connector;
+use crate::uds::{BoundUnixListener, UdsConnection, bind_listener};
+use crate::yamux;
+use crate::{compress, fdpass, jail, netns};
 
 const DEFAULT_BACKLOG: u32 = 64;
+/// How often a rebinding endpoint checks that the spec still resolves to the namespace it joined.
+const NETNS_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A namespace as a spec names it. Forwards naming the same one share a single check.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Named {
+    setns_path: Option<PathBuf>,
+    setns_pid: Option<u32>,
+    namespace: Option<String>,
+    netns_dir: PathBuf,
+}
+
+/// Last resolution of one [`Named`] namespace and when it was made.
+type Check = Arc<tokio::sync::Mutex<Option<(Instant, Option<NetnsId>)>>>;
+
+static CHECKS: LazyLock<Mutex<HashMap<Named, Check>>> = LazyLock::new(Default::default);
 
 /// Enters the requested network namespace, binds the Unix Domain Socket, and forwards each accepted
-/// UDS stream into the target TCP service inside the namespace.
+/// UDS stream into the target TCP service inside the namespace. Host UDS endpoints (no namespace, or
+/// the host side of a reverse forward) run the same loop on the main runtime.
 pub fn spawn(spec: ForwardSpec, shutdown: ShutdownRx) -> JoinHandle<Result<()>> {
-    spawn_blocking(move || {
-        netns::maybe_enter(&spec)?;
-        let rt = Builder::new_current_thread()
-            .enable_all()
-            .build()
-            .context("failed to build namespace runtime")?;
-        rt.block_on(namespace_loop(spec, shutdown))
+    // A jail is reached through its helper rather than joined; see [`crate::jail`].
+    if spec.requires_namespace_endpoint() && spec.jail().is_none() {
+        spawn_rebinding(spec, shutdown, namespace_loop)
+    } else {
+        tokio::spawn(namespace_loop(spec, shutdown))
+    }
+}
+
+/// Run `endpoint` on the runtime thread of the spec's network namespace (see
+/// [`crate::pipeline::netns_runtime`]), so the rest of the process stays in the root namespace.
+pub fn spawn_in_namespace<F, Fut>(
+    spec: ForwardSpec,
+    shutdown: ShutdownRx,
+    endpoint: F,
+) -> JoinHandle<Result<()>>
+where
+    F: FnOnce(ForwardSpec, ShutdownRx) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<()>> + 'static,
+{
+    tokio::spawn(async move {
+        let probe = spec.clone();
+        let runtime = spawn_blocking(move || NamespaceRuntime::for_spec(&probe)).await??;
+        runtime.run(move || endpoint(spec, shutdown)).await
+    })
+}
+
+/// Like [`spawn_in_namespace`], for endpoints that bind their sockets inside the namespace. If the
+/// namespace is deleted and recreated (or a container restarts with a fresh one), the endpoint is
+/// stopped and `endpoint` runs again on the new namespace's thread to rebind there, instead of
+/// serving the dead namespace until pfwd restarts.
+pub fn spawn_rebinding<F, Fut>(
+    spec: ForwardSpec,
+    mut shutdown: ShutdownRx,
+    endpoint: F,
+) -> JoinHandle<Result<()>>
+where
+    F: Fn(ForwardSpec, ShutdownRx) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + 'static,
+{
+    let endpoint = Arc::new(endpoint);
+    tokio::spawn(async move {
+        loop {
+            let probe = spec.clone();
+            let runtime = spawn_blocking(move || NamespaceRuntime::for_spec(&probe)).await??;
+            let entered = runtime.id();
+            let (stop_tx, stop_rx) = pipeline::shutdown_channel();
+            let (endpoint, instance) = (endpoint.clone(), spec.clone());
+            let run = runtime.run(move || endpoint(instance, stop_rx));
+            if !run_until_replaced(&spec, &mut shutdown, entered, run, stop_tx).await? {
+                return Ok(());
+            }
+        }
     })
 }
 
+/// Drive `run` until it finishes or shutdown is requested (`false`), or until the spec resolves to
+/// a namespace other than `entered` (`true`, after `stop` has wound the endpoint down).
+async fn run_until_replaced(
+    spec: &ForwardSpec,
+    shutdown: &mut ShutdownRx,
+    entered: NetnsId,
+    run: impl Future<Output = Result<()>>,
+    stop: ShutdownTx,
+) -> Result<bool> {
+    tokio::pin!(run);
+    let mut check = interval(NETNS_CHECK_INTERVAL);
+    check.tick().await;
+    loop {
+        tokio::select! {
+            res = &mut run => return res.map(|()| false),
+            res = shutdown.changed() => {
+                if res.is_err() || *shutdown.borrow() {
+                    let _ = stop.send(true);
+                    return run.await.map(|()| false);
+                }
+            }
+            _ = check.tick() => {
+                if let Some(current) = current_namespace(spec).await
+                    && current != entered
+                {
+                    info!(
+                        label = spec.label.as_deref().unwrap_or("unnamed"),
+                        old = %entered,
+                        new = %current,
+                        "namespace was recreated; rebinding"
+                    );
+                    let _ = stop.send(true);
+                    run.await?;
+                    return Ok(true);
+                }
+            }
+        }
+    }
+}
+
+/// Namespace `spec` resolves to now. Resolving may ask a container runtime, so it happens at most
+/// once per [`NETNS_CHECK_INTERVAL`] for all forwards naming the same namespace; the rest reuse
+/// that answer.
+async fn current_namespace(spec: &ForwardSpec) -> Option<NetnsId> {
+    let named = Named {
+        setns_path: spec.setns_path.clone(),
+        setns_pid: spec.setns_pid,
+        namespace: spec.namespace.clone(),
+        netns_dir: spec.netns_dir().to_path_buf(),
+    };
+    let check = {
+        let mut checks = CHECKS.lock().expect("namespace check registry poisoned");
+        if !checks.contains_key(&named) {
+            checks.retain(|_, check| Arc::strong_count(check) > 1 || fresh(check));
+        }
+        checks.entry(named).or_default().clone()
+    };
+    let mut last = check.lock().await;
+    if let Some((at, id)) = *last
+        && at.elapsed() < NETNS_CHECK_INTERVAL
+    {
+        return id;
+    }
+    let probe = spec.clone();
+    let id = spawn_blocking(move || netns::resolve_id(&probe))
+        .await
+        .ok()
+        .flatten();
+    *last = Some((Instant::now(), id));
+    id
+}
+
+/// Whether `check` was resolved recently enough that a forward may still be using it.
+fn fresh(check: &Check) -> bool {
+    check
+        .try_lock()
+        .map(|last| last.is_some_and(|(at, _)| at.elapsed() < 2 * NETNS_CHECK_INTERVAL))
+        .unwrap_or(true)
+}
+
 async fn namespace_loop(spec: ForwardSpec, shutdown: ShutdownRx) -> Result<()> {
     let spec = Arc::new(spec);
     let uds_path = spec.uds_path().to_path_buf();
     let backlog = spec.backlog.unwrap_or(DEFAULT_BACKLOG);
-    let owner = spec.owner.clone();
     let mode = spec.mode;
-    let guard = bind_listener(&uds_path, owner, mode)?;
+    let (mount_ns, owner) = {
+        let spec = spec.clone();
+        spawn_blocking(move || {
+            anyhow::Ok((
+                netns::desired_mount_ns(&spec)?,
+                netns::desired_owner(&spec)?,
+            ))
+        })
+        .await??
+    };
+    let guard = bind_listener(&uds_path, spec.uds_type(), mount_ns.as_deref(), owner, mode)
+        .inspect_err(|err| Metrics::for_spec(&spec, "namespace_endpoint").bind_error(err))?;
     info!(
         label = spec.label.as_deref().unwrap_or("unnamed"),
         uds = %uds_path.display(),
+        uds_mount_ns = ?mount_ns,
         target = spec.target.as_deref().unwrap_or(""),
+        target_exec = spec.target_exec.as_deref().unwrap_or(""),
         backlog,
+        muxed = spec.muxed(),
+        fd_passing = spec.fd_passing(),
         "namespace endpoint listening"
     );
+    let _lifecycle = lifecycle::up(&spec, uds_path.display()).await;
 
     namespace_accept_loop(guard, spec, shutdown).await
 }
@@ -49,6 +219,15 @@ async fn namespace_accept_loop(
     spec: Arc<ForwardSpec>,
     mut shutdown: ShutdownRx,
 ) -> Result<()> {
+    let mut connector =
+        Connector::for_spec(&spec).context("namespace endpoint requires a target")?;
+    if let Some(jail) = spec.jail().filter(|_| spec.requires_namespace_endpoint()) {
+        connector = connector.through_jail(jail::Helper::start(jail)?);
+    }
+    let _pool = connector.warm_pool();
+    let relay = Relay::for_spec(&spec, "namespace_endpoint")?;
+    let session_id = spec.uds_session_id();
+    let mut gate = Gate::for_spec(&spec);
     loop {
         tokio::select! {
             biased;
@@ -58,31 +237,148 @@ async fn namespace_accept_loop(
                     break;
                 }
             }
-            accept_res = guard.accept() => {
-                let (stream, _) = accept_res?;
-                let target = spec
-                    .target
-                    .clone()
-                    .expect("validated target missing unexpectedly");
-                let spec_label = spec.label.clone();
-                tokio::spawn(async move {
-                    if let Err(err) = bridge_unix_to_tcp(stream, target).await {
-                        warn!(label = spec_label.as_deref().unwrap_or("unnamed"), error = %err, "bridge failed");
+            accept_res = gate.accept(|| guard.accept()) => {
+                let connector = connector.clone();
+                let relay = relay.clone();
+                let target = connector.target().to_string();
+                let mut stream = match accept_res? {
+                    UdsConnection::Stream(stream) => stream,
+                    // Validation keeps seqpacket endpoints away from mux and fd passing.
+                    UdsConnection::Seqpacket(mut stream) => {
+                        let session = endpoint_session(&spec);
+                        let span = session.span();
+                        tokio::spawn(async move {
+                            let res = session.run(&target, async {
+                                if session_id {
+                                    session::receive_id(&mut stream).await?;
+                                }
+                                bridge_unix_to_tcp(stream, connector, relay).await
+                            }).await;
+                            session.close(&target, &res);
+                        }.instrument(span));
+                        continue;
                     }
-                });
+                };
+                if spec.muxed() {
+                    tokio::spawn(serve_mux(stream, connector, relay, spec.clone()).in_current_span());
+                    continue;
+                }
+                let session = endpoint_session(&spec);
+                let span = session.span();
+                if spec.fd_passing() {
+                    tokio::spawn(async move {
+                        let res = session.run(&target, async {
+                            if session_id {
+                                session::receive_id(&mut stream).await?;
+                            }
+                            pass_target_fd(stream, connector, relay.metrics()).await
+                        }).await;
+                        session.close(&target, &res);
+                    }.instrument(span));
+                    continue;
+                }
+                let compressed = spec.uds_compress() != UdsCompress::None;
+                tokio::spawn(async move {
+                    let res = session.run(&target, async {
+                        if session_id {
+                            session::receive_id(&mut stream).await?;
+                        }
+                        if compressed {
+                            match compress::accept(stream).await {
+                                Ok(stream) => bridge_unix_to_tcp(stream, connector, relay).await,
+                                Err(err) => Err(err.context("compression handshake failed")),
+                            }
+                        } else {
+                            bridge_unix_to_tcp(stream, connector, relay).await
+                        }
+                    }).await;
+                    session.close(&target, &res);
+                }.instrument(span));
             }
         }
     }
     Ok(())
 }
 
+/// Treat an accepted UDS connection as a yamux session and bridge every stream the host proxy opens
+/// on it to the namespace-local target.
+async fn serve_mux(stream: UnixStream, connector: Connector, relay: Relay, spec: Arc<ForwardSpec>) {
+    let mut mux = yamux::Session::new(stream, yamux::Mode::Server);
+    while let Some(mut stream) = mux.accept().await {
+        let connector = connector.clone();
+        let relay = relay.clone();
+        let session = endpoint_session(&spec);
+        let span = session.span();
+        let session_id = spec.uds_session_id();
+        tokio::spawn(
+            async move {
+                let target = connector.target().to_string();
+                let res = session
+                    .run(&target, async {
+                        if session_id {
+                            session::receive_id(&mut stream).await?;
+                        }
+                        bridge_unix_to_tcp(stream, connector, relay).await
+                    })
+                    .await;
+                session.close(&target, &res);
+            }
+            .instrument(span),
+        );
+    }
+}
+
+/// Session of a namespace endpoint, which continues the host proxy's when its ID is sent across.
+fn endpoint_session(spec: &ForwardSpec) -> Session {
+    if spec.uds_session_id() {
+        Session::continued(spec)
+    } else {
+        Session::new(spec, None)
+    }
+}
+
+/// Connect to the target and hand the connected socket to the host proxy over SCM_RIGHTS instead
+/// of relaying its bytes. The bytes never cross this leg, so none are reported; the host proxy
+/// accounts for them.
+async fn pass_target_fd(
+    stream: UnixStream,
+    connector: Connector,
+    metrics: &Arc<Metrics>,
+) -> Result<(u64, u64)> {
+    let _active = metrics.session();
+    let target = match metrics.target_connect(connector.connect()).await {
+        Ok(target) => target,
+        Err(err) => {
+            fdpass::send_failure(&stream).await.ok();
+            return Err(err.context(format!("connect failed for target {}", connector.target())));
+        }
+    };
+    let Some(fd) = target.socket_fd() else {
+        fdpass::send_failure(&stream).await.ok();
+        bail!("target {} cannot be passed as a socket", connector.target());
+    };
+    fdpass::send_fd(&stream, fd).await?;
+    Ok((0, 0))
+}
+
 /// For each accepted UDS stream, open a TCP connection to the namespace-local target and stream
 /// bytes until EOF.
-async fn bridge_unix_to_tcp(mut unix_stream: UnixStream, target: String) -> Result<()> {
-    let mut tcp = TcpStream::connect(&target)
+async fn bridge_unix_to_tcp<S>(
+    unix_stream: S,
+    connector: Connector,
+    relay: Relay,
+) -> Result<(u64, u64)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let _active = relay.metrics().session();
+    let mut tcp = relay
+        .metrics()
+        .target_connect(connector.connect())
         .await
-        .with_context(|| format!("connect failed for target {}", target))?;
+        .with_context(|| format!("connect failed for target {}", connector.target()))?;
     tcp.set_nodelay(true).ok();
-    copy_bidirectional(&mut unix_stream, &mut tcp).await?;
-    Ok(())
+    let res = relay.run(unix_stream, &mut tcp).await;
+    session::record_tcp_info(None, tcp.socket_fd(), relay.metrics());
+    res
 }
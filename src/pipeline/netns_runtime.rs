@@ -0,0 +1,167 @@
+//! One runtime thread per network namespace, shared by every endpoint that runs inside it.
+//!
+//! `setns()` only moves the calling thread, so in-namespace work needs a thread of its own. Rather
+//! than one per forward, threads are kept in a registry keyed by the resolved namespace file (and
+//! the `/etc/netns` overlay, which is per thread too); 300 forwards into 100 namespaces use 100
+//! threads. Each thread drives a current-thread runtime with a `LocalSet`, so endpoint futures
+//! need not be `Send`.
+//!
+//! A thread exits once its last endpoint has finished. The registry only holds a weak handle, so
+//! an idle thread does not keep a deleted namespace alive. If the file now names a different
+//! namespace (it was deleted and recreated), the next lookup starts a fresh thread.
+//!
+//! Every job runs inside a `netns` span carrying the namespace as configured and its identity, so
+//! each log line an endpoint emits can be filtered by tenant namespace, not just by `label`.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{LazyLock, Mutex};
+
+use anyhow::{Context, Result, bail};
+use futures::future::LocalBoxFuture;
+use tokio::runtime::Builder;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::LocalSet;
+use tracing::{Instrument, debug, info, info_span};
+
+use crate::config::ForwardSpec;
+use crate::netns::{self, NetnsId};
+
+type Job = Box<dyn FnOnce() -> LocalBoxFuture<'static, ()> + Send>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    path: PathBuf,
+    etc_overlay: Option<String>,
+}
+
+struct Entry {
+    id: NetnsId,
+    jobs: mpsc::WeakUnboundedSender<Job>,
+}
+
+static REGISTRY: LazyLock<Mutex<HashMap<Key, Entry>>> = LazyLock::new(Default::default);
+
+/// Handle on the runtime thread of one namespace.
+pub struct NamespaceRuntime {
+    id: NetnsId,
+    /// The namespace as the spec names it, for logs.
+    namespace: String,
+    jobs: mpsc::UnboundedSender<Job>,
+}
+
+impl NamespaceRuntime {
+    /// Thread for the namespace `spec` resolves to now, started on first use. Blocks while the
+    /// namespace is resolved (which may query a container runtime) and while a thread starts.
+    pub fn for_spec(spec: &ForwardSpec) -> Result<Self> {
+        let path =
+            netns::desired_netns_path(spec)?.context("spec does not name a network namespace")?;
+        let namespace = spec
+            .namespace
+            .clone()
+            .unwrap_or_else(|| path.display().to_string());
+        let key = Key {
+            etc_overlay: netns::etc_overlay(spec).map(str::to_string),
+            path,
+        };
+        let current = NetnsId::of(&key.path)
+            .with_context(|| format!("failed to open namespace file {}", key.path.display()))?;
+        let mut registry = REGISTRY.lock().expect("namespace registry poisoned");
+        if let Some(entry) = registry.get(&key)
+            && entry.id == current
+            && let Some(jobs) = entry.jobs.upgrade()
+        {
+            return Ok(Self {
+                id: entry.id,
+                namespace,
+                jobs,
+            });
+        }
+        let runtime = Self::start(&key, namespace)?;
+        registry.retain(|_, entry| entry.jobs.strong_count() > 0);
+        registry.insert(
+            key,
+            Entry {
+                id: runtime.id,
+                jobs: runtime.jobs.downgrade(),
+            },
+        );
+        Ok(runtime)
+    }
+
+    fn start(key: &Key, namespace: String) -> Result<Self> {
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+        let (jobs_tx, mut jobs_rx) = mpsc::unbounded_channel::<Job>();
+        let thread_key = key.clone();
+        std::thread::Builder::new()
+            .name("pfwd-netns".into())
+            .spawn(move || {
+                let setup = netns::enter(&thread_key.path, thread_key.etc_overlay.as_deref())
+                    .and_then(|()| netns::current_id())
+                    .and_then(|id| {
+                        let rt = Builder::new_current_thread()
+                            .enable_all()
+                            .build()
+                            .context("failed to build namespace runtime")?;
+                        Ok((id, rt))
+                    });
+                let rt = match setup {
+                    Ok((id, rt)) => {
+                        let _ = ready_tx.send(Ok(id));
+                        rt
+                    }
+                    Err(err) => {
+                        let _ = ready_tx.send(Err(err));
+                        return;
+                    }
+                };
+                let local = LocalSet::new();
+                local.spawn_local(async move {
+                    while let Some(job) = jobs_rx.recv().await {
+                        tokio::task::spawn_local(job());
+                    }
+                });
+                rt.block_on(local);
+                debug!(namespace = %thread_key.path.display(), "namespace runtime thread exiting");
+            })
+            .context("failed to spawn namespace runtime thread")?;
+        let id = ready_rx
+            .recv()
+            .context("namespace runtime thread exited during setup")??;
+        info!(namespace = %key.path.display(), %id, "namespace runtime thread started");
+        Ok(Self {
+            id,
+            namespace,
+            jobs: jobs_tx,
+        })
+    }
+
+    /// Namespace the thread joined.
+    pub fn id(&self) -> NetnsId {
+        self.id
+    }
+
+    /// Run the future `f` builds on the namespace thread and wait for its result. The thread stays
+    /// up at least until it completes.
+    pub async fn run<F, Fut, T>(self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<T>> + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let keep_alive = self.jobs.clone();
+        let span = info_span!("netns", namespace = %self.namespace, netns = %self.id);
+        let job: Job = Box::new(move || {
+            Box::pin(async move {
+                let _ = tx.send(f().instrument(span).await);
+                drop(keep_alive);
+            })
+        });
+        if self.jobs.send(job).is_err() {
+            bail!("namespace runtime thread exited");
+        }
+        drop(self);
+        rx.await.context("namespace task panicked")?
+    }
+}
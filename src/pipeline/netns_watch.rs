@@ -0,0 +1,499 @@
+//! Forwards that follow `ip netns` namespaces under `/var/run/netns` (or the spec's `netns_dir`),
+//! so pfwd can start before Neutron or `ip netns add` creates them.
+//!
+//! A spec naming a plain namespace is started once its namespace file exists and stopped when the
+//! file is removed; it is started again if the namespace comes back, once the stopped forward has
+//! finished and released its socket. Errors from such a forward
+//! still stop pfwd, as they would for any other spec. With `namespace_wait_timeout`, pfwd gives up
+//! if the namespace has not appeared that long after startup, instead of waiting indefinitely.
+//!
+//! `namespace = "glob:<pattern>"` makes the spec a template instead: every matching namespace
+//! gets its own instance, with the namespace name in place of `{namespace}` in its `uds` and
+//! `label` (unlabelled templates are labelled with the namespace name). An instance that fails is
+//! logged and started again on a later scan while its namespace still exists, so one broken
+//! namespace does not stop the others.
+//!
+//! `namespace = "neutron:dhcp"` (or `router`, or `all` for both) is a template over the
+//! `qdhcp-<uuid>` / `qrouter-<uuid>` namespaces the Neutron agents create. Their network or router
+//! UUID also replaces `{uuid}`, and labels unlabelled instances.
+//!
+//! The directory is watched with inotify and rescanned on every change, with a slow periodic
+//! rescan as a safety net (and a fast one where inotify is unavailable). `ip netns add` creates
+//! the file before bind-mounting the namespace onto it, so a name only counts once the mount is
+//! in place.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, sleep, sleep_until};
+use tracing::{info, warn};
+
+use crate::config::{ForwardSpec, NAMESPACE_PLACEHOLDER, UUID_PLACEHOLDER};
+use crate::pipeline::{self, ShutdownRx, ShutdownTx};
+use crate::{forward, log_limit, netns, status};
+
+/// Rescan interval while inotify reports changes.
+const RESCAN_INTERVAL: Duration = Duration::from_secs(30);
+/// Rescan interval without inotify.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Delay before looking again at a namespace file that is not mounted yet.
+const MOUNT_RETRY: Duration = Duration::from_millis(100);
+
+pub fn spawn(spec: ForwardSpec, shutdown: ShutdownRx) -> JoinHandle<Result<()>> {
+    tokio::spawn(watch_loop(spec, shutdown))
+}
+
+/// Which namespaces a spec runs in.
+enum Selector {
+    Exact(String),
+    Glob(String),
+    /// Namespace name prefixes followed by a UUID.
+    Neutron(&'static [&'static str]),
+}
+
+impl Selector {
+    fn for_spec(spec: &ForwardSpec) -> Option<Self> {
+        if let Some(pattern) = spec.namespace_glob() {
+            return Some(Selector::Glob(pattern.to_string()));
+        }
+        if let Some(kind) = spec.neutron_kind() {
+            return Some(Selector::Neutron(match kind {
+                "dhcp" => &["qdhcp-"],
+                "router" => &["qrouter-"],
+                _ => &["qdhcp-", "qrouter-"],
+            }));
+        }
+        spec.netns_name()
+            .map(|name| Selector::Exact(name.to_string()))
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Selector::Exact(exact) => exact == name,
+            Selector::Glob(pattern) => glob_match(pattern, name),
+            Selector::Neutron(_) => self.neutron_uuid(name).is_some(),
+        }
+    }
+
+    /// The network or router UUID in a Neutron namespace name.
+    fn neutron_uuid<'a>(&self, name: &'a str) -> Option<&'a str> {
+        let Selector::Neutron(prefixes) = self else {
+            return None;
+        };
+        prefixes
+            .iter()
+            .find_map(|prefix| name.strip_prefix(prefix))
+            .filter(|uuid| is_uuid(uuid))
+    }
+}
+
+/// A running forward for one namespace.
+struct Instance {
+    spec: ForwardSpec,
+    shutdown: ShutdownTx,
+    handle: JoinHandle<Result<()>>,
+}
+
+async fn watch_loop(template: ForwardSpec, mut shutdown: ShutdownRx) -> Result<()> {
+    let selector = Selector::for_spec(&template)
+        .context("namespace watcher needs a named or `glob:` namespace")?;
+    let label = template.label.as_deref().unwrap_or("unnamed");
+    let dir = template.netns_dir().to_path_buf();
+    let mut events = NetnsEvents::new(&dir);
+    let (done_tx, mut done_rx) = mpsc::unbounded_channel();
+    let mut instances: HashMap<String, Instance> = HashMap::new();
+    // Instances told to stop, by namespace, until they finish. A namespace that comes back is not
+    // started again before then, or the old instance could unlink the new one's socket.
+    let mut stopping: HashSet<String> = HashSet::new();
+    let mut stopped: FuturesUnordered<BoxFuture<'static, String>> = FuturesUnordered::new();
+    let mut waiting_logged = false;
+    let mut rescan = true;
+    let mut pending = false;
+    // Cleared once the namespace has been seen, so the timeout only applies at startup.
+    let mut wait_deadline = match selector {
+        Selector::Exact(_) => template
+            .namespace_wait_timeout()
+            .map(|t| Instant::now() + t),
+        _ => None,
+    };
+    let res = loop {
+        // Collect instances that ended on their own.
+        let finished: Vec<String> = instances
+            .iter()
+            .filter(|(_, instance)| instance.handle.is_finished())
+            .map(|(name, _)| name.clone())
+            .collect();
+        let mut failed = None;
+        for name in finished {
+            let instance = instances.remove(&name).expect("finished instance");
+            let res = match instance.handle.await {
+                Ok(res) => res,
+                Err(join_err) => Err(join_err.into()),
+            };
+            match (res, &selector) {
+                (Ok(()), _) => {}
+                (Err(err), Selector::Glob(_) | Selector::Neutron(_)) => {
+                    status::record_failed(&instance.spec, &err);
+                    if let Some(suppressed) = log_limit::allow("namespace forward failed", &name) {
+                        warn!(namespace = %name, error = %err, suppressed, "namespace forward failed");
+                    }
+                }
+                (Err(err), Selector::Exact(_)) => failed = Some(err),
+            }
+        }
+        if let Some(err) = failed {
+            break Err(err);
+        }
+
+        if rescan {
+            let (present, unmounted) = matching_namespaces(&dir, &selector)?;
+            pending = unmounted;
+            let removed: Vec<String> = instances
+                .keys()
+                .filter(|name| !present.contains(*name))
+                .cloned()
+                .collect();
+            for name in removed {
+                let instance = instances.remove(&name).expect("removed instance");
+                info!(namespace = %name, "namespace removed; stopping its forward");
+                netns::forget(&dir.join(&name));
+                let _ = instance.shutdown.send(true);
+                stopping.insert(name.clone());
+                stopped.push(Box::pin(async move {
+                    let _ = instance.handle.await;
+                    name
+                }));
+            }
+            for name in present {
+                if instances.contains_key(&name) || stopping.contains(&name) {
+                    continue;
+                }
+                let spec = instantiate(&template, &selector, &name);
+                if let Err(err) = spec.validate() {
+                    warn!(namespace = %name, error = %err, "skipping namespace");
+                    continue;
+                }
+                info!(label, namespace = %name, "namespace present; starting its forward");
+                instances.insert(name, start(spec, done_tx.clone()));
+                wait_deadline = None;
+            }
+            if instances.is_empty() && !waiting_logged {
+                match &selector {
+                    Selector::Exact(name) => {
+                        info!(label, namespace = %name, "waiting for namespace")
+                    }
+                    Selector::Glob(pattern) => {
+                        info!(label, pattern, "waiting for matching namespaces")
+                    }
+                    Selector::Neutron(prefixes) => {
+                        info!(label, ?prefixes, "waiting for neutron namespaces")
+                    }
+                }
+            }
+            waiting_logged = instances.is_empty();
+        }
+
+        rescan = tokio::select! {
+            _ = events.changed(pending) => true,
+            _ = done_rx.recv() => false,
+            Some(name) = stopped.next(), if !stopped.is_empty() => {
+                stopping.remove(&name);
+                true
+            }
+            _ = deadline(wait_deadline) => {
+                let timeout = template.namespace_wait_timeout().unwrap_or_default();
+                break Err(anyhow::anyhow!(
+                    "network namespace {} did not appear within {timeout:?}",
+                    template.namespace.as_deref().unwrap_or_default()
+                ));
+            }
+            res = shutdown.changed() => {
+                if res.is_err() || *shutdown.borrow() {
+                    break Ok(());
+                }
+                false
+            }
+        };
+    };
+    for instance in instances.into_values() {
+        let _ = instance.shutdown.send(true);
+        let _ = instance.handle.await;
+    }
+    while stopped.next().await.is_some() {}
+    res
+}
+
+/// Resolve at `at`, or never.
+async fn deadline(at: Option<Instant>) {
+    match at {
+        Some(at) => sleep_until(at).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Mounted namespaces matching `selector`, and whether a matching file is still waiting for its
+/// mount. The directory only exists once the first namespace has been created.
+fn matching_namespaces(dir: &Path, selector: &Selector) -> Result<(HashSet<String>, bool)> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok((HashSet::new(), false)),
+        Err(err) => return Err(err).with_context(|| format!("failed to read {}", dir.display())),
+    };
+    let mut names = HashSet::new();
+    let mut unmounted = false;
+    for entry in entries {
+        let entry = entry?;
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        if !selector.matches(&name) {
+            continue;
+        }
+        if is_namespace_file(&entry.path()) {
+            names.insert(name);
+        } else {
+            unmounted = true;
+        }
+    }
+    Ok((names, unmounted))
+}
+
+#[cfg(target_os = "linux")]
+fn is_namespace_file(path: &Path) -> bool {
+    use nix::sys::statfs::{NSFS_MAGIC, statfs};
+
+    statfs(path).is_ok_and(|fs| fs.filesystem_type() == NSFS_MAGIC)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_namespace_file(_path: &Path) -> bool {
+    true
+}
+
+fn instantiate(template: &ForwardSpec, selector: &Selector, name: &str) -> ForwardSpec {
+    let mut spec = template.clone();
+    if let Selector::Exact(_) = selector {
+        return spec;
+    }
+    let uuid = selector.neutron_uuid(name);
+    let expand = |text: &str| {
+        let text = text.replace(NAMESPACE_PLACEHOLDER, name);
+        match uuid {
+            Some(uuid) => text.replace(UUID_PLACEHOLDER, uuid),
+            None => text,
+        }
+    };
+    spec.namespace = Some(name.to_string());
+    spec.uds = template
+        .uds
+        .as_deref()
+        .map(|uds| PathBuf::from(expand(&uds.to_string_lossy())));
+    spec.label = Some(match template.label.as_deref() {
+        Some(label) => expand(label),
+        None => uuid.unwrap_or(name).to_string(),
+    });
+    spec
+}
+
+/// `8-4-4-4-12` hex digits, as Neutron names its resources.
+fn is_uuid(text: &str) -> bool {
+    text.len() == 36
+        && text.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        })
+}
+
+/// Run one instance's tasks on their own shutdown channel, so it can be stopped alone. `done`
+/// wakes the watcher when the instance ends by itself.
+fn start(spec: ForwardSpec, done: mpsc::UnboundedSender<()>) -> Instance {
+    let (shutdown_tx, shutdown_rx) = pipeline::shutdown_channel();
+    let mut tasks = FuturesUnordered::new();
+    forward::enqueue_instance(&mut tasks, spec.clone(), shutdown_rx);
+    let shutdown = shutdown_tx.clone();
+    let handle = tokio::spawn(async move {
+        let res = forward::join_all(tasks, &shutdown_tx).await;
+        let _ = done.send(());
+        res
+    });
+    Instance {
+        spec,
+        shutdown,
+        handle,
+    }
+}
+
+/// Shell-style match supporting `*` (any run of characters) and `?` (one character).
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` and the name index it is currently absorbing up to.
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Wakes the watcher when the namespace directory may have changed.
+struct NetnsEvents {
+    #[cfg(target_os = "linux")]
+    dir: PathBuf,
+    #[cfg(target_os = "linux")]
+    inotify: Option<inotify::Watch>,
+}
+
+impl NetnsEvents {
+    fn new(dir: &Path) -> Self {
+        #[cfg(target_os = "linux")]
+        {
+            let inotify = inotify::Watch::new(dir)
+                .inspect_err(|err| {
+                    warn!(error = %err, dir = %dir.display(), "inotify unavailable; polling instead");
+                })
+                .ok();
+            Self {
+                dir: dir.to_path_buf(),
+                inotify,
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = dir;
+            Self {}
+        }
+    }
+
+    /// Wait for a change, or for the next periodic rescan; `pending` asks for a quick one.
+    async fn changed(&mut self, pending: bool) {
+        #[cfg(target_os = "linux")]
+        if let Some(watch) = self.inotify.as_mut() {
+            let interval = if pending {
+                MOUNT_RETRY
+            } else {
+                RESCAN_INTERVAL
+            };
+            tokio::select! {
+                _ = sleep(interval) => {}
+                res = watch.changed() => {
+                    if let Err(err) = res {
+                        warn!(error = %err, dir = %self.dir.display(), "inotify failed; polling instead");
+                        self.inotify = None;
+                    }
+                }
+            }
+            return;
+        }
+        sleep(if pending { MOUNT_RETRY } else { POLL_INTERVAL }).await;
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use std::io;
+    use std::os::fd::{AsFd, AsRawFd, RawFd};
+    use std::path::{Path, PathBuf};
+
+    use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+    use tokio::io::unix::AsyncFd;
+
+    /// `AsyncFd` wants `AsRawFd`, which nix's `Inotify` only provides through `AsFd`.
+    struct Fd(Inotify);
+
+    impl AsRawFd for Fd {
+        fn as_raw_fd(&self) -> RawFd {
+            self.0.as_fd().as_raw_fd()
+        }
+    }
+
+    /// Inotify watch on a directory that may not exist yet: until it does, its parent is watched
+    /// for it to be created.
+    pub struct Watch {
+        fd: AsyncFd<Fd>,
+        dir: PathBuf,
+        dir_watched: bool,
+    }
+
+    impl Watch {
+        pub fn new(dir: &Path) -> io::Result<Self> {
+            let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+            let mut watch = Self {
+                fd: AsyncFd::new(Fd(inotify))?,
+                dir: dir.to_path_buf(),
+                dir_watched: false,
+            };
+            if !watch.watch_dir()? {
+                let parent = dir.parent().unwrap_or(Path::new("/"));
+                watch.inotify().add_watch(
+                    parent,
+                    AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO,
+                )?;
+            }
+            Ok(watch)
+        }
+
+        fn inotify(&self) -> &Inotify {
+            &self.fd.get_ref().0
+        }
+
+        /// Start watching the directory itself; `false` if it does not exist yet.
+        fn watch_dir(&mut self) -> io::Result<bool> {
+            let flags = AddWatchFlags::IN_CREATE
+                | AddWatchFlags::IN_DELETE
+                | AddWatchFlags::IN_MOVED_FROM
+                | AddWatchFlags::IN_MOVED_TO;
+            match self.inotify().add_watch(&self.dir, flags) {
+                Ok(_) => {
+                    self.dir_watched = true;
+                    Ok(true)
+                }
+                Err(nix::errno::Errno::ENOENT) => Ok(false),
+                Err(err) => Err(err.into()),
+            }
+        }
+
+        /// Wait for events and drain them; callers rescan rather than interpret each event.
+        pub async fn changed(&mut self) -> io::Result<()> {
+            loop {
+                let mut guard = self.fd.readable().await?;
+                match guard.try_io(|fd| fd.get_ref().0.read_events().map_err(io::Error::from)) {
+                    Ok(res) => {
+                        res?;
+                        break;
+                    }
+                    Err(_would_block) => continue,
+                }
+            }
+            if !self.dir_watched {
+                self.watch_dir()?;
+            }
+            Ok(())
+        }
+    }
+}
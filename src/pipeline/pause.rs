@@ -0,0 +1,94 @@
+//! Pausing a forward's stream listeners from the admin interfaces, e.g. for brief maintenance of
+//! its target, without closing the bound sockets.
+//!
+//! A paused listener stops calling `accept`. New connections wait in the kernel's accept backlog
+//! and are taken once the forward resumes, so clients see a slow connect rather than a refusal
+//! while the backlog has room. With [`Pause::Reject`], connections are accepted and closed straight
+//! away instead. Open sessions carry on either way. Pauses are kept by label, so a forward
+//! restarted by a reload stays paused until it is resumed.
+
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+
+use tokio::sync::watch;
+use tracing::debug;
+
+use crate::config::ForwardSpec;
+
+/// What a paused listener does with new connections.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Pause {
+    /// Leave them in the accept backlog until the forward resumes.
+    Queue,
+    /// Accept and close them.
+    Reject,
+}
+
+/// Paused forwards by label.
+static PAUSED: LazyLock<watch::Sender<BTreeMap<String, Pause>>> =
+    LazyLock::new(|| watch::Sender::new(BTreeMap::new()));
+
+/// Pause the listeners of the forwards labelled `label`.
+pub fn pause(label: &str, pause: Pause) {
+    PAUSED.send_modify(|paused| {
+        paused.insert(label.to_string(), pause);
+    });
+}
+
+/// Resume the listeners of the forwards labelled `label`, returning whether they were paused.
+pub fn resume(label: &str) -> bool {
+    PAUSED.send_if_modified(|paused| paused.remove(label).is_some())
+}
+
+/// Whether the forwards labelled `label` are paused.
+pub fn paused(label: &str) -> bool {
+    PAUSED.borrow().contains_key(label)
+}
+
+/// The labels of the paused forwards.
+pub fn labels() -> Vec<String> {
+    PAUSED.borrow().keys().cloned().collect()
+}
+
+/// Where a forward's accept loop looks whether it is paused.
+pub struct Gate {
+    label: String,
+    paused: watch::Receiver<BTreeMap<String, Pause>>,
+}
+
+impl Gate {
+    pub fn for_spec(spec: &ForwardSpec) -> Self {
+        Self {
+            label: spec.label.clone().unwrap_or_else(|| "unnamed".to_string()),
+            paused: PAUSED.subscribe(),
+        }
+    }
+
+    /// Run `accept` while the forward is not paused, abandoning it when the forward is paused and
+    /// starting it again when it resumes. Under [`Pause::Reject`], what it accepts is dropped.
+    pub async fn accept<F, Fut>(&mut self, mut accept: F) -> Fut::Output
+    where
+        F: FnMut() -> Fut,
+        Fut: Future,
+    {
+        loop {
+            let pause = self.paused.borrow_and_update().get(&self.label).copied();
+            match pause {
+                None => tokio::select! {
+                    res = accept() => return res,
+                    _ = self.paused.changed() => {}
+                },
+                Some(Pause::Queue) => {
+                    let _ = self.paused.changed().await;
+                }
+                Some(Pause::Reject) => tokio::select! {
+                    res = accept() => {
+                        drop(res);
+                        debug!(label = self.label, "connection closed: forward is paused");
+                    }
+                    _ = self.paused.changed() => {}
+                },
+            }
+        }
+    }
+}
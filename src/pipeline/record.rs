@@ -0,0 +1,281 @@
+//! Session recording (`record_dir`) and the `pfwd replay` side that reads recordings back.
+//!
+//! Each recorded session becomes one file in `record_dir`, named after the forward label and the
+//! wall-clock time the session started. The file starts with [`MAGIC`] followed by one frame per
+//! chunk the relay moved:
+//!
+//! ```text
+//! direction: u8 (0 = client→target, 1 = target→client)
+//! offset:    u64 big-endian, microseconds since the session started
+//! length:    u32 big-endian
+//! payload:   `length` bytes
+//! ```
+
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, bail};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{
+    self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter, ReadBuf,
+};
+use tokio::sync::mpsc;
+use tokio::time::{Instant, sleep_until};
+use tracing::{debug, info, warn};
+
+use crate::config::{ForwardSpec, ReplayArgs};
+use crate::target::Connector;
+
+/// Leading bytes of every recording, versioning the frame layout below it.
+const MAGIC: &[u8; 8] = b"PFWDREC1";
+
+/// Largest payload of one frame; longer chunks are recorded as several frames, and replay refuses
+/// frames claiming more.
+const MAX_FRAME: usize = 1024 * 1024;
+
+const CLIENT_TO_TARGET: u8 = 0;
+const TARGET_TO_CLIENT: u8 = 1;
+
+/// Frames queued for a session's recording before the session stops being recorded.
+const RECORD_QUEUE: usize = 1024;
+
+/// Distinguishes sessions of one forward that start within the same microsecond.
+static SESSION_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Opens a recording file for each session of one forward.
+#[derive(Clone)]
+pub struct Recorder {
+    dir: Arc<PathBuf>,
+    label: Arc<str>,
+}
+
+impl Recorder {
+    pub fn for_spec(spec: &ForwardSpec) -> Option<Self> {
+        let dir = spec.record_dir.clone()?;
+        Some(Self {
+            dir: Arc::new(dir),
+            label: spec.label.as_deref().unwrap_or("session").into(),
+        })
+    }
+
+    /// Start recording one session and return the sender its frames go to.
+    fn start(&self) -> mpsc::Sender<Frame> {
+        let (tx, mut rx) = mpsc::channel::<Frame>(RECORD_QUEUE);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let name = format!(
+            "{}-{}.{:06}-{}.pfwdrec",
+            self.label,
+            now.as_secs(),
+            now.subsec_micros(),
+            SESSION_SEQ.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = self.dir.join(name.replace('/', "_"));
+        tokio::spawn(async move {
+            // Recordings hold the sessions' plaintext, so only pfwd's user may read them.
+            let file = match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&path)
+                .await
+            {
+                Ok(file) => file,
+                Err(err) => {
+                    warn!(path = %path.display(), error = %err, "failed to create session recording");
+                    return;
+                }
+            };
+            debug!(path = %path.display(), "recording session");
+            let mut out = BufWriter::new(file);
+            let res: io::Result<()> = async {
+                out.write_all(MAGIC).await?;
+                while let Some(frame) = rx.recv().await {
+                    out.write_u8(frame.direction).await?;
+                    out.write_u64(frame.offset.as_micros() as u64).await?;
+                    out.write_u32(frame.data.len() as u32).await?;
+                    out.write_all(&frame.data).await?;
+                }
+                out.flush().await
+            }
+            .await;
+            if let Err(err) = res {
+                warn!(path = %path.display(), error = %err, "session recording incomplete");
+            }
+        });
+        tx
+    }
+}
+
+struct Frame {
+    direction: u8,
+    offset: Duration,
+    data: Vec<u8>,
+}
+
+/// Client-side stream whose reads (client→target) and writes (target→client) are also recorded.
+pub struct Recorded<S> {
+    inner: S,
+    started: Instant,
+    frames: Option<mpsc::Sender<Frame>>,
+}
+
+impl<S> Recorded<S> {
+    fn push(&mut self, direction: u8, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let offset = self.started.elapsed();
+        for data in data.chunks(MAX_FRAME) {
+            let Some(tx) = self.frames.as_ref() else {
+                return;
+            };
+            let frame = Frame {
+                direction,
+                offset,
+                data: data.to_vec(),
+            };
+            if let Err(err) = tx.try_send(frame) {
+                if matches!(err, mpsc::error::TrySendError::Full(_)) {
+                    warn!("recording fell behind; no longer recording this session");
+                }
+                self.frames = None;
+            }
+        }
+    }
+}
+
+/// Wrap the client leg of a session; without a recorder the stream is passed through untouched.
+pub fn record<S>(inner: S, recorder: Option<&Recorder>) -> Recorded<S> {
+    Recorded {
+        inner,
+        started: Instant::now(),
+        frames: recorder.map(Recorder::start),
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Recorded<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            this.push(CLIENT_TO_TARGET, &buf.filled()[before..]);
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Recorded<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = res {
+            this.push(TARGET_TO_CLIENT, &buf[..written]);
+        }
+        res
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Read every frame of a recording, in order.
+async fn read_frames(path: &Path) -> Result<Vec<Frame>> {
+    let file = File::open(path)
+        .await
+        .with_context(|| format!("failed to open recording {}", path.display()))?;
+    let mut input = BufReader::new(file);
+    let mut magic = [0u8; MAGIC.len()];
+    input
+        .read_exact(&mut magic)
+        .await
+        .context("recording is too short")?;
+    if &magic != MAGIC {
+        bail!("{} is not a pfwd session recording", path.display());
+    }
+    let mut frames = Vec::new();
+    loop {
+        let direction = match input.read_u8().await {
+            Ok(direction) => direction,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        };
+        let offset = Duration::from_micros(input.read_u64().await?);
+        let len = input.read_u32().await? as usize;
+        if len > MAX_FRAME {
+            bail!("recording has a frame of {len} bytes, more than the {MAX_FRAME} allowed");
+        }
+        let mut data = vec![0u8; len];
+        input
+            .read_exact(&mut data)
+            .await
+            .context("recording ends inside a frame")?;
+        frames.push(Frame {
+            direction,
+            offset,
+            data,
+        });
+    }
+    Ok(frames)
+}
+
+/// `pfwd replay`: send the client→target half of a recording to `args.target`, with the recorded
+/// pacing unless `--fast`, and copy whatever the target answers to stdout.
+pub async fn replay(args: ReplayArgs) -> Result<()> {
+    crate::target::validate(&args.target).context("invalid replay target")?;
+    let frames = read_frames(&args.recording).await?;
+    let connector = Connector::new(args.target.clone(), false);
+    let stream = connector
+        .connect()
+        .await
+        .with_context(|| format!("failed to connect to {}", args.target))?;
+    let (mut reader, mut writer) = io::split(stream);
+    let responses = tokio::spawn(async move { io::copy(&mut reader, &mut io::stdout()).await });
+
+    let started = Instant::now();
+    let mut sent = 0usize;
+    for frame in frames.iter().filter(|f| f.direction == CLIENT_TO_TARGET) {
+        if !args.fast {
+            sleep_until(started + frame.offset).await;
+        }
+        writer
+            .write_all(&frame.data)
+            .await
+            .context("target closed the connection during replay")?;
+        sent += frame.data.len();
+    }
+    writer.shutdown().await.ok();
+    let received = responses.await??;
+    let recorded: usize = frames
+        .iter()
+        .filter(|f| f.direction == TARGET_TO_CLIENT)
+        .map(|f| f.data.len())
+        .sum();
+    info!(
+        target = %args.target,
+        sent,
+        received,
+        recorded_responses = recorded,
+        "replay finished"
+    );
+    Ok(())
+}
@@ -0,0 +1,190 @@
+//! Per-session relay between a client and its target, carrying the per-forward extras that hook
+//! into the byte stream: mirroring, recording, filters, and chaos/shaping.
+//!
+//! Without a filter or chaos settings the session runs through `copy_bidirectional`. Otherwise each
+//! direction is pumped through a small delay line: a reader task hands chunks (filtered, then
+//! mangled and scheduled by [`crate::pipeline::chaos`]) to a writer task that sends each one when
+//! it is due.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use socket2::SockRef;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::spawn_blocking;
+use tokio::time::{Instant, sleep_until};
+use tracing::{Instrument, debug};
+
+use crate::config::ForwardSpec;
+use crate::filter::{self, Direction, Filter, FilterPlugin};
+use crate::metrics::{ErrorKind, Metrics};
+use crate::pipeline::chaos::{self, Chaos};
+use crate::pipeline::copy_bidirectional;
+use crate::pipeline::mirror::{self, Mirror};
+use crate::pipeline::record::{self, Recorder};
+use crate::pipeline::session;
+use crate::target::TargetStream;
+
+/// Largest chunk one read hands to the delay line.
+const CHUNK: usize = 8 * 1024;
+/// Chunks waiting for their deadline before the reader stops pulling from its side.
+const IN_FLIGHT: usize = 64;
+
+/// Everything one forward does to a session besides copying its bytes.
+#[derive(Clone)]
+pub struct Relay {
+    mirror: Option<Mirror>,
+    recorder: Option<Recorder>,
+    filter: Option<Arc<dyn FilterPlugin>>,
+    chaos: Option<Chaos>,
+    metrics: Arc<Metrics>,
+}
+
+type SessionFilter = Arc<Mutex<Box<dyn Filter>>>;
+
+impl Relay {
+    /// Relay for the `leg` of `spec` (see [`Metrics::for_spec`]).
+    pub fn for_spec(spec: &ForwardSpec, leg: &'static str) -> Result<Self> {
+        Ok(Self {
+            mirror: Mirror::for_spec(spec),
+            recorder: Recorder::for_spec(spec),
+            filter: filter::for_spec(spec)?,
+            chaos: Chaos::for_spec(spec),
+            metrics: Metrics::for_spec(spec, leg),
+        })
+    }
+
+    /// Counters the relay's sessions are accounted in.
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// Relay one session until both sides are done, returning the bytes that reached the target and
+    /// the client. The mirror and the recording see the client's bytes as they arrived, before any
+    /// filter or fault.
+    pub async fn run<C>(&self, client: C, upstream: &mut TargetStream) -> Result<(u64, u64)>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (sent, received) = self
+            .relay(client, upstream)
+            .instrument(session::relay_span())
+            .await
+            .inspect_err(|err| self.metrics.error(ErrorKind::Relay, err))?;
+        session::record_bytes(sent, received);
+        self.metrics.add_bytes(sent, received);
+        Ok((sent, received))
+    }
+
+    async fn relay<C>(&self, client: C, upstream: &mut TargetStream) -> Result<(u64, u64)>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+    {
+        let client = mirror::tap(session::counted(client), self.mirror.as_ref());
+        let mut client = record::record(client, self.recorder.as_ref());
+        let filter = match self.filter.as_ref() {
+            Some(plugin) => Some(Arc::new(Mutex::new(plugin.session()?))),
+            None => None,
+        };
+        if filter.is_none() && self.chaos.is_none() {
+            return copy_bidirectional(&mut client, upstream).await;
+        }
+        let res = {
+            let (client_read, client_write) = io::split(&mut client);
+            let (upstream_read, upstream_write) = io::split(&mut *upstream);
+            tokio::try_join!(
+                self.pump(
+                    client_read,
+                    upstream_write,
+                    Direction::ClientToTarget,
+                    filter.as_ref()
+                ),
+                self.pump(
+                    upstream_read,
+                    client_write,
+                    Direction::TargetToClient,
+                    filter.as_ref()
+                )
+            )
+        };
+        match res {
+            Ok(bytes) => Ok(bytes),
+            Err(err) if chaos::is_injected_reset(&err) => {
+                debug!("chaos: resetting session");
+                if let Some(fd) = upstream.socket_fd() {
+                    // A zero linger turns the close into an RST.
+                    SockRef::from(&fd).set_linger(Some(Duration::ZERO)).ok();
+                }
+                Ok((0, 0))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Move one direction of a session through the delay line until its reader reaches EOF, returning
+    /// the bytes written.
+    async fn pump<R, W>(
+        &self,
+        mut reader: R,
+        mut writer: W,
+        direction: Direction,
+        filter: Option<&SessionFilter>,
+    ) -> io::Result<u64>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let (tx, mut rx) = mpsc::channel::<(Instant, Vec<u8>)>(IN_FLIGHT);
+        let read = async move {
+            let mut line = self.chaos.as_ref().map(Chaos::line);
+            let mut buf = vec![0u8; CHUNK];
+            loop {
+                let n = reader.read(&mut buf).await?;
+                if n == 0 {
+                    return Ok(());
+                }
+                let mut chunk = buf[..n].to_vec();
+                if let Some(filter) = filter {
+                    // A filter call may compute for a while, e.g. a wasm module on its fuel
+                    // budget, so it stays off the runtime's worker threads.
+                    let filter = Arc::clone(filter);
+                    chunk = spawn_blocking(move || {
+                        filter
+                            .lock()
+                            .expect("filter poisoned")
+                            .filter(direction, chunk)
+                    })
+                    .await
+                    .map_err(io::Error::other)?
+                    .map_err(io::Error::other)?;
+                }
+                let due = match line.as_mut() {
+                    Some(line) => {
+                        chunk = line.mangle(chunk)?;
+                        line.schedule(chunk.len()).await
+                    }
+                    None => Instant::now(),
+                };
+                if chunk.is_empty() {
+                    continue;
+                }
+                if tx.send((due, chunk)).await.is_err() {
+                    return Ok(());
+                }
+            }
+        };
+        let write = async move {
+            let mut written = 0;
+            while let Some((due, chunk)) = rx.recv().await {
+                sleep_until(due).await;
+                writer.write_all(&chunk).await?;
+                written += chunk.len() as u64;
+            }
+            writer.shutdown().await?;
+            Ok(written)
+        };
+        tokio::try_join!(read, write).map(|((), written)| written)
+    }
+}
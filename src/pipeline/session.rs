@@ -0,0 +1,520 @@
+//! Proxied sessions: their tracing spans and the accounting record logged when they close.
+//!
+//! Each session runs in a `session` span carrying its peer, forward label, namespace, session ID,
+//! and the bytes relayed once it ends, with `uds_connect`, `target_connect`, and `relay` child
+//! spans for its phases. They all use [`TARGET`], which is what `otlp_endpoint` exports. When the session ends,
+//! one `session closed` event sums it up for accounting. Opening and closing are also written to
+//! the [`access_log`](crate::access_log) when one is configured, closed sessions are exported as
+//! [`ipfix`](crate::ipfix) flows, and open sessions are listed by [`live`] with the bytes they
+//! have relayed so far. Where the client or target is TCP, `session closed` also carries its
+//! [`tcp_info`](crate::tcp_info) as the relay ended.
+//!
+//! The session ID is random, so it is unique across processes. With `uds_session_id`, the host
+//! proxy sends it as the first 8 bytes of each UDS connection and the namespace endpoint adopts it,
+//! so both legs of one connection log the same `session_id`.
+//!
+//! A session can be killed from the admin interfaces, which drops its bridge and with it both
+//! sockets; it closes with the reason `killed`.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::io;
+use std::os::fd::BorrowedFd;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context as _, Result, anyhow};
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::Notify;
+use tracing::field::{Empty, display};
+use tracing::{Instrument, Span, info, info_span, warn};
+
+use crate::access_log;
+use crate::config::ForwardSpec;
+use crate::metrics::{ErrorKind, Metrics};
+use crate::pipeline::{capture, copy_bidirectional};
+use crate::srv::random_u64;
+use crate::tcp_info::{self, TcpInfo};
+use crate::{ipfix, status};
+
+pub const TARGET: &str = "pfwd::session";
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+static SESSIONS: LazyLock<Mutex<BTreeMap<u64, Arc<Live>>>> = LazyLock::new(Default::default);
+
+tokio::task_local! {
+    /// The session whose bridge is running, for [`counted`] to account bytes to.
+    static CURRENT: Arc<Live>;
+}
+
+/// What is known about a session while it is open.
+struct Live {
+    id: u64,
+    /// Unset on a namespace endpoint until the host proxy's ID arrives.
+    session_id: OnceLock<u64>,
+    span: Span,
+    label: String,
+    peer: Option<String>,
+    namespace: Option<String>,
+    target: OnceLock<String>,
+    started: Instant,
+    started_unix_ms: u64,
+    client_to_target: AtomicU64,
+    target_to_client: AtomicU64,
+    /// `TCP_INFO` of the client and target sockets, once the relay is done.
+    tcp_info: OnceLock<(Option<TcpInfo>, Option<TcpInfo>)>,
+    /// Notified to abort the bridge.
+    kill: Notify,
+}
+
+/// One open session, as listed by [`live`].
+#[derive(Serialize)]
+pub struct LiveSession {
+    pub id: u64,
+    pub session_id: Option<String>,
+    pub label: String,
+    pub peer: Option<String>,
+    pub namespace: Option<String>,
+    /// Unset until admission has settled where the session goes.
+    pub target: Option<String>,
+    pub started_unix_ms: u64,
+    pub duration_ms: u64,
+    pub bytes_client_to_target: u64,
+    pub bytes_target_to_client: u64,
+}
+
+/// Sessions open right now, oldest first. Bytes are counted as they are relayed, except on legs
+/// that hand the connection over instead (`fd_passing`).
+pub fn live() -> Vec<LiveSession> {
+    SESSIONS
+        .lock()
+        .expect("session registry poisoned")
+        .values()
+        .map(|live| LiveSession {
+            id: live.id,
+            session_id: live.session_id.get().map(|&id| format_id(id)),
+            label: live.label.clone(),
+            peer: live.peer.clone(),
+            namespace: live.namespace.clone(),
+            target: live.target.get().cloned(),
+            started_unix_ms: live.started_unix_ms,
+            duration_ms: live.started.elapsed().as_millis() as u64,
+            bytes_client_to_target: live.client_to_target.load(Ordering::Relaxed),
+            bytes_target_to_client: live.target_to_client.load(Ordering::Relaxed),
+        })
+        .collect()
+}
+
+/// How many sessions are open right now.
+pub fn open_count() -> usize {
+    SESSIONS.lock().expect("session registry poisoned").len()
+}
+
+/// How many sessions of the forwards labelled `label` are open right now.
+pub fn count_labelled(label: &str) -> usize {
+    SESSIONS
+        .lock()
+        .expect("session registry poisoned")
+        .values()
+        .filter(|live| live.label == label)
+        .count()
+}
+
+/// Kill the open sessions of the forwards labelled `label`, returning how many there were.
+pub fn kill_labelled(label: &str) -> usize {
+    kill_where(|live| live.label == label)
+}
+
+/// Kill the open session numbered `id`, as in [`live`], returning whether there was one.
+pub fn kill_id(id: u64) -> bool {
+    kill_where(|live| live.id == id) > 0
+}
+
+/// Kill the open sessions with `session_id`, returning how many there were. Both legs of one
+/// connection carry it under `uds_session_id`.
+pub fn kill_session_id(session_id: u64) -> usize {
+    kill_where(|live| live.session_id.get() == Some(&session_id))
+}
+
+fn kill_where(matching: impl Fn(&Live) -> bool) -> usize {
+    let sessions = SESSIONS.lock().expect("session registry poisoned");
+    let mut killed = 0;
+    for live in sessions.values().filter(|live| matching(live)) {
+        // Stores a permit if the bridge has not started yet, so it ends as soon as it does.
+        live.kill.notify_one();
+        killed += 1;
+    }
+    killed
+}
+
+/// Parse a session ID as logged.
+pub fn parse_id(session_id: &str) -> Result<u64> {
+    u64::from_str_radix(session_id, 16)
+        .with_context(|| format!("invalid session ID {session_id}; expected 16 hex digits"))
+}
+
+/// One session of a forward, from accept until [`Session::close`]; listed by [`live`] meanwhile.
+pub struct Session {
+    live: Arc<Live>,
+}
+
+impl Session {
+    /// Session of `spec`'s forward, with a new session ID. `peer` is the client address where one
+    /// is known; the namespace end of a UDS only sees the host proxy.
+    pub fn new(spec: &ForwardSpec, peer: Option<&dyn Display>) -> Self {
+        let session = Self::open(spec, peer);
+        session.live.adopt_id(random_u64());
+        session
+    }
+
+    /// Session of a namespace endpoint whose host proxy sends the session ID (`uds_session_id`);
+    /// it has none until [`receive_id`] reads it.
+    pub fn continued(spec: &ForwardSpec) -> Self {
+        Self::open(spec, None)
+    }
+
+    fn open(spec: &ForwardSpec, peer: Option<&dyn Display>) -> Self {
+        let label = spec.label.clone().unwrap_or_else(|| "unnamed".to_string());
+        let peer = peer.map(ToString::to_string);
+        let namespace = spec.namespace_name();
+        let span = info_span!(
+            target: TARGET,
+            "session",
+            peer,
+            label,
+            namespace,
+            session_id = Empty,
+            bytes_client_to_target = Empty,
+            bytes_target_to_client = Empty,
+        );
+        let live = Arc::new(Live {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            session_id: OnceLock::new(),
+            span,
+            label,
+            peer,
+            namespace,
+            target: OnceLock::new(),
+            started: Instant::now(),
+            started_unix_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            client_to_target: AtomicU64::new(0),
+            target_to_client: AtomicU64::new(0),
+            tcp_info: OnceLock::new(),
+            kill: Notify::new(),
+        });
+        access_log::open(access_log::Open {
+            id: live.id,
+            label: &live.label,
+            peer: live.peer.as_deref(),
+            namespace: live.namespace.as_deref(),
+        });
+        SESSIONS
+            .lock()
+            .expect("session registry poisoned")
+            .insert(live.id, live.clone());
+        Self { live }
+    }
+
+    /// Span the session's task runs in.
+    pub fn span(&self) -> Span {
+        self.live.span.clone()
+    }
+
+    /// Run the session's `bridge` to `target`, counting the bytes it relays as they go, until it
+    /// ends or the session is killed.
+    pub async fn run<F>(&self, target: &str, bridge: F) -> Result<(u64, u64)>
+    where
+        F: Future<Output = Result<(u64, u64)>>,
+    {
+        let _ = self.live.target.set(target.to_string());
+        tokio::select! {
+            res = CURRENT.scope(self.live.clone(), bridge) => res,
+            () = self.live.kill.notified() => Err(anyhow!("killed")),
+        }
+    }
+
+    /// Log the accounting record for the session, which relayed `res` to `target`: the bytes
+    /// moved each way, or the error that ended it.
+    pub fn close(self, target: &str, res: &Result<(u64, u64)>) {
+        let live = &self.live;
+        let duration_ms = live.started.elapsed().as_millis() as u64;
+        let (label, peer, namespace) =
+            (&live.label, live.peer.as_deref(), live.namespace.as_deref());
+        let session_id = live.session_id.get().map(|&id| format_id(id));
+        let reason = match res {
+            Ok(_) => "eof".to_string(),
+            Err(err) => format!("{err:#}"),
+        };
+        let (bytes_client_to_target, bytes_target_to_client) =
+            res.as_ref().map_or((0, 0), |bytes| *bytes);
+        access_log::close(access_log::Close {
+            id: live.id,
+            label,
+            peer,
+            namespace,
+            target,
+            duration_ms,
+            bytes_client_to_target,
+            bytes_target_to_client,
+            reason: &reason,
+        });
+        if ipfix::enabled() {
+            // A failed session still relayed what it counted before it failed.
+            let (client_to_target, target_to_client) = match res {
+                Ok(bytes) => *bytes,
+                Err(_) => (
+                    live.client_to_target.load(Ordering::Relaxed),
+                    live.target_to_client.load(Ordering::Relaxed),
+                ),
+            };
+            ipfix::export(ipfix::Flow {
+                label,
+                protocol: ipfix::PROTOCOL_TCP,
+                client: peer.and_then(|peer| peer.parse().ok()),
+                target: target.parse().ok(),
+                started_unix_ms: live.started_unix_ms,
+                bytes_client_to_target: client_to_target,
+                bytes_target_to_client: target_to_client,
+                packets: None,
+            });
+        }
+        let (client_tcp, target_tcp) = live.tcp_info.get().copied().unwrap_or_default();
+        let client_rtt_us = client_tcp.map(|info| info.rtt.as_micros() as u64);
+        let client_retransmits = client_tcp.map(|info| info.retransmits);
+        let client_delivery_rate = client_tcp.map(|info| info.delivery_rate);
+        let target_rtt_us = target_tcp.map(|info| info.rtt.as_micros() as u64);
+        let target_retransmits = target_tcp.map(|info| info.retransmits);
+        let target_delivery_rate = target_tcp.map(|info| info.delivery_rate);
+        // The record repeats the span's fields so it stands alone, e.g. as one JSON log line.
+        if res.is_ok() {
+            status::record_success(label, namespace);
+            info!(
+                label,
+                peer,
+                namespace,
+                session_id,
+                target,
+                duration_ms,
+                bytes_client_to_target,
+                bytes_target_to_client,
+                reason,
+                client_rtt_us,
+                client_retransmits,
+                client_delivery_rate,
+                target_rtt_us,
+                target_retransmits,
+                target_delivery_rate,
+                "session closed"
+            );
+        } else {
+            status::record_error(label, namespace, reason.clone());
+            warn!(
+                label,
+                peer,
+                namespace,
+                session_id,
+                target,
+                duration_ms,
+                reason,
+                client_rtt_us,
+                client_retransmits,
+                client_delivery_rate,
+                target_rtt_us,
+                target_retransmits,
+                target_delivery_rate,
+                "session closed"
+            );
+        }
+    }
+
+    /// Close a session the admission hook turned away before it reached `target`.
+    pub fn reject(self, target: &str) {
+        let live = &self.live;
+        access_log::close(access_log::Close {
+            id: live.id,
+            label: &live.label,
+            peer: live.peer.as_deref(),
+            namespace: live.namespace.as_deref(),
+            target,
+            duration_ms: live.started.elapsed().as_millis() as u64,
+            bytes_client_to_target: 0,
+            bytes_target_to_client: 0,
+            reason: "rejected",
+        });
+    }
+}
+
+impl Live {
+    fn adopt_id(&self, id: u64) {
+        if self.session_id.set(id).is_ok() {
+            self.span.record("session_id", display(format_id(id)));
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        SESSIONS
+            .lock()
+            .expect("session registry poisoned")
+            .remove(&self.live.id);
+    }
+}
+
+/// Session IDs are logged as 16 hex digits.
+fn format_id(id: u64) -> String {
+    format!("{id:016x}")
+}
+
+/// Send the running session's ID over a freshly connected UDS, for the namespace endpoint's
+/// [`receive_id`].
+pub async fn send_id<S: AsyncWrite + Unpin>(uds: &mut S) -> Result<()> {
+    let id = CURRENT
+        .try_with(|live| live.session_id.get().copied())
+        .ok()
+        .flatten()
+        .context("no session ID to send")?;
+    uds.write_all(&id.to_be_bytes())
+        .await
+        .context("failed to send session ID")
+}
+
+/// Read the session ID the host proxy sent with [`send_id`] and make it the running session's.
+pub async fn receive_id<S: AsyncRead + Unpin>(uds: &mut S) -> Result<()> {
+    let id = uds
+        .read_u64()
+        .await
+        .context("failed to read session ID from host proxy")?;
+    let _ = CURRENT.try_with(|live| live.adopt_id(id));
+    Ok(())
+}
+
+pub fn uds_connect_span(uds: &impl Display) -> Span {
+    info_span!(target: TARGET, "uds_connect", uds = %uds)
+}
+
+pub fn target_connect_span(target: &str) -> Span {
+    info_span!(target: TARGET, "target_connect", target)
+}
+
+pub fn relay_span() -> Span {
+    info_span!(target: TARGET, "relay")
+}
+
+/// Note the bytes a session relayed on its span, the current one.
+pub fn record_bytes(client_to_target: u64, target_to_client: u64) {
+    let span = Span::current();
+    span.record("bytes_client_to_target", client_to_target);
+    span.record("bytes_target_to_client", target_to_client);
+}
+
+/// Read the `TCP_INFO` of the running session's `client` and `target` sockets, those that are TCP,
+/// into `metrics` and the session's `session closed` record. Call it once the relay is done,
+/// whether or not it failed, while both sockets are still open.
+pub fn record_tcp_info(
+    client: Option<BorrowedFd<'_>>,
+    target: Option<BorrowedFd<'_>>,
+    metrics: &Metrics,
+) {
+    let client = client.and_then(tcp_info::query);
+    let target = target.and_then(tcp_info::query);
+    metrics.tcp_info(client.as_ref(), target.as_ref());
+    let _ = CURRENT.try_with(|live| live.tcp_info.set((client, target)));
+}
+
+/// [`copy_bidirectional`] between a session's client and its target in a `relay` span, accounted
+/// in `metrics`.
+pub async fn relay<C, T>(client: &mut C, target: &mut T, metrics: &Metrics) -> Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let (sent, received) = copy_bidirectional(&mut counted(client), target)
+        .instrument(relay_span())
+        .await
+        .inspect_err(|err| metrics.error(ErrorKind::Relay, err))?;
+    record_bytes(sent, received);
+    metrics.add_bytes(sent, received);
+    Ok((sent, received))
+}
+
+/// `client` of the running session, counting what it reads as bytes towards the target and what
+/// is written to it as bytes towards the client, and capturing both while its forward is
+/// [captured](crate::pipeline::capture). Outside [`Session::run`] nothing is counted.
+pub fn counted<S>(client: S) -> Counted<S> {
+    let live = CURRENT.try_with(Arc::clone).ok();
+    let capture = live.as_ref().and_then(|live| {
+        capture::stream(
+            &live.label,
+            live.namespace.as_deref(),
+            live.id,
+            live.peer.as_deref(),
+            live.target.get().map(String::as_str),
+        )
+    });
+    Counted {
+        inner: client,
+        live,
+        capture,
+    }
+}
+
+pub struct Counted<S> {
+    inner: S,
+    live: Option<Arc<Live>>,
+    capture: Option<capture::Stream>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Some(live) = self.live.as_ref() {
+            let n = (buf.filled().len() - before) as u64;
+            live.client_to_target.fetch_add(n, Ordering::Relaxed);
+        }
+        if let Some(capture) = self.capture.as_mut() {
+            capture.client_to_target(&buf.filled()[before..]);
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            if let Some(live) = self.live.as_ref() {
+                live.target_to_client.fetch_add(n as u64, Ordering::Relaxed);
+            }
+            if let Some(capture) = self.capture.as_mut() {
+                capture.target_to_client(&buf[..n]);
+            }
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
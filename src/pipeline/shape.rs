@@ -0,0 +1,88 @@
+//! Link emulation (`shape_*` options): a token bucket for rate and burst, plus a per-chunk delay
+//! drawn from a configurable distribution. Both run inside the delay line in [`crate::pipeline::chaos`],
+//! one bucket per direction, so each direction behaves like its own link.
+
+use std::time::Duration;
+
+use tokio::time::{Instant, sleep};
+
+use crate::config::{ForwardSpec, ShapeDistribution};
+use crate::pipeline::chaos::Rng;
+
+/// Burst used when `shape_rate` is set without `shape_burst`: one full-size Ethernet frame.
+const DEFAULT_BURST: u64 = 1500;
+
+/// Rate and delay settings for one forward.
+#[derive(Debug, Clone, Copy)]
+pub struct Shaping {
+    rate: Option<u64>,
+    burst: u64,
+    delay: Duration,
+    jitter: Duration,
+    distribution: ShapeDistribution,
+}
+
+impl Shaping {
+    pub fn for_spec(spec: &ForwardSpec) -> Option<Self> {
+        if !spec.shaped() {
+            return None;
+        }
+        Some(Self {
+            rate: spec.shape_rate,
+            burst: spec.shape_burst.unwrap_or(DEFAULT_BURST),
+            delay: Duration::from_millis(spec.shape_delay_ms.unwrap_or(0)),
+            jitter: Duration::from_millis(spec.shape_delay_jitter_ms.unwrap_or(0)),
+            distribution: spec.shape_delay_distribution(),
+        })
+    }
+
+    /// Fresh bucket for one direction of a session, starting full.
+    pub fn bucket(&self) -> Option<TokenBucket> {
+        self.rate.map(|rate| TokenBucket {
+            rate: rate as f64,
+            burst: self.burst as f64,
+            tokens: self.burst as f64,
+            last: Instant::now(),
+        })
+    }
+
+    /// Propagation delay for one chunk.
+    pub fn delay(&self, rng: &mut Rng) -> Duration {
+        if self.jitter.is_zero() {
+            return self.delay;
+        }
+        let jitter = self.jitter.as_secs_f64();
+        let offset = match self.distribution {
+            ShapeDistribution::Uniform => (rng.unit() * 2.0 - 1.0) * jitter,
+            ShapeDistribution::Normal => {
+                // Box-Muller; `1 - unit` keeps the logarithm away from zero.
+                let radius = (-2.0 * (1.0 - rng.unit()).ln()).sqrt();
+                radius * (std::f64::consts::TAU * rng.unit()).cos() * jitter
+            }
+        };
+        Duration::from_secs_f64((self.delay.as_secs_f64() + offset).max(0.0))
+    }
+}
+
+/// Bytes may leave once the bucket holds enough tokens; it refills at `rate` per second up to
+/// `burst`. A chunk larger than what is left drives the bucket into debt, which is paid off by
+/// waiting, so long-run throughput matches `rate` whatever the chunk sizes.
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub async fn take(&mut self, bytes: usize) {
+        let now = Instant::now();
+        self.tokens =
+            (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.burst);
+        self.last = now;
+        self.tokens -= bytes as f64;
+        if self.tokens < 0.0 {
+            sleep(Duration::from_secs_f64(-self.tokens / self.rate)).await;
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! Protocol sniffing for host proxies that share one listener between several UDS backends.
+//!
+//! The first bytes of each connection are peeked (not consumed), so the chosen backend sees the
+//! stream from its first byte. Clients that stay silent past [`SNIFF_TIMEOUT`] are treated as
+//! unrecognised, which lets server-speaks-first protocols fall through to the default `uds`.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use tokio::io;
+use tokio::time::{Instant, sleep};
+
+use crate::config::ForwardSpec;
+use crate::pipeline::listener::ClientStream;
+
+const SNIFF_TIMEOUT: Duration = Duration::from_secs(2);
+const SNIFF_POLL: Duration = Duration::from_millis(10);
+/// Longest signature below (`OPTIONS `).
+const SNIFF_BYTES: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Ssh,
+    Tls,
+    Http,
+}
+
+const SIGNATURES: &[(&[u8], Protocol)] = &[
+    (b"SSH-", Protocol::Ssh),
+    // Handshake record, protocol major version 3.
+    (b"\x16\x03", Protocol::Tls),
+    (b"GET ", Protocol::Http),
+    (b"HEAD ", Protocol::Http),
+    (b"POST ", Protocol::Http),
+    (b"PUT ", Protocol::Http),
+    (b"DELETE ", Protocol::Http),
+    (b"OPTIONS ", Protocol::Http),
+    (b"PATCH ", Protocol::Http),
+    (b"CONNECT ", Protocol::Http),
+    (b"TRACE ", Protocol::Http),
+];
+
+/// `Some(result)` once the prefix decides the protocol (or rules every signature out), `None` while
+/// more bytes could still match.
+fn classify(prefix: &[u8]) -> Option<Option<Protocol>> {
+    let mut undecided = false;
+    for &(signature, protocol) in SIGNATURES {
+        if prefix.starts_with(signature) {
+            return Some(Some(protocol));
+        }
+        undecided |= signature.starts_with(prefix);
+    }
+    (!undecided).then_some(None)
+}
+
+/// Peek at the client's first bytes until they identify a protocol, rule all of them out, or the
+/// client goes quiet.
+pub async fn sniff(stream: &ClientStream) -> io::Result<Option<Protocol>> {
+    let deadline = Instant::now() + SNIFF_TIMEOUT;
+    let mut buf = [0u8; SNIFF_BYTES];
+    let mut seen = 0;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(None);
+        }
+        let len = match tokio::time::timeout(remaining, stream.peek(&mut buf)).await {
+            Ok(len) => len?,
+            Err(_) => return Ok(None),
+        };
+        if len == 0 {
+            return Ok(None);
+        }
+        if let Some(protocol) = classify(&buf[..len]) {
+            return Ok(protocol);
+        }
+        if len == seen {
+            // Peek returns what is already queued; give the client a moment to send more.
+            sleep(SNIFF_POLL).await;
+        }
+        seen = len;
+    }
+}
+
+/// UDS backend per sniffed protocol, plus the spec's `uds` as the fallback.
+#[derive(Debug, Clone)]
+pub struct SniffRoutes {
+    ssh: Option<PathBuf>,
+    tls: Option<PathBuf>,
+    http: Option<PathBuf>,
+    fallback: Option<PathBuf>,
+}
+
+impl SniffRoutes {
+    pub fn from_spec(spec: &ForwardSpec) -> Self {
+        Self {
+            ssh: spec.uds_ssh.clone(),
+            tls: spec.uds_tls.clone(),
+            http: spec.uds_http.clone(),
+            fallback: spec.uds.clone(),
+        }
+    }
+
+    pub fn route(&self, protocol: Option<Protocol>) -> Option<&Path> {
+        let routed = match protocol {
+            Some(Protocol::Ssh) => self.ssh.as_deref(),
+            Some(Protocol::Tls) => self.tls.as_deref(),
+            Some(Protocol::Http) => self.http.as_deref(),
+            None => None,
+        };
+        routed.or(self.fallback.as_deref())
+    }
+}
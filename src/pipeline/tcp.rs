@@ -1,10 +1,16 @@
 use anyhow::{Context, Result};
-use tokio::net::{TcpListener, TcpStream};
 use tokio::task::JoinHandle;
-use tracing::{info, instrument, warn};
+use tracing::{Instrument, info, instrument};
 
 use crate::config::ForwardSpec;
-use crate::pipeline::{ShutdownRx, copy_bidirectional};
+use crate::pipeline::ShutdownRx;
+use crate::pipeline::admission::{Admission, Verdict};
+use crate::pipeline::lifecycle;
+use crate::pipeline::listener::{ClientStream, StreamListener};
+use crate::pipeline::pause::Gate;
+use crate::pipeline::relay::Relay;
+use crate::pipeline::session::{self, Session};
+use crate::target::Connector;
 
 /// Spawn a direct TCP proxy task that forwards bytes between host clients and a remote target.
 ///
@@ -13,21 +19,24 @@ pub fn spawn(spec: ForwardSpec, shutdown: ShutdownRx) -> JoinHandle<Result<()>>
     tokio::spawn(async move { tcp_proxy_loop(spec, shutdown).await })
 }
 
-#[instrument(skip_all, fields(listen = spec.listen.as_deref().unwrap_or_default(), target = spec.target.as_deref().unwrap_or_default()))]
+#[instrument(skip_all, fields(listen = spec.listen.as_deref().unwrap_or_default(), target = spec.target.as_deref().or(spec.target_exec.as_deref()).unwrap_or_default()))]
 async fn tcp_proxy_loop(spec: ForwardSpec, mut shutdown: ShutdownRx) -> Result<()> {
     let listen_addr = spec
         .listen
         .as_ref()
         .context("listen address missing for tcp proxy")?;
-    let target = spec
-        .target
-        .clone()
-        .context("tcp proxy requires target address")?;
+    let connector = Connector::for_spec(&spec).context("tcp proxy requires a target")?;
+    let _pool = connector.warm_pool();
+    let relay = Relay::for_spec(&spec, "tcp")?;
+    let admission = Admission::for_spec(&spec)?;
+    let mptcp = spec.mptcp();
+    let target = connector.target();
 
-    let listener = TcpListener::bind(listen_addr)
-        .await
-        .with_context(|| format!("failed to bind {}", listen_addr))?;
+    let listener = StreamListener::bind(listen_addr, spec.mptcp(), spec.ipv6_only)
+        .inspect_err(|err| relay.metrics().bind_error(err))?;
     info!(%listen_addr, %target, "tcp proxy listening");
+    let _lifecycle = lifecycle::up(&spec, listen_addr).await;
+    let mut gate = Gate::for_spec(&spec);
 
     loop {
         tokio::select! {
@@ -38,14 +47,27 @@ async fn tcp_proxy_loop(spec: ForwardSpec, mut shutdown: ShutdownRx) -> Result<(
                     break;
                 }
             }
-            accept_res = listener.accept() => {
+            accept_res = gate.accept(|| listener.accept()) => {
                 let (client, peer) = accept_res?;
-                let target = target.clone();
+                let connector = connector.clone();
+                let relay = relay.clone();
+                let admission = admission.clone();
+                let session = Session::new(&spec, Some(&peer));
+                let span = session.span();
                 tokio::spawn(async move {
-                    if let Err(err) = bridge_tcp(client, target).await {
-                        warn!(peer = %peer, error = %err, "tcp proxy session failed");
-                    }
-                });
+                    let connector = match admission.check(&peer).await {
+                        Verdict::Accept => connector,
+                        Verdict::Reject => {
+                            info!(peer = %peer, "connection rejected by admission hook");
+                            session.reject(connector.target());
+                            return;
+                        }
+                        Verdict::Target(target) => Connector::new(target, mptcp),
+                    };
+                    let target = connector.target().to_string();
+                    let res = session.run(&target, bridge_tcp(client, connector, relay)).await;
+                    session.close(&target, &res);
+                }.instrument(span));
             }
         }
     }
@@ -53,13 +75,22 @@ async fn tcp_proxy_loop(spec: ForwardSpec, mut shutdown: ShutdownRx) -> Result<(
     Ok(())
 }
 
-/// Dial the upstream target and forward bytes in both directions until either side closes.
-async fn bridge_tcp(mut client: TcpStream, target: String) -> Result<()> {
+/// Dial the upstream target and forward bytes in both directions until either side closes, returning
+/// the bytes relayed each way.
+async fn bridge_tcp(
+    mut client: ClientStream,
+    connector: Connector,
+    relay: Relay,
+) -> Result<(u64, u64)> {
+    let _active = relay.metrics().session();
     client.set_nodelay(true).ok();
-    let mut upstream = TcpStream::connect(&target)
+    let mut upstream = relay
+        .metrics()
+        .target_connect(connector.connect())
         .await
-        .with_context(|| format!("tcp proxy failed to connect to {}", target))?;
+        .with_context(|| format!("tcp proxy failed to connect to {}", connector.target()))?;
     upstream.set_nodelay(true).ok();
-    copy_bidirectional(&mut client, &mut upstream).await?;
-    Ok(())
+    let res = relay.run(&mut client, &mut upstream).await;
+    session::record_tcp_info(client.socket_fd(), upstream.socket_fd(), relay.metrics());
+    res
 }
@@ -1,25 +1,147 @@
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::hash_map::Entry;
+use std::fmt;
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use anyhow::{Context, Result};
-use tokio::net::UdpSocket;
+use anyhow::{Context, Result, bail};
+use tokio::net::{UdpSocket, UnixDatagram};
 use tokio::task::JoinHandle;
 use tokio::time::interval;
 use tracing::{debug, info, warn};
 
 use crate::config::ForwardSpec;
+use crate::metrics::{ActiveGuard, Direction, ErrorKind, Metrics};
 use crate::pipeline::ShutdownRx;
+use crate::pipeline::lifecycle;
+use crate::pipeline::multicast::bind_udp_listener;
+use crate::pipeline::udp_cid::{CidTracker, ClientCell};
+use crate::{ipfix, log_limit};
 
-const CLEANUP_INTERVAL: Duration = Duration::from_secs(30);
+pub(crate) const CLEANUP_INTERVAL: Duration = Duration::from_secs(30);
+/// Largest UDP payload we relay.
+pub(crate) const MAX_DATAGRAM: usize = 65_507;
+/// Largest client header `encode_frame` prepends (IPv6 family + address + port).
+pub(crate) const MAX_FRAME_HEADER: usize = 19;
 
 /// Lightweight session that holds the remote-facing UDP socket and the JoinHandle in charge of
 /// sending responses back to the originating client.
-struct UdpSession {
+pub(crate) struct UdpSession {
     remote: Arc<UdpSocket>,
     last_seen: Instant,
     pump_handle: JoinHandle<()>,
+    relayed: Arc<Relayed>,
+    _open: ActiveGuard,
+}
+
+/// What a session relayed, exported as an IPFIX flow when it ends.
+struct Relayed {
+    label: String,
+    client: SocketAddr,
+    target: Option<SocketAddr>,
+    started_unix_ms: u64,
+    datagrams_client_to_target: AtomicU64,
+    bytes_client_to_target: AtomicU64,
+    datagrams_target_to_client: AtomicU64,
+    bytes_target_to_client: AtomicU64,
+}
+
+impl Relayed {
+    fn count(&self, direction: Direction, len: usize) {
+        let (datagrams, bytes) = match direction {
+            Direction::ClientToTarget => (
+                &self.datagrams_client_to_target,
+                &self.bytes_client_to_target,
+            ),
+            Direction::TargetToClient => (
+                &self.datagrams_target_to_client,
+                &self.bytes_target_to_client,
+            ),
+        };
+        datagrams.fetch_add(1, Ordering::Relaxed);
+        bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+}
+
+impl Drop for UdpSession {
+    fn drop(&mut self) {
+        let relayed = &self.relayed;
+        ipfix::export(ipfix::Flow {
+            label: &relayed.label,
+            protocol: ipfix::PROTOCOL_UDP,
+            client: Some(relayed.client),
+            target: relayed.target,
+            started_unix_ms: relayed.started_unix_ms,
+            bytes_client_to_target: relayed.bytes_client_to_target.load(Ordering::Relaxed),
+            bytes_target_to_client: relayed.bytes_target_to_client.load(Ordering::Relaxed),
+            packets: Some((
+                relayed.datagrams_client_to_target.load(Ordering::Relaxed),
+                relayed.datagrams_target_to_client.load(Ordering::Relaxed),
+            )),
+        });
+    }
+}
+
+/// Where the response pump delivers datagrams coming back from the upstream target.
+pub(crate) enum ReplyTo {
+    /// Straight back to a UDP client through the listener socket.
+    Udp {
+        socket: Arc<UdpSocket>,
+        client: SocketAddr,
+    },
+    /// Back to a UDP client whose address may change while the session is alive; responses also
+    /// teach the tracker the server's connection IDs.
+    Tracked {
+        socket: Arc<UdpSocket>,
+        client: ClientCell,
+        tracker: CidTracker,
+    },
+    /// Back across a datagram UDS to the host proxy, prefixed with the client's frame header.
+    /// `peer` is `None` when the socket is one end of a connected socketpair.
+    Unix {
+        socket: Arc<UnixDatagram>,
+        peer: Option<PathBuf>,
+        client: SocketAddr,
+    },
+}
+
+impl ReplyTo {
+    fn client(&self) -> SocketAddr {
+        match self {
+            ReplyTo::Udp { client, .. } | ReplyTo::Unix { client, .. } => *client,
+            ReplyTo::Tracked { client, .. } => *client.lock().expect("client cell poisoned"),
+        }
+    }
+
+    async fn send(&self, payload: &[u8], frame: &mut Vec<u8>) -> std::io::Result<()> {
+        match self {
+            ReplyTo::Udp { socket, client } => socket.send_to(payload, client).await.map(drop),
+            ReplyTo::Tracked {
+                socket,
+                client,
+                tracker,
+            } => {
+                tracker.observe_server(client, payload);
+                let client = *client.lock().expect("client cell poisoned");
+                socket.send_to(payload, client).await.map(drop)
+            }
+            ReplyTo::Unix {
+                socket,
+                peer,
+                client,
+            } => {
+                encode_frame(*client, payload, frame);
+                match peer {
+                    Some(peer) => socket.send_to(frame, peer).await.map(drop),
+                    None => socket.send(frame).await.map(drop),
+                }
+            }
+        }
+    }
 }
 
 pub fn spawn(spec: ForwardSpec, shutdown: ShutdownRx) -> JoinHandle<Result<()>> {
@@ -37,17 +159,26 @@ async fn udp_proxy_loop(spec: ForwardSpec, mut shutdown: ShutdownRx) -> Result<(
         .context("udp proxy requires udp_target address")?
         .clone();
     let idle_timeout = spec.udp_idle_timeout();
+    let tracker = CidTracker::new(spec.udp_session_key());
 
     let client_socket = Arc::new(
-        UdpSocket::bind(listen_addr)
+        bind_udp_listener(listen_addr)
             .await
-            .with_context(|| format!("failed to bind udp listener {}", listen_addr))?,
+            .inspect_err(|err| Metrics::for_spec(&spec, "udp").bind_error(err))?,
+    );
+    info!(
+        %listen_addr,
+        %target_addr,
+        idle_secs = idle_timeout.as_secs(),
+        session_key = ?spec.udp_session_key(),
+        "udp proxy listening"
     );
-    info!(%listen_addr, %target_addr, idle_secs = idle_timeout.as_secs(), "udp proxy listening");
+    let _lifecycle = lifecycle::up(&spec, listen_addr).await;
+    let metrics = Metrics::for_spec(&spec, "udp");
 
     let mut sessions: HashMap<SocketAddr, UdpSession> = HashMap::new();
     let mut cleanup = interval(CLEANUP_INTERVAL);
-    let mut buf = vec![0u8; 65_507];
+    let mut buf = vec![0u8; MAX_DATAGRAM];
 
     loop {
         tokio::select! {
@@ -59,37 +190,30 @@ async fn udp_proxy_loop(spec: ForwardSpec, mut shutdown: ShutdownRx) -> Result<(
                 }
             }
             _ = cleanup.tick() => {
-                prune_sessions(&mut sessions, idle_timeout);
+                prune_sessions(&mut sessions, idle_timeout, &metrics);
+                if let Some(tracker) = tracker.as_ref() {
+                    tracker.prune();
+                }
             }
             recv = client_socket.recv_from(&mut buf) => {
                 let (len, client_addr) = recv?;
-                let mut drop_session = false;
+                if let Some(tracker) = tracker.as_ref()
+                    && let Some(previous) = tracker.observe_client(client_addr, &buf[..len])
+                    && let Some(session) = sessions.remove(&previous)
                 {
-                    let session = match sessions.get_mut(&client_addr) {
-                        Some(existing) => existing,
-                        None => {
-                            let session = create_session(
-                                client_addr,
-                                target_addr.clone(),
-                                client_socket.clone(),
-                                shutdown.clone(),
-                            )
-                            .await?;
-                            sessions.insert(client_addr, session);
-                            sessions.get_mut(&client_addr).expect("session just inserted")
-                        }
-                    };
-                    session.last_seen = Instant::now();
-                    if let Err(err) = session.remote.send(&buf[..len]).await {
-                        warn!(client = %client_addr, error = %err, "failed to send udp datagram upstream");
-                        drop_session = true;
-                    }
-                }
-                if drop_session {
-                    if let Some(session) = sessions.remove(&client_addr) {
-                        session.pump_handle.abort();
-                    }
+                    info!(from = %previous, to = %client_addr, "udp client changed address; keeping its session");
+                    sessions.insert(client_addr, session);
                 }
+                let reply = || match tracker.as_ref() {
+                    Some(tracker) => ReplyTo::Tracked {
+                        socket: client_socket.clone(),
+                        client: tracker.register(client_addr),
+                        tracker: tracker.clone(),
+                    },
+                    None => ReplyTo::Udp { socket: client_socket.clone(), client: client_addr },
+                };
+                relay_upstream(&mut sessions, client_addr, client_addr, &buf[..len], &target_addr, reply, &metrics, &shutdown)
+                    .await?;
             }
         }
     }
@@ -98,14 +222,74 @@ async fn udp_proxy_loop(spec: ForwardSpec, mut shutdown: ShutdownRx) -> Result<(
     Ok(())
 }
 
+/// Send one client datagram upstream, creating the client's session on first contact. A session
+/// whose upstream send fails is torn down so the next datagram starts over with a fresh socket.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn relay_upstream<K>(
+    sessions: &mut HashMap<K, UdpSession>,
+    key: K,
+    client: SocketAddr,
+    payload: &[u8],
+    target_addr: &str,
+    reply: impl FnOnce() -> ReplyTo,
+    metrics: &Arc<Metrics>,
+    shutdown: &ShutdownRx,
+) -> Result<()>
+where
+    K: Eq + Hash + Clone,
+{
+    let session = match sessions.entry(key.clone()) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => {
+            let session = create_session(
+                client,
+                target_addr.to_string(),
+                reply(),
+                metrics,
+                shutdown.clone(),
+            )
+            .await
+            .inspect_err(|err| metrics.connect_error(ErrorKind::TargetConnect, err))?;
+            entry.insert(session)
+        }
+    };
+    session.last_seen = Instant::now();
+    match session.remote.send(payload).await {
+        Ok(_) => {
+            metrics.datagram(Direction::ClientToTarget, payload.len());
+            session
+                .relayed
+                .count(Direction::ClientToTarget, payload.len());
+        }
+        Err(err) => {
+            metrics.datagram_dropped();
+            if let Some(suppressed) = log_limit::allow("failed to send udp datagram upstream", &err)
+            {
+                warn!(client = %client, error = %err, suppressed, "failed to send udp datagram upstream");
+            }
+            if let Some(session) = sessions.remove(&key) {
+                session.pump_handle.abort();
+            }
+        }
+    }
+    Ok(())
+}
+
 /// Remove idle UDP sessions and abort their response pump tasks so resources are reclaimed.
-fn prune_sessions(sessions: &mut HashMap<SocketAddr, UdpSession>, idle_timeout: Duration) {
+pub(crate) fn prune_sessions<K>(
+    sessions: &mut HashMap<K, UdpSession>,
+    idle_timeout: Duration,
+    metrics: &Metrics,
+) where
+    K: fmt::Debug,
+{
     let now = Instant::now();
     sessions.retain(|client, session| {
         let idle = now.duration_since(session.last_seen);
         if idle > idle_timeout {
-            debug!(client = %client, idle_secs = idle.as_secs(), "dropping idle udp session");
+            debug!(client = ?client, idle_secs = idle.as_secs(), "dropping idle udp session");
             session.pump_handle.abort();
+            metrics.udp_session_expired();
             false
         } else {
             true
@@ -114,7 +298,7 @@ fn prune_sessions(sessions: &mut HashMap<SocketAddr, UdpSession>, idle_timeout:
 }
 
 /// Abort any remaining per-client tasks when the UDP proxy loop is exiting.
-fn drain_sessions(mut sessions: HashMap<SocketAddr, UdpSession>) {
+pub(crate) fn drain_sessions<K>(mut sessions: HashMap<K, UdpSession>) {
     for (_, session) in sessions.drain() {
         session.pump_handle.abort();
     }
@@ -123,9 +307,10 @@ fn drain_sessions(mut sessions: HashMap<SocketAddr, UdpSession>) {
 /// Create a new per-client relay socket and launch a task that copies remote responses back to the
 /// original client address.
 async fn create_session(
-    client_addr: SocketAddr,
+    client: SocketAddr,
     target_addr: String,
-    client_socket: Arc<UdpSocket>,
+    reply: ReplyTo,
+    metrics: &Arc<Metrics>,
     shutdown: ShutdownRx,
 ) -> Result<UdpSession> {
     let remote_socket = Arc::new(
@@ -138,13 +323,33 @@ async fn create_session(
         .await
         .with_context(|| format!("failed to connect udp target {}", target_addr))?;
 
-    let remote_reader =
-        spawn_remote_pump(remote_socket.clone(), client_socket, client_addr, shutdown);
+    let relayed = Arc::new(Relayed {
+        label: metrics.label().to_string(),
+        client,
+        target: remote_socket.peer_addr().ok(),
+        started_unix_ms: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64,
+        datagrams_client_to_target: AtomicU64::new(0),
+        bytes_client_to_target: AtomicU64::new(0),
+        datagrams_target_to_client: AtomicU64::new(0),
+        bytes_target_to_client: AtomicU64::new(0),
+    });
+    let remote_reader = spawn_remote_pump(
+        remote_socket.clone(),
+        reply,
+        metrics.clone(),
+        relayed.clone(),
+        shutdown,
+    );
 
     Ok(UdpSession {
         remote: remote_socket,
         last_seen: Instant::now(),
         pump_handle: remote_reader,
+        relayed,
+        _open: metrics.udp_session(),
     })
 }
 
@@ -152,12 +357,15 @@ async fn create_session(
 /// the originating client. It terminates when the session is idle or shutdown is triggered.
 fn spawn_remote_pump(
     remote_socket: Arc<UdpSocket>,
-    client_socket: Arc<UdpSocket>,
-    client_addr: SocketAddr,
+    reply: ReplyTo,
+    metrics: Arc<Metrics>,
+    relayed: Arc<Relayed>,
     mut shutdown: ShutdownRx,
 ) -> JoinHandle<()> {
     tokio::spawn(async move {
-        let mut buf = vec![0u8; 65_507];
+        let client_addr = reply.client();
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        let mut frame = Vec::with_capacity(MAX_DATAGRAM + MAX_FRAME_HEADER);
         loop {
             tokio::select! {
                 biased;
@@ -169,13 +377,20 @@ fn spawn_remote_pump(
                 recv = remote_socket.recv(&mut buf) => {
                     match recv {
                         Ok(len) => {
-                            if let Err(err) = client_socket.send_to(&buf[..len], client_addr).await {
-                                warn!(client = %client_addr, error = %err, "failed to forward udp response");
+                            if let Err(err) = reply.send(&buf[..len], &mut frame).await {
+                                metrics.datagram_dropped();
+                                if let Some(suppressed) = log_limit::allow("failed to forward udp response", &err) {
+                                    warn!(client = %client_addr, error = %err, suppressed, "failed to forward udp response");
+                                }
                                 break;
                             }
+                            metrics.datagram(Direction::TargetToClient, len);
+                            relayed.count(Direction::TargetToClient, len);
                         }
                         Err(err) => {
-                            warn!(client = %client_addr, error = %err, "udp remote recv failed");
+                            if let Some(suppressed) = log_limit::allow("udp remote recv failed", &err) {
+                                warn!(client = %client_addr, error = %err, suppressed, "udp remote recv failed");
+                            }
                             break;
                         }
                     }
@@ -184,3 +399,39 @@ fn spawn_remote_pump(
         }
     })
 }
+
+/// Prefix `payload` with the UDP client address so the other end of a datagram UDS knows which
+/// client the datagram belongs to. Layout: family (4 or 6), address octets, big-endian port.
+pub(crate) fn encode_frame(client: SocketAddr, payload: &[u8], frame: &mut Vec<u8>) {
+    frame.clear();
+    match client.ip() {
+        IpAddr::V4(ip) => {
+            frame.push(4);
+            frame.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            frame.push(6);
+            frame.extend_from_slice(&ip.octets());
+        }
+    }
+    frame.extend_from_slice(&client.port().to_be_bytes());
+    frame.extend_from_slice(payload);
+}
+
+/// Split a datagram received over a UDS into the client address and the original payload.
+pub(crate) fn decode_frame(frame: &[u8]) -> Result<(SocketAddr, &[u8])> {
+    let (&family, rest) = frame.split_first().context("empty udp frame")?;
+    let (ip, rest) = match family {
+        4 if rest.len() >= 6 => {
+            let octets: [u8; 4] = rest[..4].try_into().expect("length checked");
+            (IpAddr::from(octets), &rest[4..])
+        }
+        6 if rest.len() >= 18 => {
+            let octets: [u8; 16] = rest[..16].try_into().expect("length checked");
+            (IpAddr::from(octets), &rest[16..])
+        }
+        _ => bail!("malformed udp frame header"),
+    };
+    let port = u16::from_be_bytes([rest[0], rest[1]]);
+    Ok((SocketAddr::new(ip, port), &rest[2..]))
+}
@@ -0,0 +1,168 @@
+//! Connection-ID aware client tracking for the direct UDP proxy.
+//!
+//! Protocols such as QUIC and WireGuard carry an identifier chosen by the server in every packet
+//! the client sends. Learning those identifiers from server responses lets the proxy recognise a
+//! client whose source address changed (NAT rebinding, roaming) and keep routing it through the
+//! existing upstream socket instead of starting a fresh session the server knows nothing about.
+//!
+//! QUIC identifiers are only learned from long-header packets, whose connection IDs travel in the
+//! clear. IDs handed out later in encrypted `NEW_CONNECTION_ID` frames are invisible to the proxy,
+//! so a client that deliberately migrates onto such an ID still starts a new session.
+
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::config::UdpSessionKey;
+
+/// Current address of one client, shared between the proxy loop and the session's response pump.
+pub(crate) type ClientCell = Arc<Mutex<SocketAddr>>;
+
+const QUIC_LONG_HEADER: u8 = 0x80;
+const QUIC_MAX_CID_LEN: usize = 20;
+const WIREGUARD_HANDSHAKE_RESPONSE: u8 = 2;
+const WIREGUARD_TRANSPORT_DATA: u8 = 4;
+const WIREGUARD_INDEX_LEN: usize = 4;
+
+#[derive(Clone)]
+pub(crate) struct CidTracker {
+    protocol: UdpSessionKey,
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    by_cid: HashMap<Vec<u8>, Weak<Mutex<SocketAddr>>>,
+    by_addr: HashMap<SocketAddr, Weak<Mutex<SocketAddr>>>,
+    /// Lengths of the QUIC connection IDs learned so far; short headers do not encode it.
+    cid_lens: BTreeSet<usize>,
+}
+
+impl CidTracker {
+    /// Returns `None` when sessions are keyed by address alone.
+    pub(crate) fn new(protocol: UdpSessionKey) -> Option<Self> {
+        (protocol != UdpSessionKey::Address).then(|| Self {
+            protocol,
+            inner: Arc::default(),
+        })
+    }
+
+    /// Register a newly created session for `client` and return the cell its pump replies to.
+    pub(crate) fn register(&self, client: SocketAddr) -> ClientCell {
+        let cell = Arc::new(Mutex::new(client));
+        let mut inner = self.inner.lock().expect("cid tracker poisoned");
+        inner.by_addr.insert(client, Arc::downgrade(&cell));
+        cell
+    }
+
+    /// Inspect a datagram from `from` before the session lookup. Returns the client's previous
+    /// address when the datagram belongs to a known connection that arrived from a new address;
+    /// the caller then moves the session over.
+    pub(crate) fn observe_client(&self, from: SocketAddr, datagram: &[u8]) -> Option<SocketAddr> {
+        let mut inner = self.inner.lock().expect("cid tracker poisoned");
+        if let Some(cell) = inner.by_addr.get(&from).and_then(Weak::upgrade) {
+            // The client's own initial DCID identifies the connection until the server answers.
+            if self.protocol == UdpSessionKey::Quic
+                && let Some(dcid) = quic_long_header_cids(datagram).map(|(dcid, _)| dcid)
+            {
+                inner.remember(dcid, &cell);
+            }
+            return None;
+        }
+
+        let cell = match self.protocol {
+            UdpSessionKey::Address => None,
+            UdpSessionKey::Quic => match quic_long_header_cids(datagram) {
+                Some((dcid, _)) => inner.lookup(dcid),
+                None => inner.lookup_quic_short(datagram),
+            },
+            UdpSessionKey::Wireguard => {
+                wireguard_receiver_index(datagram).and_then(|index| inner.lookup(index))
+            }
+        }?;
+
+        let mut current = cell.lock().expect("client cell poisoned");
+        let previous = *current;
+        *current = from;
+        drop(current);
+        inner.by_addr.remove(&previous);
+        inner.by_addr.insert(from, Arc::downgrade(&cell));
+        Some(previous)
+    }
+
+    /// Learn the server-chosen identifier from a datagram travelling back to the client.
+    pub(crate) fn observe_server(&self, cell: &ClientCell, datagram: &[u8]) {
+        let id = match self.protocol {
+            UdpSessionKey::Address => None,
+            UdpSessionKey::Quic => quic_long_header_cids(datagram).map(|(_, scid)| scid),
+            UdpSessionKey::Wireguard => wireguard_sender_index(datagram),
+        };
+        if let Some(id) = id.filter(|id| !id.is_empty()) {
+            let mut inner = self.inner.lock().expect("cid tracker poisoned");
+            inner.remember(id, cell);
+        }
+    }
+
+    /// Forget identifiers whose session has been dropped.
+    pub(crate) fn prune(&self) {
+        let mut inner = self.inner.lock().expect("cid tracker poisoned");
+        inner.by_cid.retain(|_, cell| cell.strong_count() > 0);
+        inner.by_addr.retain(|_, cell| cell.strong_count() > 0);
+    }
+}
+
+impl Inner {
+    fn remember(&mut self, id: &[u8], cell: &ClientCell) {
+        if !self.by_cid.contains_key(id) {
+            self.cid_lens.insert(id.len());
+            self.by_cid.insert(id.to_vec(), Arc::downgrade(cell));
+        }
+    }
+
+    fn lookup(&self, id: &[u8]) -> Option<ClientCell> {
+        self.by_cid.get(id).and_then(Weak::upgrade)
+    }
+
+    fn lookup_quic_short(&self, datagram: &[u8]) -> Option<ClientCell> {
+        let cids = datagram.get(1..)?;
+        self.cid_lens
+            .iter()
+            .filter_map(|&len| cids.get(..len))
+            .find_map(|dcid| self.lookup(dcid))
+    }
+}
+
+/// Destination and source connection IDs of a QUIC long-header packet.
+fn quic_long_header_cids(datagram: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (&first, rest) = datagram.split_first()?;
+    if first & QUIC_LONG_HEADER == 0 {
+        return None;
+    }
+    let rest = rest.get(4..)?; // version
+    let (dcid, rest) = length_prefixed(rest)?;
+    let (scid, _) = length_prefixed(rest)?;
+    Some((dcid, scid))
+}
+
+fn length_prefixed(buf: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (&len, rest) = buf.split_first()?;
+    let len = usize::from(len);
+    if len > QUIC_MAX_CID_LEN || rest.len() < len {
+        return None;
+    }
+    Some(rest.split_at(len))
+}
+
+/// Index the server assigned itself, announced in its handshake response.
+fn wireguard_sender_index(datagram: &[u8]) -> Option<&[u8]> {
+    (*datagram.first()? == WIREGUARD_HANDSHAKE_RESPONSE)
+        .then(|| datagram.get(4..4 + WIREGUARD_INDEX_LEN))
+        .flatten()
+}
+
+/// Server index the client addresses its transport data to.
+fn wireguard_receiver_index(datagram: &[u8]) -> Option<&[u8]> {
+    (*datagram.first()? == WIREGUARD_TRANSPORT_DATA)
+        .then(|| datagram.get(4..4 + WIREGUARD_INDEX_LEN))
+        .flatten()
+}
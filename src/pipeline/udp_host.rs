@@ -0,0 +1,150 @@
+use std::io;
+use std::os::unix::net::UnixDatagram as StdUnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use tokio::net::UnixDatagram;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, instrument, warn};
+
+use crate::config::ForwardSpec;
+use crate::log_limit;
+use crate::metrics::{Direction, Metrics};
+use crate::pipeline::ShutdownRx;
+use crate::pipeline::lifecycle;
+use crate::pipeline::multicast::bind_udp_listener;
+use crate::pipeline::udp::{MAX_DATAGRAM, MAX_FRAME_HEADER, decode_frame, encode_frame};
+use crate::uds::bind_datagram;
+
+/// Listens for UDP clients on the host and relays their datagrams over a `SOCK_DGRAM` Unix socket
+/// to the namespace endpoint. Each datagram carries the client address so responses can be routed
+/// back without per-client state on this side.
+pub fn spawn(spec: ForwardSpec, shutdown: ShutdownRx) -> JoinHandle<Result<()>> {
+    tokio::spawn(async move {
+        let endpoint = spec.uds_path().to_path_buf();
+        // The endpoint needs an address to answer to, so the host side binds its own datagram
+        // socket next to the endpoint's and applies the same ownership and mode.
+        let reply_path = reply_socket_path(&endpoint);
+        let bound = bind_datagram(&reply_path, spec.owner.clone(), spec.mode)?;
+        info!(
+            uds = %endpoint.display(),
+            reply_uds = %reply_path.display(),
+            "udp host proxy relaying over uds"
+        );
+        udp_host_loop(spec, bound.socket().clone(), Some(&endpoint), shutdown).await
+    })
+}
+
+/// Like [`spawn`], but relays over one end of a socketpair whose other end is held by an
+/// in-process namespace endpoint.
+pub fn spawn_paired(
+    spec: ForwardSpec,
+    socket: StdUnixDatagram,
+    shutdown: ShutdownRx,
+) -> JoinHandle<Result<()>> {
+    tokio::spawn(async move {
+        socket
+            .set_nonblocking(true)
+            .context("failed to set nonblocking mode for udp socketpair")?;
+        let socket = UnixDatagram::from_std(socket)?;
+        udp_host_loop(spec, Arc::new(socket), None, shutdown).await
+    })
+}
+
+/// `endpoint` is the endpoint's socket path, or `None` when `unix` is already connected to it.
+#[instrument(skip_all, fields(udp_listen = spec.udp_listen.as_deref().unwrap_or_default()))]
+async fn udp_host_loop(
+    spec: ForwardSpec,
+    unix: Arc<UnixDatagram>,
+    endpoint: Option<&Path>,
+    mut shutdown: ShutdownRx,
+) -> Result<()> {
+    let listen_addr = spec
+        .udp_listen
+        .as_ref()
+        .context("udp_listen address missing for udp host proxy")?;
+    let metrics = Metrics::for_spec(&spec, "udp_host_proxy");
+    let client_socket = bind_udp_listener(listen_addr)
+        .await
+        .inspect_err(|err| metrics.bind_error(err))?;
+    info!(%listen_addr, "udp host proxy listening");
+    let _lifecycle = lifecycle::up(&spec, listen_addr).await;
+
+    let mut client_buf = vec![0u8; MAX_DATAGRAM];
+    let mut frame = Vec::with_capacity(MAX_DATAGRAM + MAX_FRAME_HEADER);
+    let mut unix_buf = vec![0u8; MAX_DATAGRAM + MAX_FRAME_HEADER];
+
+    loop {
+        tokio::select! {
+            biased;
+            res = shutdown.changed() => {
+                if res.is_err() || *shutdown.borrow() {
+                    info!(%listen_addr, "shutdown received; stopping udp host proxy");
+                    break;
+                }
+            }
+            recv = client_socket.recv_from(&mut client_buf) => {
+                let (len, client) = recv?;
+                encode_frame(client, &client_buf[..len], &mut frame);
+                let sent = match endpoint {
+                    Some(endpoint) => unix.send_to(&frame, endpoint).await,
+                    None => unix.send(&frame).await,
+                };
+                match sent {
+                    Ok(_) => metrics.datagram(Direction::ClientToTarget, len),
+                    Err(err) => {
+                        metrics.datagram_dropped();
+                        log_send_error(endpoint, &err);
+                    }
+                }
+            }
+            recv = unix.recv(&mut unix_buf) => {
+                let len = recv?;
+                match decode_frame(&unix_buf[..len]) {
+                    Ok((client, payload)) => match client_socket.send_to(payload, client).await {
+                        Ok(_) => metrics.datagram(Direction::TargetToClient, payload.len()),
+                        Err(err) => {
+                            metrics.datagram_dropped();
+                            if let Some(suppressed) = log_limit::allow("failed to forward udp response", &err) {
+                                warn!(client = %client, error = %err, suppressed, "failed to forward udp response");
+                            }
+                        }
+                    },
+                    Err(err) => {
+                        metrics.datagram_dropped();
+                        if let Some(suppressed) = log_limit::allow("dropping malformed udp frame from endpoint", &err) {
+                            warn!(error = %err, suppressed, "dropping malformed udp frame from endpoint");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Path of the host-side reply socket for a given endpoint socket, e.g. `dns.sock.host`.
+fn reply_socket_path(endpoint: &Path) -> PathBuf {
+    let mut name = endpoint.as_os_str().to_os_string();
+    name.push(".host");
+    PathBuf::from(name)
+}
+
+/// UDP is lossy anyway, so a missing endpoint drops the datagram instead of failing the proxy.
+fn log_send_error(endpoint: Option<&Path>, err: &io::Error) {
+    let uds = endpoint.map_or_else(|| "socketpair".into(), |path| path.display().to_string());
+    match err.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused => {
+            debug!(%uds, error = %err, "udp endpoint unavailable; dropping datagram");
+        }
+        _ => {
+            if let Some(suppressed) =
+                log_limit::allow("failed to relay udp datagram to endpoint", &uds)
+            {
+                warn!(%uds, error = %err, suppressed, "failed to relay udp datagram to endpoint");
+            }
+        }
+    }
+}
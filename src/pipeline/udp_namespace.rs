@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::os::unix::net::UnixDatagram as StdUnixDatagram;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use tokio::net::UnixDatagram;
+use tokio::task::{JoinHandle, spawn_blocking};
+use tokio::time::interval;
+use tracing::{info, warn};
+
+use crate::config::ForwardSpec;
+use crate::log_limit;
+use crate::metrics::Metrics;
+use crate::netns;
+use crate::pipeline::ShutdownRx;
+use crate::pipeline::namespace::{spawn_in_namespace, spawn_rebinding};
+use crate::pipeline::udp::{
+    CLEANUP_INTERVAL, MAX_DATAGRAM, MAX_FRAME_HEADER, ReplyTo, UdpSession, decode_frame,
+    drain_sessions, prune_sessions, relay_upstream,
+};
+use crate::uds::bind_datagram;
+
+/// Enters the requested network namespace, binds a datagram Unix socket, and relays every framed
+/// datagram from a UDP host proxy to the in-namespace `udp_target`.
+pub fn spawn(spec: ForwardSpec, shutdown: ShutdownRx) -> JoinHandle<Result<()>> {
+    spawn_rebinding(spec, shutdown, |spec, shutdown| async move {
+        let uds_path = spec.uds_path().to_path_buf();
+        let owner = {
+            let spec = spec.clone();
+            spawn_blocking(move || netns::desired_owner(&spec)).await??
+        };
+        let bound = bind_datagram(&uds_path, owner, spec.mode)?;
+        info!(uds = %uds_path.display(), "udp namespace endpoint bound");
+        udp_namespace_loop(spec, bound.socket().clone(), false, shutdown).await
+    })
+}
+
+/// Like [`spawn`], but receives datagrams on one end of a socketpair shared with an in-process
+/// host proxy instead of binding a path.
+pub fn spawn_paired(
+    spec: ForwardSpec,
+    socket: StdUnixDatagram,
+    shutdown: ShutdownRx,
+) -> JoinHandle<Result<()>> {
+    spawn_in_namespace(spec, shutdown, |spec, shutdown| async move {
+        socket
+            .set_nonblocking(true)
+            .context("failed to set nonblocking mode for udp socketpair")?;
+        let socket = UnixDatagram::from_std(socket)?;
+        udp_namespace_loop(spec, Arc::new(socket), true, shutdown).await
+    })
+}
+
+/// `paired` sockets are connected to their only peer, so replies go out with `send`; otherwise
+/// they are addressed to the path the host proxy sent from.
+async fn udp_namespace_loop(
+    spec: ForwardSpec,
+    socket: Arc<UnixDatagram>,
+    paired: bool,
+    mut shutdown: ShutdownRx,
+) -> Result<()> {
+    let label = spec.label.as_deref().unwrap_or("unnamed");
+    let target_addr = spec
+        .udp_target
+        .clone()
+        .context("udp namespace endpoint requires udp_target address")?;
+    let idle_timeout = spec.udp_idle_timeout();
+    let metrics = Metrics::for_spec(&spec, "udp_namespace_endpoint");
+    info!(
+        label,
+        %target_addr,
+        idle_secs = idle_timeout.as_secs(),
+        paired,
+        "udp namespace endpoint listening"
+    );
+
+    // Keyed by the sending host proxy as well as the client, since several host proxies may share
+    // one endpoint socket.
+    let mut sessions: HashMap<(Option<PathBuf>, SocketAddr), UdpSession> = HashMap::new();
+    let mut cleanup = interval(CLEANUP_INTERVAL);
+    let mut buf = vec![0u8; MAX_DATAGRAM + MAX_FRAME_HEADER];
+
+    loop {
+        tokio::select! {
+            biased;
+            res = shutdown.changed() => {
+                if res.is_err() || *shutdown.borrow() {
+                    info!(label, "shutdown received; stopping udp namespace endpoint");
+                    break;
+                }
+            }
+            _ = cleanup.tick() => {
+                prune_sessions(&mut sessions, idle_timeout, &metrics);
+            }
+            recv = socket.recv_from(&mut buf) => {
+                let (len, peer) = recv?;
+                let peer = peer.as_pathname().map(PathBuf::from);
+                if peer.is_none() && !paired {
+                    metrics.datagram_dropped();
+                    if let Some(suppressed) = log_limit::allow("dropping datagram from unbound unix peer", label) {
+                        warn!(label, suppressed, "dropping datagram from unbound unix peer; replies would have nowhere to go");
+                    }
+                    continue;
+                }
+                let (client, payload) = match decode_frame(&buf[..len]) {
+                    Ok(decoded) => decoded,
+                    Err(err) => {
+                        metrics.datagram_dropped();
+                        if let Some(suppressed) = log_limit::allow("dropping malformed udp frame", label) {
+                            warn!(label, error = %err, suppressed, "dropping malformed udp frame");
+                        }
+                        continue;
+                    }
+                };
+                let reply = || ReplyTo::Unix { socket: socket.clone(), peer: peer.clone(), client };
+                relay_upstream(&mut sessions, (peer.clone(), client), client, payload, &target_addr, reply, &metrics, &shutdown)
+                    .await?;
+            }
+        }
+    }
+
+    drain_sessions(sessions);
+    Ok(())
+}
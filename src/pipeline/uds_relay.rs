@@ -0,0 +1,90 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use tokio::task::JoinHandle;
+use tracing::{Instrument, info, instrument};
+
+use crate::config::{ForwardSpec, UdsType};
+use crate::metrics::Metrics;
+use crate::pipeline::ShutdownRx;
+use crate::pipeline::host::connect_uds;
+use crate::pipeline::lifecycle;
+use crate::pipeline::pause::Gate;
+use crate::pipeline::session::{self, Session};
+use crate::uds::{UdsConnection, bind_listener};
+
+/// Listens on `uds_listen` with the spec's owner/mode and relays each connection to the Unix
+/// socket at `uds_target`, e.g. to hand a root-owned control socket to an unprivileged user.
+pub fn spawn(spec: ForwardSpec, shutdown: ShutdownRx) -> JoinHandle<Result<()>> {
+    tokio::spawn(async move { uds_relay_loop(spec, shutdown).await })
+}
+
+#[instrument(skip_all, fields(uds_listen = ?spec.uds_listen))]
+async fn uds_relay_loop(spec: ForwardSpec, mut shutdown: ShutdownRx) -> Result<()> {
+    let listen_path = spec
+        .uds_listen
+        .clone()
+        .context("uds relay requires uds_listen path")?;
+    let target_path = spec
+        .uds_target
+        .clone()
+        .context("uds relay requires uds_target path")?;
+    let metrics = Metrics::for_spec(&spec, "uds_relay");
+    let guard = bind_listener(
+        &listen_path,
+        UdsType::Stream,
+        None,
+        spec.owner.clone(),
+        spec.mode,
+    )
+    .inspect_err(|err| metrics.bind_error(err))?;
+    info!(
+        label = spec.label.as_deref().unwrap_or("unnamed"),
+        uds_listen = %listen_path.display(),
+        uds_target = %target_path.display(),
+        "uds relay listening"
+    );
+    let _lifecycle = lifecycle::up(&spec, listen_path.display()).await;
+    let mut gate = Gate::for_spec(&spec);
+
+    loop {
+        tokio::select! {
+            biased;
+            res = shutdown.changed() => {
+                if res.is_err() || *shutdown.borrow() {
+                    info!(uds_listen = %listen_path.display(), "shutdown received; stopping uds relay");
+                    break;
+                }
+            }
+            accept_res = gate.accept(|| guard.accept()) => {
+                let stream = accept_res?;
+                let target_path = target_path.clone();
+                let metrics = metrics.clone();
+                let session = Session::new(&spec, None);
+                let span = session.span();
+                tokio::spawn(async move {
+                    let target = target_path.display().to_string();
+                    let res = session.run(&target, bridge_unix_to_unix(stream, target_path, &metrics)).await;
+                    session.close(&target, &res);
+                }.instrument(span));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn bridge_unix_to_unix(
+    mut client: UdsConnection,
+    target: PathBuf,
+    metrics: &Arc<Metrics>,
+) -> Result<(u64, u64)> {
+    let _active = metrics.session();
+    let mut upstream = metrics
+        .uds_connect(
+            connect_uds(&target, None, Some(metrics))
+                .instrument(session::uds_connect_span(&target.display())),
+        )
+        .await?;
+    session::relay(&mut client, &mut upstream, metrics).await
+}
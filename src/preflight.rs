@@ -0,0 +1,133 @@
+//! Startup checks for the privileges the configured forwards will need: `CAP_SYS_ADMIN` to join
+//! namespaces, write access where Unix sockets are bound, and the right to hand those sockets to
+//! their `owner`. Every problem is reported at once, before anything starts, instead of forwards
+//! failing one at a time as they reach the step that needs it.
+//!
+//! The checks are conservative: paths resolved in another mount namespace, or owners mapped
+//! through a user namespace, are only known at bind time and are left to it.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Result, bail};
+use nix::unistd::{AccessFlags, Gid, Uid, access, getegid, geteuid, getgroups};
+
+use crate::config::{ForwardSpec, NAMESPACE_PLACEHOLDER, UUID_PLACEHOLDER};
+use crate::uds;
+
+const CAP_CHOWN: u32 = 0;
+const CAP_SYS_ADMIN: u32 = 21;
+
+/// Check every spec and fail with the full list of problems found.
+pub fn check(specs: &[ForwardSpec]) -> Result<()> {
+    let caps = Capabilities::current();
+    let mut problems = Vec::new();
+    for spec in specs {
+        let label = spec.label.as_deref().unwrap_or("unnamed");
+        if spec.enters_namespace() && !caps.has(CAP_SYS_ADMIN) {
+            problems.push(format!(
+                "{label}: joining a network namespace needs CAP_SYS_ADMIN; run pfwd as root or grant it with `setcap cap_sys_admin+ep`"
+            ));
+        }
+        let paths = bound_paths(spec);
+        for path in &paths {
+            if let Some(problem) = check_socket_dir(path) {
+                problems.push(format!("{label}: {problem}"));
+            }
+        }
+        if let Some(owner) = spec.owner.as_ref()
+            && !paths.is_empty()
+            && !spec.setns_user()
+            && !caps.has(CAP_CHOWN)
+        {
+            if Uid::from_raw(owner.uid) != geteuid() {
+                problems.push(format!(
+                    "{label}: giving sockets to uid {} needs CAP_CHOWN; run pfwd as that user or grant it with `setcap cap_chown+ep`",
+                    owner.uid
+                ));
+            }
+            let gid = Gid::from_raw(owner.gid);
+            if gid != getegid() && !getgroups().is_ok_and(|groups| groups.contains(&gid)) {
+                problems.push(format!(
+                    "{label}: giving sockets to gid {} needs CAP_CHOWN or membership in that group",
+                    owner.gid
+                ));
+            }
+        }
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+    bail!("pre-flight checks failed:\n  - {}", problems.join("\n  - "));
+}
+
+/// Socket paths this process binds on its own filesystem for `spec`.
+fn bound_paths(spec: &ForwardSpec) -> Vec<&Path> {
+    let mut paths = Vec::new();
+    let binds_uds = spec.requires_namespace_endpoint()
+        || spec.requires_uds_endpoint()
+        || spec.requires_udp_namespace_endpoint();
+    if binds_uds
+        && spec.uds_mount_ns.is_none()
+        && !spec.setns_mount()
+        && let Some(uds) = spec.uds.as_deref()
+        && uds::abstract_name(uds).is_none()
+    {
+        paths.push(uds);
+    }
+    if spec.requires_uds_relay()
+        && let Some(listen) = spec.uds_listen.as_deref()
+        && uds::abstract_name(listen).is_none()
+    {
+        paths.push(listen);
+    }
+    paths
+}
+
+/// Whether the socket at `path` can be created: its directory, or the closest ancestor that
+/// exists (missing directories are created at bind time), must be writable.
+fn check_socket_dir(path: &Path) -> Option<String> {
+    let parent = path.parent()?;
+    let templated = parent.to_string_lossy();
+    let mut dir =
+        if templated.contains(NAMESPACE_PLACEHOLDER) || templated.contains(UUID_PLACEHOLDER) {
+            // Instances substitute the namespace; check the part of the path that is fixed.
+            parent
+                .ancestors()
+                .find(|dir| !dir.to_string_lossy().contains('{'))?
+        } else {
+            parent
+        };
+    while fs::symlink_metadata(dir).is_err() {
+        dir = dir.parent()?;
+    }
+    match access(dir, AccessFlags::W_OK | AccessFlags::X_OK) {
+        Ok(()) => None,
+        Err(err) => Some(format!(
+            "cannot create sockets in {} for {}: {err}",
+            dir.display(),
+            path.display()
+        )),
+    }
+}
+
+/// Effective capability set of this process.
+struct Capabilities(Option<u64>);
+
+impl Capabilities {
+    /// Read `CapEff` from `/proc/self/status`; where that is unavailable, nothing is assumed
+    /// missing and the kernel has the final say.
+    fn current() -> Self {
+        let effective = fs::read_to_string("/proc/self/status")
+            .ok()
+            .and_then(|status| {
+                let line = status.lines().find(|line| line.starts_with("CapEff:"))?;
+                u64::from_str_radix(line["CapEff:".len()..].trim(), 16).ok()
+            });
+        Self(effective)
+    }
+
+    fn has(&self, cap: u32) -> bool {
+        self.0.is_none_or(|caps| caps & (1 << cap) != 0)
+    }
+}
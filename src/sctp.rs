@@ -0,0 +1,90 @@
+//! SCTP support for stream forwards, enabled with the `sctp` cargo feature.
+//!
+//! `sctp://host:port` listen and target addresses create one-to-one style (`SOCK_STREAM`) SCTP
+//! sockets. Those expose the same byte-stream semantics as TCP, so once created they are driven
+//! through tokio's TCP types and the rest of the pipeline does not need to know the difference.
+//! Message-oriented protocols such as Diameter are self-delimiting and survive this unchanged.
+
+use anyhow::Result;
+#[cfg(not(feature = "sctp"))]
+use anyhow::bail;
+#[cfg(feature = "sctp")]
+use anyhow::{Context, bail};
+use tokio::net::{TcpListener, TcpStream};
+
+const SCHEME: &str = "sctp://";
+
+/// Returns the `host:port` part of an `sctp://` address, or `None` for plain TCP addresses.
+pub fn strip_scheme(addr: &str) -> Option<&str> {
+    addr.strip_prefix(SCHEME)
+}
+
+/// Reject `sctp://` addresses at config load when the feature is compiled out.
+pub fn validate(addr: &str) -> Result<()> {
+    if strip_scheme(addr).is_some() && !cfg!(feature = "sctp") {
+        bail!("{addr} needs SCTP support; rebuild pfwd with `--features sctp`");
+    }
+    Ok(())
+}
+
+#[cfg(feature = "sctp")]
+pub fn bind_listener(addr: &str, backlog: u32) -> Result<TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+    use std::net::SocketAddr;
+
+    let local: SocketAddr = addr
+        .parse()
+        .with_context(|| format!("sctp listen address {addr} must be ip:port"))?;
+    let socket = Socket::new(
+        Domain::for_address(local),
+        Type::STREAM,
+        Some(Protocol::from(nix::libc::IPPROTO_SCTP)),
+    )
+    .context("failed to create sctp socket (is the sctp kernel module loaded?)")?;
+    socket.set_reuse_address(true)?;
+    socket
+        .bind(&local.into())
+        .with_context(|| format!("failed to bind sctp listener {addr}"))?;
+    socket.listen(backlog.try_into().unwrap_or(i32::MAX))?;
+    socket.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+#[cfg(feature = "sctp")]
+pub async fn connect(addr: &str) -> Result<TcpStream> {
+    use socket2::{Domain, Protocol, Socket, Type};
+    use tokio::net::{TcpSocket, lookup_host};
+
+    let mut last_err = None;
+    for remote in lookup_host(addr)
+        .await
+        .with_context(|| format!("failed to resolve sctp target {addr}"))?
+    {
+        let socket = Socket::new(
+            Domain::for_address(remote),
+            Type::STREAM,
+            Some(Protocol::from(nix::libc::IPPROTO_SCTP)),
+        )
+        .context("failed to create sctp socket (is the sctp kernel module loaded?)")?;
+        socket.set_nonblocking(true)?;
+        let socket = TcpSocket::from_std_stream(socket.into());
+        match socket.connect(remote).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    match last_err {
+        Some(err) => Err(err).with_context(|| format!("sctp connect to {addr} failed")),
+        None => bail!("sctp target {addr} resolved to no addresses"),
+    }
+}
+
+#[cfg(not(feature = "sctp"))]
+pub fn bind_listener(addr: &str, _backlog: u32) -> Result<TcpListener> {
+    bail!("cannot listen on sctp://{addr}: pfwd was built without the `sctp` feature")
+}
+
+#[cfg(not(feature = "sctp"))]
+pub async fn connect(addr: &str) -> Result<TcpStream> {
+    bail!("cannot connect to sctp://{addr}: pfwd was built without the `sctp` feature")
+}
@@ -0,0 +1,69 @@
+//! Which forwards an admin command acts on: those with one label, or with any label matching a
+//! pattern, e.g. `label~=qdhcp-*` for every forward of one tenant or namespace class.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{Result, bail};
+
+use crate::pipeline::netns_watch::glob_match;
+
+/// `label=<name>`, or `label~=<pattern>` with `*` and `?` as in `glob:` namespaces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selector {
+    Label(String),
+    Glob(String),
+}
+
+impl Selector {
+    /// From a command's `label` and `selector` arguments, which are alternatives. `None` when
+    /// neither is given.
+    pub fn from_args(label: Option<String>, selector: Option<String>) -> Result<Option<Self>> {
+        match (label, selector) {
+            (None, None) => Ok(None),
+            (Some(label), None) => Ok(Some(Selector::Label(label))),
+            (None, Some(selector)) => Ok(Some(selector.parse()?)),
+            (Some(_), Some(_)) => bail!("give one of `label` and `selector`, not both"),
+        }
+    }
+
+    pub fn matches(&self, label: &str) -> bool {
+        match self {
+            Selector::Label(name) => name == label,
+            Selector::Glob(pattern) => glob_match(pattern, label),
+        }
+    }
+
+    /// The distinct `labels` this picks, in order.
+    pub fn select<'a>(&self, labels: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+        let selected: BTreeSet<&str> = labels
+            .into_iter()
+            .filter(|label| self.matches(label))
+            .collect();
+        selected.into_iter().map(str::to_string).collect()
+    }
+}
+
+impl FromStr for Selector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(pattern) = s.strip_prefix("label~=") {
+            return Ok(Selector::Glob(pattern.to_string()));
+        }
+        if let Some(label) = s.strip_prefix("label=") {
+            return Ok(Selector::Label(label.to_string()));
+        }
+        bail!("invalid selector {s} (expected label=<name> or label~=<pattern>)")
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Selector::Label(label) => write!(f, "label={label}"),
+            Selector::Glob(pattern) => write!(f, "label~={pattern}"),
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! `SOCK_SEQPACKET` Unix sockets for UDS legs that must keep record boundaries.
+//!
+//! Each write becomes one record and each read returns at most one record, so a relay that copies
+//! between a seqpacket socket and a byte stream maps records to whatever chunks the stream side
+//! delivers, and a client writing whole messages to the seqpacket side sees them arrive whole.
+
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll, ready};
+
+use socket2::Socket;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+pub struct SeqpacketListener {
+    inner: AsyncFd<Socket>,
+}
+
+impl SeqpacketListener {
+    pub fn from_socket(socket: Socket) -> io::Result<Self> {
+        Ok(Self {
+            inner: AsyncFd::new(socket)?,
+        })
+    }
+
+    pub async fn accept(&self) -> io::Result<SeqpacketStream> {
+        loop {
+            let mut guard = self.inner.readable().await?;
+            match guard.try_io(|inner| inner.get_ref().accept()) {
+                Ok(Ok((socket, _))) => {
+                    socket.set_nonblocking(true)?;
+                    return SeqpacketStream::from_socket(socket);
+                }
+                Ok(Err(err)) => return Err(err),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+pub struct SeqpacketStream {
+    inner: AsyncFd<Socket>,
+    /// Tail of a record larger than the caller's read buffer.
+    pending: Vec<u8>,
+    pending_pos: usize,
+}
+
+impl SeqpacketStream {
+    pub fn from_socket(socket: Socket) -> io::Result<Self> {
+        Ok(Self {
+            inner: AsyncFd::new(socket)?,
+            pending: Vec::new(),
+            pending_pos: 0,
+        })
+    }
+
+    /// Size of the next record without consuming it.
+    fn peek_len(socket: &Socket) -> io::Result<usize> {
+        let mut probe = [std::mem::MaybeUninit::<u8>::uninit(); 1];
+        socket.recv_with_flags(&mut probe, nix::libc::MSG_PEEK | nix::libc::MSG_TRUNC)
+    }
+}
+
+impl AsyncRead for SeqpacketStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.pending_pos < this.pending.len() {
+            let len = buf.remaining().min(this.pending.len() - this.pending_pos);
+            buf.put_slice(&this.pending[this.pending_pos..this.pending_pos + len]);
+            this.pending_pos += len;
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            let mut guard = ready!(this.inner.poll_read_ready(cx))?;
+            let res = guard.try_io(|inner| {
+                let socket = inner.get_ref();
+                let record = Self::peek_len(socket)?;
+                if record <= buf.remaining() {
+                    let len = (&*socket).read(buf.initialize_unfilled_to(record))?;
+                    buf.advance(len);
+                    return Ok(());
+                }
+                let mut whole = vec![0u8; record];
+                let len = (&*socket).read(&mut whole)?;
+                whole.truncate(len);
+                let head = buf.remaining();
+                buf.put_slice(&whole[..head]);
+                this.pending = whole;
+                this.pending_pos = head;
+                Ok(())
+            });
+            match res {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for SeqpacketStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.inner.poll_write_ready(cx))?;
+            match guard.try_io(|inner| inner.get_ref().write(buf)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.inner.get_ref().shutdown(Shutdown::Write))
+    }
+}
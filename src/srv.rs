@@ -0,0 +1,269 @@
+//! `srv:_service._proto.name` targets, resolved through DNS SRV records (RFC 2782).
+//!
+//! The SRV query goes to the first nameserver in `/etc/resolv.conf` that answers, over UDP with a
+//! TCP retry for truncated answers. Records are cached for the smallest TTL in the answer and then
+//! re-resolved, so a forward follows the service when its records move. Each connection tries the
+//! records in RFC 2782 order: ascending priority, weighted-random within a priority.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, bail};
+use tokio::time::timeout;
+use tracing::debug;
+
+use crate::pipeline::dns::{exchange_tcp, exchange_udp};
+use crate::pipeline::dns_cache::{is_truncated, read_u16, read_u32, skip_name};
+
+const SCHEME: &str = "srv:";
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+/// Floor for the cache lifetime so zero-TTL answers do not cost a query per connection.
+const MIN_TTL: Duration = Duration::from_secs(1);
+
+/// Returns the SRV owner name of a `srv:` target, or `None` for other targets.
+pub fn strip_scheme(target: &str) -> Option<&str> {
+    target.strip_prefix(SCHEME)
+}
+
+pub fn validate(target: &str) -> Result<()> {
+    if let Some(name) = strip_scheme(target) {
+        encode_name(name)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub host: String,
+}
+
+impl SrvRecord {
+    /// `host:port` suitable for address resolution, bracketing IPv6 literals.
+    pub fn authority(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// SRV lookups for one target name, cached until the answer's TTL runs out.
+#[derive(Debug)]
+pub struct SrvResolver {
+    name: String,
+    cache: Mutex<Option<(Vec<SrvRecord>, Instant)>>,
+}
+
+impl SrvResolver {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            cache: Mutex::new(None),
+        }
+    }
+
+    /// Records in the order one connection should try them.
+    pub async fn candidates(&self) -> Result<Vec<SrvRecord>> {
+        let cached = self
+            .cache
+            .lock()
+            .expect("srv cache poisoned")
+            .as_ref()
+            .filter(|(_, expires)| Instant::now() < *expires)
+            .map(|(records, _)| records.clone());
+        let records = match cached {
+            Some(records) => records,
+            None => {
+                let (records, ttl) = lookup(&self.name).await?;
+                debug!(name = %self.name, records = records.len(), ?ttl, "resolved srv target");
+                *self.cache.lock().expect("srv cache poisoned") =
+                    Some((records.clone(), Instant::now() + ttl.max(MIN_TTL)));
+                records
+            }
+        };
+        Ok(order(records))
+    }
+}
+
+async fn lookup(name: &str) -> Result<(Vec<SrvRecord>, Duration)> {
+    let servers = nameservers()?;
+    let id = random_u64() as u16;
+    let query = build_query(name, id)?;
+    let mut last_err = None;
+    for server in servers {
+        let res = async {
+            let response = timeout(QUERY_TIMEOUT, exchange_udp(&server, &query))
+                .await
+                .context("srv query timed out")??;
+            if !is_truncated(&response) {
+                return Ok(response);
+            }
+            timeout(QUERY_TIMEOUT, exchange_tcp(&server, &query))
+                .await
+                .context("srv query over tcp timed out")?
+        }
+        .await;
+        match res {
+            Ok(response) => return parse_response(&response, name),
+            Err(err) => {
+                debug!(%server, error = %err, "srv query failed");
+                last_err = Some(err);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| anyhow::anyhow!("no nameservers in {RESOLV_CONF}")))
+        .with_context(|| format!("failed to resolve srv target {name}"))
+}
+
+fn nameservers() -> Result<Vec<String>> {
+    let conf = std::fs::read_to_string(RESOLV_CONF)
+        .with_context(|| format!("failed to read {RESOLV_CONF}"))?;
+    Ok(conf
+        .lines()
+        .filter_map(|line| line.strip_prefix("nameserver"))
+        .filter_map(|rest| rest.split_whitespace().next())
+        .map(|addr| match addr.parse::<std::net::IpAddr>() {
+            Ok(std::net::IpAddr::V6(ip)) => format!("[{ip}]:53"),
+            _ => format!("{addr}:53"),
+        })
+        .collect())
+}
+
+fn encode_name(name: &str) -> Result<Vec<u8>> {
+    let name = name.trim_end_matches('.');
+    if name.is_empty() {
+        bail!("srv target needs a name, e.g. srv:_ldap._tcp.example.com");
+    }
+    let mut out = Vec::with_capacity(name.len() + 2);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            bail!("srv target {name} has an empty or over-long label");
+        }
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+    if out.len() > 255 {
+        bail!("srv target {name} is longer than 255 bytes");
+    }
+    Ok(out)
+}
+
+fn build_query(name: &str, id: u16) -> Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(32 + name.len());
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00]); // RD
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); // one question
+    query.extend(encode_name(name)?);
+    query.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Decode a possibly compressed name starting at `pos`.
+fn read_name(msg: &[u8], mut pos: usize) -> Option<String> {
+    let mut labels = Vec::new();
+    // Bounds the number of compression pointers followed.
+    for _ in 0..128 {
+        let len = *msg.get(pos)?;
+        match len & 0xC0 {
+            0x00 if len == 0 => return Some(labels.join(".")),
+            0x00 => {
+                let label = msg.get(pos + 1..pos + 1 + usize::from(len))?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + usize::from(len);
+            }
+            0xC0 => pos = usize::from(read_u16(msg, pos)? & 0x3FFF),
+            _ => return None,
+        }
+    }
+    None
+}
+
+fn parse_response(msg: &[u8], name: &str) -> Result<(Vec<SrvRecord>, Duration)> {
+    let malformed = || anyhow::anyhow!("malformed srv answer for {name}");
+    match msg.get(3).map(|flags| flags & 0x0F) {
+        Some(0) => {}
+        Some(3) => bail!("srv target {name} does not exist"),
+        Some(rcode) => bail!("srv query for {name} failed with rcode {rcode}"),
+        None => return Err(malformed()),
+    }
+    let questions = read_u16(msg, 4).ok_or_else(malformed)?;
+    let answers = read_u16(msg, 6).ok_or_else(malformed)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(msg, pos).ok_or_else(malformed)? + 4;
+    }
+    let mut records = Vec::new();
+    let mut ttl = u32::MAX;
+    for _ in 0..answers {
+        pos = skip_name(msg, pos).ok_or_else(malformed)?;
+        let rtype = read_u16(msg, pos).ok_or_else(malformed)?;
+        let record_ttl = read_u32(msg, pos + 4).ok_or_else(malformed)?;
+        let rdlength = usize::from(read_u16(msg, pos + 8).ok_or_else(malformed)?);
+        let rdata = pos + 10;
+        if rdata + rdlength > msg.len() {
+            return Err(malformed());
+        }
+        // CNAMEs ahead of the SRV records are skipped; their TTL still bounds the cache.
+        ttl = ttl.min(record_ttl);
+        if rtype == TYPE_SRV {
+            let host = read_name(msg, rdata + 6).ok_or_else(malformed)?;
+            records.push(SrvRecord {
+                priority: read_u16(msg, rdata).ok_or_else(malformed)?,
+                weight: read_u16(msg, rdata + 2).ok_or_else(malformed)?,
+                port: read_u16(msg, rdata + 4).ok_or_else(malformed)?,
+                host,
+            });
+        }
+        pos = rdata + rdlength;
+    }
+    // A lone "." target means the service is explicitly unavailable (RFC 2782).
+    records.retain(|record| !record.host.is_empty());
+    if records.is_empty() {
+        bail!("srv target {name} has no usable records");
+    }
+    Ok((records, Duration::from_secs(u64::from(ttl))))
+}
+
+/// Ascending priority; within a priority, repeatedly pick by weight (zero weights rarely first).
+fn order(mut records: Vec<SrvRecord>) -> Vec<SrvRecord> {
+    records.sort_by_key(|record| (record.priority, record.weight != 0));
+    let mut ordered = Vec::with_capacity(records.len());
+    while !records.is_empty() {
+        let priority = records[0].priority;
+        let group_len = records
+            .iter()
+            .take_while(|r| r.priority == priority)
+            .count();
+        let mut group: Vec<SrvRecord> = records.drain(..group_len).collect();
+        while !group.is_empty() {
+            let total: u64 = group.iter().map(|r| u64::from(r.weight)).sum();
+            let pick = random_u64() % (total + 1);
+            let mut running = 0;
+            let index = group
+                .iter()
+                .position(|r| {
+                    running += u64::from(r.weight);
+                    running >= pick
+                })
+                .unwrap_or(0);
+            ordered.push(group.remove(index));
+        }
+    }
+    ordered
+}
+
+/// Each `RandomState` is freshly keyed, which is enough randomness for load spreading.
+pub(crate) fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
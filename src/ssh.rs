@@ -0,0 +1,570 @@
+//! Jump-host targets (`ssh://[user@]bastion[:port]/host:port`), enabled with the `ssh` cargo
+//! feature.
+//!
+//! pfwd carries its own SSH client (russh) and opens a `direct-tcpip` channel to `host:port` for
+//! each session, the request `ssh -W` makes. Sessions through one bastion share a single SSH
+//! connection per network namespace, which is reopened when the bastion drops it. The bastion's
+//! host key must already be in `~/.ssh/known_hosts`: unknown and changed keys are refused, as with
+//! `StrictHostKeyChecking=yes`. pfwd offers the keys held by `ssh-agent` (`SSH_AUTH_SOCK`), then
+//! the unencrypted default identities in `~/.ssh`. The user defaults to the account pfwd runs as.
+//! `ssh_config` is not read.
+
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+
+use anyhow::{Context, Result, bail};
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::addr::HostPort;
+
+const SCHEME: &str = "ssh://";
+const DEFAULT_PORT: u16 = 22;
+
+/// A jump-host target parsed from `ssh://[user@]bastion[:port]/host:port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshTarget {
+    pub user: Option<String>,
+    pub bastion: String,
+    pub port: u16,
+    pub destination: HostPort,
+}
+
+impl SshTarget {
+    /// Returns `Ok(None)` when `target` is not an ssh URL.
+    pub fn parse(target: &str) -> Result<Option<Self>> {
+        let Some(rest) = target.strip_prefix(SCHEME) else {
+            return Ok(None);
+        };
+        let (authority, destination) = rest
+            .split_once('/')
+            .context("ssh targets must look like ssh://[user@]bastion[:port]/host:port")?;
+        let destination = destination
+            .parse()
+            .with_context(|| format!("invalid ssh target destination in {target}"))?;
+        let (user, hostport) = match authority.rsplit_once('@') {
+            Some((user, hostport)) => (Some(user.to_string()), hostport),
+            None => (None, authority),
+        };
+        if user.as_deref().is_some_and(str::is_empty) {
+            bail!("ssh target {target} has an empty user");
+        }
+        let (bastion, port) = split_host_port(hostport)?;
+        if bastion.is_empty() {
+            bail!("ssh target is missing the bastion host");
+        }
+        Ok(Some(Self {
+            user,
+            bastion,
+            port,
+            destination,
+        }))
+    }
+}
+
+/// Reject malformed `ssh://` targets at config load, and all of them when the feature is
+/// compiled out.
+pub fn validate(target: &str) -> Result<()> {
+    if SshTarget::parse(target)?.is_some() && !cfg!(feature = "ssh") {
+        bail!("{target} needs SSH support; rebuild pfwd with `--features ssh`");
+    }
+    Ok(())
+}
+
+fn split_host_port(hostport: &str) -> Result<(String, u16)> {
+    if let Some(rest) = hostport.strip_prefix('[') {
+        let (host, tail) = rest
+            .split_once(']')
+            .context("unterminated IPv6 bracket in ssh bastion")?;
+        let port = match tail.strip_prefix(':') {
+            Some(port) => port.parse().context("invalid ssh port")?,
+            None if tail.is_empty() => DEFAULT_PORT,
+            None => bail!("unexpected characters after ssh bastion address"),
+        };
+        return Ok((host.to_string(), port));
+    }
+    match hostport.rsplit_once(':') {
+        Some((host, port)) => Ok((host.to_string(), port.parse().context("invalid ssh port")?)),
+        None => Ok((hostport.to_string(), DEFAULT_PORT)),
+    }
+}
+
+#[cfg(feature = "ssh")]
+mod client {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::sync::{Arc, LazyLock, Mutex};
+    use std::time::Duration;
+
+    use anyhow::{Context, Result, bail};
+    use russh::client::{self, Handle, Msg};
+    use russh::keys::agent::client::AgentClient;
+    use russh::keys::{self, PrivateKeyWithHashAlg, PublicKeyOrCertificate};
+    use tracing::{debug, info};
+
+    use super::{SshStream, SshTarget};
+    use crate::addr::HostPort;
+    use crate::netns::{self, NetnsId};
+
+    /// Bounds the TCP connect, key exchange, and login to a bastion.
+    const LOGIN_TIMEOUT: Duration = Duration::from_secs(30);
+    const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+    /// Identities tried after the agent's keys, in the order `ssh` tries them.
+    const DEFAULT_IDENTITIES: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+    /// Originator `direct-tcpip` channels report, the values `ssh -W` sends.
+    const ORIGINATOR: (&str, u32) = ("127.0.0.1", 65535);
+
+    /// One bastion login: who logs in where, from which network namespace.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    struct Login {
+        user: String,
+        bastion: String,
+        port: u16,
+        netns: Option<NetnsId>,
+    }
+
+    /// Where the client finds its trust anchors and keys: `~/.ssh` outside of tests.
+    #[derive(Debug, Clone)]
+    struct Files {
+        known_hosts: PathBuf,
+        identities: Vec<PathBuf>,
+    }
+
+    impl Files {
+        fn home() -> Result<Self> {
+            let ssh = std::env::home_dir()
+                .context("cannot find ~/.ssh without a home directory")?
+                .join(".ssh");
+            Ok(Self {
+                known_hosts: ssh.join("known_hosts"),
+                identities: DEFAULT_IDENTITIES
+                    .iter()
+                    .map(|name| ssh.join(name))
+                    .collect(),
+            })
+        }
+    }
+
+    /// Connection to one [`Login`], opened by the first session that needs it.
+    type Shared = Arc<tokio::sync::Mutex<Option<Arc<Handle<HostKeys>>>>>;
+
+    static CONNECTIONS: LazyLock<Mutex<HashMap<Login, Shared>>> = LazyLock::new(Default::default);
+
+    impl SshTarget {
+        /// Open a channel to the destination through the bastion, logging in first unless a
+        /// connection to it is already up.
+        pub async fn connect(&self) -> Result<SshStream> {
+            let login = Login {
+                user: match self.user.clone() {
+                    Some(user) => user,
+                    None => current_user()?,
+                },
+                bastion: self.bastion.clone(),
+                port: self.port,
+                netns: netns::current_id().ok(),
+            };
+            let shared = CONNECTIONS
+                .lock()
+                .unwrap()
+                .entry(login.clone())
+                .or_default()
+                .clone();
+            let handle = connection(&login, &shared).await?;
+            let channel = match self.open(&handle).await {
+                Err(_) if handle.is_closed() => {
+                    // The bastion dropped the connection since the last session; log in again once.
+                    let handle = connection(&login, &shared).await?;
+                    self.open(&handle).await
+                }
+                opened => opened,
+            }?;
+            Ok(SshStream(channel.into_stream()))
+        }
+
+        async fn open(&self, handle: &Handle<HostKeys>) -> Result<russh::Channel<Msg>> {
+            let (host, port) = match &self.destination {
+                HostPort::Ip(addr) => (addr.ip().to_string(), addr.port()),
+                HostPort::Name { host, port } => (host.clone(), *port),
+            };
+            handle
+                .channel_open_direct_tcpip(host, port.into(), ORIGINATOR.0, ORIGINATOR.1)
+                .await
+                .with_context(|| {
+                    format!(
+                        "bastion {} refused a channel to {}",
+                        self.bastion, self.destination
+                    )
+                })
+        }
+    }
+
+    /// The shared connection for `login`, logging in again when there is none or it has closed.
+    async fn connection(login: &Login, shared: &Shared) -> Result<Arc<Handle<HostKeys>>> {
+        let mut slot = shared.lock().await;
+        if let Some(handle) = slot.as_ref()
+            && !handle.is_closed()
+        {
+            return Ok(handle.clone());
+        }
+        let handle = tokio::time::timeout(LOGIN_TIMEOUT, log_in(login, &Files::home()?))
+            .await
+            .with_context(|| format!("timed out logging in to ssh bastion {}", login.bastion))??;
+        info!(user = %login.user, bastion = %login.bastion, port = login.port, "ssh bastion connected");
+        Ok(slot.insert(Arc::new(handle)).clone())
+    }
+
+    async fn log_in(login: &Login, files: &Files) -> Result<Handle<HostKeys>> {
+        let config = Arc::new(client::Config {
+            keepalive_interval: Some(KEEPALIVE_INTERVAL),
+            nodelay: true,
+            ..Default::default()
+        });
+        let host_keys = HostKeys {
+            bastion: login.bastion.clone(),
+            port: login.port,
+            known_hosts: files.known_hosts.clone(),
+        };
+        let mut handle = client::connect(config, (login.bastion.as_str(), login.port), host_keys)
+            .await
+            .with_context(|| {
+                format!(
+                    "failed to connect to ssh bastion {}:{}",
+                    login.bastion, login.port
+                )
+            })?;
+        authenticate(&mut handle, &login.user, &files.identities)
+            .await
+            .with_context(|| format!("ssh login as {} to {} failed", login.user, login.bastion))?;
+        Ok(handle)
+    }
+
+    /// Offer the agent's keys, then the default identities, until the bastion accepts one.
+    async fn authenticate(
+        handle: &mut Handle<HostKeys>,
+        user: &str,
+        identities: &[PathBuf],
+    ) -> Result<()> {
+        let hash_alg = handle.best_supported_rsa_hash().await?.flatten();
+        match AgentClient::connect_env().await {
+            Ok(mut agent) => {
+                for identity in agent.request_identities().await.unwrap_or_default() {
+                    let key = identity.public_key().into_owned();
+                    if handle
+                        .authenticate_publickey_with(user, key, hash_alg, &mut agent)
+                        .await?
+                        .success()
+                    {
+                        return Ok(());
+                    }
+                }
+            }
+            Err(err) => debug!(error = %err, "no ssh-agent for bastion login"),
+        }
+        for path in identities {
+            // Missing files and passphrase-protected keys are skipped, as `BatchMode` would.
+            let Ok(key) = keys::load_secret_key(path, None) else {
+                continue;
+            };
+            let key = PrivateKeyWithHashAlg::new(Arc::new(key), hash_alg);
+            if handle.authenticate_publickey(user, key).await?.success() {
+                return Ok(());
+            }
+        }
+        bail!("the bastion accepted none of the ssh-agent keys or default identities in ~/.ssh")
+    }
+
+    fn current_user() -> Result<String> {
+        users::get_current_username()
+            .and_then(|name| name.into_string().ok())
+            .context(
+                "cannot tell which user to log in to the ssh bastion as; set one in the target",
+            )
+    }
+
+    /// Accepts a bastion only if its host key is recorded in `known_hosts`.
+    pub struct HostKeys {
+        bastion: String,
+        port: u16,
+        known_hosts: PathBuf,
+    }
+
+    impl client::Handler for HostKeys {
+        type Error = anyhow::Error;
+
+        async fn check_server_key(&mut self, key: &PublicKeyOrCertificate) -> Result<bool> {
+            let PublicKeyOrCertificate::PublicKey { key, .. } = key else {
+                bail!(
+                    "ssh bastion {} presented a host certificate; pfwd only checks plain host keys against known_hosts",
+                    self.bastion
+                );
+            };
+            let known_hosts = self.known_hosts.display();
+            match keys::check_known_hosts_path(&self.bastion, self.port, key, &self.known_hosts) {
+                Ok(true) => Ok(true),
+                Ok(false) => bail!(
+                    "host key of ssh bastion {} is not in {known_hosts}",
+                    self.bastion
+                ),
+                Err(keys::Error::KeyChanged { line }) => bail!(
+                    "host key of ssh bastion {} does not match {known_hosts} line {line}",
+                    self.bastion
+                ),
+                Err(err) => Err(err).with_context(|| format!("failed to read {known_hosts}")),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::net::SocketAddr;
+
+        use russh::keys::ssh_key::LineEnding;
+        use russh::keys::{Algorithm, PrivateKey};
+        use russh::server::{self, Auth, ChannelOpenHandle, Session};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        use super::*;
+
+        /// Accepts one client key and echoes every `direct-tcpip` channel, recording where it
+        /// was asked to go.
+        #[derive(Clone)]
+        struct Bastion {
+            client_key: keys::PublicKey,
+            opened: Arc<Mutex<Vec<(String, u32)>>>,
+        }
+
+        impl server::Handler for Bastion {
+            type Error = russh::Error;
+
+            async fn auth_publickey(
+                &mut self,
+                _user: &str,
+                key: &keys::PublicKey,
+            ) -> Result<Auth, Self::Error> {
+                Ok(if *key == self.client_key {
+                    Auth::Accept
+                } else {
+                    Auth::reject()
+                })
+            }
+
+            async fn channel_open_direct_tcpip(
+                &mut self,
+                channel: russh::Channel<server::Msg>,
+                host: &str,
+                port: u32,
+                _originator_address: &str,
+                _originator_port: u32,
+                reply: ChannelOpenHandle,
+                _session: &mut Session,
+            ) -> Result<(), Self::Error> {
+                self.opened.lock().unwrap().push((host.to_string(), port));
+                reply.accept().await;
+                tokio::spawn(async move {
+                    let (mut read, mut write) = tokio::io::split(channel.into_stream());
+                    let _ = tokio::io::copy(&mut read, &mut write).await;
+                });
+                Ok(())
+            }
+        }
+
+        fn random_key() -> PrivateKey {
+            PrivateKey::random(&mut keys::key::safe_rng(), Algorithm::Ed25519).unwrap()
+        }
+
+        /// Serve one bastion with `host_key` on a loopback port, and lay out client files that
+        /// record `known` as its host key.
+        async fn bastion(
+            dir: &str,
+            host_key: PrivateKey,
+            known: &PrivateKey,
+        ) -> (SocketAddr, Files, Bastion) {
+            let dir = std::env::temp_dir().join(format!("pfwd-ssh-{dir}-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let client_key = random_key();
+            let identity = dir.join("id_ed25519");
+            std::fs::write(&identity, client_key.to_openssh(LineEnding::LF).unwrap()).unwrap();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let known_hosts = dir.join("known_hosts");
+            let line = format!(
+                "[127.0.0.1]:{} {}\n",
+                addr.port(),
+                known.public_key().to_openssh().unwrap()
+            );
+            std::fs::write(&known_hosts, line).unwrap();
+            let handler = Bastion {
+                client_key: client_key.public_key().clone(),
+                opened: Arc::default(),
+            };
+            let config = Arc::new(server::Config {
+                keys: vec![host_key],
+                ..Default::default()
+            });
+            let server = handler.clone();
+            tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let session = server::run_stream(config, stream, server).await.unwrap();
+                let _ = session.await;
+            });
+            let files = Files {
+                known_hosts,
+                identities: vec![dir.join("id_missing"), identity],
+            };
+            (addr, files, handler)
+        }
+
+        fn login(addr: SocketAddr) -> Login {
+            Login {
+                user: "ops".to_string(),
+                bastion: addr.ip().to_string(),
+                port: addr.port(),
+                netns: None,
+            }
+        }
+
+        #[tokio::test]
+        async fn tunnels_through_the_bastion() {
+            let host_key = random_key();
+            let known = host_key.clone();
+            let (addr, files, bastion) = bastion("tunnel", host_key, &known).await;
+            let target = SshTarget::parse(&format!("ssh://ops@{addr}/db.internal:5432"))
+                .unwrap()
+                .unwrap();
+            let handle = log_in(&login(addr), &files).await.unwrap();
+            let mut stream = SshStream(target.open(&handle).await.unwrap().into_stream());
+            stream.write_all(b"ping").await.unwrap();
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            assert_eq!(
+                *bastion.opened.lock().unwrap(),
+                [("db.internal".to_string(), 5432)]
+            );
+        }
+
+        #[tokio::test]
+        async fn refuses_a_changed_host_key() {
+            let (addr, files, _) = bastion("changed", random_key(), &random_key()).await;
+            let Err(err) = log_in(&login(addr), &files).await else {
+                panic!("logged in despite a changed host key");
+            };
+            let message = format!("{err:#}");
+            assert!(message.contains("does not match"), "{message}");
+        }
+    }
+}
+
+/// Byte stream of one `direct-tcpip` channel through a bastion.
+#[cfg(feature = "ssh")]
+pub struct SshStream(russh::ChannelStream<russh::client::Msg>);
+
+/// Uninhabited: `ssh://` targets are rejected at config load without the `ssh` feature.
+#[cfg(not(feature = "ssh"))]
+pub enum SshStream {}
+
+#[cfg(not(feature = "ssh"))]
+impl SshTarget {
+    pub async fn connect(&self) -> Result<SshStream> {
+        bail!("ssh targets need SSH support; rebuild pfwd with `--features ssh`")
+    }
+}
+
+#[cfg(feature = "ssh")]
+impl AsyncRead for SshStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+#[cfg(feature = "ssh")]
+impl AsyncWrite for SshStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+#[cfg(not(feature = "ssh"))]
+impl AsyncRead for SshStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut TaskContext<'_>,
+        _buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "ssh"))]
+impl AsyncWrite for SshStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut TaskContext<'_>,
+        _buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match *self {}
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match *self {}
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match *self {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ssh_targets() {
+        let target = SshTarget::parse("ssh://ops@[2001:db8::1]:2200/10.0.0.5:5432")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            target,
+            SshTarget {
+                user: Some("ops".to_string()),
+                bastion: "2001:db8::1".to_string(),
+                port: 2200,
+                destination: "10.0.0.5:5432".parse().unwrap(),
+            }
+        );
+        let target = SshTarget::parse("ssh://bastion/db.internal:5432")
+            .unwrap()
+            .unwrap();
+        assert_eq!((target.user, target.port), (None, DEFAULT_PORT));
+        assert_eq!(SshTarget::parse("10.0.0.5:5432").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_malformed_ssh_targets() {
+        for target in [
+            "ssh://bastion/10.0.0.5",
+            "ssh://bastion",
+            "ssh://@bastion/10.0.0.5:22",
+            "ssh:///10.0.0.5:22",
+            "ssh://bastion:x/10.0.0.5:22",
+        ] {
+            assert!(SshTarget::parse(target).is_err(), "{target}");
+        }
+    }
+}
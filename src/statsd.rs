@@ -0,0 +1,186 @@
+//! statsd push of the per-forward counters (`statsd_addr`).
+//!
+//! Every `statsd_interval_secs` the counters are read and sent over UDP: what counters gained
+//! since the previous push as `|c`, and the session gauges as `|g`. The forward's `label`,
+//! `namespace`, and `leg` travel as DogStatsD tags (`|#label:...`), which the Datadog agent and
+//! Telegraf's statsd input understand.
+
+use std::collections::HashMap;
+use std::iter;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::net::{UdpSocket, lookup_host};
+use tokio::task::JoinHandle;
+use tokio::time::{MissedTickBehavior, interval};
+use tracing::{debug, info};
+
+use crate::metrics::{self, ErrorKind, Sample};
+use crate::pipeline::ShutdownRx;
+
+/// Push interval unless `statsd_interval_secs` says otherwise.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Lines are packed into datagrams up to this size, which stays under a typical path MTU.
+const MAX_PACKET: usize = 1432;
+
+/// Where and how often to push.
+pub struct Statsd {
+    pub addr: String,
+    pub prefix: String,
+    pub interval: Duration,
+}
+
+/// Counter totals at the previous push, keyed like the registry.
+type Previous = HashMap<(String, String, &'static str), Vec<u64>>;
+
+impl Statsd {
+    /// Push every interval until shutdown, then once more so the last counts are not lost.
+    pub fn spawn(self, mut shutdown: ShutdownRx) -> JoinHandle<Result<()>> {
+        tokio::spawn(async move {
+            let addr = lookup_host(&self.addr)
+                .await
+                .ok()
+                .and_then(|mut addrs| addrs.next())
+                .with_context(|| format!("failed to resolve statsd address {}", self.addr))?;
+            let local: SocketAddr = if addr.is_ipv6() {
+                (Ipv6Addr::UNSPECIFIED, 0).into()
+            } else {
+                (Ipv4Addr::UNSPECIFIED, 0).into()
+            };
+            let socket = UdpSocket::bind(local)
+                .await
+                .context("failed to bind statsd socket")?;
+            socket.connect(addr).await?;
+            info!(addr = %self.addr, interval_secs = self.interval.as_secs(), "pushing metrics to statsd");
+            let mut previous = Previous::new();
+            let mut ticks = interval(self.interval);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    res = shutdown.changed() => {
+                        if res.is_err() || *shutdown.borrow() {
+                            self.push(&socket, &mut previous).await;
+                            return Ok(());
+                        }
+                    }
+                    _ = ticks.tick() => self.push(&socket, &mut previous).await,
+                }
+            }
+        })
+    }
+
+    async fn push(&self, socket: &UdpSocket, previous: &mut Previous) {
+        let mut packet = String::new();
+        for sample in metrics::samples() {
+            for line in self.lines(&sample, previous) {
+                if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET {
+                    send(socket, &packet).await;
+                    packet.clear();
+                }
+                if !packet.is_empty() {
+                    packet.push('\n');
+                }
+                packet.push_str(&line);
+            }
+        }
+        if !packet.is_empty() {
+            send(socket, &packet).await;
+        }
+    }
+
+    /// Lines for one leg: counters that moved since the last push, and every gauge.
+    fn lines(&self, sample: &Sample, previous: &mut Previous) -> Vec<String> {
+        let mut tags = format!("label:{},leg:{}", tag_value(&sample.label), sample.leg);
+        if !sample.namespace.is_empty() {
+            tags.push_str(",namespace:");
+            tags.push_str(&tag_value(&sample.namespace));
+        }
+        let mut counters = vec![
+            ("sessions.accepted", String::new(), sample.sessions_accepted),
+            ("connect_errors", String::new(), sample.connect_errors),
+            ("uds_retries", String::new(), sample.uds_retries),
+            (
+                "bytes",
+                ",direction:client_to_target".to_string(),
+                sample.bytes_client_to_target,
+            ),
+            (
+                "bytes",
+                ",direction:target_to_client".to_string(),
+                sample.bytes_target_to_client,
+            ),
+            (
+                "udp.sessions.created",
+                String::new(),
+                sample.udp_sessions_created,
+            ),
+            (
+                "udp.sessions.expired",
+                String::new(),
+                sample.udp_sessions_expired,
+            ),
+            (
+                "udp.datagrams",
+                ",direction:client_to_target".to_string(),
+                sample.datagrams_client_to_target,
+            ),
+            (
+                "udp.datagrams",
+                ",direction:target_to_client".to_string(),
+                sample.datagrams_target_to_client,
+            ),
+            (
+                "udp.datagrams.dropped",
+                String::new(),
+                sample.datagrams_dropped,
+            ),
+            (
+                "client.tcp.retransmits",
+                String::new(),
+                sample.client_tcp_retransmits,
+            ),
+            (
+                "target.tcp.retransmits",
+                String::new(),
+                sample.target_tcp_retransmits,
+            ),
+        ];
+        for (kind, total) in ErrorKind::ALL.iter().zip(sample.errors) {
+            counters.push(("errors", format!(",kind:{}", kind.as_str()), total));
+        }
+        let totals: Vec<u64> = counters.iter().map(|(_, _, total)| *total).collect();
+        let key = (sample.label.clone(), sample.namespace.clone(), sample.leg);
+        let last = previous.insert(key, totals).unwrap_or_default();
+        let prefix = &self.prefix;
+        let mut lines = Vec::new();
+        // A leg's first push has nothing to subtract.
+        let last = last.into_iter().chain(iter::repeat(0));
+        for ((name, extra, total), last) in counters.iter().zip(last) {
+            let delta = total.saturating_sub(last);
+            if delta > 0 {
+                lines.push(format!("{prefix}.{name}:{delta}|c|#{tags}{extra}"));
+            }
+        }
+        for (name, value) in [
+            ("sessions.active", sample.sessions_active),
+            ("udp.sessions", sample.udp_sessions),
+        ] {
+            lines.push(format!("{prefix}.{name}:{value}|g|#{tags}"));
+        }
+        lines
+    }
+}
+
+async fn send(socket: &UdpSocket, packet: &str) {
+    // Nobody listening is normal for statsd; the next push tries again.
+    if let Err(err) = socket.send(packet.as_bytes()).await {
+        debug!(error = %err, "failed to send statsd packet");
+    }
+}
+
+/// Tag values may not contain the separators of the line format.
+fn tag_value(value: &str) -> String {
+    value.replace([',', '|', '#', '\n'], "_")
+}
@@ -0,0 +1,552 @@
+//! What the forwards are doing right now, for operators without a metrics endpoint: which
+//! listeners are bound, which sessions are open, each leg's session and byte counters, and each
+//! forward's last error.
+//!
+//! `SIGUSR1` logs a snapshot at `info`, `status_file` gets one as JSON every
+//! `status_interval_secs`, and the [`admin`](crate::admin) socket answers `status` with it. Forwards also say how many listeners they open, so `/readyz` can tell
+//! when every one of them is bound.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::task::JoinHandle;
+use tokio::time::{MissedTickBehavior, interval};
+use tracing::{info, warn};
+
+use crate::config::ForwardSpec;
+use crate::metrics::{self, ErrorKind, Sample};
+use crate::pipeline::ShutdownRx;
+use crate::pipeline::pause;
+use crate::pipeline::session::{self, LiveSession};
+
+/// Status file interval unless `status_interval_secs` says otherwise.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A bound listener of a forward.
+#[derive(Clone)]
+pub struct Listener {
+    pub label: String,
+    pub namespace: Option<String>,
+    pub listen: String,
+    pub since: Instant,
+}
+
+/// The error that ended a forward's latest failed session.
+#[derive(Clone, Serialize)]
+pub struct LastError {
+    pub label: String,
+    pub namespace: Option<String>,
+    pub message: String,
+    pub time_unix_ms: u64,
+}
+
+static LISTENERS: LazyLock<Mutex<BTreeMap<u64, Listener>>> = LazyLock::new(Default::default);
+
+/// Last errors by label and namespace.
+type Errors = BTreeMap<(String, Option<String>), LastError>;
+
+static ERRORS: LazyLock<Mutex<Errors>> = LazyLock::new(Default::default);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Failures in a row that make a listening forward degraded.
+const DEGRADED_AFTER: u32 = 3;
+/// How long a degraded forward stays so after its latest failure without a session succeeding.
+const DEGRADED_FOR: Duration = Duration::from_secs(60);
+/// How often state changes are looked for and logged.
+const STATE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where a forward stands.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    /// Not every listener is bound yet: its namespace has not appeared, or a listener is
+    /// (re)binding.
+    Starting,
+    Listening,
+    /// Listening, but target or UDS connects keep failing.
+    Degraded,
+    /// Bound, but not accepting until resumed; see [`pause`].
+    Paused,
+    /// A `glob:` or `neutron:` instance whose tasks failed, until it is started again.
+    Failed,
+}
+
+impl State {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            State::Starting => "starting",
+            State::Listening => "listening",
+            State::Degraded => "degraded",
+            State::Paused => "paused",
+            State::Failed => "failed",
+        }
+    }
+}
+
+/// What is known about one forward, by label and namespace.
+#[derive(Default)]
+struct Forward {
+    /// Listeners it opens once up; 0 for template instances, which are not configured as such.
+    expected: usize,
+    /// Target and UDS connect failures since the last successful session.
+    failures: u32,
+    last_failure: Option<Instant>,
+    /// Why its tasks failed, until a listener is bound again.
+    failed: Option<String>,
+}
+
+type Forwards = BTreeMap<(String, Option<String>), Forward>;
+
+static FORWARDS: LazyLock<Mutex<Forwards>> = LazyLock::new(Default::default);
+
+/// A forward's state and what it was derived from.
+#[derive(Clone, Serialize)]
+pub struct ForwardStatus {
+    pub label: String,
+    pub namespace: Option<String>,
+    pub state: State,
+    pub listeners_bound: usize,
+    pub listeners_expected: usize,
+    pub consecutive_failures: u32,
+    pub failure: Option<String>,
+}
+
+fn label(spec: &ForwardSpec) -> String {
+    spec.label.clone().unwrap_or_else(|| "unnamed".to_string())
+}
+
+fn with_forward(label: String, namespace: Option<String>, update: impl FnOnce(&mut Forward)) {
+    update(
+        FORWARDS
+            .lock()
+            .expect("forward registry poisoned")
+            .entry((label, namespace))
+            .or_default(),
+    );
+}
+
+/// Note that `spec`'s forward opens `listeners` listeners once it is up. Forwards sharing a label
+/// and namespace add up.
+pub fn expect(spec: &ForwardSpec, listeners: usize) {
+    with_forward(label(spec), spec.namespace_name(), |forward| {
+        forward.expected += listeners
+    });
+}
+
+/// Undo [`expect`] for `spec`'s forward, which is being removed. The forward is forgotten once
+/// nothing else expects listeners under its label and namespace.
+pub fn forget(spec: &ForwardSpec, listeners: usize) {
+    let mut forwards = FORWARDS.lock().expect("forward registry poisoned");
+    let key = (label(spec), spec.namespace_name());
+    if let Some(forward) = forwards.get_mut(&key) {
+        forward.expected = forward.expected.saturating_sub(listeners);
+        if forward.expected == 0 {
+            forwards.remove(&key);
+        }
+    }
+}
+
+/// Count a failed target or UDS connect of the forward labelled `label` in `namespace`.
+pub fn record_failure(label: &str, namespace: Option<&str>) {
+    with_forward(
+        label.to_string(),
+        namespace.map(str::to_string),
+        |forward| {
+            forward.failures += 1;
+            forward.last_failure = Some(Instant::now());
+        },
+    );
+}
+
+/// A session of the forward labelled `label` in `namespace` ended cleanly.
+pub fn record_success(label: &str, namespace: Option<&str>) {
+    if let Some(forward) = FORWARDS
+        .lock()
+        .expect("forward registry poisoned")
+        .get_mut(&(label.to_string(), namespace.map(str::to_string)))
+    {
+        forward.failures = 0;
+    }
+}
+
+/// The tasks of `spec`'s forward failed with `err`.
+pub fn record_failed(spec: &ForwardSpec, err: &anyhow::Error) {
+    with_forward(label(spec), spec.namespace_name(), |forward| {
+        forward.failed = Some(format!("{err:#}"))
+    });
+}
+
+/// Every forward that is configured, has a listener bound, or failed, with its state.
+pub fn forwards() -> Vec<ForwardStatus> {
+    let listeners = listeners();
+    FORWARDS
+        .lock()
+        .expect("forward registry poisoned")
+        .iter()
+        .filter_map(|((label, namespace), forward)| {
+            let bound = listeners
+                .iter()
+                .filter(|l| l.label == *label && l.namespace == *namespace)
+                .count();
+            let state = if forward.failed.is_some() {
+                State::Failed
+            } else if bound < forward.expected {
+                State::Starting
+            } else if bound == 0 {
+                // A template instance whose namespace went away.
+                return None;
+            } else if pause::paused(label) {
+                State::Paused
+            } else if forward.failures >= DEGRADED_AFTER
+                && forward
+                    .last_failure
+                    .is_some_and(|at| at.elapsed() < DEGRADED_FOR)
+            {
+                State::Degraded
+            } else {
+                State::Listening
+            };
+            Some(ForwardStatus {
+                label: label.clone(),
+                namespace: namespace.clone(),
+                state,
+                listeners_bound: bound,
+                listeners_expected: forward.expected,
+                consecutive_failures: forward.failures,
+                failure: forward.failed.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Forwards still missing listeners, e.g. because their namespace has not appeared or a listener
+/// is rebinding.
+pub fn pending() -> Vec<ForwardStatus> {
+    forwards()
+        .into_iter()
+        .filter(|forward| forward.listeners_bound < forward.listeners_expected)
+        .collect()
+}
+
+/// Keeps a listener listed until dropped with the listener's loop.
+pub struct Registration(u64);
+
+/// List the listener `spec`'s forward just bound on `listen`.
+pub fn register(spec: &ForwardSpec, listen: String) -> Registration {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let listener = Listener {
+        label: label(spec),
+        namespace: spec.namespace_name(),
+        listen,
+        since: Instant::now(),
+    };
+    with_forward(
+        listener.label.clone(),
+        listener.namespace.clone(),
+        |forward| forward.failed = None,
+    );
+    LISTENERS
+        .lock()
+        .expect("listener registry poisoned")
+        .insert(id, listener);
+    Registration(id)
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        LISTENERS
+            .lock()
+            .expect("listener registry poisoned")
+            .remove(&self.0);
+    }
+}
+
+/// Bound listeners in the order they came up.
+pub fn listeners() -> Vec<Listener> {
+    LISTENERS
+        .lock()
+        .expect("listener registry poisoned")
+        .values()
+        .cloned()
+        .collect()
+}
+
+/// Note `message` as the last error of the forward labelled `label` in `namespace`.
+pub fn record_error(label: &str, namespace: Option<&str>, message: String) {
+    let error = LastError {
+        label: label.to_string(),
+        namespace: namespace.map(str::to_string),
+        message,
+        time_unix_ms: unix_ms(SystemTime::now()),
+    };
+    ERRORS
+        .lock()
+        .expect("error registry poisoned")
+        .insert((error.label.clone(), error.namespace.clone()), error);
+}
+
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Log every bound listener and every leg's counters.
+pub fn log_snapshot() {
+    let listeners = listeners();
+    let samples = metrics::samples();
+    let forwards = forwards();
+    let sessions = session::live();
+    info!(
+        forwards = forwards.len(),
+        listeners = listeners.len(),
+        sessions = sessions.len(),
+        legs = samples.len(),
+        "status snapshot"
+    );
+    for forward in &forwards {
+        info!(
+            label = %forward.label,
+            namespace = forward.namespace.as_deref(),
+            state = forward.state.as_str(),
+            listeners_bound = forward.listeners_bound,
+            listeners_expected = forward.listeners_expected,
+            consecutive_failures = forward.consecutive_failures,
+            failure = forward.failure.as_deref(),
+            "forward state"
+        );
+    }
+    for listener in &listeners {
+        info!(
+            label = %listener.label,
+            namespace = listener.namespace.as_deref(),
+            listen = %listener.listen,
+            up_secs = listener.since.elapsed().as_secs(),
+            "listener up"
+        );
+    }
+    for session in &sessions {
+        info!(
+            id = session.id,
+            session_id = session.session_id.as_deref(),
+            label = %session.label,
+            peer = session.peer.as_deref(),
+            namespace = session.namespace.as_deref(),
+            target = session.target.as_deref(),
+            duration_ms = session.duration_ms,
+            bytes_client_to_target = session.bytes_client_to_target,
+            bytes_target_to_client = session.bytes_target_to_client,
+            "session open"
+        );
+    }
+    for sample in &samples {
+        info!(
+            label = %sample.label,
+            namespace = (!sample.namespace.is_empty()).then_some(sample.namespace.as_str()),
+            leg = sample.leg,
+            sessions_active = sample.sessions_active,
+            sessions_accepted = sample.sessions_accepted,
+            bytes_client_to_target = sample.bytes_client_to_target,
+            bytes_target_to_client = sample.bytes_target_to_client,
+            connect_errors = sample.connect_errors,
+            uds_retries = sample.uds_retries,
+            udp_sessions = sample.udp_sessions,
+            udp_sessions_created = sample.udp_sessions_created,
+            udp_sessions_expired = sample.udp_sessions_expired,
+            datagrams_client_to_target = sample.datagrams_client_to_target,
+            datagrams_target_to_client = sample.datagrams_target_to_client,
+            datagrams_dropped = sample.datagrams_dropped,
+            client_tcp_retransmits = sample.client_tcp_retransmits,
+            target_tcp_retransmits = sample.target_tcp_retransmits,
+            errors = %error_counts(sample),
+            "leg counters"
+        );
+    }
+    for error in last_errors() {
+        info!(
+            label = %error.label,
+            namespace = error.namespace.as_deref(),
+            message = %error.message,
+            time_unix_ms = error.time_unix_ms,
+            "last error"
+        );
+    }
+}
+
+/// Non-zero error counters of a leg as `kind=count` pairs, e.g. `target_connect=3 timeout=1`.
+fn error_counts(sample: &Sample) -> String {
+    let counts: Vec<String> = ErrorKind::ALL
+        .iter()
+        .zip(sample.errors)
+        .filter(|(_, count)| *count > 0)
+        .map(|(kind, count)| format!("{}={count}", kind.as_str()))
+        .collect();
+    counts.join(" ")
+}
+
+fn last_errors() -> Vec<LastError> {
+    ERRORS
+        .lock()
+        .expect("error registry poisoned")
+        .values()
+        .cloned()
+        .collect()
+}
+
+#[derive(Serialize)]
+struct ListenerStatus {
+    label: String,
+    namespace: Option<String>,
+    listen: String,
+    up_secs: u64,
+}
+
+/// Contents of the status file, and the admin socket's `status` answer.
+#[derive(Serialize)]
+pub struct Snapshot {
+    time_unix_ms: u64,
+    forwards: Vec<ForwardStatus>,
+    listeners: Vec<ListenerStatus>,
+    sessions: Vec<LiveSession>,
+    legs: Vec<Sample>,
+    errors: Vec<LastError>,
+}
+
+/// The status file's snapshot as it stands.
+pub fn snapshot() -> Snapshot {
+    Snapshot {
+        time_unix_ms: unix_ms(SystemTime::now()),
+        forwards: forwards(),
+        listeners: listeners()
+            .into_iter()
+            .map(|l| ListenerStatus {
+                up_secs: l.since.elapsed().as_secs(),
+                label: l.label,
+                namespace: l.namespace,
+                listen: l.listen,
+            })
+            .collect(),
+        sessions: session::live(),
+        legs: metrics::samples(),
+        errors: last_errors(),
+    }
+}
+
+/// Replace `path` with a fresh snapshot every `every` until shutdown. The snapshot is written
+/// next to it and renamed over it, so readers never see half a file.
+pub fn spawn_file_writer(
+    path: PathBuf,
+    every: Duration,
+    mut shutdown: ShutdownRx,
+) -> JoinHandle<Result<()>> {
+    tokio::spawn(async move {
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        let mut ticks = interval(every);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                res = shutdown.changed() => {
+                    if res.is_err() || *shutdown.borrow() {
+                        return Ok(());
+                    }
+                }
+                _ = ticks.tick() => {
+                    let res = async {
+                        let mut json = serde_json::to_vec_pretty(&snapshot())?;
+                        json.push(b'\n');
+                        tokio::fs::write(&temp, json).await?;
+                        tokio::fs::rename(&temp, &path).await?;
+                        anyhow::Ok(())
+                    };
+                    if let Err(err) = res.await {
+                        warn!(path = %path.display(), error = %err, "failed to write status file");
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Log a snapshot on every `SIGUSR1` until shutdown.
+pub fn spawn_dump_on_signal(mut shutdown: ShutdownRx) -> JoinHandle<Result<()>> {
+    tokio::spawn(async move {
+        let mut usr1 =
+            signal(SignalKind::user_defined1()).context("failed to listen for SIGUSR1")?;
+        loop {
+            tokio::select! {
+                res = shutdown.changed() => {
+                    if res.is_err() || *shutdown.borrow() {
+                        return Ok(());
+                    }
+                }
+                _ = usr1.recv() => log_snapshot(),
+            }
+        }
+    })
+}
+
+/// Log every forward's state changes until shutdown. Changes are noticed within a second, and a
+/// forward first seen past `starting` is logged as coming from it.
+pub fn spawn_state_watch(mut shutdown: ShutdownRx) -> JoinHandle<Result<()>> {
+    tokio::spawn(async move {
+        let mut known: BTreeMap<(String, Option<String>), State> = BTreeMap::new();
+        let mut ticks = interval(STATE_CHECK_INTERVAL);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                res = shutdown.changed() => {
+                    if res.is_err() || *shutdown.borrow() {
+                        return Ok(());
+                    }
+                }
+                _ = ticks.tick() => {
+                    let mut seen = BTreeMap::new();
+                    for forward in forwards() {
+                        let key = (forward.label.clone(), forward.namespace.clone());
+                        let from = known.get(&key).copied().unwrap_or(State::Starting);
+                        if forward.state != from {
+                            log_change(&forward, from);
+                        }
+                        seen.insert(key, forward.state);
+                    }
+                    known = seen;
+                }
+            }
+        }
+    })
+}
+
+fn log_change(forward: &ForwardStatus, from: State) {
+    let (label, namespace) = (&forward.label, forward.namespace.as_deref());
+    let (from, to) = (from.as_str(), forward.state.as_str());
+    match forward.state {
+        State::Starting | State::Listening | State::Paused => {
+            info!(%label, namespace, from, to, "forward state changed")
+        }
+        State::Degraded => warn!(
+            %label,
+            namespace,
+            from,
+            to,
+            consecutive_failures = forward.consecutive_failures,
+            "forward state changed"
+        ),
+        State::Failed => warn!(
+            %label,
+            namespace,
+            from,
+            to,
+            failure = forward.failure.as_deref(),
+            "forward state changed"
+        ),
+    }
+}
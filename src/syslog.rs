@@ -0,0 +1,188 @@
+//! `--log-output syslog`: tracing events as datagrams to the local syslog daemon on `/dev/log`.
+//!
+//! Each event becomes one RFC 3164 message, `<PRI>pfwd[pid]: text`, whose priority combines
+//! `syslog_facility` with the severity its level maps to through `syslog_severity`. The daemon
+//! stamps the time and host itself, and journald picks the same socket up on systemd hosts.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+const SOCKET: &str = "/dev/log";
+
+/// Facilities by name, as `logger -p` spells them.
+const FACILITIES: &[(&str, u8)] = &[
+    ("kern", 0),
+    ("user", 1),
+    ("mail", 2),
+    ("daemon", 3),
+    ("auth", 4),
+    ("syslog", 5),
+    ("lpr", 6),
+    ("news", 7),
+    ("uucp", 8),
+    ("cron", 9),
+    ("authpriv", 10),
+    ("ftp", 11),
+    ("local0", 16),
+    ("local1", 17),
+    ("local2", 18),
+    ("local3", 19),
+    ("local4", 20),
+    ("local5", 21),
+    ("local6", 22),
+    ("local7", 23),
+];
+
+const SEVERITIES: &[(&str, u8)] = &[
+    ("emerg", 0),
+    ("alert", 1),
+    ("crit", 2),
+    ("err", 3),
+    ("warning", 4),
+    ("notice", 5),
+    ("info", 6),
+    ("debug", 7),
+];
+
+const LEVELS: [Level; 5] = [
+    Level::ERROR,
+    Level::WARN,
+    Level::INFO,
+    Level::DEBUG,
+    Level::TRACE,
+];
+
+fn lookup(table: &[(&str, u8)], name: &str) -> Option<u8> {
+    table.iter().find(|(n, _)| *n == name).map(|(_, v)| *v)
+}
+
+/// Facility and level → severity mapping from `syslog_facility` and `syslog_severity`.
+#[derive(Clone, Copy)]
+pub struct Priorities {
+    pub facility: u8,
+    /// Severity of each entry of [`LEVELS`].
+    severities: [u8; 5],
+}
+
+impl Priorities {
+    /// `facility` defaults to `daemon`; `severity` overrides the usual mapping per level.
+    pub fn new(facility: Option<&str>, severity: &BTreeMap<String, String>) -> Result<Self> {
+        let facility = facility.unwrap_or("daemon");
+        let Some(facility) = lookup(FACILITIES, facility) else {
+            bail!(
+                "unknown syslog_facility `{facility}` (expected e.g. daemon, user, or local0..local7)"
+            );
+        };
+        // error → err, warn → warning, info → info, debug and trace → debug
+        let mut severities = [3, 4, 6, 7, 7];
+        for (level, name) in severity {
+            let Some(index) = LEVELS
+                .iter()
+                .position(|l| l.as_str().eq_ignore_ascii_case(level))
+            else {
+                bail!(
+                    "unknown log level `{level}` in syslog_severity (expected error, warn, info, debug, or trace)"
+                );
+            };
+            let Some(value) = lookup(SEVERITIES, name) else {
+                bail!(
+                    "unknown syslog severity `{name}` for {level} (expected emerg, alert, crit, err, warning, notice, info, or debug)"
+                );
+            };
+            severities[index] = value;
+        }
+        Ok(Self {
+            facility,
+            severities,
+        })
+    }
+
+    pub fn severity(&self, level: Level) -> u8 {
+        let index = LEVELS.iter().position(|l| *l == level).unwrap_or(2);
+        self.severities[index]
+    }
+}
+
+/// Connection to the syslog daemon.
+pub struct Syslog {
+    socket: UnixDatagram,
+    priorities: Priorities,
+    tag: String,
+}
+
+impl Syslog {
+    pub fn connect(priorities: Priorities) -> Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket
+            .connect(SOCKET)
+            .with_context(|| format!("failed to connect to syslog at {SOCKET}"))?;
+        Ok(Self {
+            socket,
+            priorities,
+            tag: format!("pfwd[{}]", std::process::id()),
+        })
+    }
+
+    fn send(&self, level: Level, text: &[u8]) {
+        let pri =
+            u16::from(self.priorities.facility) * 8 + u16::from(self.priorities.severity(level));
+        let mut message = format!("<{pri}>{}: ", self.tag).into_bytes();
+        message.extend_from_slice(text.trim_ascii());
+        // A restarted daemon has a new socket; reconnect once. Failures cannot be logged anywhere.
+        if self.socket.send(&message).is_err() && self.socket.connect(Path::new(SOCKET)).is_ok() {
+            let _ = self.socket.send(&message);
+        }
+    }
+}
+
+/// One event's formatted text, sent when the formatter is done with it.
+pub struct Message<'a> {
+    syslog: &'a Syslog,
+    level: Level,
+    text: Vec<u8>,
+}
+
+impl Write for Message<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.text.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Message<'_> {
+    fn drop(&mut self) {
+        if !self.text.is_empty() {
+            self.syslog.send(self.level, &self.text);
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = Message<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Message {
+            syslog: self,
+            level: Level::INFO,
+            text: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        Message {
+            syslog: self,
+            level: *meta.level(),
+            text: Vec::new(),
+        }
+    }
+}
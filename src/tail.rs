@@ -0,0 +1,72 @@
+//! `pfwd tail`: follow the running daemon's session opens and closes over its
+//! [`admin`](crate::admin) socket, the same records the access log gets, without finding and
+//! tailing a log file.
+
+use anyhow::Result;
+use serde_json::{Value, json};
+
+use crate::admin::Client;
+use crate::config::TailArgs;
+
+pub async fn run(args: &TailArgs) -> Result<()> {
+    let mut client = Client::connect(&args.socket).await?;
+    client
+        .send(&json!({ "command": "tail", "label": args.label, "peer": args.peer }))
+        .await?;
+    loop {
+        let record = client.receive().await?;
+        let line = if args.json {
+            record.to_string()
+        } else {
+            describe(&record)
+        };
+        println!("{line}");
+    }
+}
+
+/// One record as a line of text: time, event, session id, forward, and what the event adds.
+fn describe(record: &Value) -> String {
+    let field = |name: &str| match &record[name] {
+        Value::Null => "-".to_string(),
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    };
+    let time = record["time_unix_ms"]
+        .as_u64()
+        .map_or("-".to_string(), clock);
+    match record["event"].as_str() {
+        Some("open") => format!(
+            "{time} open  #{} {} peer={} namespace={}",
+            field("id"),
+            field("label"),
+            field("peer"),
+            field("namespace"),
+        ),
+        Some("close") => format!(
+            "{time} close #{} {} peer={} namespace={} target={} duration_ms={} up={} down={} reason={}",
+            field("id"),
+            field("label"),
+            field("peer"),
+            field("namespace"),
+            field("target"),
+            field("duration_ms"),
+            field("bytes_client_to_target"),
+            field("bytes_target_to_client"),
+            field("reason"),
+        ),
+        Some("lagged") => format!("{time} lagged: missed {} records", field("missed")),
+        _ => record.to_string(),
+    }
+}
+
+/// Time of day in UTC, to the millisecond.
+fn clock(unix_ms: u64) -> String {
+    let ms = unix_ms % 1000;
+    let secs = unix_ms / 1000 % 86400;
+    format!(
+        "{:02}:{:02}:{:02}.{ms:03}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
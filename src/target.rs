@@ -0,0 +1,503 @@
+//! Outbound connectors for the `target` leg of a forward.
+//!
+//! Plain `host:port` targets are dialed directly, racing every resolved address with Happy
+//! Eyeballs (see [`crate::happy_eyeballs`]). `srv:_service._proto.name` targets dial the hosts
+//! named by SRV records (see [`crate::srv`]). `ssh://[user@]bastion[:port]/host:port` targets
+//! open a channel through a bastion with pfwd's embedded SSH client (see [`crate::ssh`]).
+//! `sctp://host:port` targets open an SCTP association (see [`crate::sctp`]), and
+//! `vsock:<cid>:<port>` targets reach a VM over AF_VSOCK (see [`crate::vsock`]). `target_exec` specs spawn a handler per connection instead of
+//! dialing anything. Endpoints in a FreeBSD jail dial through the jail's helper (see
+//! [`crate::jail`]).
+
+use std::collections::{HashMap, VecDeque};
+use std::mem::MaybeUninit;
+use std::net::SocketAddr;
+use std::os::fd::{AsFd, BorrowedFd};
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, bail};
+use socket2::SockRef;
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpStream, lookup_host};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tracing::{Instrument, warn};
+
+use crate::addr::HostPort;
+use crate::config::{ForwardSpec, TargetBalance};
+use crate::pipeline::session;
+use crate::srv::SrvResolver;
+use crate::ssh::{SshStream, SshTarget};
+use crate::vsock::{VsockAddr, VsockStream};
+use crate::{happy_eyeballs, jail, log_limit, mptcp, sctp, srv, ssh};
+
+/// Spawn a `target_exec` handler for one connection, inetd style: the connection is its stdin and
+/// stdout. The command line is split on whitespace without shell quoting.
+fn spawn_exec(command: &str) -> Result<ChildTunnel> {
+    let mut argv = command.split_whitespace();
+    let program = argv.next().context("target_exec command is empty")?;
+    let child = Command::new(program)
+        .args(argv)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("failed to spawn target_exec handler {program}"))?;
+    ChildTunnel::new(child)
+}
+
+/// Byte stream carried over the stdio of a `target_exec` handler.
+pub struct ChildTunnel {
+    _child: Child,
+    /// Dropped on shutdown so the half-close reaches the far side of the tunnel.
+    stdin: Option<ChildStdin>,
+    stdout: ChildStdout,
+}
+
+impl ChildTunnel {
+    fn new(mut child: Child) -> Result<Self> {
+        let stdin = child.stdin.take().context("child stdin unavailable")?;
+        let stdout = child.stdout.take().context("child stdout unavailable")?;
+        Ok(Self {
+            _child: child,
+            stdin: Some(stdin),
+            stdout,
+        })
+    }
+
+    fn stdin(&mut self) -> io::Result<&mut ChildStdin> {
+        self.stdin
+            .as_mut()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+/// Connected target leg, regardless of how it was reached.
+pub enum TargetStream {
+    Tcp(TcpStream),
+    /// One-to-one SCTP socket driven through tokio's TCP type.
+    Sctp(TcpStream),
+    Child(ChildTunnel),
+    /// `direct-tcpip` channel through an ssh bastion.
+    Ssh(SshStream),
+    Vsock(VsockStream),
+}
+
+impl TargetStream {
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            TargetStream::Tcp(tcp) => tcp.set_nodelay(nodelay),
+            // TCP_NODELAY does not exist at the SCTP level.
+            TargetStream::Sctp(_)
+            | TargetStream::Child(_)
+            | TargetStream::Ssh(_)
+            | TargetStream::Vsock(_) => Ok(()),
+        }
+    }
+
+    /// Socket that can be handed to another process and used there as a TCP stream.
+    pub fn socket_fd(&self) -> Option<BorrowedFd<'_>> {
+        match self {
+            TargetStream::Tcp(tcp) | TargetStream::Sctp(tcp) => Some(tcp.as_fd()),
+            TargetStream::Child(_) | TargetStream::Ssh(_) | TargetStream::Vsock(_) => None,
+        }
+    }
+}
+
+/// Validate a target string at config load without dialing it.
+pub fn validate(target: &str) -> Result<()> {
+    ssh::validate(target)?;
+    if SshTarget::parse(target)?.is_some() {
+        return Ok(());
+    }
+    sctp::validate(target)?;
+    if VsockAddr::parse(target)?.is_some() || srv::strip_scheme(target).is_some() {
+        return srv::validate(target);
+    }
+    sctp::strip_scheme(target)
+        .unwrap_or(target)
+        .parse::<HostPort>()?;
+    Ok(())
+}
+
+const POOL_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Idle target connections handed to new sessions ahead of dialing.
+#[derive(Debug)]
+struct WarmPool {
+    size: usize,
+    idle: Mutex<VecDeque<TcpStream>>,
+    /// Wakes the refill task after a session takes a connection.
+    taken: Notify,
+}
+
+impl WarmPool {
+    fn new(size: usize) -> Self {
+        Self {
+            size,
+            idle: Mutex::new(VecDeque::with_capacity(size)),
+            taken: Notify::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.idle.lock().expect("target pool poisoned").len()
+    }
+
+    fn push(&self, tcp: TcpStream) {
+        self.idle
+            .lock()
+            .expect("target pool poisoned")
+            .push_back(tcp);
+    }
+
+    /// Oldest connection the target has not closed in the meantime. Data the target already sent
+    /// (e.g. a greeting banner) stays queued for the session.
+    fn take(&self) -> Option<TcpStream> {
+        let mut idle = self.idle.lock().expect("target pool poisoned");
+        let mut taken = None;
+        while let Some(tcp) = idle.pop_front() {
+            let mut probe = [MaybeUninit::uninit()];
+            match SockRef::from(&tcp).peek(&mut probe) {
+                Ok(0) => continue,
+                Err(err) if err.kind() != io::ErrorKind::WouldBlock => continue,
+                _ => {
+                    taken = Some(tcp);
+                    break;
+                }
+            }
+        }
+        drop(idle);
+        self.taken.notify_one();
+        taken
+    }
+}
+
+/// Refills a connector's warm pool while held. Dropping it, when the forward stops, ends the
+/// refilling and closes the idle connections.
+#[must_use = "the pool is only kept filled while this is held"]
+pub struct PoolRefill(Option<(Arc<WarmPool>, JoinHandle<()>)>);
+
+impl Drop for PoolRefill {
+    fn drop(&mut self) {
+        if let Some((pool, task)) = self.0.take() {
+            task.abort();
+            pool.idle.lock().expect("target pool poisoned").clear();
+        }
+    }
+}
+
+/// Addresses from one lookup and when they stop being reused.
+type ResolvedAddrs = (Vec<SocketAddr>, Instant);
+
+/// Dials one forward's target leg with the socket options its spec asks for.
+#[derive(Debug, Clone)]
+pub struct Connector {
+    /// Target address, or the handler command line when `exec` is set.
+    target: String,
+    mptcp: bool,
+    exec: bool,
+    /// Shared so clones reuse the cached SRV answer until its TTL expires.
+    srv: Option<Arc<SrvResolver>>,
+    balance: TargetBalance,
+    /// Round-robin position, shared by every clone of this connector.
+    next: Arc<AtomicUsize>,
+    resolve_ttl: Duration,
+    /// Resolved addresses per `host:port`, shared by every clone of this connector.
+    resolved: Arc<Mutex<HashMap<String, ResolvedAddrs>>>,
+    /// Pre-established connections, filled by [`Connector::warm_pool`].
+    pool: Option<Arc<WarmPool>>,
+    /// Helper that opens TCP connections inside the spec's jail.
+    jail: Option<Arc<jail::Helper>>,
+}
+
+impl Connector {
+    pub fn new(target: String, mptcp: bool) -> Self {
+        let srv = srv::strip_scheme(&target).map(|name| Arc::new(SrvResolver::new(name)));
+        Self {
+            target,
+            mptcp,
+            exec: false,
+            srv,
+            balance: TargetBalance::First,
+            next: Arc::new(AtomicUsize::new(0)),
+            resolve_ttl: Duration::ZERO,
+            resolved: Arc::default(),
+            pool: None,
+            jail: None,
+        }
+    }
+
+    /// Connector for the spec's `target`, or one that spawns its `target_exec` handler.
+    pub fn for_spec(spec: &ForwardSpec) -> Result<Self> {
+        if let Some(command) = spec.target_exec.clone() {
+            return Ok(Self {
+                target: command,
+                mptcp: false,
+                exec: true,
+                srv: None,
+                balance: TargetBalance::First,
+                next: Arc::new(AtomicUsize::new(0)),
+                resolve_ttl: Duration::ZERO,
+                resolved: Arc::default(),
+                pool: None,
+                jail: None,
+            });
+        }
+        let target = spec.target.clone().context("spec has no target")?;
+        Ok(Self {
+            balance: spec.target_balance(),
+            resolve_ttl: spec.target_resolve_ttl(),
+            pool: spec.target_pool.map(|size| Arc::new(WarmPool::new(size))),
+            ..Self::new(target, spec.mptcp())
+        })
+    }
+
+    /// Start keeping the spec's `target_pool` connections open in the background, until the
+    /// returned guard is dropped; a no-op without a pool. Must run on the runtime (and in the
+    /// namespace) that sessions will use.
+    pub fn warm_pool(&self) -> PoolRefill {
+        let Some(pool) = self.pool.clone() else {
+            return PoolRefill(None);
+        };
+        let dialer = Self {
+            pool: None,
+            ..self.clone()
+        };
+        let refill = pool.clone();
+        let task = tokio::spawn(async move {
+            let pool = refill;
+            loop {
+                while pool.len() < pool.size {
+                    match dialer.connect_tcp().await {
+                        Ok(tcp) => pool.push(tcp),
+                        Err(err) => {
+                            if let Some(suppressed) = log_limit::allow(
+                                "failed to pre-connect target; retrying",
+                                &dialer.target,
+                            ) {
+                                warn!(target = %dialer.target, error = %err, suppressed, "failed to pre-connect target; retrying");
+                            }
+                            tokio::time::sleep(POOL_RETRY_DELAY).await;
+                        }
+                    }
+                }
+                pool.taken.notified().await;
+            }
+        });
+        PoolRefill(Some((pool, task)))
+    }
+
+    /// Dial TCP targets from inside a jail, through `helper`.
+    pub fn through_jail(self, helper: jail::Helper) -> Self {
+        Self {
+            jail: Some(Arc::new(helper)),
+            ..self
+        }
+    }
+
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// Dial the target, either directly or through an ssh bastion, or spawn the exec handler.
+    pub async fn connect(&self) -> Result<TargetStream> {
+        self.dial()
+            .instrument(session::target_connect_span(&self.target))
+            .await
+    }
+
+    async fn dial(&self) -> Result<TargetStream> {
+        let target = self.target.as_str();
+        if self.exec {
+            return Ok(TargetStream::Child(spawn_exec(target)?));
+        }
+        if let Some(ssh) = SshTarget::parse(target)? {
+            return Ok(TargetStream::Ssh(ssh.connect().await?));
+        }
+        if let Some(addr) = sctp::strip_scheme(target) {
+            return Ok(TargetStream::Sctp(sctp::connect(addr).await?));
+        }
+        if let Some(addr) = VsockAddr::parse(target)? {
+            return Ok(TargetStream::Vsock(VsockStream::connect(addr).await?));
+        }
+        if let Some(tcp) = self.pool.as_ref().and_then(|pool| pool.take()) {
+            return Ok(TargetStream::Tcp(tcp));
+        }
+        Ok(TargetStream::Tcp(self.connect_tcp().await?))
+    }
+
+    async fn connect_tcp(&self) -> Result<TcpStream> {
+        if let Some(jail) = self.jail.as_ref() {
+            return jail.connect(&self.target).await;
+        }
+        if let Some(srv) = self.srv.as_ref() {
+            let mut last_err = None;
+            for record in srv.candidates().await? {
+                match self.dial_tcp(&record.authority()).await {
+                    Ok(tcp) => return Ok(tcp),
+                    Err(err) => last_err = Some(err),
+                }
+            }
+            return Err(last_err.context("srv target has no records")?);
+        }
+        self.dial_tcp(&self.target).await
+    }
+
+    async fn dial_tcp(&self, target: &str) -> Result<TcpStream> {
+        let mut addrs = self.resolve(target).await?;
+        if addrs.is_empty() {
+            bail!("{target} resolved to no addresses");
+        }
+        // Rotating keeps the remaining addresses as Happy Eyeballs fallbacks.
+        let start = match self.balance {
+            TargetBalance::First => 0,
+            TargetBalance::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+            TargetBalance::Random => srv::random_u64() as usize,
+        };
+        let len = addrs.len();
+        addrs.rotate_left(start % len);
+        let addrs = happy_eyeballs::interleave(addrs);
+        let mptcp = self.mptcp;
+        happy_eyeballs::race(addrs, |addr| async move {
+            if mptcp {
+                mptcp::connect(addr).await
+            } else {
+                TcpStream::connect(addr).await
+            }
+        })
+        .await
+        .with_context(|| format!("failed to connect {target}"))
+    }
+
+    /// Addresses for `target`, reused for `resolve_ttl` after each lookup. A failed re-resolution
+    /// falls back to the previous answer so a DNS outage does not take the forward down.
+    async fn resolve(&self, target: &str) -> Result<Vec<SocketAddr>> {
+        if let Ok(HostPort::Ip(addr)) = target.parse() {
+            return Ok(vec![addr]);
+        }
+        let cached = self
+            .resolved
+            .lock()
+            .expect("resolver cache poisoned")
+            .get(target)
+            .cloned();
+        if let Some((addrs, expires)) = cached.as_ref()
+            && Instant::now() < *expires
+        {
+            return Ok(addrs.clone());
+        }
+        let addrs = match lookup_host(target).await {
+            Ok(addrs) => addrs.collect::<Vec<_>>(),
+            Err(err) => match cached {
+                Some((stale, _)) => {
+                    warn!(%target, error = %err, "re-resolving target failed; reusing previous addresses");
+                    return Ok(stale);
+                }
+                None => return Err(err).with_context(|| format!("failed to resolve {target}")),
+            },
+        };
+        if !self.resolve_ttl.is_zero() {
+            self.resolved
+                .lock()
+                .expect("resolver cache poisoned")
+                .insert(
+                    target.to_string(),
+                    (addrs.clone(), Instant::now() + self.resolve_ttl),
+                );
+        }
+        Ok(addrs)
+    }
+}
+
+impl AsyncRead for TargetStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TargetStream::Tcp(tcp) | TargetStream::Sctp(tcp) => Pin::new(tcp).poll_read(cx, buf),
+            TargetStream::Child(child) => Pin::new(&mut child.stdout).poll_read(cx, buf),
+            TargetStream::Ssh(ssh) => Pin::new(ssh).poll_read(cx, buf),
+            TargetStream::Vsock(vsock) => Pin::new(vsock).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for TargetStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            TargetStream::Tcp(tcp) | TargetStream::Sctp(tcp) => Pin::new(tcp).poll_write(cx, buf),
+            TargetStream::Child(child) => match child.stdin() {
+                Ok(stdin) => Pin::new(stdin).poll_write(cx, buf),
+                Err(err) => Poll::Ready(Err(err)),
+            },
+            TargetStream::Ssh(ssh) => Pin::new(ssh).poll_write(cx, buf),
+            TargetStream::Vsock(vsock) => Pin::new(vsock).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TargetStream::Tcp(tcp) | TargetStream::Sctp(tcp) => Pin::new(tcp).poll_flush(cx),
+            TargetStream::Child(child) => match child.stdin.as_mut() {
+                Some(stdin) => Pin::new(stdin).poll_flush(cx),
+                None => Poll::Ready(Ok(())),
+            },
+            TargetStream::Ssh(ssh) => Pin::new(ssh).poll_flush(cx),
+            TargetStream::Vsock(vsock) => Pin::new(vsock).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TargetStream::Tcp(tcp) | TargetStream::Sctp(tcp) => Pin::new(tcp).poll_shutdown(cx),
+            TargetStream::Child(child) => {
+                if let Some(stdin) = child.stdin.as_mut() {
+                    std::task::ready!(Pin::new(stdin).poll_flush(cx))?;
+                }
+                child.stdin = None;
+                Poll::Ready(Ok(()))
+            }
+            TargetStream::Ssh(ssh) => Pin::new(ssh).poll_shutdown(cx),
+            TargetStream::Vsock(vsock) => Pin::new(vsock).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn dropping_the_refill_closes_the_pool() {
+        use tokio::io::AsyncReadExt;
+        use tokio::net::TcpListener;
+
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connector = Connector {
+            pool: Some(Arc::new(WarmPool::new(1))),
+            ..Connector::new(target.local_addr().unwrap().to_string(), false)
+        };
+        let refill = connector.warm_pool();
+        let (mut pooled, _) = target.accept().await.unwrap();
+        drop(refill);
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), pooled.read(&mut buf))
+            .await
+            .expect("pooled connection was left open");
+        assert_eq!(read.unwrap(), 0);
+    }
+}
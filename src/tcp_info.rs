@@ -0,0 +1,43 @@
+//! `TCP_INFO` of a session's sockets, read when its relay is done, so slow sessions can be told
+//! apart: a high RTT or many retransmits on the client or target side point at the network there,
+//! while clean numbers on both sides point at pfwd or the target itself.
+
+use std::mem;
+use std::os::fd::{AsRawFd, BorrowedFd};
+use std::time::Duration;
+
+use nix::libc;
+
+/// What the kernel knows about one TCP connection.
+#[derive(Clone, Copy)]
+pub struct TcpInfo {
+    /// Smoothed round-trip time.
+    pub rtt: Duration,
+    /// Retransmitted segments over the connection's lifetime.
+    pub retransmits: u32,
+    /// Most recent delivery rate estimate, in bytes per second; 0 before the kernel has one.
+    pub delivery_rate: u64,
+}
+
+/// `TCP_INFO` of `fd`, or `None` if it is not a TCP socket.
+pub fn query(fd: BorrowedFd<'_>) -> Option<TcpInfo> {
+    // SAFETY: `tcp_info` is all integers, for which zero is valid. Older kernels fill less of the
+    // struct, and what they leave out reads as zero.
+    let mut info: libc::tcp_info = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    // SAFETY: `info` is a writable `tcp_info` of `len` bytes, and the kernel writes at most that.
+    let res = unsafe {
+        libc::getsockopt(
+            fd.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            (&raw mut info).cast(),
+            &mut len,
+        )
+    };
+    (res == 0).then(|| TcpInfo {
+        rtt: Duration::from_micros(info.tcpi_rtt.into()),
+        retransmits: info.tcpi_total_retrans,
+        delivery_rate: info.tcpi_delivery_rate,
+    })
+}
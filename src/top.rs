@@ -0,0 +1,222 @@
+//! `pfwd top`: a live view of the running daemon over its [`admin`](crate::admin) socket, like
+//! `iftop` scoped to pfwd's sessions.
+//!
+//! Every refresh asks for the `status` snapshot and works out each open stream session's
+//! throughput from how its byte counts moved since the previous one. Forwards are listed with the
+//! sum of their sessions, then the sessions themselves, busiest first. On a terminal the screen is
+//! redrawn in place; otherwise frames are printed one after another.
+
+use std::collections::HashMap;
+use std::io::{IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::time::{MissedTickBehavior, interval};
+
+use crate::admin::Client;
+use crate::config::TopArgs;
+
+#[derive(Deserialize)]
+struct Snapshot {
+    forwards: Vec<Forward>,
+    sessions: Vec<Session>,
+}
+
+#[derive(Deserialize)]
+struct Forward {
+    label: String,
+    namespace: Option<String>,
+    state: String,
+    listeners_bound: usize,
+    listeners_expected: usize,
+}
+
+#[derive(Deserialize)]
+struct Session {
+    id: u64,
+    label: String,
+    namespace: Option<String>,
+    peer: Option<String>,
+    target: Option<String>,
+    duration_ms: u64,
+    bytes_client_to_target: u64,
+    bytes_target_to_client: u64,
+}
+
+/// Bytes per second towards the target and towards the client.
+#[derive(Clone, Copy, Default)]
+struct Rate {
+    up: f64,
+    down: f64,
+}
+
+impl Rate {
+    fn total(self) -> f64 {
+        self.up + self.down
+    }
+
+    fn add(&mut self, other: Rate) {
+        self.up += other.up;
+        self.down += other.down;
+    }
+}
+
+pub async fn run(args: &TopArgs) -> Result<()> {
+    let mut client = Client::connect(&args.socket).await?;
+    let terminal = std::io::stdout().is_terminal();
+    let mut ticks = interval(Duration::from_secs(args.interval.max(1)));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // Byte counts by session at the previous refresh.
+    let mut previous: HashMap<u64, (u64, u64)> = HashMap::new();
+    let mut polled: Option<Instant> = None;
+    loop {
+        ticks.tick().await;
+        let answer = client.call(&json!({ "command": "status" })).await?;
+        let snapshot: Snapshot =
+            serde_json::from_value(answer).context("unexpected status answer from pfwd")?;
+        let elapsed = polled.map(|at| at.elapsed());
+        polled = Some(Instant::now());
+        let rates: Vec<Rate> = snapshot
+            .sessions
+            .iter()
+            .map(|session| rate(session, previous.get(&session.id), elapsed))
+            .collect();
+        previous = snapshot
+            .sessions
+            .iter()
+            .map(|s| (s.id, (s.bytes_client_to_target, s.bytes_target_to_client)))
+            .collect();
+        let frame = render(&snapshot, &rates, args.limit);
+        let mut stdout = std::io::stdout().lock();
+        if terminal {
+            // Home the cursor and clear the screen.
+            write!(stdout, "\x1b[H\x1b[2J")?;
+        }
+        writeln!(stdout, "{frame}")?;
+        stdout.flush()?;
+    }
+}
+
+/// Throughput of `session` since the previous refresh, or since it opened if it is new.
+fn rate(session: &Session, previous: Option<&(u64, u64)>, elapsed: Option<Duration>) -> Rate {
+    let open = Duration::from_millis(session.duration_ms);
+    let (since, (up, down)) = match (previous, elapsed) {
+        (Some(&bytes), Some(elapsed)) => (elapsed, bytes),
+        (None, Some(elapsed)) => (elapsed.min(open), (0, 0)),
+        // The first refresh has nothing to compare with; average over the session's life.
+        (_, None) => (open, (0, 0)),
+    };
+    let secs = since.as_secs_f64().max(0.001);
+    Rate {
+        up: session.bytes_client_to_target.saturating_sub(up) as f64 / secs,
+        down: session.bytes_target_to_client.saturating_sub(down) as f64 / secs,
+    }
+}
+
+fn render(snapshot: &Snapshot, rates: &[Rate], limit: usize) -> String {
+    let mut totals: HashMap<(&str, Option<&str>), (usize, Rate)> = HashMap::new();
+    let mut all = Rate::default();
+    for (session, &rate) in snapshot.sessions.iter().zip(rates) {
+        let entry = totals
+            .entry((&session.label, session.namespace.as_deref()))
+            .or_default();
+        entry.0 += 1;
+        entry.1.add(rate);
+        all.add(rate);
+    }
+    let mut out = format!(
+        "pfwd top - {} forwards, {} sessions, up {}/s, down {}/s\n\n",
+        snapshot.forwards.len(),
+        snapshot.sessions.len(),
+        human(all.up),
+        human(all.down),
+    );
+    out += &format!(
+        "{:<24} {:<20} {:<10} {:>9} {:>8} {:>9} {:>9}\n",
+        "FORWARD", "NAMESPACE", "STATE", "LISTENERS", "SESSIONS", "UP/s", "DOWN/s"
+    );
+    let mut forwards: Vec<(&Forward, usize, Rate)> = snapshot
+        .forwards
+        .iter()
+        .map(|forward| {
+            let (sessions, rate) = totals
+                .get(&(forward.label.as_str(), forward.namespace.as_deref()))
+                .copied()
+                .unwrap_or_default();
+            (forward, sessions, rate)
+        })
+        .collect();
+    forwards.sort_by(|a, b| b.2.total().total_cmp(&a.2.total()).then(b.1.cmp(&a.1)));
+    for (forward, sessions, rate) in forwards {
+        out += &format!(
+            "{:<24} {:<20} {:<10} {:>9} {:>8} {:>9} {:>9}\n",
+            forward.label,
+            forward.namespace.as_deref().unwrap_or("-"),
+            forward.state,
+            format!("{}/{}", forward.listeners_bound, forward.listeners_expected),
+            sessions,
+            human(rate.up),
+            human(rate.down),
+        );
+    }
+    out += &format!(
+        "\n{:>6} {:<24} {:<22} {:<22} {:>8} {:>9} {:>9} {:>9}\n",
+        "ID", "FORWARD", "PEER", "TARGET", "AGE", "UP/s", "DOWN/s", "TOTAL"
+    );
+    let mut sessions: Vec<(&Session, Rate)> = snapshot
+        .sessions
+        .iter()
+        .zip(rates.iter().copied())
+        .collect();
+    sessions.sort_by(|a, b| {
+        b.1.total().total_cmp(&a.1.total()).then_with(|| {
+            let total = |s: &Session| s.bytes_client_to_target + s.bytes_target_to_client;
+            total(b.0).cmp(&total(a.0))
+        })
+    });
+    for (session, rate) in sessions.iter().take(limit) {
+        out += &format!(
+            "{:>6} {:<24} {:<22} {:<22} {:>8} {:>9} {:>9} {:>9}\n",
+            session.id,
+            session.label,
+            session.peer.as_deref().unwrap_or("-"),
+            session.target.as_deref().unwrap_or("-"),
+            age(session.duration_ms),
+            human(rate.up),
+            human(rate.down),
+            human((session.bytes_client_to_target + session.bytes_target_to_client) as f64),
+        );
+    }
+    if sessions.len() > limit {
+        out += &format!("  ... {} more\n", sessions.len() - limit);
+    }
+    out
+}
+
+/// Bytes with a binary unit suffix, e.g. `1.5M`.
+fn human(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "K", "M", "G", "T"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{value:.0}{}", UNITS[unit])
+    } else {
+        format!("{value:.1}{}", UNITS[unit])
+    }
+}
+
+/// A duration in its two largest units, e.g. `3m05s`.
+pub fn age(duration_ms: u64) -> String {
+    let secs = duration_ms / 1000;
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
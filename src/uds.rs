@@ -1,50 +1,260 @@
+use std::ffi::OsStr;
 use std::fs;
-use std::os::fd::AsRawFd;
-use std::os::unix::fs::FileTypeExt;
-use std::os::unix::net::UnixListener as StdUnixListener;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, lchown};
+use std::os::unix::net::{
+    UnixDatagram as StdUnixDatagram, UnixListener as StdUnixListener, UnixStream as StdUnixStream,
+};
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
 
 use anyhow::{Context, Result, bail};
 use nix::libc;
-use nix::sys::stat::{Mode, fchmod};
-use nix::unistd::{Gid, Uid, fchown};
-use tokio::net::unix::SocketAddr;
-use tokio::net::{UnixListener, UnixStream};
+use nix::sys::stat::{FchmodatFlags, Mode, fchmod, fchmodat};
+use socket2::{Domain, SockAddr, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{UnixDatagram, UnixListener, UnixStream};
 
-use crate::config::Owner;
+use crate::config::{Owner, UdsType};
+use crate::netns;
+use crate::seqpacket::{SeqpacketListener, SeqpacketStream};
+
+/// Backlog for Unix listeners, matching what `std` uses.
+const LISTEN_BACKLOG: i32 = 128;
 
 pub struct BoundUnixListener {
     path: PathBuf,
-    listener: UnixListener,
+    /// Mount namespace the path was bound in, so cleanup unlinks the same file.
+    mount_ns: Option<PathBuf>,
+    listener: ListenerSocket,
+}
+
+enum ListenerSocket {
+    Stream(UnixListener),
+    Seqpacket(SeqpacketListener),
 }
 
 impl BoundUnixListener {
-    pub async fn accept(&self) -> std::io::Result<(UnixStream, SocketAddr)> {
-        self.listener.accept().await
+    pub async fn accept(&self) -> std::io::Result<UdsConnection> {
+        match &self.listener {
+            ListenerSocket::Stream(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok(UdsConnection::Stream(stream))
+            }
+            ListenerSocket::Seqpacket(listener) => {
+                Ok(UdsConnection::Seqpacket(listener.accept().await?))
+            }
+        }
     }
 }
 
 impl Drop for BoundUnixListener {
     fn drop(&mut self) {
-        match fs::remove_file(&self.path) {
-            Ok(_) => {}
-            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
-            Err(err) => {
-                tracing::warn!(
-                    path = %self.path.display(),
-                    error = %err,
-                    "failed to remove unix socket during drop"
-                );
+        if abstract_name(&self.path).is_some() {
+            return;
+        }
+        match self.mount_ns.as_deref() {
+            Some(mount_ns) => {
+                let removed = netns::within_mount_ns(mount_ns, || {
+                    remove_socket_file(&self.path);
+                    Ok(())
+                });
+                if let Err(err) = removed {
+                    tracing::warn!(
+                        path = %self.path.display(),
+                        error = %err,
+                        "failed to remove unix socket during drop"
+                    );
+                }
             }
-        };
+            None => remove_socket_file(&self.path),
+        }
+    }
+}
+
+/// Name of a Linux abstract-namespace socket written as `@name`. Abstract sockets have no
+/// filesystem entry (so no stale-file cleanup, chmod, or chown) and are scoped to the network
+/// namespace they are bound in.
+pub fn abstract_name(path: &Path) -> Option<&[u8]> {
+    path.as_os_str().as_bytes().strip_prefix(b"@")
+}
+
+/// Address for a socket path, or for an abstract `@name`.
+fn sock_addr(path: &Path) -> std::io::Result<SockAddr> {
+    match abstract_name(path) {
+        Some(name) => {
+            let mut bytes = Vec::with_capacity(name.len() + 1);
+            bytes.push(0);
+            bytes.extend_from_slice(name);
+            SockAddr::unix(OsStr::from_bytes(&bytes))
+        }
+        None => SockAddr::unix(path),
     }
 }
 
+/// Bind a listener of the given socket type at `path`, resolved inside `mount_ns` when given so
+/// the socket appears where processes in that mount namespace look for it.
 pub fn bind_listener(
     path: &Path,
+    kind: UdsType,
+    mount_ns: Option<&Path>,
     owner: Option<Owner>,
     mode: Option<u32>,
 ) -> Result<BoundUnixListener> {
+    let is_abstract = abstract_name(path).is_some();
+    let bind = || -> Result<Socket> {
+        if !is_abstract {
+            prepare_socket_path(path)?;
+        }
+        let socket = Socket::new(Domain::UNIX, kind.socket_type(), None)
+            .context("failed to create unix socket")?;
+        if !is_abstract {
+            restrict_before_bind(&socket, mode)?;
+        }
+        socket
+            .bind(&sock_addr(path)?)
+            .with_context(|| format!("unable to bind unix socket {}", path.display()))?;
+        socket.listen(LISTEN_BACKLOG)?;
+        socket
+            .set_nonblocking(true)
+            .context("failed to set nonblocking mode for unix listener")?;
+        if !is_abstract {
+            apply_permissions(path, owner, mode)?;
+        }
+        Ok(socket)
+    };
+    let socket = match mount_ns {
+        Some(mount_ns) if !is_abstract => netns::within_mount_ns(mount_ns, bind)?,
+        _ => bind()?,
+    };
+
+    let listener = match kind {
+        UdsType::Stream => ListenerSocket::Stream(UnixListener::from_std(StdUnixListener::from(
+            OwnedFd::from(socket),
+        ))?),
+        UdsType::Seqpacket => ListenerSocket::Seqpacket(SeqpacketListener::from_socket(socket)?),
+    };
+    Ok(BoundUnixListener {
+        path: path.to_path_buf(),
+        mount_ns: mount_ns.filter(|_| !is_abstract).map(Path::to_path_buf),
+        listener,
+    })
+}
+
+/// Connect to the listener at `path` (or abstract `@name`) from the calling thread's namespaces.
+/// Unix sockets connect immediately or fail, so this does not wait on the runtime.
+pub fn connect_socket(path: &Path, kind: UdsType) -> std::io::Result<Socket> {
+    let socket = Socket::new(Domain::UNIX, kind.socket_type(), None)?;
+    socket.connect(&sock_addr(path)?)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Accepted or dialed UDS connection of either socket type.
+pub enum UdsConnection {
+    Stream(UnixStream),
+    Seqpacket(SeqpacketStream),
+}
+
+impl UdsConnection {
+    /// Register a socket returned by [`connect_socket`] with the runtime.
+    pub fn from_socket(socket: Socket, kind: UdsType) -> std::io::Result<Self> {
+        match kind {
+            UdsType::Stream => Ok(Self::Stream(UnixStream::from_std(StdUnixStream::from(
+                OwnedFd::from(socket),
+            ))?)),
+            UdsType::Seqpacket => Ok(Self::Seqpacket(SeqpacketStream::from_socket(socket)?)),
+        }
+    }
+}
+
+impl AsyncRead for UdsConnection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            UdsConnection::Stream(stream) => Pin::new(stream).poll_read(cx, buf),
+            UdsConnection::Seqpacket(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UdsConnection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            UdsConnection::Stream(stream) => Pin::new(stream).poll_write(cx, buf),
+            UdsConnection::Seqpacket(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            UdsConnection::Stream(stream) => Pin::new(stream).poll_flush(cx),
+            UdsConnection::Seqpacket(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            UdsConnection::Stream(stream) => Pin::new(stream).poll_shutdown(cx),
+            UdsConnection::Seqpacket(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Unix datagram socket bound to a filesystem path; the path is unlinked on drop.
+pub struct BoundUnixDatagram {
+    path: PathBuf,
+    socket: Arc<UnixDatagram>,
+}
+
+impl BoundUnixDatagram {
+    pub fn socket(&self) -> &Arc<UnixDatagram> {
+        &self.socket
+    }
+}
+
+impl Drop for BoundUnixDatagram {
+    fn drop(&mut self) {
+        remove_socket_file(&self.path);
+    }
+}
+
+pub fn bind_datagram(
+    path: &Path,
+    owner: Option<Owner>,
+    mode: Option<u32>,
+) -> Result<BoundUnixDatagram> {
+    prepare_socket_path(path)?;
+    let socket = Socket::new(Domain::UNIX, Type::DGRAM, None)
+        .context("failed to create unix datagram socket")?;
+    restrict_before_bind(&socket, mode)?;
+    socket
+        .bind(&SockAddr::unix(path)?)
+        .with_context(|| format!("unable to bind unix datagram socket {}", path.display()))?;
+    socket
+        .set_nonblocking(true)
+        .context("failed to set nonblocking mode for unix datagram socket")?;
+    apply_permissions(path, owner, mode)?;
+
+    let socket = UnixDatagram::from_std(StdUnixDatagram::from(OwnedFd::from(socket)))?;
+    Ok(BoundUnixDatagram {
+        path: path.to_path_buf(),
+        socket: Arc::new(socket),
+    })
+}
+
+/// Create the parent directory and clear a stale socket left behind by a previous run.
+fn prepare_socket_path(path: &Path) -> Result<()> {
     if let Some(parent) = path.parent() {
         fs::create_dir_all(parent)
             .with_context(|| format!("failed to create socket directory {}", parent.display()))?;
@@ -65,32 +275,97 @@ pub fn bind_listener(
             }
         }
     }
+    Ok(())
+}
 
-    let std_listener = StdUnixListener::bind(path)
-        .with_context(|| format!("unable to bind unix socket {}", path.display()))?;
-    std_listener
-        .set_nonblocking(true)
-        .context("failed to set nonblocking mode for unix listener")?;
+/// Keep the file `bind` creates for `socket` to pfwd's user until [`apply_permissions`] sets its
+/// final owner and mode. Linux creates the file with the socket's own mode less the umask, so a
+/// listener that is already accepting never has wider permissions than configured.
+fn restrict_before_bind(socket: &Socket, mode: Option<u32>) -> Result<()> {
+    if let Some(mode) = mode {
+        let owner_only = Mode::from_bits_truncate((mode & 0o700) as libc::mode_t);
+        fchmod(socket.as_raw_fd(), owner_only).context("failed to restrict unix socket mode")?;
+    }
+    Ok(())
+}
 
+/// Apply owner and mode to the bound socket file. `fchmod`/`fchown` on a socket fd do not touch
+/// the filesystem entry, so this works on the path the socket was just bound to. Neither change
+/// follows a symlink put in the socket's place, so neither can be redirected to another file.
+fn apply_permissions(path: &Path, owner: Option<Owner>, mode: Option<u32>) -> Result<()> {
     if let Some(mode) = mode {
-        let bits: libc::mode_t = mode
-            .try_into()
-            .context("mode must fit into platform mode_t")?;
-        let mode = Mode::from_bits(bits).context("invalid mode bits")?;
-        fchmod(std_listener.as_raw_fd(), mode)?;
+        let mode = Mode::from_bits(mode as libc::mode_t)
+            .filter(|_| mode & !0o7777 == 0)
+            .with_context(|| format!("invalid mode bits {mode:#o}"))?;
+        fchmodat(None, path, mode, FchmodatFlags::NoFollowSymlink)
+            .with_context(|| format!("failed to chmod {}", path.display()))?;
     }
     if let Some(owner) = owner {
-        fchown(
-            std_listener.as_raw_fd(),
-            Some(Uid::from_raw(owner.uid)),
-            Some(Gid::from_raw(owner.gid)),
-        )
-        .with_context(|| format!("failed to chown {}", path.display()))?;
+        lchown(path, Some(owner.uid), Some(owner.gid))
+            .with_context(|| format!("failed to chown {}", path.display()))?;
     }
+    Ok(())
+}
 
-    let listener = UnixListener::from_std(std_listener)?;
-    Ok(BoundUnixListener {
-        path: path.to_path_buf(),
-        listener,
-    })
+fn remove_socket_file(path: &Path) {
+    match fs::remove_file(path) {
+        Ok(_) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => {
+            tracing::warn!(
+                path = %path.display(),
+                error = %err,
+                "failed to remove unix socket during drop"
+            );
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{PermissionsExt, symlink};
+
+    use super::*;
+
+    #[test]
+    fn applies_mode_without_following_symlinks() {
+        let dir = std::env::temp_dir().join(format!("pfwd-uds-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("s.sock");
+        let _socket = StdUnixListener::bind(&path).unwrap();
+        apply_permissions(&path, None, Some(0o640)).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o7777,
+            0o640
+        );
+        assert!(apply_permissions(&path, None, Some(0o10_000)).is_err());
+
+        let victim = dir.join("victim");
+        fs::write(&victim, "").unwrap();
+        fs::set_permissions(&victim, fs::Permissions::from_mode(0o600)).unwrap();
+        fs::remove_file(&path).unwrap();
+        symlink(&victim, &path).unwrap();
+        assert!(apply_permissions(&path, None, Some(0o666)).is_err());
+        assert_eq!(
+            fs::metadata(&victim).unwrap().permissions().mode() & 0o7777,
+            0o600
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn binds_owner_only_until_permissions_apply() {
+        let path = std::env::temp_dir().join(format!("pfwd-uds-pre-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let socket = Socket::new(Domain::UNIX, Type::STREAM, None).unwrap();
+        restrict_before_bind(&socket, Some(0o666)).unwrap();
+        socket.bind(&SockAddr::unix(&path).unwrap()).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o077, 0);
+        apply_permissions(&path, None, Some(0o666)).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o7777,
+            0o666
+        );
+        fs::remove_file(&path).unwrap();
+    }
 }
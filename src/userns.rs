@@ -0,0 +1,61 @@
+//! uid/gid translation through a process's user namespace, for `setns_user`.
+//!
+//! The kernel refuses `setns(CLONE_NEWUSER)` from a multithreaded process, so pfwd never joins the
+//! user namespace itself. Instead `owner` is read as ids inside the namespace and mapped to the host
+//! ids that appear as those from within, which is what `chown` needs to be given from outside.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+
+use crate::config::Owner;
+
+/// One `/proc/<pid>/{uid,gid}_map`: ranges of `inside outside count`.
+struct IdMap {
+    ranges: Vec<(u32, u32, u32)>,
+}
+
+impl IdMap {
+    fn read(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let ranges = text
+            .lines()
+            .map(|line| {
+                let mut fields = line.split_whitespace().map(str::parse::<u32>);
+                match (fields.next(), fields.next(), fields.next()) {
+                    (Some(Ok(inside)), Some(Ok(outside)), Some(Ok(count))) => {
+                        Ok((inside, outside, count))
+                    }
+                    _ => bail!("malformed line {line:?} in {}", path.display()),
+                }
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { ranges })
+    }
+
+    fn to_host(&self, id: u32) -> Option<u32> {
+        self.ranges.iter().find_map(|&(inside, outside, count)| {
+            let offset = id.checked_sub(inside)?;
+            (offset < count).then(|| outside + offset)
+        })
+    }
+}
+
+/// Host ids that process `pid`'s user namespace sees as `owner` (root when unset).
+pub fn host_owner(pid: u32, owner: Option<&Owner>) -> Result<Owner> {
+    let (uid, gid) = owner.map_or((0, 0), |owner| (owner.uid, owner.gid));
+    let uid_map = IdMap::read(Path::new(&format!("/proc/{pid}/uid_map")))?;
+    let gid_map = IdMap::read(Path::new(&format!("/proc/{pid}/gid_map")))?;
+    let Some(host_uid) = uid_map.to_host(uid) else {
+        bail!("uid {uid} is not mapped in the user namespace of process {pid}");
+    };
+    let Some(host_gid) = gid_map.to_host(gid) else {
+        bail!("gid {gid} is not mapped in the user namespace of process {pid}");
+    };
+    Ok(Owner {
+        uid: host_uid,
+        gid: host_gid,
+    })
+}
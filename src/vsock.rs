@@ -0,0 +1,197 @@
+//! AF_VSOCK listeners and targets, written as `vsock:<cid>:<port>` (e.g. `vsock:3:2222`).
+//!
+//! vsock lets pfwd bridge between VM guests and host namespaces without any guest networking.
+//! tokio has no vsock types, so the sockets are driven through [`AsyncFd`] directly.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context as TaskContext, Poll, ready};
+
+use anyhow::{Context, Result, bail};
+use socket2::{Domain, SockAddr, Socket, Type};
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const SCHEME: &str = "vsock:";
+/// `VMADDR_CID_ANY`, accepted as `vsock:any:<port>` for listeners.
+const CID_ANY: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VsockAddr {
+    pub cid: u32,
+    pub port: u32,
+}
+
+impl VsockAddr {
+    /// Returns `Ok(None)` for addresses that are not `vsock:` URIs.
+    pub fn parse(addr: &str) -> Result<Option<Self>> {
+        match addr.strip_prefix(SCHEME) {
+            Some(_) => addr.parse().map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl FromStr for VsockAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let rest = s
+            .strip_prefix(SCHEME)
+            .with_context(|| format!("{s} is not a vsock:<cid>:<port> address"))?;
+        let (cid, port) = rest
+            .split_once(':')
+            .with_context(|| format!("vsock address {s} must look like vsock:<cid>:<port>"))?;
+        let cid = match cid {
+            "any" => CID_ANY,
+            cid => cid
+                .parse()
+                .with_context(|| format!("invalid vsock cid in {s}"))?,
+        };
+        let port = port
+            .parse()
+            .with_context(|| format!("invalid vsock port in {s}"))?;
+        Ok(Self { cid, port })
+    }
+}
+
+impl fmt::Display for VsockAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.cid == CID_ANY {
+            write!(f, "{SCHEME}any:{}", self.port)
+        } else {
+            write!(f, "{SCHEME}{}:{}", self.cid, self.port)
+        }
+    }
+}
+
+fn vsock_socket() -> Result<Socket> {
+    let socket = Socket::new(Domain::VSOCK, Type::STREAM, None)
+        .context("failed to create vsock socket (is vsock available on this host?)")?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+pub struct VsockListener {
+    inner: AsyncFd<Socket>,
+}
+
+impl VsockListener {
+    pub fn bind(addr: VsockAddr, backlog: u32) -> Result<Self> {
+        let socket = vsock_socket()?;
+        socket
+            .bind(&SockAddr::vsock(addr.cid, addr.port))
+            .with_context(|| format!("failed to bind {addr}"))?;
+        socket.listen(backlog.try_into().unwrap_or(i32::MAX))?;
+        Ok(Self {
+            inner: AsyncFd::new(socket)?,
+        })
+    }
+
+    pub async fn accept(&self) -> io::Result<(VsockStream, VsockAddr)> {
+        loop {
+            let mut guard = self.inner.readable().await?;
+            match guard.try_io(|inner| inner.get_ref().accept()) {
+                Ok(Ok((socket, peer))) => {
+                    socket.set_nonblocking(true)?;
+                    let (cid, port) = peer.as_vsock_address().unwrap_or((CID_ANY, 0));
+                    let stream = VsockStream {
+                        inner: AsyncFd::new(socket)?,
+                    };
+                    return Ok((stream, VsockAddr { cid, port }));
+                }
+                Ok(Err(err)) => return Err(err),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+pub struct VsockStream {
+    inner: AsyncFd<Socket>,
+}
+
+impl VsockStream {
+    pub async fn connect(addr: VsockAddr) -> Result<Self> {
+        if addr.cid == CID_ANY {
+            bail!("vsock targets need a concrete cid, not `any`");
+        }
+        let socket = vsock_socket()?;
+        match socket.connect(&SockAddr::vsock(addr.cid, addr.port)) {
+            Ok(()) => {}
+            Err(err) if err.raw_os_error() == Some(nix::libc::EINPROGRESS) => {}
+            Err(err) => return Err(err).with_context(|| format!("failed to connect {addr}")),
+        }
+        let inner = AsyncFd::new(socket)?;
+        let _ = inner.writable().await?;
+        if let Some(err) = inner.get_ref().take_error()? {
+            return Err(err).with_context(|| format!("failed to connect {addr}"));
+        }
+        Ok(Self { inner })
+    }
+}
+
+impl VsockStream {
+    pub async fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.inner.readable().await?;
+            match guard.try_io(|inner| {
+                // SAFETY: `recv` only writes initialised bytes into the buffer it is given.
+                let uninit =
+                    unsafe { &mut *(buf as *mut [u8] as *mut [std::mem::MaybeUninit<u8>]) };
+                inner.get_ref().peek(uninit)
+            }) {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncRead for VsockStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.inner.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|inner| inner.get_ref().read(unfilled)) {
+                Ok(Ok(len)) => {
+                    buf.advance(len);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(err)) => return Poll::Ready(Err(err)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for VsockStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.inner.poll_write_ready(cx))?;
+            match guard.try_io(|inner| inner.get_ref().write(buf)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.inner.get_ref().shutdown(Shutdown::Write))
+    }
+}
@@ -0,0 +1,636 @@
+//! Minimal yamux session used to multiplex many client sessions over one Unix stream.
+//!
+//! Only the subset of the protocol pfwd needs is implemented: data/window-update framing with
+//! per-stream flow control, SYN/ACK/FIN/RST stream lifecycle, ping replies, and go-away. Each
+//! logical stream is surfaced as a [`DuplexStream`] so the pipeline code can keep using
+//! `copy_bidirectional` unchanged.
+//!
+//! Memory stays bounded whatever the peer does: a stream that sends past the receive window it was
+//! granted is reset, outbound data waits in a bounded queue, and at most one ping reply is queued
+//! at a time.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Result, bail};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf};
+use tokio::io::{WriteHalf, duplex, split};
+use tokio::sync::{Notify, Semaphore, mpsc};
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+const VERSION: u8 = 0;
+const HEADER_LEN: usize = 12;
+
+const TYPE_DATA: u8 = 0;
+const TYPE_WINDOW_UPDATE: u8 = 1;
+const TYPE_PING: u8 = 2;
+const TYPE_GO_AWAY: u8 = 3;
+
+const FLAG_SYN: u16 = 1;
+const FLAG_ACK: u16 = 2;
+const FLAG_FIN: u16 = 4;
+const FLAG_RST: u16 = 8;
+
+/// Default yamux receive window for every stream.
+const INITIAL_WINDOW: usize = 256 * 1024;
+/// Largest data frame we emit; keeps per-stream latency fair when many streams are busy.
+const MAX_FRAME: usize = 16 * 1024;
+/// Buffer between the user-facing half of a stream and its pump tasks.
+const STREAM_BUFFER: usize = 64 * 1024;
+/// Data frames waiting for the transport, across every stream, before writers wait.
+const DATA_QUEUE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Opens streams with odd identifiers (host proxy side).
+    Client,
+    /// Accepts streams and would open even identifiers (namespace endpoint side).
+    Server,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Frame {
+    ty: u8,
+    flags: u16,
+    stream_id: u32,
+    length: u32,
+    body: Vec<u8>,
+}
+
+impl Frame {
+    /// A frame from its header, without the body a data frame's `length` announces.
+    fn decode_header(header: &[u8; HEADER_LEN]) -> Result<Self> {
+        if header[0] != VERSION {
+            bail!("unsupported yamux version {}", header[0]);
+        }
+        Ok(Self {
+            ty: header[1],
+            flags: u16::from_be_bytes([header[2], header[3]]),
+            stream_id: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+            length: u32::from_be_bytes([header[8], header[9], header[10], header[11]]),
+            body: Vec::new(),
+        })
+    }
+
+    fn data(stream_id: u32, flags: u16, body: Vec<u8>) -> Self {
+        Self {
+            ty: TYPE_DATA,
+            flags,
+            stream_id,
+            length: body.len() as u32,
+            body,
+        }
+    }
+
+    fn window_update(stream_id: u32, flags: u16, delta: u32) -> Self {
+        Self {
+            ty: TYPE_WINDOW_UPDATE,
+            flags,
+            stream_id,
+            length: delta,
+            body: Vec::new(),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + self.body.len());
+        out.push(VERSION);
+        out.push(self.ty);
+        out.extend_from_slice(&self.flags.to_be_bytes());
+        out.extend_from_slice(&self.stream_id.to_be_bytes());
+        out.extend_from_slice(&self.length.to_be_bytes());
+        out.extend_from_slice(&self.body);
+        out
+    }
+}
+
+enum Inbound {
+    Data(Vec<u8>),
+    Fin,
+    Rst,
+}
+
+struct StreamEntry {
+    tx: mpsc::UnboundedSender<Inbound>,
+    window: Arc<Semaphore>,
+    /// What the peer may still send before our next window update; never more than
+    /// [`INITIAL_WINDOW`] is queued for the stream.
+    recv_window: Arc<AtomicUsize>,
+    reset: Arc<Notify>,
+}
+
+impl StreamEntry {
+    fn reset(&self) {
+        self.window.close();
+        self.reset.notify_one();
+        let _ = self.tx.send(Inbound::Rst);
+    }
+}
+
+struct Shared {
+    streams: Mutex<HashMap<u32, StreamEntry>>,
+    /// Data frames, whose senders wait while the transport is behind.
+    data: mpsc::Sender<Frame>,
+    /// Window updates and stream lifecycle, written ahead of queued data.
+    control: mpsc::UnboundedSender<Frame>,
+    /// A ping reply is queued; further pings go unanswered until it is written.
+    pong_queued: AtomicBool,
+    next_id: AtomicU32,
+    closed: AtomicBool,
+}
+
+/// One multiplexed connection. Dropping the session tears down the underlying transport and every
+/// stream still attached to it.
+pub struct Session {
+    shared: Arc<Shared>,
+    incoming: mpsc::UnboundedReceiver<DuplexStream>,
+    reader: JoinHandle<()>,
+    writer: JoinHandle<()>,
+}
+
+impl Session {
+    pub fn new<T>(io: T, mode: Mode) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (rd, wr) = split(io);
+        let (data_tx, data_rx) = mpsc::channel(DATA_QUEUE);
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        let shared = Arc::new(Shared {
+            streams: Mutex::new(HashMap::new()),
+            data: data_tx,
+            control: control_tx,
+            pong_queued: AtomicBool::new(false),
+            next_id: AtomicU32::new(match mode {
+                Mode::Client => 1,
+                Mode::Server => 2,
+            }),
+            closed: AtomicBool::new(false),
+        });
+        let writer = tokio::spawn(write_loop(wr, control_rx, data_rx, shared.clone()));
+        let reader = tokio::spawn(read_loop(rd, shared.clone(), mode, incoming_tx));
+        Self {
+            shared,
+            incoming,
+            reader,
+            writer,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire)
+    }
+
+    /// Open a new outbound stream. Data may be written immediately; the peer's ACK is not awaited.
+    pub fn open(&self) -> Result<DuplexStream> {
+        if self.is_closed() {
+            bail!("yamux session closed");
+        }
+        let id = self.shared.next_id.fetch_add(2, Ordering::Relaxed);
+        if id > u32::MAX - 2 {
+            bail!("yamux stream identifiers exhausted");
+        }
+        let stream = attach_stream(&self.shared, id);
+        if self
+            .shared
+            .control
+            .send(Frame::window_update(id, FLAG_SYN, 0))
+            .is_err()
+        {
+            bail!("yamux session closed");
+        }
+        Ok(stream)
+    }
+
+    /// Wait for the next stream opened by the peer. Returns `None` once the transport is gone.
+    pub async fn accept(&mut self) -> Option<DuplexStream> {
+        self.incoming.recv().await
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.reader.abort();
+        self.writer.abort();
+        close_all(&self.shared);
+    }
+}
+
+fn close_all(shared: &Shared) {
+    shared.closed.store(true, Ordering::Release);
+    let streams: Vec<_> = shared.streams.lock().expect("poisoned").drain().collect();
+    for (_, entry) in streams {
+        entry.reset();
+    }
+}
+
+async fn write_loop<W>(
+    mut wr: WriteHalf<W>,
+    mut control: mpsc::UnboundedReceiver<Frame>,
+    mut data: mpsc::Receiver<Frame>,
+    shared: Arc<Shared>,
+) where
+    W: AsyncWrite,
+{
+    loop {
+        // A stream's SYN is queued before any of its data, so it is written first.
+        let frame = tokio::select! {
+            biased;
+            frame = control.recv() => frame,
+            frame = data.recv() => frame,
+        };
+        let Some(frame) = frame else {
+            break;
+        };
+        if frame.ty == TYPE_PING {
+            shared.pong_queued.store(false, Ordering::Release);
+        }
+        if let Err(err) = wr.write_all(&frame.encode()).await {
+            debug!(error = %err, "yamux transport write failed");
+            break;
+        }
+    }
+    close_all(&shared);
+}
+
+async fn read_loop<R>(
+    mut rd: ReadHalf<R>,
+    shared: Arc<Shared>,
+    mode: Mode,
+    incoming: mpsc::UnboundedSender<DuplexStream>,
+) where
+    R: AsyncRead,
+{
+    if let Err(err) = read_frames(&mut rd, &shared, mode, &incoming).await {
+        debug!(error = %err, "yamux transport closed");
+    }
+    close_all(&shared);
+}
+
+async fn read_frames<R>(
+    rd: &mut ReadHalf<R>,
+    shared: &Arc<Shared>,
+    mode: Mode,
+    incoming: &mpsc::UnboundedSender<DuplexStream>,
+) -> Result<()>
+where
+    R: AsyncRead,
+{
+    let mut header = [0u8; HEADER_LEN];
+    loop {
+        rd.read_exact(&mut header).await?;
+        let mut frame = Frame::decode_header(&header)?;
+        match frame.ty {
+            TYPE_DATA | TYPE_WINDOW_UPDATE => {
+                if frame.ty == TYPE_DATA && frame.length > 0 {
+                    if frame.length as usize > INITIAL_WINDOW {
+                        bail!(
+                            "yamux frame of {} bytes exceeds receive window",
+                            frame.length
+                        );
+                    }
+                    frame.body = vec![0u8; frame.length as usize];
+                    rd.read_exact(&mut frame.body).await?;
+                }
+                handle_stream_frame(shared, mode, incoming, frame);
+            }
+            TYPE_PING => {
+                if frame.flags & FLAG_SYN != 0 && !shared.pong_queued.swap(true, Ordering::AcqRel) {
+                    let _ = shared.control.send(Frame {
+                        ty: TYPE_PING,
+                        flags: FLAG_ACK,
+                        stream_id: 0,
+                        length: frame.length,
+                        body: Vec::new(),
+                    });
+                }
+            }
+            TYPE_GO_AWAY => return Ok(()),
+            other => bail!("unknown yamux frame type {other}"),
+        }
+    }
+}
+
+fn handle_stream_frame(
+    shared: &Arc<Shared>,
+    mode: Mode,
+    incoming: &mpsc::UnboundedSender<DuplexStream>,
+    frame: Frame,
+) {
+    let Frame {
+        ty,
+        flags,
+        stream_id,
+        length,
+        body,
+    } = frame;
+    if flags & FLAG_SYN != 0 {
+        let known = shared
+            .streams
+            .lock()
+            .expect("poisoned")
+            .contains_key(&stream_id);
+        if !known {
+            if mode == Mode::Client {
+                let _ = shared
+                    .control
+                    .send(Frame::window_update(stream_id, FLAG_RST, 0));
+                return;
+            }
+            let stream = attach_stream(shared, stream_id);
+            let _ = shared
+                .control
+                .send(Frame::window_update(stream_id, FLAG_ACK, 0));
+            if incoming.send(stream).is_err() {
+                reset_stream(shared, stream_id);
+                return;
+            }
+        }
+    }
+
+    if flags & FLAG_RST != 0 {
+        if let Some(entry) = shared.streams.lock().expect("poisoned").remove(&stream_id) {
+            entry.reset();
+        }
+        return;
+    }
+
+    let streams = shared.streams.lock().expect("poisoned");
+    let Some(entry) = streams.get(&stream_id) else {
+        return;
+    };
+    if ty == TYPE_WINDOW_UPDATE {
+        entry.window.add_permits(length as usize);
+    } else if !body.is_empty() {
+        let granted = entry
+            .recv_window
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |window| {
+                window.checked_sub(body.len())
+            })
+            .is_ok();
+        if !granted {
+            drop(streams);
+            debug!(
+                stream_id,
+                "yamux stream overran its receive window; resetting it"
+            );
+            reset_stream(shared, stream_id);
+            return;
+        }
+        let _ = entry.tx.send(Inbound::Data(body));
+    }
+    if flags & FLAG_FIN != 0 {
+        let _ = entry.tx.send(Inbound::Fin);
+    }
+}
+
+fn reset_stream(shared: &Shared, stream_id: u32) {
+    if let Some(entry) = shared.streams.lock().expect("poisoned").remove(&stream_id) {
+        entry.reset();
+    }
+    let _ = shared
+        .control
+        .send(Frame::window_update(stream_id, FLAG_RST, 0));
+}
+
+/// Register a stream and spawn the pumps that translate between frames and its duplex pipe.
+fn attach_stream(shared: &Arc<Shared>, id: u32) -> DuplexStream {
+    let (user, inner) = duplex(STREAM_BUFFER);
+    let (tx, rx) = mpsc::unbounded_channel();
+    let window = Arc::new(Semaphore::new(INITIAL_WINDOW));
+    let recv_window = Arc::new(AtomicUsize::new(INITIAL_WINDOW));
+    let reset = Arc::new(Notify::new());
+    shared.streams.lock().expect("poisoned").insert(
+        id,
+        StreamEntry {
+            tx,
+            window: window.clone(),
+            recv_window: recv_window.clone(),
+            reset: reset.clone(),
+        },
+    );
+
+    let (rd, wr) = split(inner);
+    let outbound = tokio::spawn(pump_outbound(rd, window, reset, shared.clone(), id));
+    let inbound = tokio::spawn(pump_inbound(wr, rx, recv_window, shared.clone(), id));
+    let shared = shared.clone();
+    tokio::spawn(async move {
+        let _ = outbound.await;
+        let _ = inbound.await;
+        shared.streams.lock().expect("poisoned").remove(&id);
+    });
+    user
+}
+
+/// Copy bytes written by the local user into data frames, respecting the peer's send window.
+async fn pump_outbound(
+    mut rd: ReadHalf<DuplexStream>,
+    window: Arc<Semaphore>,
+    reset: Arc<Notify>,
+    shared: Arc<Shared>,
+    id: u32,
+) {
+    let mut buf = vec![0u8; MAX_FRAME];
+    loop {
+        let credit = tokio::select! {
+            _ = reset.notified() => return,
+            permit = window.acquire() => {
+                let Ok(permit) = permit else { return };
+                permit.forget();
+                let extra = window.available_permits().min(MAX_FRAME - 1);
+                match window.try_acquire_many(extra as u32) {
+                    Ok(more) => {
+                        more.forget();
+                        1 + extra
+                    }
+                    Err(_) => 1,
+                }
+            }
+        };
+        let read = tokio::select! {
+            _ = reset.notified() => return,
+            read = rd.read(&mut buf[..credit]) => read,
+        };
+        match read {
+            Ok(0) => {
+                let _ = shared
+                    .data
+                    .send(Frame::data(id, FLAG_FIN, Vec::new()))
+                    .await;
+                return;
+            }
+            Ok(n) => {
+                if n < credit {
+                    window.add_permits(credit - n);
+                }
+                let frame = Frame::data(id, 0, buf[..n].to_vec());
+                if shared.data.send(frame).await.is_err() {
+                    return;
+                }
+            }
+            Err(_) => {
+                reset_stream(&shared, id);
+                return;
+            }
+        }
+    }
+}
+
+/// Deliver frames received from the peer to the local user and replenish the peer's window as the
+/// data is handed off.
+async fn pump_inbound(
+    mut wr: WriteHalf<DuplexStream>,
+    mut rx: mpsc::UnboundedReceiver<Inbound>,
+    recv_window: Arc<AtomicUsize>,
+    shared: Arc<Shared>,
+    id: u32,
+) {
+    while let Some(event) = rx.recv().await {
+        match event {
+            Inbound::Data(body) => {
+                if wr.write_all(&body).await.is_err() {
+                    reset_stream(&shared, id);
+                    return;
+                }
+                recv_window.fetch_add(body.len(), Ordering::AcqRel);
+                let _ = shared
+                    .control
+                    .send(Frame::window_update(id, 0, body.len() as u32));
+            }
+            Inbound::Fin | Inbound::Rst => break,
+        }
+    }
+    let _ = wr.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::DuplexStream;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    const WAIT: Duration = Duration::from_secs(10);
+
+    async fn read_frame(io: &mut DuplexStream) -> Frame {
+        let mut header = [0u8; HEADER_LEN];
+        io.read_exact(&mut header).await.unwrap();
+        let mut frame = Frame::decode_header(&header).unwrap();
+        if frame.ty == TYPE_DATA {
+            frame.body = vec![0u8; frame.length as usize];
+            io.read_exact(&mut frame.body).await.unwrap();
+        }
+        frame
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let frames = [
+            Frame::data(7, FLAG_FIN, b"hello".to_vec()),
+            Frame::window_update(u32::MAX - 1, FLAG_SYN | FLAG_ACK, 65536),
+        ];
+        for frame in frames {
+            let encoded = frame.encode();
+            assert_eq!(encoded.len(), HEADER_LEN + frame.body.len());
+            let header: [u8; HEADER_LEN] = encoded[..HEADER_LEN].try_into().unwrap();
+            let mut decoded = Frame::decode_header(&header).unwrap();
+            decoded.body = encoded[HEADER_LEN..].to_vec();
+            assert_eq!(decoded, frame);
+        }
+        assert_eq!(
+            Frame::window_update(1, FLAG_RST, 0).encode(),
+            [0, 1, 0, 8, 0, 0, 0, 1, 0, 0, 0, 0]
+        );
+        let mut header = [0u8; HEADER_LEN];
+        header[0] = 1;
+        assert!(Frame::decode_header(&header).is_err());
+    }
+
+    #[tokio::test]
+    async fn streams_round_trip_past_the_window() {
+        let (client_io, server_io) = duplex(64 * 1024);
+        let client = Session::new(client_io, Mode::Client);
+        let mut server = Session::new(server_io, Mode::Server);
+        // Several windows' worth, so the transfer depends on window updates.
+        let payload: Vec<u8> = (0..4 * INITIAL_WINDOW).map(|i| i as u8).collect();
+
+        let echo = tokio::spawn(async move {
+            let mut stream = server.accept().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            stream.write_all(&received).await.unwrap();
+            stream.shutdown().await.unwrap();
+            // Keep the session up until the client has read the echo.
+            let _ = server.accept().await;
+        });
+
+        let mut stream = client.open().unwrap();
+        let (mut rd, mut wr) = split(&mut stream);
+        let send = async {
+            wr.write_all(&payload).await.unwrap();
+            wr.shutdown().await.unwrap();
+        };
+        let mut echoed = Vec::new();
+        let receive = rd.read_to_end(&mut echoed);
+        let ((), received) = timeout(WAIT, async { tokio::join!(send, receive) })
+            .await
+            .expect("echo stalled");
+        received.unwrap();
+        assert_eq!(echoed, payload);
+        drop(client);
+        echo.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn answers_pings() {
+        let (mut peer, io) = duplex(4096);
+        let _session = Session::new(io, Mode::Server);
+        let ping = Frame {
+            ty: TYPE_PING,
+            flags: FLAG_SYN,
+            stream_id: 0,
+            length: 42,
+            body: Vec::new(),
+        };
+        peer.write_all(&ping.encode()).await.unwrap();
+        let pong = timeout(WAIT, read_frame(&mut peer)).await.unwrap();
+        assert_eq!(
+            (pong.ty, pong.flags, pong.length),
+            (TYPE_PING, FLAG_ACK, 42)
+        );
+    }
+
+    #[tokio::test]
+    async fn resets_streams_that_overrun_their_window() {
+        let (mut peer, io) = duplex(1024 * 1024);
+        let mut session = Session::new(io, Mode::Server);
+        peer.write_all(&Frame::window_update(1, FLAG_SYN, 0).encode())
+            .await
+            .unwrap();
+        // The stream is never read, so no window update can make room for the second frame.
+        let _stream = timeout(WAIT, session.accept()).await.unwrap().unwrap();
+        let chunk = vec![0u8; INITIAL_WINDOW * 3 / 4];
+        for _ in 0..2 {
+            peer.write_all(&Frame::data(1, 0, chunk.clone()).encode())
+                .await
+                .unwrap();
+        }
+        let reset = timeout(WAIT, async {
+            loop {
+                let frame = read_frame(&mut peer).await;
+                if frame.flags & FLAG_RST != 0 {
+                    return frame;
+                }
+            }
+        })
+        .await
+        .expect("stream was not reset");
+        assert_eq!(reset.stream_id, 1);
+        assert!(session.shared.streams.lock().unwrap().get(&1).is_none());
+    }
+}